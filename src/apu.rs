@@ -1,9 +1,23 @@
 use std::collections::VecDeque;
 use serde::{Serialize, Deserialize};
+use crate::apulog::ApuWrite;
+use crate::blip::BlipBuffer;
+use crate::region::Region;
 
-const CPU_CLOCK_HZ: f64 = 1_789_773.0;
-const AUDIO_SAMPLE_RATE: f64 = 44100.0;
-const CYCLES_PER_SAMPLE: f64 = CPU_CLOCK_HZ / AUDIO_SAMPLE_RATE;
+/// Output sample rate until `Apu::set_sample_rate` picks another.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Output rates the front-end offers.
+pub const SAMPLE_RATES: [u32; 3] = [44100, 48000, 96000];
+/// Largest change `Apu::set_rate_adjust` makes to the output rate, as a fraction.
+pub const MAX_RATE_ADJUST: f64 = 0.005;
+/// Output samples `Apu::recent_samples` keeps, a power of two for the spectrum view.
+pub const SAMPLE_HISTORY_LEN: usize = 2048;
+
+// Register writes a frame can log before the buffer has to grow; games make a few hundred
+const WRITE_LOG_CAPACITY: usize = 8192;
+
+/// Channels in the order `Apu::set_channel_volume` indexes them.
+pub const CHANNEL_NAMES: [&str; 5] = ["Pulse 1", "Pulse 2", "Triangle", "Noise", "DMC"];
 
 const LENGTH_COUNTER_TABLE: [u8; 32] = [
     10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
@@ -22,8 +36,68 @@ const TRIANGLE_WAVE_TABLE: [u8; 32] = [
     12, 13, 14, 15,
 ];
 
-const NOISE_PERIOD_TABLE: [u16; 16] =
-    [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+// Middle of the triangle's range, 7.5, in the half steps `Triangle::output_half_steps` uses
+const TRIANGLE_MIDPOINT_HALF_STEPS: u8 = 15;
+// Mixer lookup sizes: pulse 1 + pulse 2 runs 0-30, and 3 * triangle + 2 * noise + DMC runs 0-202
+const PULSE_TABLE_LEN: usize = 31;
+const TND_TABLE_LEN: usize = 203;
+
+/// The APU's region-dependent periods, all in CPU cycles. PAL consoles run the APU
+/// from a slower clock with retuned tables; Dendy famiclones use the NTSC ones.
+struct ApuTiming {
+    noise_periods: [u16; 16],
+    /// DMC bit periods.
+    dmc_rates: [u16; 16],
+    /// Frame sequencer step points; the APU-cycle figures usually quoted are half these.
+    quarter_1: u32,
+    half_1: u32,
+    quarter_3: u32,
+    step4_irq: u32,
+    step4_half_2: u32,
+    step4_wrap: u32,
+    step5_half_2: u32,
+    step5_wrap: u32,
+}
+
+const NTSC_TIMING: ApuTiming = ApuTiming {
+    noise_periods: [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068],
+    dmc_rates: [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54],
+    quarter_1: 7457,
+    half_1: 14913,
+    quarter_3: 22371,
+    step4_irq: 29828,
+    step4_half_2: 29829,
+    step4_wrap: 29830,
+    step5_half_2: 37281,
+    step5_wrap: 37282,
+};
+
+const PAL_TIMING: ApuTiming = ApuTiming {
+    noise_periods: [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+    dmc_rates: [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50],
+    quarter_1: 8313,
+    half_1: 16627,
+    quarter_3: 24939,
+    step4_irq: 33252,
+    step4_half_2: 33253,
+    step4_wrap: 33254,
+    step5_half_2: 41565,
+    step5_wrap: 41566,
+};
+
+impl ApuTiming {
+    fn for_region(region: Region) -> &'static ApuTiming {
+        match region {
+            Region::Ntsc | Region::Dendy => &NTSC_TIMING,
+            Region::Pal => &PAL_TIMING,
+        }
+    }
+
+    // The period at the same table position in `to`; a value from neither table is kept
+    fn convert_period(period: u16, from: &[u16; 16], to: &[u16; 16]) -> u16 {
+        from.iter().position(|&p| p == period).map_or(period, |index| to[index])
+    }
+}
 
 #[derive(Default)]
 struct Envelope {
@@ -56,6 +130,8 @@ impl Envelope {
         self.start = true;
     }
 
+    // Quarter-frame clock. The divider counts period..=0, so after the start clock the
+    // decay level drops once every period + 1 clocks
     fn clock(&mut self) {
         if self.start {
             self.start = false;
@@ -135,29 +211,31 @@ impl Sweep {
         self.reload = true;
     }
 
-    fn clock(&mut self, timer_period: &mut u16, channel_num: u8) -> bool {
-        let mut muted = false;
-        let change = *timer_period >> self.shift;
-
+    // Period the unit would move the timer to; pulse 1 negates in one's complement, so
+    // it subtracts one more than pulse 2. Only a shift of 0 can take the difference
+    // below zero, and that never updates the period, so it is clamped for display.
+    fn target_period(&self, timer_period: u16, channel_num: u8) -> u16 {
+        let change = timer_period >> self.shift;
         if self.negate {
-            let subtract_amount = change + if channel_num == 1 { 1 } else { 0 };
-            if *timer_period < subtract_amount {
-                muted = true;
-            }
-        }
-
-        let target_period = if self.negate {
-            timer_period.wrapping_sub(change + if channel_num == 1 { 1 } else { 0 })
+            timer_period.saturating_sub(change + if channel_num == 1 { 1 } else { 0 })
         } else {
-            *timer_period + change
-        };
-
-        if *timer_period < 8 || target_period > 0x7FF {
-            muted = true;
+            timer_period + change
         }
+    }
+
+    // Whether the unit silences the channel. This is combinational on hardware, so it
+    // follows every period write straight away, whether or not the sweep is enabled.
+    // A negated target is never above the current period and so never mutes, which
+    // leaves the result the same for both channels.
+    fn mutes(&self, timer_period: u16) -> bool {
+        timer_period < 8 || (!self.negate && self.target_period(timer_period, 2) > 0x7FF)
+    }
 
-        if self.divider == 0 && self.enabled && self.shift > 0 && !muted {
-            *timer_period = target_period;
+    // Half-frame clock. The period is updated whenever the divider is at 0, which after
+    // a $4001/$4005 write can be the very next clock, and then every period + 1 clocks
+    fn clock(&mut self, timer_period: &mut u16, channel_num: u8) {
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.mutes(*timer_period) {
+            *timer_period = self.target_period(*timer_period, channel_num);
         }
 
         if self.divider == 0 || self.reload {
@@ -166,7 +244,6 @@ impl Sweep {
         } else {
             self.divider -= 1;
         }
-        muted
     }
 
     fn save_state(&self) -> SweepState {
@@ -190,6 +267,27 @@ impl Sweep {
     }
 }
 
+/// Length counter writes waiting for the APU cycle they land on. The frame counter may
+/// clock the length counter on that same cycle, and the hardware orders the two: a
+/// reload is dropped if the clock found the counter above zero, and a new halt flag
+/// only takes effect after the clock.
+#[derive(Default)]
+struct PendingLength {
+    reload: Option<u8>,
+    halt: Option<bool>,
+}
+
+impl PendingLength {
+    fn apply(&mut self, counter: &mut u8, halt: &mut bool, clocked_nonzero: bool) {
+        if let Some(value) = self.reload.take().filter(|_| !clocked_nonzero) {
+            *counter = value;
+        }
+        if let Some(value) = self.halt.take() {
+            *halt = value;
+        }
+    }
+}
+
 #[derive(Default)]
 struct Pulse {
     enabled: bool,
@@ -201,7 +299,7 @@ struct Pulse {
     timer_value: u16,
     length_counter: u8,
     length_counter_halt: bool,
-    sweep_muted: bool,
+    pending_length: PendingLength,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -215,7 +313,6 @@ pub struct PulseState {
     timer_value: u16,
     length_counter: u8,
     length_counter_halt: bool,
-    sweep_muted: bool,
 }
 
 impl Pulse {
@@ -239,7 +336,7 @@ impl Pulse {
     }
 
     fn clock_sweep(&mut self, channel_num: u8) {
-        self.sweep_muted = self.sweep.clock(&mut self.timer_period, channel_num);
+        self.sweep.clock(&mut self.timer_period, channel_num);
     }
 
     fn clock_envelope(&mut self) {
@@ -250,8 +347,7 @@ impl Pulse {
         if !self.enabled
             || self.length_counter == 0
             || PULSE_DUTY_TABLE[self.duty_mode as usize][self.duty_step as usize] == 0
-            || self.timer_period < 8
-            || self.sweep_muted
+            || self.sweep.mutes(self.timer_period)
         {
             0
         } else {
@@ -259,9 +355,22 @@ impl Pulse {
         }
     }
 
+    fn debug_info(&self, channel_num: u8) -> ChannelDebugInfo {
+        ChannelDebugInfo {
+            enabled: self.enabled,
+            period: self.timer_period,
+            timer: self.timer_value,
+            length_counter: self.length_counter,
+            volume: self.envelope.output(),
+            duty: self.duty_mode,
+            sweep_target: self.sweep.target_period(self.timer_period, channel_num),
+            output: self.output(),
+        }
+    }
+
     fn write_ctrl(&mut self, data: u8) {
         self.duty_mode = (data & 0xC0) >> 6;
-        self.length_counter_halt = (data & 0x20) != 0;
+        self.pending_length.halt = Some((data & 0x20) != 0);
         self.envelope.write(data);
     }
 
@@ -275,8 +384,10 @@ impl Pulse {
 
     fn write_timer_hi(&mut self, data: u8) {
         self.timer_period = (self.timer_period & 0x00FF) | (((data & 0x07) as u16) << 8);
+        // A channel disabled through $4015 ignores the length load, but the timer,
+        // sequencer and envelope restart below still happen
         if self.enabled {
-            self.length_counter = LENGTH_COUNTER_TABLE[(data >> 3) as usize];
+            self.pending_length.reload = Some(LENGTH_COUNTER_TABLE[(data >> 3) as usize]);
         }
         self.timer_value = self.timer_period;
         self.envelope.start = true;
@@ -287,9 +398,14 @@ impl Pulse {
         self.enabled = enabled;
         if !enabled {
             self.length_counter = 0;
+            self.pending_length.reload = None;
         }
     }
 
+    fn apply_length_writes(&mut self, clocked_nonzero: bool) {
+        self.pending_length.apply(&mut self.length_counter, &mut self.length_counter_halt, clocked_nonzero);
+    }
+
     fn save_state(&self) -> PulseState {
         PulseState {
             enabled: self.enabled,
@@ -301,7 +417,6 @@ impl Pulse {
             timer_value: self.timer_value,
             length_counter: self.length_counter,
             length_counter_halt: self.length_counter_halt,
-            sweep_muted: self.sweep_muted,
         }
     }
 
@@ -315,7 +430,7 @@ impl Pulse {
         self.timer_value = state.timer_value;
         self.length_counter = state.length_counter;
         self.length_counter_halt = state.length_counter_halt;
-        self.sweep_muted = state.sweep_muted;
+        self.pending_length = PendingLength::default();
     }
 }
 
@@ -327,9 +442,13 @@ struct Triangle {
     duty_step: u8,
     length_counter: u8,
     length_counter_halt: bool,
+    pending_length: PendingLength,
     linear_counter: u8,
     linear_counter_period: u8,
     linear_counter_reload: bool,
+    // Holds the output at its midpoint while the period is ultrasonic; a listener
+    // setting, so it is not part of the saved state
+    silence_ultrasonic: bool,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -347,7 +466,15 @@ pub struct TriangleState {
 
 impl Triangle {
     fn new() -> Self {
-        Self::default()
+        Triangle {
+            silence_ultrasonic: true,
+            ..Self::default()
+        }
+    }
+
+    // Periods 0 and 1 step the sequencer at over 440 kHz, far above anything audible
+    fn is_ultrasonic(&self) -> bool {
+        self.timer_period < 2
     }
 
     fn clock_timer(&mut self) {
@@ -355,7 +482,9 @@ impl Triangle {
             self.timer_value -= 1;
         } else {
             self.timer_value = self.timer_period;
-            if self.length_counter > 0 && self.linear_counter > 0 && self.timer_period > 1 {
+            // While silenced the sequencer is held, so the wave resumes where it left off
+            let held = self.silence_ultrasonic && self.is_ultrasonic();
+            if self.length_counter > 0 && self.linear_counter > 0 && !held {
                 self.duty_step = (self.duty_step + 1) % 32;
             }
         }
@@ -379,14 +508,34 @@ impl Triangle {
     }
 
     fn output(&self) -> u8 {
+        self.output_half_steps() / 2
+    }
+
+    // Level in half steps, so the 7.5 midpoint an ultrasonic wave averages out to is exact
+    fn output_half_steps(&self) -> u8 {
         if !self.enabled || self.length_counter == 0 || self.linear_counter == 0 {
             return 0;
         }
-        TRIANGLE_WAVE_TABLE[self.duty_step as usize]
+        if self.silence_ultrasonic && self.is_ultrasonic() {
+            return TRIANGLE_MIDPOINT_HALF_STEPS;
+        }
+        TRIANGLE_WAVE_TABLE[self.duty_step as usize] * 2
+    }
+
+    fn debug_info(&self) -> ChannelDebugInfo {
+        ChannelDebugInfo {
+            enabled: self.enabled,
+            period: self.timer_period,
+            timer: self.timer_value,
+            length_counter: self.length_counter,
+            volume: self.linear_counter,
+            output: self.output(),
+            ..ChannelDebugInfo::default()
+        }
     }
 
     fn write_ctrl(&mut self, data: u8) {
-        self.length_counter_halt = (data & 0x80) != 0;
+        self.pending_length.halt = Some((data & 0x80) != 0);
         self.linear_counter_period = data & 0x7F;
     }
 
@@ -397,8 +546,9 @@ impl Triangle {
     fn write_timer_hi(&mut self, data: u8) {
         self.timer_period = (self.timer_period & 0x00FF) | (((data & 0x07) as u16) << 8);
         if self.enabled {
-            self.length_counter = LENGTH_COUNTER_TABLE[(data >> 3) as usize];
+            self.pending_length.reload = Some(LENGTH_COUNTER_TABLE[(data >> 3) as usize]);
         }
+        // The linear counter reload is requested even while the channel is disabled
         self.linear_counter_reload = true;
     }
 
@@ -406,9 +556,14 @@ impl Triangle {
         self.enabled = enabled;
         if !enabled {
             self.length_counter = 0;
+            self.pending_length.reload = None;
         }
     }
 
+    fn apply_length_writes(&mut self, clocked_nonzero: bool) {
+        self.pending_length.apply(&mut self.length_counter, &mut self.length_counter_halt, clocked_nonzero);
+    }
+
     fn save_state(&self) -> TriangleState {
         TriangleState {
             enabled: self.enabled,
@@ -430,6 +585,7 @@ impl Triangle {
         self.duty_step = state.duty_step;
         self.length_counter = state.length_counter;
         self.length_counter_halt = state.length_counter_halt;
+        self.pending_length = PendingLength::default();
         self.linear_counter = state.linear_counter;
         self.linear_counter_period = state.linear_counter_period;
         self.linear_counter_reload = state.linear_counter_reload;
@@ -444,6 +600,7 @@ struct Noise {
     timer_value: u16,
     length_counter: u8,
     length_counter_halt: bool,
+    pending_length: PendingLength,
     mode: bool,
     shift_register: u16,
 }
@@ -502,20 +659,34 @@ impl Noise {
         }
     }
 
+    fn debug_info(&self) -> ChannelDebugInfo {
+        ChannelDebugInfo {
+            enabled: self.enabled,
+            period: self.timer_period,
+            timer: self.timer_value,
+            length_counter: self.length_counter,
+            volume: self.envelope.output(),
+            duty: self.mode as u8,
+            output: self.output(),
+            ..ChannelDebugInfo::default()
+        }
+    }
+
     fn write_ctrl(&mut self, data: u8) {
-        self.length_counter_halt = (data & 0x20) != 0;
+        self.pending_length.halt = Some((data & 0x20) != 0);
         self.envelope.write(data);
     }
 
-    fn write_period(&mut self, data: u8) {
+    fn write_period(&mut self, data: u8, timing: &ApuTiming) {
         self.mode = (data & 0x80) != 0;
-        self.timer_period = NOISE_PERIOD_TABLE[(data & 0x0F) as usize];
+        self.timer_period = timing.noise_periods[(data & 0x0F) as usize];
     }
 
     fn write_length(&mut self, data: u8) {
         if self.enabled {
-            self.length_counter = LENGTH_COUNTER_TABLE[(data >> 3) as usize];
+            self.pending_length.reload = Some(LENGTH_COUNTER_TABLE[(data >> 3) as usize]);
         }
+        // Restarts the envelope whether or not the length load was accepted
         self.envelope.start = true;
     }
 
@@ -523,9 +694,14 @@ impl Noise {
         self.enabled = enabled;
         if !enabled {
             self.length_counter = 0;
+            self.pending_length.reload = None;
         }
     }
 
+    fn apply_length_writes(&mut self, clocked_nonzero: bool) {
+        self.pending_length.apply(&mut self.length_counter, &mut self.length_counter_halt, clocked_nonzero);
+    }
+
     fn save_state(&self) -> NoiseState {
         NoiseState {
             enabled: self.enabled,
@@ -546,120 +722,756 @@ impl Noise {
         self.timer_value = state.timer_value;
         self.length_counter = state.length_counter;
         self.length_counter_halt = state.length_counter_halt;
+        self.pending_length = PendingLength::default();
         self.mode = state.mode;
         self.shift_register = state.shift_register;
     }
 }
 
+/// Delta modulation channel. A 7-bit output level is stepped up or down by 2 for each
+/// bit of a 1-bit delta sample read from PRG space, or set directly through $4011.
+#[derive(Default)]
+struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer_value: u16,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+    interrupt: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct DmcState {
+    irq_enabled: bool,
+    loop_flag: bool,
+    timer_period: u16,
+    timer_value: u16,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+    interrupt: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            timer_period: NTSC_TIMING.dmc_rates[0],
+            sample_address: 0xC000,
+            sample_length: 1,
+            silence: true,
+            ..Self::default()
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer_value > 0 {
+            self.timer_value -= 1;
+        } else {
+            self.timer_value = self.timer_period - 1;
+            self.clock_output();
+        }
+    }
+
+    // Shifts out one bit, moving the output level unless that would leave 0..=127;
+    // every 8 bits the next byte is taken from the sample buffer, which leaves it empty
+    // for the memory reader to refill. With nothing buffered the channel stays silent,
+    // holding its level, for the next 8 bits.
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining = self.bits_remaining.saturating_sub(1);
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.shift_register = sample;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    // The "length counter" of a DMC sample is its bytes left, which can exceed 255
+    fn debug_info(&self) -> ChannelDebugInfo {
+        ChannelDebugInfo {
+            enabled: self.bytes_remaining > 0,
+            period: self.timer_period,
+            timer: self.timer_value,
+            output: self.output(),
+            ..ChannelDebugInfo::default()
+        }
+    }
+
+    /// Address the memory reader wants to fetch, if the sample buffer is empty and
+    /// the sample still has bytes left.
+    fn fetch_address(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // The reader wraps from the top of memory back to 0x8000, never into RAM
+        self.current_address = match self.current_address {
+            0xFFFF => 0x8000,
+            addr => addr + 1,
+        };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.interrupt = true;
+            }
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn write_ctrl(&mut self, data: u8, timing: &ApuTiming) {
+        self.irq_enabled = (data & 0x80) != 0;
+        self.loop_flag = (data & 0x40) != 0;
+        self.timer_period = timing.dmc_rates[(data & 0x0F) as usize];
+        if !self.irq_enabled {
+            self.interrupt = false;
+        }
+    }
+
+    // $4011 sets the 7-bit DAC level outright. Games play PCM by writing it in a timed
+    // loop, so the level reaches the mixer from the very next cycle; the output unit's
+    // +/-2 steps carry on from whatever was written.
+    fn write_direct_load(&mut self, data: u8) {
+        self.output_level = data & 0x7F;
+    }
+
+    fn write_address(&mut self, data: u8) {
+        self.sample_address = 0xC000 | ((data as u16) << 6);
+    }
+
+    fn write_length(&mut self, data: u8) {
+        self.sample_length = ((data as u16) << 4) | 1;
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.interrupt = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn save_state(&self) -> DmcState {
+        DmcState {
+            irq_enabled: self.irq_enabled,
+            loop_flag: self.loop_flag,
+            timer_period: self.timer_period,
+            timer_value: self.timer_value,
+            sample_address: self.sample_address,
+            sample_length: self.sample_length,
+            current_address: self.current_address,
+            bytes_remaining: self.bytes_remaining,
+            sample_buffer: self.sample_buffer,
+            shift_register: self.shift_register,
+            bits_remaining: self.bits_remaining,
+            silence: self.silence,
+            output_level: self.output_level,
+            interrupt: self.interrupt,
+        }
+    }
+
+    fn load_state(&mut self, state: &DmcState) {
+        self.irq_enabled = state.irq_enabled;
+        self.loop_flag = state.loop_flag;
+        self.timer_period = state.timer_period;
+        self.timer_value = state.timer_value;
+        self.sample_address = state.sample_address;
+        self.sample_length = state.sample_length;
+        self.current_address = state.current_address;
+        self.bytes_remaining = state.bytes_remaining;
+        self.sample_buffer = state.sample_buffer;
+        self.shift_register = state.shift_register;
+        self.bits_remaining = state.bits_remaining;
+        self.silence = state.silence;
+        self.output_level = state.output_level;
+        self.interrupt = state.interrupt;
+    }
+}
+
+// First-order IIR high-pass, y[n] = a * (y[n-1] + x[n] - x[n-1])
+struct HighPass {
+    coefficient: f32,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl HighPass {
+    fn new(cutoff_hz: f64, sample_rate: f64) -> Self {
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz);
+        HighPass { coefficient: (rc / (rc + 1.0 / sample_rate)) as f32, last_input: 0.0, last_output: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.last_output = self.coefficient * (self.last_output + input - self.last_input);
+        self.last_input = input;
+        self.last_output
+    }
+}
+
+// First-order IIR low-pass, y[n] = y[n-1] + b * (x[n] - y[n-1])
+struct LowPass {
+    coefficient: f32,
+    last_output: f32,
+}
+
+impl LowPass {
+    fn new(cutoff_hz: f64, sample_rate: f64) -> Self {
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz);
+        let dt = 1.0 / sample_rate;
+        LowPass { coefficient: (dt / (rc + dt)) as f32, last_output: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        self.last_output += self.coefficient * (input - self.last_output);
+        self.last_output
+    }
+}
+
+/// The filters between the console's mixer and its audio output: high-pass at 90 Hz
+/// and 440 Hz, then low-pass at 14 kHz, each first order.
+struct FilterChain {
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FilterChainState {
+    high_pass_90: (f32, f32),
+    high_pass_440: (f32, f32),
+    low_pass_14k: f32,
+}
+
+impl FilterChain {
+    fn new(sample_rate: f64) -> Self {
+        FilterChain {
+            high_pass_90: HighPass::new(90.0, sample_rate),
+            high_pass_440: HighPass::new(440.0, sample_rate),
+            low_pass_14k: LowPass::new(14_000.0, sample_rate),
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.high_pass_90.process(input);
+        let output = self.high_pass_440.process(output);
+        self.low_pass_14k.process(output)
+    }
+
+    fn save_state(&self) -> FilterChainState {
+        FilterChainState {
+            high_pass_90: (self.high_pass_90.last_input, self.high_pass_90.last_output),
+            high_pass_440: (self.high_pass_440.last_input, self.high_pass_440.last_output),
+            low_pass_14k: self.low_pass_14k.last_output,
+        }
+    }
+
+    fn load_state(&mut self, state: &FilterChainState) {
+        (self.high_pass_90.last_input, self.high_pass_90.last_output) = state.high_pass_90;
+        (self.high_pass_440.last_input, self.high_pass_440.last_output) = state.high_pass_440;
+        self.low_pass_14k.last_output = state.low_pass_14k;
+    }
+}
+
+impl FilterChainState {
+    fn is_finite(&self) -> bool {
+        [self.high_pass_90.0, self.high_pass_90.1, self.high_pass_440.0, self.high_pass_440.1, self.low_pass_14k]
+            .iter()
+            .all(|value| value.is_finite())
+    }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 enum FrameCounterMode {
     Step4,
     Step5,
 }
 
+impl FrameCounterMode {
+    fn to_state(self) -> u8 {
+        match self {
+            FrameCounterMode::Step4 => 0,
+            FrameCounterMode::Step5 => 1,
+        }
+    }
+
+    fn from_state(value: u8) -> Self {
+        match value {
+            0 => FrameCounterMode::Step4,
+            _ => FrameCounterMode::Step5,
+        }
+    }
+}
+
+/// One channel's registers and counters as the debugger shows them. Fields a channel
+/// does not have stay 0.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelDebugInfo {
+    /// Enabled in $4015; for the DMC, whether its sample has bytes left.
+    pub enabled: bool,
+    /// Timer reload value; CPU cycles for the noise and DMC, timer ticks otherwise.
+    pub period: u16,
+    pub timer: u16,
+    pub length_counter: u8,
+    /// Envelope or constant volume, 0-15; the triangle's linear counter.
+    pub volume: u8,
+    /// Pulse duty mode 0-3; the noise's short-loop mode bit.
+    pub duty: u8,
+    /// Period the pulse sweep unit would set next.
+    pub sweep_target: u16,
+    /// Level fed to the mixer right now: 0-15, or 0-127 for the DMC.
+    pub output: u8,
+}
+
+/// Snapshot of the APU for channel viewers and the console debugger; see `Apu::debug_snapshot`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ApuDebugInfo {
+    /// In `CHANNEL_NAMES` order.
+    pub channels: [ChannelDebugInfo; 5],
+    pub dmc_bytes_remaining: u16,
+    pub dmc_address: u16,
+    /// 4 or 5.
+    pub frame_counter_steps: u8,
+    /// CPU cycles into the current frame sequence.
+    pub frame_counter_cycle: u32,
+    pub frame_interrupt: bool,
+    pub dmc_interrupt: bool,
+}
+
 pub struct Apu {
     pulse1: Pulse,
     pulse2: Pulse,
     triangle: Triangle,
     noise: Noise,
-    dmc_enabled: bool,
+    dmc: Dmc,
     sample_accumulator: f64,
+    // CPU cycles per output sample; depends on the region's CPU clock, the output rate
+    // and the rate adjustment
+    cycles_per_sample: f64,
+    clock_hz: f64,
+    // Picks the noise, DMC and frame sequencer timing; see `ApuTiming`
+    region: Region,
+    // Output samples per second; a setting of the sound card, not part of the console state
+    sample_rate: u32,
+    // Output rate relative to 44.1 kHz, steered by the front-end; not part of the console state
+    rate_adjust: f64,
+    // Listener volume per channel, in `CHANNEL_NAMES` order; not part of the console state
+    channel_volume: [f32; 5],
+    // The only channel heard when set, whatever the volumes say; a listener setting too
+    solo: Option<usize>,
+    // The mixer's two nonlinear curves, see `Apu::mix`
+    pulse_table: [f32; PULSE_TABLE_LEN],
+    tnd_table: [f32; TND_TABLE_LEN],
+    // Band-limited synthesis in place of point sampling; a setting like the volumes
+    band_limited: bool,
+    blip: BlipBuffer,
+    // Channel levels last given to `blip`; None forces the next cycle to remix
+    blip_levels: Option<[u8; 5]>,
     cpu_cycle_counter: u64,
     sample_buffer: VecDeque<f32>,
+    // The last SAMPLE_HISTORY_LEN output samples, kept after take_samples drains them
+    sample_history: VecDeque<f32>,
+    // Output filtering: the console's filter chain, or the single high-pass kept for comparison
+    hardware_filters: bool,
+    filters: FilterChain,
     last_input_sample: f32,
     last_output_sample: f32,
     frame_counter_cycle: u32,
     frame_counter_mode: FrameCounterMode,
+    // Mode from the last $4017 write, applied once the write's delay has run out
+    pending_frame_counter_mode: FrameCounterMode,
+    // CPU cycles until a $4017 write restarts the sequencer; 0 when none is pending
+    frame_counter_reset_delay: u8,
     interrupt_inhibit: bool,
     frame_interrupt: bool,
+    // Set when the frame counter clocked the length counters during the current cycle
+    length_clocked: bool,
+    // Register writes since the front-end last drained them, while logging is on
+    write_log: Option<Vec<ApuWrite>>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ApuState {
+    region: Region,
     pulse1: PulseState,
     pulse2: PulseState,
     triangle: TriangleState,
     noise: NoiseState,
-    dmc_enabled: bool,
+    dmc: DmcState,
     sample_accumulator: f64,
     cpu_cycle_counter: u64,
     last_input_sample: f32,
     last_output_sample: f32,
+    filters: FilterChainState,
     frame_counter_cycle: u32,
     frame_counter_mode: u8,
+    pending_frame_counter_mode: u8,
+    frame_counter_reset_delay: u8,
     interrupt_inhibit: bool,
     frame_interrupt: bool,
 }
 
+impl ApuState {
+    /// Rejects values the APU could never hold, which would otherwise index out of
+    /// its tables or silence the noise channel for good.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (name, pulse) in [("Pulse 1", &self.pulse1), ("Pulse 2", &self.pulse2)] {
+            if pulse.duty_mode as usize >= PULSE_DUTY_TABLE.len() || pulse.duty_step >= 8 {
+                return Err(format!("{} duty {}/{} is out of range", name, pulse.duty_mode, pulse.duty_step));
+            }
+        }
+        if self.triangle.duty_step as usize >= TRIANGLE_WAVE_TABLE.len() {
+            return Err(format!("Triangle step {} is out of range", self.triangle.duty_step));
+        }
+        if self.noise.shift_register == 0 || self.noise.shift_register > 0x7FFF {
+            return Err(format!("Noise shift register {:#06X} is not a non-zero 15-bit value", self.noise.shift_register));
+        }
+        let timing = ApuTiming::for_region(self.region);
+        if !timing.dmc_rates.contains(&self.dmc.timer_period) || self.dmc.output_level > 0x7F || self.dmc.bits_remaining > 8 {
+            return Err(format!(
+                "DMC period {}, level {} or bit count {} is out of range",
+                self.dmc.timer_period, self.dmc.output_level, self.dmc.bits_remaining
+            ));
+        }
+        if self.frame_counter_mode > 1 || self.pending_frame_counter_mode > 1 {
+            return Err(format!("Unknown frame counter mode {}", self.frame_counter_mode.max(self.pending_frame_counter_mode)));
+        }
+        if self.frame_counter_reset_delay > 4 || self.frame_counter_cycle > timing.step5_wrap {
+            return Err(format!(
+                "Frame counter cycle {} or reset delay {} is out of range",
+                self.frame_counter_cycle, self.frame_counter_reset_delay
+            ));
+        }
+        if !(self.sample_accumulator.is_finite()
+            && self.last_input_sample.is_finite()
+            && self.last_output_sample.is_finite()
+            && self.filters.is_finite())
+        {
+            return Err("APU sample state is not a finite number".to_string());
+        }
+        Ok(())
+    }
+}
+
 impl Apu {
     pub fn new() -> Self {
+        Apu::with_sample_rate(DEFAULT_SAMPLE_RATE)
+    }
+
+    /// An APU whose output runs at `sample_rate` samples per second.
+    pub fn with_sample_rate(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
         Apu {
             pulse1: Pulse::new(),
             pulse2: Pulse::new(),
             triangle: Triangle::new(),
             noise: Noise::new(),
-            dmc_enabled: false,
+            dmc: Dmc::new(),
             sample_accumulator: 0.0,
+            cycles_per_sample: Region::Ntsc.cpu_clock_hz() / rate,
+            clock_hz: Region::Ntsc.cpu_clock_hz(),
+            region: Region::Ntsc,
+            sample_rate,
+            rate_adjust: 1.0,
+            channel_volume: [1.0; 5],
+            solo: None,
+            pulse_table: std::array::from_fn(|n| if n == 0 { 0.0 } else { 95.88 / (8128.0 / n as f32 + 100.0) }),
+            tnd_table: std::array::from_fn(|n| if n == 0 { 0.0 } else { 163.67 / (24329.0 / n as f32 + 100.0) }),
+            band_limited: true,
+            blip: BlipBuffer::new(Region::Ntsc.cpu_clock_hz(), rate),
+            blip_levels: None,
+            hardware_filters: true,
+            filters: FilterChain::new(rate),
             last_input_sample: 0.0,
             last_output_sample: 0.0,
             cpu_cycle_counter: 0,
             sample_buffer: VecDeque::with_capacity(4096),
+            sample_history: VecDeque::with_capacity(SAMPLE_HISTORY_LEN),
             frame_counter_cycle: 0,
             frame_counter_mode: FrameCounterMode::Step4,
+            pending_frame_counter_mode: FrameCounterMode::Step4,
+            frame_counter_reset_delay: 0,
             interrupt_inhibit: false,
             frame_interrupt: false,
+            length_clocked: false,
+            write_log: None,
         }
     }
 
+    /// Switches to the region's noise, DMC and frame sequencer timing and resamples for
+    /// its CPU clock so the output rate stays the same. Periods the game has already set
+    /// move to the same table entry in the new timing.
+    pub fn set_region(&mut self, region: Region) {
+        self.retime(self.region, region);
+        self.region = region;
+        self.clock_hz = region.cpu_clock_hz();
+        self.update_sample_clock();
+    }
+
+    fn retime(&mut self, from: Region, to: Region) {
+        let (from, to) = (ApuTiming::for_region(from), ApuTiming::for_region(to));
+        self.noise.timer_period = ApuTiming::convert_period(self.noise.timer_period, &from.noise_periods, &to.noise_periods);
+        self.dmc.timer_period = ApuTiming::convert_period(self.dmc.timer_period, &from.dmc_rates, &to.dmc_rates);
+    }
+
+    /// Scales the output rate by `ratio`, kept within `MAX_RATE_ADJUST` of 1.0, so the
+    /// front-end can make each frame's worth of audio a few samples longer or shorter and
+    /// hold its queue steady while video sets the pace. A shift this small cannot be heard.
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.rate_adjust = ratio.clamp(1.0 - MAX_RATE_ADJUST, 1.0 + MAX_RATE_ADJUST);
+        self.update_sample_clock();
+    }
+
+    pub fn rate_adjust(&self) -> f64 {
+        self.rate_adjust
+    }
+
+    /// Changes the output rate, for when the sound card runs at a rate other than the one
+    /// the APU was made with. The output filters are retuned without losing their state.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate || sample_rate == 0 {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        let state = self.filters.save_state();
+        self.filters = FilterChain::new(sample_rate as f64);
+        self.filters.load_state(&state);
+        self.update_sample_clock();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn update_sample_clock(&mut self) {
+        let sample_rate = self.sample_rate as f64 * self.rate_adjust;
+        self.cycles_per_sample = self.clock_hz / sample_rate;
+        self.blip.set_clock_rate(self.clock_hz, sample_rate);
+    }
+
+    /// Chooses between band-limited synthesis, on by default, which keeps high notes
+    /// free of aliasing, and the cheaper sampler that takes the mix every 1/44100 s.
+    pub fn set_band_limited(&mut self, enabled: bool) {
+        if enabled != self.band_limited {
+            self.band_limited = enabled;
+            self.blip.clear();
+            self.blip_levels = None;
+        }
+    }
+
+    pub fn band_limited(&self) -> bool {
+        self.band_limited
+    }
+
+    /// Chooses the console's output filters (high-pass at 90 Hz and 440 Hz, low-pass at
+    /// 14 kHz), on by default, or the single gentle high-pass used before, for comparison.
+    pub fn set_hardware_filters(&mut self, enabled: bool) {
+        self.hardware_filters = enabled;
+    }
+
+    pub fn hardware_filters(&self) -> bool {
+        self.hardware_filters
+    }
+
+    /// Holds the triangle at the middle of its range while its period is 0 or 1, on by
+    /// default. Games use those periods to silence the channel; the console then plays an
+    /// ultrasonic tone that only shows up as whine and clicks. Off steps the wave as the
+    /// hardware does.
+    pub fn set_silence_ultrasonic_triangle(&mut self, enabled: bool) {
+        self.triangle.silence_ultrasonic = enabled;
+    }
+
+    pub fn silence_ultrasonic_triangle(&self) -> bool {
+        self.triangle.silence_ultrasonic
+    }
+
+    /// Scales one channel's output level, 0.0 (silent) to 1.0 (as on hardware), before
+    /// the channels are mixed. The NES mixer is nonlinear, so turning one pulse channel
+    /// down also changes how loud the other sounds, and the triangle, noise and DMC
+    /// likewise affect each other; this models the hardware mix rather than a linear desk.
+    pub fn set_channel_volume(&mut self, channel: usize, volume: f32) {
+        if let Some(slot) = self.channel_volume.get_mut(channel) {
+            *slot = volume.clamp(0.0, 1.0);
+            self.blip_levels = None;
+        }
+    }
+
+    pub fn channel_volumes(&self) -> [f32; 5] {
+        self.channel_volume
+    }
+
+    /// Plays only one channel (a `CHANNEL_NAMES` index) at its own volume, or all of them
+    /// again with None. The others keep running unheard, so switching is seamless; since
+    /// the mix is nonlinear a soloed channel sounds a little louder than in the full mix.
+    pub fn set_solo(&mut self, channel: Option<usize>) {
+        self.solo = channel.filter(|&channel| channel < CHANNEL_NAMES.len());
+        self.blip_levels = None;
+    }
+
+    pub fn solo(&self) -> Option<usize> {
+        self.solo
+    }
+
     pub fn take_samples(&mut self) -> Vec<f32> {
         self.sample_buffer.drain(..).collect()
     }
 
-    pub fn poll_frame_interrupt(&mut self) -> bool {
-        let occurred = self.frame_interrupt;
-        self.frame_interrupt = false;
-        occurred
+    /// Copy of the most recent output samples, oldest first, for audio debugging. Holds
+    /// fewer than `SAMPLE_HISTORY_LEN` until that many have been produced.
+    pub fn recent_samples(&self) -> Vec<f32> {
+        self.sample_history.iter().copied().collect()
     }
 
-    fn clock_frame_counter_step(&mut self) {
-        const STEP1: u32 = 7457;
-        const STEP2: u32 = 14913;
-        const STEP3: u32 = 22371;
-        const STEP4_4STEP: u32 = 29781;
-        const STEP4_5STEP: u32 = 29781;
-        const STEP5_5STEP: u32 = 37281;
+    /// Starts or stops recording register writes for `logged_writes`. The buffer is
+    /// reserved up front so that recording a write never allocates, as long as the
+    /// front-end drains it every frame. Like the volumes, this survives a power cycle.
+    pub fn set_write_logging(&mut self, enabled: bool) {
+        if enabled != self.write_log.is_some() {
+            self.write_log = enabled.then(|| Vec::with_capacity(WRITE_LOG_CAPACITY));
+        }
+    }
 
-        match self.frame_counter_mode {
-            FrameCounterMode::Step4 => match self.frame_counter_cycle {
-                STEP1 => self.clock_quarter_frame(),
-                STEP2 => {
-                    self.clock_quarter_frame();
-                    self.clock_half_frame();
-                }
-                STEP3 => self.clock_quarter_frame(),
-                STEP4_4STEP => {
-                    self.clock_quarter_frame();
-                    self.clock_half_frame();
-                    if !self.interrupt_inhibit {
-                        self.frame_interrupt = true;
-                    }
-                }
-                _ => {}
-            },
-            FrameCounterMode::Step5 => match self.frame_counter_cycle {
-                STEP1 => self.clock_quarter_frame(),
-                STEP2 => {
-                    self.clock_quarter_frame();
-                    self.clock_half_frame();
-                }
-                STEP3 => self.clock_quarter_frame(),
-                STEP4_5STEP => {}
-                STEP5_5STEP => {
+    pub fn write_logging(&self) -> bool {
+        self.write_log.is_some()
+    }
+
+    /// Register writes recorded since the last `clear_logged_writes`, oldest first.
+    pub fn logged_writes(&self) -> &[ApuWrite] {
+        self.write_log.as_deref().unwrap_or(&[])
+    }
+
+    pub fn clear_logged_writes(&mut self) {
+        if let Some(log) = &mut self.write_log {
+            log.clear();
+        }
+    }
+
+    /// Level of the APU's IRQ line. It stays asserted until the game acknowledges the
+    /// interrupt: reading $4015 or setting the inhibit bit in $4017 clears the frame IRQ,
+    /// and writing $4015 or turning off the IRQ bit in $4010 clears the DMC's.
+    pub fn irq_pending(&self) -> bool {
+        self.frame_interrupt || self.dmc.interrupt
+    }
+
+    /// Address the DMC wants to read a sample byte from. The bus answers with
+    /// `dmc_fill_sample_buffer`, since the APU has no access to memory itself.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    pub fn dmc_fill_sample_buffer(&mut self, data: u8) {
+        self.dmc.fill_sample_buffer(data);
+    }
+
+    // Advances the frame sequencer by one CPU cycle. Step times are counted from the
+    // cycle a $4017 write takes effect. In 4-step mode the IRQ flag is raised on three
+    // cycles in a row, the last of which starts the next sequence.
+    fn clock_frame_counter(&mut self) {
+        if self.frame_counter_reset_delay > 0 {
+            self.frame_counter_reset_delay -= 1;
+            if self.frame_counter_reset_delay == 0 {
+                self.frame_counter_mode = self.pending_frame_counter_mode;
+                self.frame_counter_cycle = 0;
+                if self.frame_counter_mode == FrameCounterMode::Step5 {
                     self.clock_quarter_frame();
                     self.clock_half_frame();
                 }
-                _ => {}
-            },
+                return;
+            }
+        }
+
+        let timing = ApuTiming::for_region(self.region);
+        self.frame_counter_cycle += 1;
+        match (self.frame_counter_mode, self.frame_counter_cycle) {
+            (_, cycle) if cycle == timing.quarter_1 || cycle == timing.quarter_3 => self.clock_quarter_frame(),
+            (_, cycle) if cycle == timing.half_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (FrameCounterMode::Step4, cycle) if cycle == timing.step4_irq => self.set_frame_interrupt(),
+            (FrameCounterMode::Step4, cycle) if cycle == timing.step4_half_2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                self.set_frame_interrupt();
+            }
+            (FrameCounterMode::Step5, cycle) if cycle == timing.step5_half_2 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            // Wraps with `>=` so a restored state can never run past the end of a sequence
+            (FrameCounterMode::Step4, cycle) if cycle >= timing.step4_wrap => {
+                self.set_frame_interrupt();
+                self.frame_counter_cycle = 0;
+            }
+            (FrameCounterMode::Step5, cycle) if cycle >= timing.step5_wrap => self.frame_counter_cycle = 0,
+            _ => {}
+        }
+    }
+
+    // Runs this cycle's frame counter step, then lands length counter writes made since
+    // the last cycle; see `PendingLength` for how the two are ordered
+    fn clock_length_and_writes(&mut self) {
+        let before = [
+            self.pulse1.length_counter,
+            self.pulse2.length_counter,
+            self.triangle.length_counter,
+            self.noise.length_counter,
+        ];
+        self.length_clocked = false;
+        self.clock_frame_counter();
+        let clocked = before.map(|length| self.length_clocked && length > 0);
+        self.pulse1.apply_length_writes(clocked[0]);
+        self.pulse2.apply_length_writes(clocked[1]);
+        self.triangle.apply_length_writes(clocked[2]);
+        self.noise.apply_length_writes(clocked[3]);
+    }
+
+    fn set_frame_interrupt(&mut self) {
+        if !self.interrupt_inhibit {
+            self.frame_interrupt = true;
         }
     }
 
@@ -671,6 +1483,7 @@ impl Apu {
     }
 
     fn clock_half_frame(&mut self) {
+        self.length_clocked = true;
         self.pulse1.clock_length_counter();
         self.pulse2.clock_length_counter();
         self.triangle.clock_length_counter();
@@ -689,81 +1502,106 @@ impl Apu {
                 self.noise.clock_timer();
             }
             self.triangle.clock_timer();
+            self.dmc.clock_timer();
 
-            self.clock_frame_counter_step();
-            self.frame_counter_cycle += 1;
+            self.clock_length_and_writes();
 
-            let reset_cycle = match self.frame_counter_mode {
-                FrameCounterMode::Step4 => 29781,
-                FrameCounterMode::Step5 => 37282,
-            };
-            if self.frame_counter_cycle >= reset_cycle {
-                self.frame_counter_cycle = 0;
-                if self.frame_counter_mode == FrameCounterMode::Step5 {
-                    self.clock_quarter_frame();
-                    self.clock_half_frame();
+            if self.band_limited {
+                // Only a change in some channel's level needs the mixer
+                let levels = self.channel_levels();
+                if self.blip_levels != Some(levels) {
+                    self.blip_levels = Some(levels);
+                    let amplitude = self.mix(levels);
+                    self.blip.set_amplitude(amplitude);
+                }
+                if let Some(sample) = self.blip.clock() {
+                    self.push_sample(sample);
+                }
+            } else {
+                self.sample_accumulator += 1.0;
+                while self.sample_accumulator >= self.cycles_per_sample {
+                    self.sample_accumulator -= self.cycles_per_sample;
+                    let sample = self.mix(self.channel_levels());
+                    self.push_sample(sample);
                 }
             }
+        }
+    }
 
-            self.sample_accumulator += 1.0;
-            while self.sample_accumulator >= CYCLES_PER_SAMPLE {
-                self.sample_accumulator -= CYCLES_PER_SAMPLE;
+    /// Copies out every channel's registers and counters without touching any of them,
+    /// so it is safe to call at any point and cheap enough to call every frame.
+    pub fn debug_snapshot(&self) -> ApuDebugInfo {
+        ApuDebugInfo {
+            channels: [
+                self.pulse1.debug_info(1),
+                self.pulse2.debug_info(2),
+                self.triangle.debug_info(),
+                self.noise.debug_info(),
+                self.dmc.debug_info(),
+            ],
+            dmc_bytes_remaining: self.dmc.bytes_remaining,
+            dmc_address: self.dmc.current_address,
+            frame_counter_steps: match self.frame_counter_mode {
+                FrameCounterMode::Step4 => 4,
+                FrameCounterMode::Step5 => 5,
+            },
+            frame_counter_cycle: self.frame_counter_cycle,
+            frame_interrupt: self.frame_interrupt,
+            dmc_interrupt: self.dmc.interrupt,
+        }
+    }
 
-                let pulse1_out = self.pulse1.output() as f32;
-                let pulse2_out = self.pulse2.output() as f32;
-                let triangle_out = self.triangle.output() as f32;
-                let noise_out = self.noise.output() as f32;
-                let dmc_out = 0.0;
+    // The triangle's level is in half steps, see `Triangle::output_half_steps`
+    fn channel_levels(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output_half_steps(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
 
-                let pulse_mix = if pulse1_out == 0.0 && pulse2_out == 0.0 {
-                    0.0
-                } else {
-                    95.88 / ((8128.0 / (pulse1_out + pulse2_out)) + 100.0)
-                };
-                let tnd_mix = if triangle_out == 0.0 && noise_out == 0.0 && dmc_out == 0.0 {
-                    0.0
-                } else {
-                    159.79
-                        / ((1.0
-                            / (triangle_out / 8227.0 + noise_out / 12241.0 + dmc_out / 22638.0))
-                            + 100.0)
-                };
-                let output_sample_raw = pulse_mix + tnd_mix;
-                let output_sample_scaled = (output_sample_raw * 0.7) - 0.35;
+    // Nonlinear NES mix of the channel levels, after the listener's channel volumes and solo,
+    // read from the pulse and triangle/noise/DMC tables. Listener volumes and the
+    // triangle's half steps fall between entries and are interpolated.
+    fn mix(&self, levels: [u8; 5]) -> f32 {
+        let [pulse1_out, pulse2_out, triangle_out, noise_out, dmc_out] =
+            std::array::from_fn(|channel| match self.solo {
+                Some(solo) if solo != channel => 0.0,
+                _ => levels[channel] as f32 * self.channel_volume[channel],
+            });
+        let triangle_out = triangle_out / 2.0;
+
+        let pulse_mix = mix_lookup(&self.pulse_table, pulse1_out + pulse2_out);
+        let tnd_mix = mix_lookup(&self.tnd_table, 3.0 * triangle_out + 2.0 * noise_out + dmc_out);
+        pulse_mix + tnd_mix
+    }
 
-                let alpha = 0.99;
-                let filtered_output = alpha
-                    * (self.last_output_sample + output_sample_scaled - self.last_input_sample);
-                self.last_input_sample = output_sample_scaled;
-                self.last_output_sample = filtered_output;
+    fn push_sample(&mut self, output_sample_raw: f32) {
+        let output_sample_scaled = (output_sample_raw * 0.7) - 0.35;
 
-                self.sample_buffer.push_back(filtered_output);
-            }
+        let filtered_output = if self.hardware_filters {
+            self.filters.process(output_sample_scaled)
+        } else {
+            let alpha = 0.99;
+            alpha * (self.last_output_sample + output_sample_scaled - self.last_input_sample)
+        };
+        self.last_input_sample = output_sample_scaled;
+        self.last_output_sample = filtered_output;
+
+        self.sample_buffer.push_back(filtered_output);
+        if self.sample_history.len() == SAMPLE_HISTORY_LEN {
+            self.sample_history.pop_front();
         }
+        self.sample_history.push_back(filtered_output);
     }
 
     pub fn mem_read(&mut self, addr: u16) -> u8 {
         match addr {
             0x4015 => {
-                let mut status = 0u8;
-                if self.pulse1.length_counter > 0 {
-                    status |= 0x01;
-                }
-                if self.pulse2.length_counter > 0 {
-                    status |= 0x02;
-                }
-                if self.triangle.length_counter > 0 {
-                    status |= 0x04;
-                }
-                if self.noise.length_counter > 0 {
-                    status |= 0x08;
-                }
-                if self.dmc_enabled {
-                    status |= 0x10;
-                }
-                if self.frame_interrupt {
-                    status |= 0x40;
-                }
+                let status = self.peek_status();
+                // Only the frame IRQ is acknowledged by the read; the DMC's stays asserted
                 self.frame_interrupt = false;
                 status
             }
@@ -771,7 +1609,40 @@ impl Apu {
         }
     }
 
+    /// $4015 as a read would return it, without acknowledging the frame IRQ: one bit per
+    /// channel with a nonzero length counter, bit 4 while the DMC sample has bytes left,
+    /// bit 6 for the frame IRQ and bit 7 for the DMC IRQ.
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0u8;
+        if self.pulse1.length_counter > 0 {
+            status |= 0x01;
+        }
+        if self.pulse2.length_counter > 0 {
+            status |= 0x02;
+        }
+        if self.triangle.length_counter > 0 {
+            status |= 0x04;
+        }
+        if self.noise.length_counter > 0 {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_interrupt {
+            status |= 0x40;
+        }
+        if self.dmc.interrupt {
+            status |= 0x80;
+        }
+        status
+    }
+
     pub fn mem_write(&mut self, addr: u16, data: u8) {
+        if let Some(log) = &mut self.write_log {
+            // The counter advances with the bus, so this is `Bus::cycles` at the write
+            log.push(ApuWrite { cycle: self.cpu_cycle_counter, addr, data });
+        }
         match addr {
             0x4000 => self.pulse1.write_ctrl(data),
             0x4001 => self.pulse1.write_sweep(data),
@@ -787,36 +1658,35 @@ impl Apu {
             0x400B => self.triangle.write_timer_hi(data),
             0x400C => self.noise.write_ctrl(data),
             0x400D => {}
-            0x400E => self.noise.write_period(data),
+            0x400E => self.noise.write_period(data, ApuTiming::for_region(self.region)),
             0x400F => self.noise.write_length(data),
-            0x4010 => {}
-            0x4011 => {}
-            0x4012 => {}
-            0x4013 => {}
+            0x4010 => self.dmc.write_ctrl(data, ApuTiming::for_region(self.region)),
+            0x4011 => self.dmc.write_direct_load(data),
+            0x4012 => self.dmc.write_address(data),
+            0x4013 => self.dmc.write_length(data),
             0x4015 => {
                 self.pulse1.set_enabled((data & 0x01) != 0);
                 self.pulse2.set_enabled((data & 0x02) != 0);
                 self.triangle.set_enabled((data & 0x04) != 0);
                 self.noise.set_enabled((data & 0x08) != 0);
-                self.dmc_enabled = (data & 0x10) != 0;
+                self.dmc.set_enabled((data & 0x10) != 0);
             }
             0x4017 => {
-                self.frame_counter_mode = if (data & 0x80) != 0 {
-                    FrameCounterMode::Step5
-                } else {
-                    FrameCounterMode::Step4
-                };
                 self.interrupt_inhibit = (data & 0x40) != 0;
                 if self.interrupt_inhibit {
                     self.frame_interrupt = false;
                 }
 
-                self.frame_counter_cycle = 0;
-
-                if self.frame_counter_mode == FrameCounterMode::Step5 {
-                    self.clock_quarter_frame();
-                    self.clock_half_frame();
-                }
+                // The new mode, the sequencer restart and 5-step mode's immediate clock
+                // wait 3 cycles when the write lands on an APU cycle and 4 when it lands
+                // between two. The write is taken to happen on the cycle after the ones
+                // already ticked, since the bus ticks an instruction's cycles after it runs.
+                self.pending_frame_counter_mode = if (data & 0x80) != 0 {
+                    FrameCounterMode::Step5
+                } else {
+                    FrameCounterMode::Step4
+                };
+                self.frame_counter_reset_delay = if (self.cpu_cycle_counter + 1).is_multiple_of(2) { 3 } else { 4 };
             }
             _ => {}
         }
@@ -824,20 +1694,21 @@ impl Apu {
 
     pub fn save_state(&self) -> ApuState {
         ApuState {
+            region: self.region,
             pulse1: self.pulse1.save_state(),
             pulse2: self.pulse2.save_state(),
             triangle: self.triangle.save_state(),
             noise: self.noise.save_state(),
-            dmc_enabled: self.dmc_enabled,
+            dmc: self.dmc.save_state(),
             sample_accumulator: self.sample_accumulator,
             cpu_cycle_counter: self.cpu_cycle_counter,
             last_input_sample: self.last_input_sample,
             last_output_sample: self.last_output_sample,
+            filters: self.filters.save_state(),
             frame_counter_cycle: self.frame_counter_cycle,
-            frame_counter_mode: match self.frame_counter_mode {
-                FrameCounterMode::Step4 => 0,
-                FrameCounterMode::Step5 => 1,
-            },
+            frame_counter_mode: self.frame_counter_mode.to_state(),
+            pending_frame_counter_mode: self.pending_frame_counter_mode.to_state(),
+            frame_counter_reset_delay: self.frame_counter_reset_delay,
             interrupt_inhibit: self.interrupt_inhibit,
             frame_interrupt: self.frame_interrupt,
         }
@@ -848,18 +1719,646 @@ impl Apu {
         self.pulse2.load_state(&state.pulse2);
         self.triangle.load_state(&state.triangle);
         self.noise.load_state(&state.noise);
-        self.dmc_enabled = state.dmc_enabled;
+        self.dmc.load_state(&state.dmc);
         self.sample_accumulator = state.sample_accumulator;
         self.cpu_cycle_counter = state.cpu_cycle_counter;
         self.last_input_sample = state.last_input_sample;
         self.last_output_sample = state.last_output_sample;
+        self.filters.load_state(&state.filters);
         self.frame_counter_cycle = state.frame_counter_cycle;
-        self.frame_counter_mode = match state.frame_counter_mode {
-            0 => FrameCounterMode::Step4,
-            _ => FrameCounterMode::Step5,
-        };
+        self.frame_counter_mode = FrameCounterMode::from_state(state.frame_counter_mode);
+        self.pending_frame_counter_mode = FrameCounterMode::from_state(state.pending_frame_counter_mode);
+        self.frame_counter_reset_delay = state.frame_counter_reset_delay;
         self.interrupt_inhibit = state.interrupt_inhibit;
         self.frame_interrupt = state.frame_interrupt;
+        // A state from another region, e.g. rewound from before a switch, keeps playing
+        // at this console's timing
+        self.retime(state.region, self.region);
         self.sample_buffer.clear();
+        self.blip.clear();
+        self.blip_levels = None;
+    }
+}
+
+// Whole-number indices, as at full volume, read their entry exactly
+fn mix_lookup(table: &[f32], index: f32) -> f32 {
+    let index = index.clamp(0.0, (table.len() - 1) as f32);
+    let whole = index as usize;
+    let fraction = index - whole as f32;
+    match table.get(whole + 1) {
+        Some(&next) if fraction > 0.0 => table[whole] + (next - table[whole]) * fraction,
+        _ => table[whole],
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_channels_skip_only_the_length_load() {
+        let mut apu = Apu::new();
+        // Length index 1 loads 254
+        for (addr, data) in [(0x4003, 0x08), (0x400B, 0x08), (0x400F, 0x08)] {
+            apu.mem_write(0x4015, 0x00);
+            apu.mem_write(addr, data);
+            apu.tick(1);
+        }
+        assert_eq!(apu.peek_status() & 0x0F, 0);
+        assert!(apu.pulse1.envelope.start);
+        assert!(apu.triangle.linear_counter_reload);
+        assert!(apu.noise.envelope.start);
+
+        apu.mem_write(0x4015, 0x0D);
+        for addr in [0x4003, 0x400B, 0x400F] {
+            apu.mem_write(addr, 0x08);
+        }
+        apu.tick(1);
+        assert_eq!(apu.peek_status() & 0x0F, 0x0D);
+        assert_eq!(apu.pulse1.length_counter, 254);
+        assert_eq!(apu.triangle.length_counter, 254);
+        assert_eq!(apu.noise.length_counter, 254);
+
+        // Disabling drops a load still waiting for its APU cycle
+        apu.mem_write(0x4003, 0x08);
+        apu.mem_write(0x4015, 0x00);
+        apu.mem_write(0x4015, 0x01);
+        apu.tick(1);
+        assert_eq!(apu.pulse1.length_counter, 0);
+    }
+
+    // Pulse 1's length counter after `write` lands on the `at`th cycle of a fresh APU,
+    // with the counter loaded with 254 first if `preload`
+    fn pulse_length_after_write(preload: bool, at: usize, write: (u16, u8)) -> u8 {
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x01);
+        if preload {
+            apu.mem_write(0x4003, 0x08);
+        }
+        apu.tick(at - 1);
+        apu.mem_write(write.0, write.1);
+        apu.tick(1);
+        apu.pulse1.length_counter
+    }
+
+    #[test]
+    fn length_writes_are_ordered_against_the_half_frame_clock() {
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x01);
+        apu.mem_write(0x4003, 0x08);
+        let changed = changes(&mut apu, 15000, |apu| apu.pulse1.length_counter as u32);
+        assert_eq!(changed.len(), 2);
+        let clock = changed[1] as usize;
+        assert_eq!(apu.pulse1.length_counter, 253);
+
+        // Length index 31 loads 30
+        let reload = (0x4003, 0xF8);
+        assert_eq!(pulse_length_after_write(true, clock, reload), 253, "reload on a clocked counter");
+        assert_eq!(pulse_length_after_write(false, clock, reload), 30, "reload on a zero counter");
+        assert_eq!(pulse_length_after_write(true, clock + 1, reload), 30, "reload a cycle later");
+
+        // The clock still sees the old halt flag
+        assert_eq!(pulse_length_after_write(true, clock, (0x4000, 0x20)), 253, "halt on the clock");
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x01);
+        apu.mem_write(0x4000, 0x20);
+        apu.mem_write(0x4003, 0x08);
+        apu.tick(clock - 1);
+        apu.mem_write(0x4000, 0x00);
+        apu.tick(1);
+        assert_eq!(apu.pulse1.length_counter, 254, "halt cleared on the clock");
+    }
+
+    // Ticks one cycle at a time and returns the cycles, counted from 1, after which
+    // `probe` read something different from the cycle before
+    fn changes(apu: &mut Apu, cycles: u32, probe: impl Fn(&Apu) -> u32) -> Vec<u32> {
+        let mut last = probe(apu);
+        (1..=cycles)
+            .filter(|_| {
+                apu.tick(1);
+                let value = probe(apu);
+                std::mem::replace(&mut last, value) != value
+            })
+            .collect()
+    }
+
+    #[test]
+    fn frame_counter_write_lands_3_or_4_cycles_later() {
+        // A write after an odd number of cycles lands on an APU cycle and waits 3
+        for (cycles_before, delay) in [(0, 3), (1, 4), (2, 3), (7, 4)] {
+            let mut apu = Apu::new();
+            apu.tick(cycles_before);
+            apu.mem_write(0x4015, 0x01);
+            apu.mem_write(0x4003, 0x08);
+            apu.tick(1);
+            apu.mem_write(0x4017, 0x80);
+            let steps = changes(&mut apu, 10, |apu| apu.debug_snapshot().frame_counter_steps as u32);
+            assert_eq!(steps, [delay], "{} cycles before", cycles_before + 1);
+            assert_eq!(apu.pulse1.length_counter, 253, "immediate half-frame clock");
+        }
+    }
+
+    #[test]
+    fn four_step_irq_flag_is_raised_on_three_cycles_in_a_row() {
+        let mut apu = Apu::new();
+        assert_eq!(changes(&mut apu, 29828, |apu| apu.irq_pending() as u32), [29828]);
+        // Read on the cycle it goes up, the flag is still reported, and the next two
+        // cycles raise it again
+        for _ in 0..3 {
+            assert_eq!(apu.mem_read(0x4015), 0x40);
+            assert_eq!(apu.mem_read(0x4015), 0x00);
+            apu.tick(1);
+        }
+        assert!(!apu.irq_pending());
+        // The sequence restarted with the third, one cycle ago
+        assert_eq!(changes(&mut apu, 29830, |apu| apu.irq_pending() as u32), [29827]);
+
+        let mut inhibited = Apu::new();
+        inhibited.mem_write(0x4017, 0x40);
+        assert!(changes(&mut inhibited, 3 * 29830, |apu| apu.irq_pending() as u32).is_empty());
+    }
+
+    #[test]
+    fn pal_frame_sequencer_steps_at_the_pal_cycles() {
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        apu.mem_write(0x4015, 0x01);
+        apu.mem_write(0x4003, 0x08);
+        let lengths = changes(&mut apu, 33254, |apu| apu.pulse1.length_counter as u32);
+        assert_eq!(lengths, [1, 16627, 33253]);
+
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        assert_eq!(changes(&mut apu, 33254, |apu| apu.irq_pending() as u32), [33252]);
+    }
+
+    #[test]
+    fn noise_and_dmc_periods_keep_their_index_across_regions() {
+        let index = |period: u16, table: &[u16; 16]| table.iter().position(|&p| p == period);
+        for (noise, rate) in [(0x00, 0x00), (0x05, 0x03), (0x0F, 0x0F)] {
+            let mut apu = Apu::new();
+            apu.mem_write(0x400E, noise);
+            apu.mem_write(0x4010, rate);
+            assert_eq!(index(apu.noise.timer_period, &NTSC_TIMING.noise_periods), Some(noise as usize));
+            assert_eq!(index(apu.dmc.timer_period, &NTSC_TIMING.dmc_rates), Some(rate as usize));
+
+            apu.set_region(Region::Pal);
+            assert_eq!(index(apu.noise.timer_period, &PAL_TIMING.noise_periods), Some(noise as usize));
+            assert_eq!(index(apu.dmc.timer_period, &PAL_TIMING.dmc_rates), Some(rate as usize));
+
+            // A PAL state loaded into an NTSC console plays at NTSC timing
+            let state = apu.save_state();
+            let mut ntsc = Apu::new();
+            ntsc.load_state(&state);
+            assert_eq!(index(ntsc.noise.timer_period, &NTSC_TIMING.noise_periods), Some(noise as usize));
+            assert_eq!(index(ntsc.dmc.timer_period, &NTSC_TIMING.dmc_rates), Some(rate as usize));
+        }
+    }
+
+    #[test]
+    fn five_step_sequence_clocks_at_the_reference_cycles() {
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x01);
+        // Envelope period 0, so its level moves on every quarter frame; length 254, unhalted
+        apu.mem_write(0x4000, 0x00);
+        apu.mem_write(0x4003, 0x08);
+        apu.tick(1);
+        apu.mem_write(0x4017, 0x80);
+        let delay = 3;
+        let quarters = changes(&mut apu, 2 * 37282 + delay, |apu| {
+            apu.pulse1.envelope.decay_level as u32 | (apu.pulse1.length_counter as u32) << 8
+        });
+
+        let sequence_quarters = [0, 7457, 14913, 22371, 37281];
+        let expected: Vec<u32> = sequence_quarters
+            .iter()
+            .copied()
+            .chain(sequence_quarters[1..].iter().map(|cycle| cycle + 37282))
+            .map(|cycle| cycle + delay)
+            .collect();
+        assert_eq!(quarters, expected);
+        // Half frames: the immediate clock, then 14913 and 37281 in each sequence
+        assert_eq!(apu.pulse1.length_counter, 254 - 5);
+        assert_eq!(apu.pulse1.envelope.decay_level, 15 - 8);
+        assert!(!apu.irq_pending());
+    }
+
+    fn impulse_response(mut process: impl FnMut(f32) -> f32, len: usize) -> Vec<f32> {
+        (0..len).map(|n| process(if n == 0 { 1.0 } else { 0.0 })).collect()
+    }
+
+    #[test]
+    fn high_pass_impulse_response_decays_geometrically() {
+        let mut filter = HighPass::new(90.0, 44100.0);
+        let a = filter.coefficient;
+        assert!((a - 0.98734).abs() < 1e-4, "coefficient {}", a);
+        let response = impulse_response(|x| filter.process(x), 2000);
+        assert_eq!(response[0], a);
+        for (n, &y) in response.iter().enumerate().skip(1) {
+            let expected = -(1.0 - a) * a.powi(n as i32);
+            assert!((y - expected).abs() < 1e-6, "sample {}: {} vs {}", n, y, expected);
+        }
+        // The taps sum to the DC gain, which is zero
+        assert!(response.iter().sum::<f32>().abs() < 1e-4);
+    }
+
+    #[test]
+    fn low_pass_impulse_response_sums_to_unity() {
+        let mut filter = LowPass::new(14_000.0, 44100.0);
+        let b = filter.coefficient;
+        assert!((b - 0.66608).abs() < 1e-4, "coefficient {}", b);
+        let response = impulse_response(|x| filter.process(x), 100);
+        for (n, &y) in response.iter().enumerate() {
+            let expected = b * (1.0 - b).powi(n as i32);
+            assert!((y - expected).abs() < 1e-6, "sample {}: {} vs {}", n, y, expected);
+        }
+        assert!((response.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn filter_chain_is_its_three_stages_in_order() {
+        let mut chain = FilterChain::new(44100.0);
+        let mut stages = (HighPass::new(90.0, 44100.0), HighPass::new(440.0, 44100.0), LowPass::new(14_000.0, 44100.0));
+        let chained = impulse_response(|x| chain.process(x), 4000);
+        let staged = impulse_response(|x| stages.2.process(stages.1.process(stages.0.process(x))), 4000);
+        assert_eq!(chained, staged);
+        // No DC gets through, and the tail has died away
+        assert!(chained.iter().sum::<f32>().abs() < 1e-4);
+        assert!(chained[3000..].iter().all(|y| y.abs() < 1e-6));
+
+        // A step settles back to silence; a reset state starts from silence
+        let mut chain = FilterChain::new(44100.0);
+        let settled = (0..44100).map(|_| chain.process(1.0)).last().unwrap();
+        assert!(settled.abs() < 1e-4, "step settles at {}", settled);
+        chain.load_state(&FilterChainState::default());
+        assert_eq!(chain.process(0.0), 0.0);
+    }
+
+    // Serves every fetch the DMC asks for, emptying the buffer as the output unit would,
+    // and returns the addresses read
+    fn dmc_fetches(apu: &mut Apu, count: usize) -> Vec<u16> {
+        let mut addresses = Vec::new();
+        while let Some(addr) = apu.dmc_fetch_address().filter(|_| addresses.len() < count) {
+            addresses.push(addr);
+            apu.dmc_fill_sample_buffer(0x55);
+            apu.dmc.sample_buffer = None;
+        }
+        addresses
+    }
+
+    #[test]
+    fn dmc_sample_address_wraps_to_0x8000() {
+        let mut apu = Apu::new();
+        apu.mem_write(0x4010, 0x80);
+        // $FFC0, 65 bytes
+        apu.mem_write(0x4012, 0xFF);
+        apu.mem_write(0x4013, 0x04);
+        apu.mem_write(0x4015, 0x10);
+        let expected: Vec<u16> = (0xFFC0..=0xFFFF).chain([0x8000]).collect();
+        assert_eq!(dmc_fetches(&mut apu, 100), expected);
+        assert_eq!(apu.peek_status() & 0x90, 0x80);
+
+        // A looping sample starts over at its own address, not at the wrapped one
+        apu.mem_write(0x4010, 0x40);
+        apu.mem_write(0x4013, 0x00);
+        apu.mem_write(0x4012, 0xFF);
+        apu.mem_write(0x4015, 0x10);
+        assert_eq!(dmc_fetches(&mut apu, 3), [0xFFC0, 0xFFC0, 0xFFC0]);
+        assert_eq!(apu.peek_status() & 0x90, 0x10);
+    }
+
+    #[test]
+    fn status_bits_come_from_their_sources_and_only_the_frame_irq_is_acknowledged() {
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x0F);
+        for (bit, addr) in [0x4003, 0x4007, 0x400B, 0x400F].into_iter().enumerate() {
+            apu.mem_write(addr, 0x08);
+            apu.tick(1);
+            assert_eq!(apu.peek_status() & 0x0F, (2 << bit) - 1);
+        }
+
+        // A one-byte sample that raises the DMC IRQ when it is fetched
+        apu.mem_write(0x4010, 0x80);
+        apu.mem_write(0x4013, 0x00);
+        apu.mem_write(0x4015, 0x1F);
+        assert_eq!(apu.peek_status(), 0x1F);
+        dmc_fetches(&mut apu, 1);
+        assert_eq!(apu.peek_status(), 0x8F);
+
+        apu.tick(29828);
+        assert_eq!(apu.peek_status(), 0xCF);
+        assert_eq!(apu.peek_status(), 0xCF, "peeking acknowledges nothing");
+        assert_eq!(apu.mem_read(0x4015), 0xCF);
+        assert_eq!(apu.mem_read(0x4015), 0x8F, "the read acknowledges only the frame IRQ");
+        assert!(apu.irq_pending());
+
+        // The DMC IRQ is acknowledged by writing $4015 or clearing its enable in $4010
+        apu.mem_write(0x4015, 0x0F);
+        assert_eq!(apu.mem_read(0x4015), 0x0F);
+        apu.mem_write(0x4015, 0x1F);
+        dmc_fetches(&mut apu, 1);
+        assert_eq!(apu.peek_status() & 0x80, 0x80);
+        apu.mem_write(0x4010, 0x00);
+        assert_eq!(apu.peek_status() & 0x80, 0x00);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn dmc_status_bit_stays_set_until_the_sample_runs_out() {
+        let mut apu = Apu::new();
+        // 17 bytes at $C000
+        apu.mem_write(0x4012, 0x00);
+        apu.mem_write(0x4013, 0x01);
+        assert_eq!(apu.peek_status() & 0x10, 0);
+        apu.mem_write(0x4015, 0x10);
+        for fetched in 0..17 {
+            assert_eq!(apu.mem_read(0x4015) & 0x10, 0x10, "{} bytes fetched", fetched);
+            assert_eq!(dmc_fetches(&mut apu, 1), [0xC000 + fetched]);
+        }
+        assert_eq!(apu.mem_read(0x4015) & 0x10, 0);
+        assert_eq!(apu.dmc_fetch_address(), None);
+
+        // Disabling stops the sample short
+        apu.mem_write(0x4015, 0x10);
+        dmc_fetches(&mut apu, 3);
+        assert_eq!(apu.peek_status() & 0x10, 0x10);
+        apu.mem_write(0x4015, 0x00);
+        assert_eq!(apu.peek_status() & 0x10, 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn mixer_tables_follow_the_nonlinear_formulas() {
+        let apu = Apu::new();
+        for pulse1 in 0..16u8 {
+            for pulse2 in 0..16u8 {
+                let sum = (pulse1 + pulse2) as f32;
+                let expected = if sum == 0.0 { 0.0 } else { 95.88 / (8128.0 / sum + 100.0) };
+                let mixed = apu.mix([pulse1, pulse2, 0, 0, 0]);
+                assert!((mixed - expected).abs() < 1e-6, "pulse {} + {}: {} vs {}", pulse1, pulse2, mixed, expected);
+            }
+        }
+        // The triangle/noise/DMC table is the usual linear-sum approximation of the
+        // three-input formula, within 0.013 of it everywhere
+        for triangle in 0..16u8 {
+            for noise in 0..16u8 {
+                for dmc in 0..128u8 {
+                    let (t, n, d) = (triangle as f32, noise as f32, dmc as f32);
+                    let expected = if triangle == 0 && noise == 0 && dmc == 0 {
+                        0.0
+                    } else {
+                        159.79 / (1.0 / (t / 8227.0 + n / 12241.0 + d / 22638.0) + 100.0)
+                    };
+                    let mixed = apu.mix([0, 0, triangle * 2, noise, dmc]);
+                    assert!(
+                        (mixed - expected).abs() < 0.0135,
+                        "triangle {} noise {} DMC {}: {} vs {}",
+                        triangle, noise, dmc, mixed, expected
+                    );
+                }
+            }
+        }
+        assert_eq!(apu.mix([0; 5]), 0.0);
+    }
+
+    #[test]
+    fn envelope_steps_every_period_plus_one_clocks() {
+        for period in 0..16u8 {
+            for loop_flag in [false, true] {
+                let mut envelope = Envelope::default();
+                envelope.write(if loop_flag { 0x20 } else { 0x00 } | period);
+                envelope.clock();
+                assert_eq!(envelope.output(), 15);
+                let step = period as i32 + 1;
+                for clock in 1..=18 * step {
+                    envelope.clock();
+                    let drops = clock / step;
+                    let expected = if loop_flag { (15 - drops).rem_euclid(16) } else { (15 - drops).max(0) };
+                    assert_eq!(envelope.output() as i32, expected, "period {} loop {} clock {}", period, loop_flag, clock);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sweep_updates_on_the_next_clock_then_every_period_plus_one() {
+        for period in 0..8u8 {
+            let mut sweep = Sweep::default();
+            sweep.write(0x80 | period << 4 | 4);
+            let mut timer_period = 0x100;
+            let mut updates = Vec::new();
+            let step = period as u32 + 1;
+            for clock in 1..=4 * step + 1 {
+                let before = timer_period;
+                sweep.clock(&mut timer_period, 2);
+                if timer_period != before {
+                    assert_eq!(timer_period, before + (before >> 4));
+                    updates.push(clock);
+                }
+            }
+            let expected: Vec<u32> = (0..5).map(|update| 1 + update * step).collect();
+            assert_eq!(updates, expected, "period {}", period);
+        }
+    }
+
+    #[test]
+    fn sweep_mutes_at_the_boundary_periods() {
+        // (timer period, sweep register, muted)
+        let cases = [
+            (0x007, 0x00, true),
+            (0x008, 0x00, false),
+            (0x3FF, 0x00, false),
+            (0x400, 0x00, true),
+            (0x400, 0x08, false),
+            (0x555, 0x01, false),
+            (0x556, 0x01, true),
+            (0x7FF, 0x07, true),
+            (0x7F0, 0x07, false),
+            (0x7FF, 0x0F, false),
+            (0x007, 0x0F, true),
+        ];
+        for (timer_period, register, muted) in cases {
+            for enabled in [0x00, 0x80] {
+                let mut pulse = Pulse::new();
+                pulse.set_enabled(true);
+                pulse.write_ctrl(0xBF);
+                pulse.write_sweep(enabled | register);
+                pulse.write_timer_lo(timer_period as u8);
+                pulse.write_timer_hi((timer_period >> 8) as u8 | 0x08);
+                pulse.apply_length_writes(false);
+                // Duty 2 is high from its second step
+                pulse.duty_step = 2;
+                let context = format!("period {:#05X} sweep {:#04X}", timer_period, enabled | register);
+                assert_eq!(pulse.sweep.mutes(timer_period), muted, "{}", context);
+                assert_eq!(pulse.output(), if muted { 0 } else { 15 }, "{}", context);
+            }
+        }
+    }
+
+    #[test]
+    fn negated_sweeps_differ_by_one_between_the_pulses() {
+        for (timer_period, shift) in [(0x100u16, 2u8), (0x7FF, 0), (0x008, 3), (0x123, 7)] {
+            let mut sweep = Sweep::default();
+            sweep.write(0x88 | shift);
+            let change = timer_period >> shift;
+            assert_eq!(sweep.target_period(timer_period, 1), timer_period.saturating_sub(change + 1));
+            assert_eq!(sweep.target_period(timer_period, 2), timer_period - change);
+            assert!(!sweep.mutes(timer_period));
+        }
+
+        // Clocked through the APU, pulse 1 ends up one below pulse 2
+        let mut apu = Apu::new();
+        apu.mem_write(0x4015, 0x03);
+        for base in [0x4000, 0x4004] {
+            apu.mem_write(base + 1, 0x8A);
+            apu.mem_write(base + 2, 0x00);
+            apu.mem_write(base + 3, 0x09);
+        }
+        apu.tick(14913);
+        assert_eq!(apu.pulse1.timer_period, 0x100 - 0x40 - 1);
+        assert_eq!(apu.pulse2.timer_period, 0x100 - 0x40);
+    }
+
+    // Plays a 100 Hz sine through $4011, one write every 100 CPU cycles, and returns the
+    // RMS distance of the output from the best-fitting sine, relative to its amplitude.
+    // The levels are run backwards through the mixer's curve, as a PCM player does, so
+    // that the mix and not the raw level is the sine.
+    fn direct_load_sine_error(band_limited: bool) -> f64 {
+        let mut apu = Apu::new();
+        apu.set_band_limited(band_limited);
+        apu.set_hardware_filters(false);
+        let clock_hz = Region::Ntsc.cpu_clock_hz();
+        for write in 0..2000 {
+            let phase = std::f64::consts::TAU * 100.0 * (write * 100) as f64 / clock_hz;
+            let mixed = 0.3 + 0.2 * phase.sin();
+            let level = 24329.0 / (163.67 / mixed - 100.0);
+            apu.mem_write(0x4011, level.round() as u8);
+            apu.tick(100);
+        }
+        // Skips the first 10 ms while the DC blocker settles
+        let samples: Vec<f64> = apu.take_samples().iter().skip(441).map(|&s| s as f64).collect();
+        let basis = |i: usize| {
+            let phase = std::f64::consts::TAU * 100.0 * i as f64 / DEFAULT_SAMPLE_RATE as f64;
+            [phase.sin(), phase.cos(), 1.0]
+        };
+
+        // Least squares through the 3x3 normal equations
+        let mut normal = [[0.0; 4]; 3];
+        for (i, &sample) in samples.iter().enumerate() {
+            let row = basis(i);
+            for r in 0..3 {
+                for c in 0..3 {
+                    normal[r][c] += row[r] * row[c];
+                }
+                normal[r][3] += row[r] * sample;
+            }
+        }
+        for pivot in 0..3 {
+            let pivot_row = normal[pivot];
+            for (r, row) in normal.iter_mut().enumerate() {
+                if r != pivot {
+                    let factor = row[pivot] / pivot_row[pivot];
+                    for (value, pivot_value) in row.iter_mut().zip(pivot_row) {
+                        *value -= factor * pivot_value;
+                    }
+                }
+            }
+        }
+        let fit: [f64; 3] = std::array::from_fn(|r| normal[r][3] / normal[r][r]);
+        let amplitude = fit[0].hypot(fit[1]);
+        let residual = samples
+            .iter()
+            .enumerate()
+            .map(|(i, &sample)| {
+                let row = basis(i);
+                sample - (fit[0] * row[0] + fit[1] * row[1] + fit[2])
+            })
+            .map(|error| error * error)
+            .sum::<f64>()
+            / samples.len() as f64;
+        assert!(amplitude > 0.05, "output amplitude {} is too small", amplitude);
+        residual.sqrt() / amplitude
+    }
+
+    #[test]
+    fn direct_load_writes_play_back_a_sine() {
+        for band_limited in [false, true] {
+            let error = direct_load_sine_error(band_limited);
+            assert!(error < 0.02, "band limited {}: error {}", band_limited, error);
+        }
+    }
+
+    // An APU playing a constant-volume 50% pulse on pulse 1 with the given timer period
+    fn square_wave_apu(timer_period: u16, band_limited: bool) -> Apu {
+        let mut apu = Apu::new();
+        apu.set_band_limited(band_limited);
+        apu.set_hardware_filters(false);
+        apu.mem_write(0x4015, 0x01);
+        apu.mem_write(0x4000, 0xBF);
+        apu.mem_write(0x4001, 0x00);
+        apu.mem_write(0x4002, timer_period as u8);
+        apu.mem_write(0x4003, (timer_period >> 8) as u8 | 0x08);
+        apu
+    }
+
+    // Energy of a pulse's output spectrum outside its harmonics, relative to the total,
+    // in dB. Bins within 3 of a harmonic (or DC) hold the Hann window's main lobe.
+    fn square_wave_alias_db(timer_period: u16, band_limited: bool) -> f32 {
+        let mut apu = square_wave_apu(timer_period, band_limited);
+        apu.tick(100_000);
+        apu.take_samples();
+        apu.tick(400_000);
+        let samples = apu.take_samples();
+        let spectrum = crate::spectrum::magnitude_spectrum(&samples);
+
+        let len = spectrum.len() * 2;
+        let frequency = Region::Ntsc.cpu_clock_hz() / (16.0 * (timer_period as f64 + 1.0));
+        let harmonic_spacing = frequency * len as f64 / DEFAULT_SAMPLE_RATE as f64;
+        let (mut harmonics, mut outside) = (0.0, 0.0);
+        for (bin, &db) in spectrum.iter().enumerate() {
+            let power = 10f64.powf(db as f64 / 10.0);
+            let harmonic = bin as f64 / harmonic_spacing;
+            if (harmonic - harmonic.round()).abs() * harmonic_spacing <= 3.0 {
+                harmonics += power;
+            } else {
+                outside += power;
+            }
+        }
+        (10.0 * (outside / (harmonics + outside)).log10()) as f32
+    }
+
+    #[test]
+    fn band_limited_square_wave_has_little_alias_energy() {
+        // Timer period 55 plays 1997.5 Hz, whose upper harmonics fold back between the
+        // lower ones when point-sampled
+        let point_sampled = square_wave_alias_db(55, false);
+        let band_limited = square_wave_alias_db(55, true);
+        assert!(band_limited < -40.0, "band limited: {:.1} dB", band_limited);
+        assert!(
+            band_limited < point_sampled - 20.0,
+            "band limited {:.1} dB, point sampled {:.1} dB",
+            band_limited,
+            point_sampled
+        );
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture` to compare the two paths
+    #[test]
+    #[ignore]
+    fn band_limited_synthesis_cost() {
+        let clock_hz = Region::Ntsc.cpu_clock_hz() as usize;
+        for band_limited in [false, true] {
+            let mut apu = square_wave_apu(55, band_limited);
+            let start = std::time::Instant::now();
+            apu.tick(clock_hz);
+            let elapsed = start.elapsed();
+            assert!(!apu.take_samples().is_empty());
+            println!("band limited {}: {:?} per emulated second", band_limited, elapsed);
+        }
+    }
+}
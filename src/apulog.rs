@@ -0,0 +1,121 @@
+// src/apulog.rs
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::cartridge::Rom;
+use crate::netplay::crc32_update;
+use crate::region::Region;
+
+const BINARY_MAGIC: &[u8; 4] = b"JNAL";
+const BINARY_VERSION: u8 = 1;
+
+/// One write to an APU register, stamped with the CPU cycle it happened on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApuWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub data: u8,
+}
+
+/// File layout for an APU write log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApuLogFormat {
+    /// `#` header lines, then one `cycle $addr $value` line per write.
+    #[default]
+    Text,
+    /// The `JNAL` magic, a version byte, the region (0 NTSC, 1 PAL, 2 Dendy), the ROM's
+    /// CRC-32 and the CPU clock in Hz, then 11-byte records of cycle (u64), address
+    /// (u16) and value; every number little-endian.
+    Binary,
+}
+
+impl ApuLogFormat {
+    pub const ALL: [ApuLogFormat; 2] = [ApuLogFormat::Text, ApuLogFormat::Binary];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApuLogFormat::Text => "Text",
+            ApuLogFormat::Binary => "Binary",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ApuLogFormat::Text => "txt",
+            ApuLogFormat::Binary => "apulog",
+        }
+    }
+}
+
+/// CRC-32 of the PRG and CHR ROM, written in the log header so a log can be matched
+/// with the game it was recorded from. CHR RAM is left out, as in `movie::rom_checksum`.
+pub fn rom_crc(rom: &Rom) -> u32 {
+    let mut crc = crc32_update(!0, &rom.prg_rom);
+    if !rom.chr_is_ram {
+        crc = crc32_update(crc, &rom.chr_rom);
+    }
+    !crc
+}
+
+/// An open APU write log. The emulator hands it each frame's writes; nothing is
+/// guaranteed to be on disk until `finish`.
+pub struct ApuLogWriter {
+    file: BufWriter<File>,
+    format: ApuLogFormat,
+    writes: u64,
+}
+
+impl ApuLogWriter {
+    pub fn create(path: &str, format: ApuLogFormat, rom_crc: u32, region: Region) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create APU log '{}': {}", path, e))?;
+        let mut log = ApuLogWriter { file: BufWriter::new(file), format, writes: 0 };
+        let clock_hz = region.cpu_clock_hz().round() as u32;
+        let header = match format {
+            ApuLogFormat::Text => {
+                let text = format!(
+                    "# JazzNess APU write log\n# rom_crc {:08X}\n# region {}\n# cpu_clock {}\n",
+                    rom_crc,
+                    region.name(),
+                    clock_hz
+                );
+                log.file.write_all(text.as_bytes())
+            }
+            ApuLogFormat::Binary => {
+                let region_code = Region::ALL.iter().position(|&r| r == region).unwrap_or(0) as u8;
+                let mut header = BINARY_MAGIC.to_vec();
+                header.push(BINARY_VERSION);
+                header.push(region_code);
+                header.extend_from_slice(&rom_crc.to_le_bytes());
+                header.extend_from_slice(&clock_hz.to_le_bytes());
+                log.file.write_all(&header)
+            }
+        };
+        header.map_err(|e| format!("Failed to write APU log '{}': {}", path, e))?;
+        Ok(log)
+    }
+
+    pub fn write(&mut self, writes: &[ApuWrite]) -> Result<(), String> {
+        for write in writes {
+            let result = match self.format {
+                ApuLogFormat::Text => writeln!(self.file, "{} ${:04X} ${:02X}", write.cycle, write.addr, write.data),
+                ApuLogFormat::Binary => {
+                    let mut record = [0u8; 11];
+                    record[0..8].copy_from_slice(&write.cycle.to_le_bytes());
+                    record[8..10].copy_from_slice(&write.addr.to_le_bytes());
+                    record[10] = write.data;
+                    self.file.write_all(&record)
+                }
+            };
+            result.map_err(|e| format!("Failed to write APU log: {}", e))?;
+        }
+        self.writes += writes.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes the log to disk and closes it, returning how many writes it holds.
+    pub fn finish(mut self) -> Result<u64, String> {
+        self.file.flush().map_err(|e| format!("Failed to write APU log: {}", e))?;
+        Ok(self.writes)
+    }
+}
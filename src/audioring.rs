@@ -0,0 +1,166 @@
+// src/audioring.rs
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Samples are stored as their f32 bits so the slots can be shared without locks or unsafe code.
+// `head` and `tail` count samples ever popped and pushed; they wrap, and only their
+// difference matters.
+struct Ring {
+    slots: Box<[AtomicU32]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl Ring {
+    fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+}
+
+/// Creates a single-producer, single-consumer ring of mono samples holding up to
+/// `capacity` of them. The producer side stays with the emulator thread and the
+/// consumer side goes to the audio device's callback; neither ever blocks the other.
+pub fn sample_ring(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    let ring = Arc::new(Ring {
+        slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (SampleProducer { ring: Arc::clone(&ring) }, SampleConsumer { ring })
+}
+
+/// The writing end of a `sample_ring`.
+pub struct SampleProducer {
+    ring: Arc<Ring>,
+}
+
+impl SampleProducer {
+    /// Appends as many of `samples` as fit and returns how many that was.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let ring = &*self.ring;
+        let capacity = ring.slots.len();
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let free = capacity - tail.wrapping_sub(ring.head.load(Ordering::Acquire));
+        let count = samples.len().min(free);
+        for (i, &sample) in samples[..count].iter().enumerate() {
+            ring.slots[tail.wrapping_add(i) % capacity].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        // Publishes the slots written above to the consumer
+        ring.tail.store(tail.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Samples waiting to be played.
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+}
+
+/// The reading end of a `sample_ring`.
+pub struct SampleConsumer {
+    ring: Arc<Ring>,
+}
+
+impl SampleConsumer {
+    /// Fills the front of `out` with the oldest waiting samples and returns how many
+    /// there were; the rest of `out` is left alone.
+    pub fn pop_into(&mut self, out: &mut [f32]) -> usize {
+        let ring = &*self.ring;
+        let capacity = ring.slots.len();
+        let head = ring.head.load(Ordering::Relaxed);
+        let available = ring.tail.load(Ordering::Acquire).wrapping_sub(head);
+        let count = out.len().min(available);
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(ring.slots[head.wrapping_add(i) % capacity].load(Ordering::Relaxed));
+        }
+        // Hands the slots read above back to the producer
+        ring.head.store(head.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    /// Throws away everything waiting to be played.
+    pub fn clear(&mut self) {
+        self.ring.head.store(self.ring.tail.load(Ordering::Acquire), Ordering::Release);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_stops_when_the_ring_is_full() {
+        let (mut producer, mut consumer) = sample_ring(4);
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.push(&[4.0, 5.0, 6.0]), 1);
+        assert_eq!(producer.push(&[7.0]), 0);
+        assert_eq!(producer.len(), 4);
+
+        let mut out = [0.0; 6];
+        assert_eq!(consumer.pop_into(&mut out), 4);
+        assert_eq!(out, [1.0, 2.0, 3.0, 4.0, 0.0, 0.0]);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn pops_keep_their_order_across_the_wrap() {
+        let (mut producer, mut consumer) = sample_ring(5);
+        let mut next = 0.0;
+        let mut expected = 0.0;
+        // Chunk sizes that do not divide the capacity, so the ends wrap at every offset
+        for round in 0..50 {
+            let chunk: Vec<f32> = (0..3).map(|i| next + i as f32).collect();
+            assert_eq!(producer.push(&chunk), 3, "round {}", round);
+            next += 3.0;
+            let mut out = [0.0; 3];
+            assert_eq!(consumer.pop_into(&mut out), 3, "round {}", round);
+            for sample in out {
+                assert_eq!(sample, expected, "round {}", round);
+                expected += 1.0;
+            }
+        }
+    }
+
+    #[test]
+    fn samples_cross_threads_in_order() {
+        const COUNT: usize = 100_000;
+        let (mut producer, mut consumer) = sample_ring(256);
+        let writer = std::thread::spawn(move || {
+            let samples: Vec<f32> = (0..COUNT).map(|i| i as f32).collect();
+            let mut sent = 0;
+            while sent < COUNT {
+                let end = (sent + 37).min(COUNT);
+                sent += producer.push(&samples[sent..end]);
+                std::thread::yield_now();
+            }
+        });
+
+        let mut received = Vec::with_capacity(COUNT);
+        let mut out = [0.0; 64];
+        while received.len() < COUNT {
+            let count = consumer.pop_into(&mut out);
+            received.extend_from_slice(&out[..count]);
+            std::thread::yield_now();
+        }
+        writer.join().unwrap();
+        assert!(received.iter().enumerate().all(|(i, &sample)| sample == i as f32));
+        assert!(consumer.is_empty());
+    }
+}
@@ -0,0 +1,501 @@
+// src/bindings.rs
+
+use sdl2::keyboard::{Keycode, Mod};
+
+use nesemu::joypad::JoypadButton;
+
+/// Groups actions for display in the controls help and the remapping UI.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    Controller1,
+    Controller2,
+    Turbo,
+    Emulation,
+    Audio,
+    Debug,
+}
+
+impl Category {
+    pub const ALL: [Category; 6] = [
+        Category::Controller1,
+        Category::Controller2,
+        Category::Turbo,
+        Category::Emulation,
+        Category::Audio,
+        Category::Debug,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Controller1 => "Controller 1",
+            Category::Controller2 => "Controller 2",
+            Category::Turbo => "Turbo",
+            Category::Emulation => "Emulation",
+            Category::Audio => "Audio",
+            Category::Debug => "Debugger",
+        }
+    }
+}
+
+/// Everything a key in the game window can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+    P2A,
+    P2B,
+    P2Select,
+    P2Start,
+    P2Up,
+    P2Down,
+    P2Left,
+    P2Right,
+    TurboA,
+    TurboB,
+    StickyTurboA,
+    StickyTurboB,
+    Pause,
+    Rewind,
+    CloseGame,
+    ShowControls,
+    PerfOverlay,
+    StepInstruction,
+    StepFrame,
+    SoloPulse1,
+    SoloPulse2,
+    SoloTriangle,
+    SoloNoise,
+    SoloDmc,
+    SoloOff,
+}
+
+impl Action {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::A => "A",
+            Action::B => "B",
+            Action::Select => "Select",
+            Action::Start => "Start",
+            Action::Up => "Up",
+            Action::Down => "Down",
+            Action::Left => "Left",
+            Action::Right => "Right",
+            Action::P2A => "A",
+            Action::P2B => "B",
+            Action::P2Select => "Select",
+            Action::P2Start => "Start",
+            Action::P2Up => "Up",
+            Action::P2Down => "Down",
+            Action::P2Left => "Left",
+            Action::P2Right => "Right",
+            Action::TurboA => "Turbo A (hold)",
+            Action::TurboB => "Turbo B (hold)",
+            Action::StickyTurboA => "Turbo A (toggle)",
+            Action::StickyTurboB => "Turbo B (toggle)",
+            Action::Pause => "Pause / resume",
+            Action::Rewind => "Rewind (hold)",
+            Action::CloseGame => "Close game",
+            Action::ShowControls => "Show controls",
+            Action::PerfOverlay => "Performance overlay",
+            Action::StepInstruction => "Step instruction (paused)",
+            Action::StepFrame => "Step frame (paused)",
+            Action::SoloPulse1 => "Solo pulse 1",
+            Action::SoloPulse2 => "Solo pulse 2",
+            Action::SoloTriangle => "Solo triangle",
+            Action::SoloNoise => "Solo noise",
+            Action::SoloDmc => "Solo DMC",
+            Action::SoloOff => "Play all channels",
+        }
+    }
+
+    pub fn category(&self) -> Category {
+        match self {
+            Action::A
+            | Action::B
+            | Action::Select
+            | Action::Start
+            | Action::Up
+            | Action::Down
+            | Action::Left
+            | Action::Right => Category::Controller1,
+            Action::P2A
+            | Action::P2B
+            | Action::P2Select
+            | Action::P2Start
+            | Action::P2Up
+            | Action::P2Down
+            | Action::P2Left
+            | Action::P2Right => Category::Controller2,
+            Action::TurboA | Action::TurboB | Action::StickyTurboA | Action::StickyTurboB => Category::Turbo,
+            Action::Pause | Action::Rewind | Action::CloseGame | Action::ShowControls => Category::Emulation,
+            Action::SoloPulse1
+            | Action::SoloPulse2
+            | Action::SoloTriangle
+            | Action::SoloNoise
+            | Action::SoloDmc
+            | Action::SoloOff => Category::Audio,
+            Action::PerfOverlay | Action::StepInstruction | Action::StepFrame => Category::Debug,
+        }
+    }
+
+    /// The controller 1 button this action presses directly.
+    pub fn joypad_button(&self) -> Option<JoypadButton> {
+        match self {
+            Action::A => Some(JoypadButton::BUTTON_A),
+            Action::B => Some(JoypadButton::BUTTON_B),
+            Action::Select => Some(JoypadButton::SELECT),
+            Action::Start => Some(JoypadButton::START),
+            Action::Up => Some(JoypadButton::UP),
+            Action::Down => Some(JoypadButton::DOWN),
+            Action::Left => Some(JoypadButton::LEFT),
+            Action::Right => Some(JoypadButton::RIGHT),
+            _ => None,
+        }
+    }
+
+    /// The controller 2 button this action presses directly.
+    pub fn player2_button(&self) -> Option<JoypadButton> {
+        match self {
+            Action::P2A => Some(JoypadButton::BUTTON_A),
+            Action::P2B => Some(JoypadButton::BUTTON_B),
+            Action::P2Select => Some(JoypadButton::SELECT),
+            Action::P2Start => Some(JoypadButton::START),
+            Action::P2Up => Some(JoypadButton::UP),
+            Action::P2Down => Some(JoypadButton::DOWN),
+            Action::P2Left => Some(JoypadButton::LEFT),
+            Action::P2Right => Some(JoypadButton::RIGHT),
+            _ => None,
+        }
+    }
+
+    /// The button autofired while this action is held.
+    pub fn turbo_button(&self) -> Option<JoypadButton> {
+        match self {
+            Action::TurboA => Some(JoypadButton::BUTTON_A),
+            Action::TurboB => Some(JoypadButton::BUTTON_B),
+            _ => None,
+        }
+    }
+
+    /// The solo this action selects, as for `Apu::set_solo`: `Some(None)` plays every
+    /// channel again.
+    pub fn solo(&self) -> Option<Option<usize>> {
+        match self {
+            Action::SoloPulse1 => Some(Some(0)),
+            Action::SoloPulse2 => Some(Some(1)),
+            Action::SoloTriangle => Some(Some(2)),
+            Action::SoloNoise => Some(Some(3)),
+            Action::SoloDmc => Some(Some(4)),
+            Action::SoloOff => Some(None),
+            _ => None,
+        }
+    }
+
+    /// The button whose sticky turbo this action toggles.
+    pub fn sticky_turbo_button(&self) -> Option<JoypadButton> {
+        match self {
+            Action::StickyTurboA => Some(JoypadButton::BUTTON_A),
+            Action::StickyTurboB => Some(JoypadButton::BUTTON_B),
+            _ => None,
+        }
+    }
+}
+
+/// Modifier keys that must be held with a hotkey. Left and right keys count the same.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers { shift: false, ctrl: false, alt: false };
+
+    pub fn from_keymod(keymod: Mod) -> Self {
+        Modifiers {
+            shift: keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD),
+            ctrl: keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD),
+            alt: keymod.intersects(Mod::LALTMOD | Mod::RALTMOD),
+        }
+    }
+}
+
+const SOLO_MODIFIERS: Modifiers = Modifiers { shift: false, ctrl: true, alt: false };
+
+/// An emulator control bound to a key plus modifiers. Disabled hotkeys stay listed
+/// but never fire, so their key reaches the game instead.
+#[derive(Clone, Debug)]
+pub struct Hotkey {
+    pub action: Action,
+    pub key: Keycode,
+    pub modifiers: Modifiers,
+    pub enabled: bool,
+}
+
+impl Hotkey {
+    fn new(action: Action, key: Keycode, modifiers: Modifiers) -> Self {
+        Hotkey { action, key, modifiers, enabled: true }
+    }
+
+    /// Key name with its modifiers, e.g. "Shift+F1".
+    pub fn key_name(&self) -> String {
+        let mut name = String::new();
+        if self.modifiers.ctrl {
+            name.push_str("Ctrl+");
+        }
+        if self.modifiers.alt {
+            name.push_str("Alt+");
+        }
+        if self.modifiers.shift {
+            name.push_str("Shift+");
+        }
+        name.push_str(&self.key.name());
+        name
+    }
+}
+
+/// Key bindings for the game window, in display order. The GUI owns the active set
+/// and hands it to the emulator thread, so the help window always shows what is live.
+///
+/// Hotkeys live in their own table and are matched before controller keys, so a key
+/// used by both only reaches the game when its hotkey is disabled or suppressed.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    keys: Vec<(Action, Keycode)>,
+    hotkeys: Vec<Hotkey>,
+}
+
+impl Bindings {
+    pub fn new() -> Self {
+        Bindings {
+            keys: vec![
+                (Action::A, Keycode::S),
+                (Action::B, Keycode::A),
+                (Action::Select, Keycode::Backspace),
+                (Action::Start, Keycode::Return),
+                (Action::Up, Keycode::Up),
+                (Action::Down, Keycode::Down),
+                (Action::Left, Keycode::Left),
+                (Action::Right, Keycode::Right),
+                // Player 2 keys avoid N and F, which step the debugger while paused
+                (Action::P2A, Keycode::H),
+                (Action::P2B, Keycode::G),
+                (Action::P2Select, Keycode::RShift),
+                (Action::P2Start, Keycode::RCtrl),
+                (Action::P2Up, Keycode::I),
+                (Action::P2Down, Keycode::K),
+                (Action::P2Left, Keycode::J),
+                (Action::P2Right, Keycode::L),
+                (Action::TurboA, Keycode::W),
+                (Action::TurboB, Keycode::Q),
+                (Action::StickyTurboA, Keycode::Num1),
+                (Action::StickyTurboB, Keycode::Num2),
+            ],
+            hotkeys: vec![
+                Hotkey::new(Action::Pause, Keycode::Space, Modifiers::NONE),
+                Hotkey::new(Action::Rewind, Keycode::R, Modifiers::NONE),
+                Hotkey::new(Action::CloseGame, Keycode::Escape, Modifiers::NONE),
+                Hotkey::new(Action::ShowControls, Keycode::F1, Modifiers::NONE),
+                Hotkey::new(Action::PerfOverlay, Keycode::F3, Modifiers::NONE),
+                Hotkey::new(Action::StepInstruction, Keycode::N, Modifiers::NONE),
+                Hotkey::new(Action::StepFrame, Keycode::F, Modifiers::NONE),
+                // Plain 1 and 2 are sticky turbo, so the solo keys need Ctrl
+                Hotkey::new(Action::SoloPulse1, Keycode::Num1, SOLO_MODIFIERS),
+                Hotkey::new(Action::SoloPulse2, Keycode::Num2, SOLO_MODIFIERS),
+                Hotkey::new(Action::SoloTriangle, Keycode::Num3, SOLO_MODIFIERS),
+                Hotkey::new(Action::SoloNoise, Keycode::Num4, SOLO_MODIFIERS),
+                Hotkey::new(Action::SoloDmc, Keycode::Num5, SOLO_MODIFIERS),
+                Hotkey::new(Action::SoloOff, Keycode::Num0, SOLO_MODIFIERS),
+            ],
+        }
+    }
+
+    /// The controller or turbo action bound to a key. Hotkeys are not included.
+    pub fn action(&self, key: Keycode) -> Option<Action> {
+        self.keys.iter().find(|(_, k)| *k == key).map(|(action, _)| *action)
+    }
+
+    /// The enabled hotkey for a key press. Modifiers must match exactly, so F1 and
+    /// Shift+F1 can be bound to different actions.
+    pub fn hotkey(&self, key: Keycode, keymod: Mod) -> Option<Action> {
+        let modifiers = Modifiers::from_keymod(keymod);
+        self.hotkeys
+            .iter()
+            .find(|h| h.enabled && h.key == key && h.modifiers == modifiers)
+            .map(|h| h.action)
+    }
+
+    /// The enabled hotkey released by a key going up. Modifiers are ignored because
+    /// they may already have been let go.
+    pub fn hotkey_release(&self, key: Keycode) -> Option<Action> {
+        self.hotkeys.iter().find(|h| h.enabled && h.key == key).map(|h| h.action)
+    }
+
+    /// Controller and turbo actions of one category with their keys, in display order.
+    pub fn keys(&self, category: Category) -> Vec<(Action, Keycode)> {
+        self.keys.iter().copied().filter(|(action, _)| action.category() == category).collect()
+    }
+
+    /// Binds a controller or turbo action to `key`. An action already on that key takes
+    /// over this action's old key, so no key ends up doing two things.
+    pub fn set_key(&mut self, action: Action, key: Keycode) {
+        let Some(old_key) = self.keys.iter().find(|(a, _)| *a == action).map(|(_, k)| *k) else {
+            return;
+        };
+        for (bound_action, bound_key) in self.keys.iter_mut() {
+            if *bound_action == action {
+                *bound_key = key;
+            } else if *bound_key == key {
+                *bound_key = old_key;
+            }
+        }
+    }
+
+    /// Puts the controller and turbo keys back to the defaults; hotkeys are left alone.
+    pub fn reset_keys(&mut self) {
+        self.keys = Bindings::new().keys;
+    }
+
+    pub fn hotkeys(&self) -> &[Hotkey] {
+        &self.hotkeys
+    }
+
+    pub fn hotkey_enabled(&self, action: Action) -> bool {
+        self.hotkeys.iter().any(|h| h.action == action && h.enabled)
+    }
+
+    pub fn set_hotkey_enabled(&mut self, action: Action, enabled: bool) {
+        for hotkey in self.hotkeys.iter_mut().filter(|h| h.action == action) {
+            hotkey.enabled = enabled;
+        }
+    }
+
+    /// Bound actions of one category with the display name of their key.
+    pub fn describe(&self, category: Category) -> Vec<(&'static str, String)> {
+        let keys = self
+            .keys
+            .iter()
+            .filter(|(action, _)| action.category() == category)
+            .map(|(action, key)| (action.name(), key.name()));
+        let hotkeys = self.hotkeys.iter().filter(|h| h.action.category() == category).map(|h| {
+            let key = if h.enabled { h.key_name() } else { format!("{} (off)", h.key_name()) };
+            (h.action.name(), key)
+        });
+        keys.chain(hotkeys).collect()
+    }
+
+    /// Plain-text listing of every binding, grouped by category.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for category in Category::ALL {
+            text.push_str(category.name());
+            text.push('\n');
+            for (action, key) in self.describe(category) {
+                text.push_str(&format!("  {:<28}{}\n", action, key));
+            }
+        }
+        text
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Host keys for the Family BASIC keyboard as (key, row, column, $4017 bit), laid out
+/// after the nesdev wiki matrix. Keys missing from a PC keyboard use the nearest spare
+/// key: STOP is Pause, the yen key is Backslash, KANA is Right Alt, GRPH is Left Alt,
+/// CLR is Home and `_` is Right Ctrl.
+const FAMILY_KEYBOARD_KEYS: [(Keycode, usize, usize, u8); 73] = [
+    (Keycode::RightBracket, 0, 0, 4),
+    (Keycode::LeftBracket, 0, 0, 3),
+    (Keycode::Return, 0, 0, 2),
+    (Keycode::F8, 0, 0, 1),
+    (Keycode::Pause, 0, 1, 4),
+    (Keycode::Backslash, 0, 1, 3),
+    (Keycode::RShift, 0, 1, 2),
+    (Keycode::RAlt, 0, 1, 1),
+    (Keycode::Semicolon, 1, 0, 4),
+    (Keycode::Quote, 1, 0, 3),
+    (Keycode::Backquote, 1, 0, 2),
+    (Keycode::F7, 1, 0, 1),
+    (Keycode::Equals, 1, 1, 4),
+    (Keycode::Minus, 1, 1, 3),
+    (Keycode::Slash, 1, 1, 2),
+    (Keycode::RCtrl, 1, 1, 1),
+    (Keycode::K, 2, 0, 4),
+    (Keycode::L, 2, 0, 3),
+    (Keycode::O, 2, 0, 2),
+    (Keycode::F6, 2, 0, 1),
+    (Keycode::Num0, 2, 1, 4),
+    (Keycode::P, 2, 1, 3),
+    (Keycode::Comma, 2, 1, 2),
+    (Keycode::Period, 2, 1, 1),
+    (Keycode::J, 3, 0, 4),
+    (Keycode::U, 3, 0, 3),
+    (Keycode::I, 3, 0, 2),
+    (Keycode::F5, 3, 0, 1),
+    (Keycode::Num8, 3, 1, 4),
+    (Keycode::Num9, 3, 1, 3),
+    (Keycode::N, 3, 1, 2),
+    (Keycode::M, 3, 1, 1),
+    (Keycode::H, 4, 0, 4),
+    (Keycode::G, 4, 0, 3),
+    (Keycode::Y, 4, 0, 2),
+    (Keycode::F4, 4, 0, 1),
+    (Keycode::Num6, 4, 1, 4),
+    (Keycode::Num7, 4, 1, 3),
+    (Keycode::V, 4, 1, 2),
+    (Keycode::B, 4, 1, 1),
+    (Keycode::D, 5, 0, 4),
+    (Keycode::R, 5, 0, 3),
+    (Keycode::T, 5, 0, 2),
+    (Keycode::F3, 5, 0, 1),
+    (Keycode::Num4, 5, 1, 4),
+    (Keycode::Num5, 5, 1, 3),
+    (Keycode::C, 5, 1, 2),
+    (Keycode::F, 5, 1, 1),
+    (Keycode::A, 6, 0, 4),
+    (Keycode::S, 6, 0, 3),
+    (Keycode::W, 6, 0, 2),
+    (Keycode::F2, 6, 0, 1),
+    (Keycode::Num3, 6, 1, 4),
+    (Keycode::E, 6, 1, 3),
+    (Keycode::Z, 6, 1, 2),
+    (Keycode::X, 6, 1, 1),
+    (Keycode::LCtrl, 7, 0, 4),
+    (Keycode::Q, 7, 0, 3),
+    (Keycode::Escape, 7, 0, 2),
+    (Keycode::F1, 7, 0, 1),
+    (Keycode::Num2, 7, 1, 4),
+    (Keycode::Num1, 7, 1, 3),
+    (Keycode::LAlt, 7, 1, 2),
+    (Keycode::LShift, 7, 1, 1),
+    (Keycode::Left, 8, 0, 4),
+    (Keycode::Right, 8, 0, 3),
+    (Keycode::Up, 8, 0, 2),
+    (Keycode::Home, 8, 0, 1),
+    (Keycode::Insert, 8, 1, 4),
+    (Keycode::Delete, 8, 1, 3),
+    (Keycode::Space, 8, 1, 2),
+    (Keycode::Down, 8, 1, 1),
+    // Backspace also deletes, as a PC typist expects
+    (Keycode::Backspace, 8, 1, 3),
+];
+
+/// Where a host key lands on the Family BASIC keyboard, as (row, column, $4017 bit).
+pub fn family_keyboard_key(key: Keycode) -> Option<(usize, usize, u8)> {
+    FAMILY_KEYBOARD_KEYS
+        .iter()
+        .find(|(k, ..)| *k == key)
+        .map(|&(_, row, column, bit)| (row, column, bit))
+}
@@ -0,0 +1,129 @@
+// src/blip.rs
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Output samples each amplitude step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// Sub-sample positions the step kernel is tabulated at.
+const KERNEL_PHASES: usize = 64;
+/// Passband as a fraction of the output Nyquist frequency.
+const CUTOFF: f64 = 0.9;
+
+/// Band-limited step synthesis, in the manner of blip_buf.
+///
+/// The input is a signal that only changes in steps, clocked once per source cycle.
+/// Each change is recorded as an impulse at its exact sub-sample position, shaped by a
+/// windowed-sinc kernel, and the impulses are summed back into a level as samples come
+/// due. Steps reach the output with their harmonics above the cutoff removed instead of
+/// aliased back into the audible range. Output lags the input by half the kernel width.
+pub struct BlipBuffer {
+    // One row of taps per sub-sample phase; each row sums to 1, and the rounding of a
+    // step's share of the taps goes in its last slot, so a step of `delta` from silence
+    // moves the output by exactly `delta` once it has passed through
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    // Impulses for the next `KERNEL_WIDTH` output samples, the next one due first
+    deltas: VecDeque<f32>,
+    // Output samples per source cycle
+    samples_per_cycle: f64,
+    // How far the current output sample period has been clocked, 0.0 to 1.0
+    phase: f64,
+    amplitude: f32,
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        BlipBuffer {
+            kernel: (0..KERNEL_PHASES).map(|phase| kernel_row(phase as f64 / KERNEL_PHASES as f64)).collect(),
+            deltas: VecDeque::from(vec![0.0; KERNEL_WIDTH]),
+            samples_per_cycle: sample_rate / clock_rate,
+            phase: 0.0,
+            amplitude: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    /// Changes the source clock, keeping whatever is already in flight.
+    pub fn set_clock_rate(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.samples_per_cycle = sample_rate / clock_rate;
+    }
+
+    /// Sets the input level from the current cycle on.
+    pub fn set_amplitude(&mut self, amplitude: f32) {
+        let delta = amplitude - self.amplitude;
+        if delta == 0.0 {
+            return;
+        }
+        self.amplitude = amplitude;
+        let row = &self.kernel[(self.phase * KERNEL_PHASES as f64) as usize];
+        let mut added = 0.0;
+        for (slot, tap) in self.deltas.iter_mut().zip(&row[..KERNEL_WIDTH - 1]) {
+            *slot += delta * tap;
+            added += delta * tap;
+        }
+        self.deltas[KERNEL_WIDTH - 1] += delta - added;
+    }
+
+    /// Advances one source cycle, returning an output sample when one is complete.
+    pub fn clock(&mut self) -> Option<f32> {
+        self.phase += self.samples_per_cycle;
+        if self.phase < 1.0 {
+            return None;
+        }
+        self.phase -= 1.0;
+        self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+        self.deltas.push_back(0.0);
+        Some(self.integrator)
+    }
+
+    /// Drops everything in flight and starts again from silence.
+    pub fn clear(&mut self) {
+        self.deltas.iter_mut().for_each(|slot| *slot = 0.0);
+        self.phase = 0.0;
+        self.amplitude = 0.0;
+        self.integrator = 0.0;
+    }
+}
+
+// Blackman-windowed sinc impulse for a step `offset` of a sample after the start of
+// the period, centred half the kernel width later
+fn kernel_row(offset: f64) -> [f32; KERNEL_WIDTH] {
+    let half = KERNEL_WIDTH as f64 / 2.0;
+    let mut taps = [0.0f64; KERNEL_WIDTH];
+    for (i, tap) in taps.iter_mut().enumerate() {
+        let x = i as f64 + 0.5 - half - offset;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+        let w = (x + half) / KERNEL_WIDTH as f64;
+        let window = if (0.0..=1.0).contains(&w) {
+            0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos()
+        } else {
+            0.0
+        };
+        *tap = sinc * window;
+    }
+    let sum: f64 = taps.iter().sum();
+    taps.map(|tap| (tap / sum) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_settles_to_exactly_its_delta() {
+        // A clock rate that is not a multiple of the sample rate, so the steps land on
+        // many sub-sample phases
+        for delay in 0..100 {
+            for delta in [0.25f32, -0.1, 0.337, 1.0] {
+                let mut blip = BlipBuffer::new(1_789_773.0, 44_100.0);
+                for _ in 0..delay {
+                    blip.clock();
+                }
+                blip.set_amplitude(delta);
+                let samples: Vec<f32> = std::iter::from_fn(|| Some(blip.clock())).flatten().take(2 * KERNEL_WIDTH).collect();
+                assert_eq!(samples[KERNEL_WIDTH..], [delta; KERNEL_WIDTH], "{} after {} cycles", delta, delay);
+            }
+        }
+    }
+}
@@ -3,8 +3,15 @@ use crate::cartridge::Rom;
 use crate::debugger::{Debugger, DebuggerState};
 use crate::gamegenie::GameGenieCode;
 use crate::joypad::{Joypad, JoypadState};
+use crate::keyboard::FamilyKeyboard;
+use crate::mapper::{self, Mapper, MapperState};
 use crate::ppu::{NesPPU, PpuState};
+use crate::region::Region;
+use crate::rewind::SnapshotMemory;
+use crate::zapper::Zapper;
+use log::info;
 use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
 
 pub trait Mem {
     fn mem_read(&mut self, addr: u16) -> u8;
@@ -27,33 +34,124 @@ pub trait Mem {
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const CARTRIDGE_SPACE: u16 = 0x4020;
+const PRG_ROM: u16 = 0x8000;
 
 #[derive(Serialize, Deserialize)]
 pub struct BusState {
     cpu_vram: Vec<u8>,
+    mapper: MapperState,
     ppu: PpuState,
     apu: ApuState,
     cycles: usize,
+    ppu_dot_remainder: usize,
     nmi_interrupt: Option<u8>,
     irq_interrupt: Option<u8>,
     joypad1: JoypadState,
     joypad2: JoypadState,
     game_genie_codes: Vec<GameGenieCode>,
+    frozen_addresses: Vec<FrozenAddress>,
+    frame_number: u64,
+    scheduled_writes: VecDeque<ScheduledWrite>,
     debugger: DebuggerState,
 }
 
+impl BusState {
+    pub(crate) fn take_memory(&mut self, mem: &mut SnapshotMemory) {
+        mem.cpu_ram = std::mem::take(&mut self.cpu_vram);
+        self.ppu.take_memory(mem);
+    }
+
+    pub(crate) fn restore_memory(&mut self, mut mem: SnapshotMemory) {
+        self.cpu_vram = std::mem::take(&mut mem.cpu_ram);
+        self.ppu.restore_memory(mem);
+    }
+
+    pub(crate) fn registers(&self) -> Vec<(&'static str, String)> {
+        let mut registers = vec![
+            ("CPU cycles", self.cycles.to_string()),
+            ("Frame number", self.frame_number.to_string()),
+            ("PPU dot remainder", self.ppu_dot_remainder.to_string()),
+            ("NMI pending", format!("{:?}", self.nmi_interrupt)),
+            ("IRQ pending", format!("{:?}", self.irq_interrupt)),
+        ];
+        registers.extend(self.ppu.registers());
+        registers
+    }
+}
+
+/// What is plugged in next to the controllers. The Zapper and the keyboard both read
+/// through $4017, so only one is connected at a time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExpansionDevice {
+    #[default]
+    None,
+    FamilyKeyboard,
+    Zapper,
+}
+
+impl ExpansionDevice {
+    pub const ALL: [ExpansionDevice; 3] = [ExpansionDevice::None, ExpansionDevice::FamilyKeyboard, ExpansionDevice::Zapper];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExpansionDevice::None => "None",
+            ExpansionDevice::FamilyKeyboard => "Family BASIC Keyboard",
+            ExpansionDevice::Zapper => "Zapper",
+        }
+    }
+}
+
+/// A "freeze" cheat: the address is written with `value` at the end of every frame,
+/// so the game never keeps a change to it for long, e.g. a lives counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrozenAddress {
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// A one-off write to `addr` at the start of frame `frame`, as counted by
+/// `Bus::frame_number`. It goes through the normal bus path, so PPU, APU and mapper
+/// registers react as they would to the game's own write.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledWrite {
+    pub frame: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
-    rom: Rom,
+    mapper: Box<dyn Mapper>,
     ppu: NesPPU,
     pub apu: Apu,
     cycles: usize,
+    frames: u64,
+    // Frame the machine is on. Unlike `frames` it is saved in states, so loading one,
+    // rewinding or rolling back run-ahead puts it back too.
+    frame_number: u64,
+    region: Region,
+    // Requested region, switched in at the next frame boundary
+    pending_region: Option<Region>,
+    // Fraction of a PPU dot carried between CPU cycles, in units of 1/denominator (PAL only)
+    ppu_dot_remainder: usize,
     nmi_interrupt: Option<u8>,
     irq_interrupt: Option<u8>,
+    // Page written to $4014, transferred once the writing instruction has finished
+    pending_oam_dma: Option<u8>,
     pub joypad1: Joypad,
     pub joypad2: Joypad,
-    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Apu) + 'call>,
+    // Replaces controller 2 on port 2 when plugged in
+    zapper: Option<Zapper>,
+    // Adds its keys to $4017 next to controller 2 when plugged in
+    keyboard: Option<FamilyKeyboard>,
+    gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad, &mut Joypad, &mut Apu) + 'call>,
     game_genie_codes: Vec<GameGenieCode>,
+    frozen_addresses: Vec<FrozenAddress>,
+    // Sorted by frame; writes for the same frame keep the order they were scheduled in
+    scheduled_writes: VecDeque<ScheduledWrite>,
+    // Logs every CPU access to $2000-$2007 with the PPU position when set
+    log_ppu_registers: bool,
     
     pub debugger: Debugger,
 }
@@ -61,85 +159,349 @@ pub struct Bus<'call> {
 impl<'call> Bus<'call> {
     pub fn new<F>(rom: Rom, gameloop_callback: F) -> Self
     where
-        F: FnMut(&NesPPU, &mut Joypad, &mut Apu) + 'call,
+        F: FnMut(&NesPPU, &mut Joypad, &mut Joypad, &mut Apu) + 'call,
     {
-        let ppu = NesPPU::new(rom.chr_rom.clone(), rom.screen_mirroring.clone());
+        let ppu = NesPPU::new(rom.chr_rom.clone(), rom.screen_mirroring.clone(), rom.chr_is_ram);
         Bus {
             cpu_vram: [0; 2048],
-            rom,
+            mapper: mapper::new_mapper(&rom),
             ppu,
             apu: Apu::new(),
             cycles: 0,
+            frames: 0,
+            frame_number: 0,
+            region: Region::Ntsc,
+            pending_region: None,
+            ppu_dot_remainder: 0,
             nmi_interrupt: None,
             irq_interrupt: None,
+            pending_oam_dma: None,
             joypad1: Joypad::new(),
             joypad2: Joypad::new(),
+            zapper: None,
+            keyboard: None,
             gameloop_callback: Box::from(gameloop_callback),
             game_genie_codes: Vec::new(),
+            frozen_addresses: Vec::new(),
+            scheduled_writes: VecDeque::new(),
+            log_ppu_registers: false,
 
             debugger: Debugger::new(),
         }
     }
 
+    /// Puts RAM, the PPU, the APU and the controllers back in their power-on state.
+    /// The cartridge, cheats, scheduled writes, debugger, frame counters, region, OAM
+    /// quirks setting, layer overrides and audio settings, including the output rate and
+    /// write logging, are left alone.
+    pub fn power_on(&mut self) {
+        self.cpu_vram = [0; 2048];
+        let mut chr = std::mem::take(&mut self.ppu.chr_rom);
+        if self.ppu.chr_is_ram {
+            chr.fill(0);
+        }
+        let oam_quirks = self.ppu.oam_quirks();
+        let (background, sprites) = self.ppu.layer_visibility();
+        self.ppu = NesPPU::new(chr, self.ppu.mirroring.clone(), self.ppu.chr_is_ram);
+        self.ppu.set_oam_quirks(oam_quirks);
+        self.ppu.set_layer_visibility(background, sprites);
+        let channel_volumes = self.apu.channel_volumes();
+        let solo = self.apu.solo();
+        let band_limited = self.apu.band_limited();
+        let hardware_filters = self.apu.hardware_filters();
+        let silence_ultrasonic_triangle = self.apu.silence_ultrasonic_triangle();
+        let write_logging = self.apu.write_logging();
+        self.apu = Apu::with_sample_rate(self.apu.sample_rate());
+        self.apu.set_write_logging(write_logging);
+        self.apu.set_band_limited(band_limited);
+        self.apu.set_hardware_filters(hardware_filters);
+        self.apu.set_silence_ultrasonic_triangle(silence_ultrasonic_triangle);
+        for (channel, volume) in channel_volumes.into_iter().enumerate() {
+            self.apu.set_channel_volume(channel, volume);
+        }
+        self.apu.set_solo(solo);
+        self.cycles = 0;
+        let region = self.pending_region.take().unwrap_or(self.region);
+        self.apply_region(region);
+        self.nmi_interrupt = None;
+        self.irq_interrupt = None;
+        self.pending_oam_dma = None;
+        self.joypad1 = Joypad::new();
+        self.joypad2 = Joypad::new();
+    }
+
+    /// Region the console runs as, including a switch that has not taken effect yet.
+    pub fn region(&self) -> Region {
+        self.pending_region.unwrap_or(self.region)
+    }
+
+    /// Switches NTSC/PAL/Dendy timing. Before the first cycle it applies at once;
+    /// otherwise it waits for the current frame to finish, so no frame is drawn with
+    /// a mix of two scanline counts and the PAL dot fraction starts from zero.
+    pub fn set_region(&mut self, region: Region) {
+        if self.cycles == 0 {
+            self.pending_region = None;
+            self.apply_region(region);
+        } else {
+            self.pending_region = Some(region);
+        }
+    }
+
+    /// Turns on the PPU's OAM-during-rendering quirks; see `NesPPU::set_oam_quirks`.
+    pub fn set_oam_quirks(&mut self, enabled: bool) {
+        self.ppu.set_oam_quirks(enabled);
+    }
+
+    /// Forces the background or sprites on or off in the picture; see `NesPPU::set_layer_visibility`.
+    pub fn set_layer_visibility(&mut self, background: Option<bool>, sprites: Option<bool>) {
+        self.ppu.set_layer_visibility(background, sprites);
+    }
+
+    fn apply_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+        self.ppu_dot_remainder = 0;
+    }
+
     pub fn set_game_genie_codes(&mut self, codes: Vec<GameGenieCode>) {
         self.game_genie_codes = codes;
     }
 
+    /// Replaces the freeze list. The values are first written when the current frame ends.
+    pub fn set_frozen_addresses(&mut self, frozen: Vec<FrozenAddress>) {
+        self.frozen_addresses = frozen;
+    }
+
+    pub fn frozen_addresses(&self) -> &[FrozenAddress] {
+        &self.frozen_addresses
+    }
+
+    /// Queues a write for the start of its frame, after the freeze writes. A frame that
+    /// has already started gets it at the next frame start. The queue is part of the
+    /// machine state, so a write that is rolled back is made again when its frame comes
+    /// round once more.
+    pub fn schedule_write(&mut self, write: ScheduledWrite) {
+        let index = self.scheduled_writes.partition_point(|pending| pending.frame <= write.frame);
+        self.scheduled_writes.insert(index, write);
+    }
+
+    /// Connects an expansion device, unplugging whichever one was there before.
+    pub fn set_expansion_device(&mut self, device: ExpansionDevice) {
+        if device == self.expansion_device() {
+            return;
+        }
+        self.zapper = (device == ExpansionDevice::Zapper).then(Zapper::new);
+        self.keyboard = (device == ExpansionDevice::FamilyKeyboard).then(FamilyKeyboard::new);
+    }
+
+    pub fn expansion_device(&self) -> ExpansionDevice {
+        if self.zapper.is_some() {
+            ExpansionDevice::Zapper
+        } else if self.keyboard.is_some() {
+            ExpansionDevice::FamilyKeyboard
+        } else {
+            ExpansionDevice::None
+        }
+    }
+
+    pub fn zapper_mut(&mut self) -> Option<&mut Zapper> {
+        self.zapper.as_mut()
+    }
+
+    pub fn keyboard_mut(&mut self) -> Option<&mut FamilyKeyboard> {
+        self.keyboard.as_mut()
+    }
+
+    /// Logs every CPU access to $2000-$2007, with the frame and PPU position, at info
+    /// level under the `ppu` target.
+    pub fn set_ppu_register_logging(&mut self, enabled: bool) {
+        self.log_ppu_registers = enabled;
+    }
+
+    fn log_ppu_access(&self, access: &str, addr: u16, data: u8) {
+        info!(
+            target: "ppu",
+            "frame {} scanline {:>3} cycle {:>3}: {} ${:04X} = ${:02X}",
+            self.frames,
+            self.ppu.scanline(),
+            self.ppu.cycle(),
+            access,
+            addr,
+            data
+        );
+    }
+
+    // DMA timing
+    //
+    // The CPU's cycles alternate between "get" cycles, where a DMA unit may read, and
+    // "put" cycles, where it may write; here a cycle is a get when the count of cycles
+    // before it is even. Both DMA units halt the CPU, then wait for a get cycle to read:
+    //
+    // - OAM DMA: a halt cycle, an alignment cycle if the next one is a put, then 256
+    //   get/put pairs copying a byte each: 513 or 514 cycles.
+    // - DMC DMA on its own: a halt cycle, a dummy cycle, an alignment cycle if needed and
+    //   the read: 3 or 4 cycles.
+    // - DMC DMA during OAM DMA: the DMC read takes the next get cycle and OAM DMA spends
+    //   the following put realigning, so the transfer is 2 cycles longer.
+    //
+    // Simplifications: the CPU's own accesses are not modelled cycle by cycle, so DMA
+    // always starts once the instruction that wrote $4014, or during which the DMC
+    // buffer emptied, has finished. On hardware a halt landing on a CPU write cycle is
+    // held off by up to 3 cycles, and a DMC request in the last cycles of an OAM DMA
+    // costs 1 or 3 cycles rather than 2; neither is emulated.
+
+    /// Starts an OAM DMA from `page`; the transfer runs when the current instruction ends.
     pub fn dma_transfer(&mut self, page: u8) {
-        let mut data = [0u8; 256];
+        self.pending_oam_dma = Some(page);
+    }
+
+    fn is_get_cycle(&self) -> bool {
+        self.cycles.is_multiple_of(2)
+    }
+
+    // Spends a cycle if needed so the next one is a get cycle
+    fn align_to_get(&mut self) {
+        if !self.is_get_cycle() {
+            self.advance(1);
+        }
+    }
+
+    fn run_oam_dma(&mut self, page: u8) {
         let start_addr = (page as u16) << 8;
+        self.advance(1);
+        self.align_to_get();
         for i in 0..256 {
-            data[i] = self.mem_read(start_addr + i as u16);
+            if self.apu.dmc_fetch_address().is_some() {
+                self.dmc_read();
+                self.advance(1);
+            }
+            let data = self.mem_read(start_addr + i);
+            self.advance(1);
+            self.ppu.write_oam_dma_byte(data);
+            self.advance(1);
         }
-        self.ppu.write_oam_dma(&data);
-        self.tick(513);
     }
 
-    fn read_prg_rom_raw(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr %= 0x4000;
+    fn run_dmc_dma(&mut self) {
+        self.advance(2);
+        self.align_to_get();
+        self.dmc_read();
+    }
+
+    // The DMC's read, on a get cycle. Its reads see Game Genie patches like any other bus read.
+    fn dmc_read(&mut self) {
+        if let Some(addr) = self.apu.dmc_fetch_address() {
+            let data = self.read_cartridge(addr);
+            self.apu.dmc_fill_sample_buffer(data);
         }
-        self.rom.prg_rom[addr as usize]
+        self.advance(1);
     }
 
-    fn read_prg_rom(&self, addr: u16) -> u8 {
-        for code in &self.game_genie_codes {
-            if code.address == addr {
-                if let Some(compare_data) = code.compare_data {
-                    let actual_data = self.read_prg_rom_raw(addr);
-                    if actual_data == compare_data {
-                        return code.new_data;
-                    } else {
-                        continue;
-                    }
-                } else {
-                    return code.new_data;
+    /// Reads cartridge space through the mapper, with Game Genie patches applied to PRG ROM.
+    ///
+    /// When several codes patch the same address, the first code whose compare value
+    /// matches the ROM byte wins, then the first code without a compare value. A code
+    /// whose compare value does not match never applies.
+    fn read_cartridge(&self, addr: u16) -> u8 {
+        let data = self.mapper.read(addr);
+        if addr < PRG_ROM {
+            return data;
+        }
+
+        let mut unconditional = None;
+        for code in self.game_genie_codes.iter().filter(|code| code.address == addr) {
+            match code.compare_data {
+                Some(compare_data) if compare_data == data => return code.new_data,
+                Some(_) => {}
+                None => {
+                    unconditional.get_or_insert(code.new_data);
                 }
             }
         }
-
-        self.read_prg_rom_raw(addr)
+        unconditional.unwrap_or(data)
     }
 
+    /// Runs the rest of the machine for `cycles` CPU cycles, then any DMA that became
+    /// due, stalling the CPU for as long as it takes.
     pub fn tick(&mut self, cycles: usize) {
+        self.advance(cycles);
+        if let Some(page) = self.pending_oam_dma.take() {
+            self.run_oam_dma(page);
+        }
+        // The DMC empties its buffer at most once every 432 cycles, so one fetch per
+        // tick keeps it fed
+        if self.apu.dmc_fetch_address().is_some() {
+            self.run_dmc_dma();
+        }
+    }
+
+    fn advance(&mut self, cycles: usize) {
         self.cycles += cycles;
         self.apu.tick(cycles);
-        let frame_complete = self.ppu.tick(cycles * 3);
+        let (dots_per_cycle, denominator) = self.region.ppu_dots_per_cpu_cycle();
+        let dots = cycles * dots_per_cycle + self.ppu_dot_remainder;
+        self.ppu_dot_remainder = dots % denominator;
+        let frame_complete = self.ppu.tick(dots / denominator);
+        self.mapper.notify_cpu_cycles(cycles);
+        if let Some(scanline) = self.ppu.poll_scanline_clock() {
+            self.mapper.notify_scanline(scanline);
+        }
 
         if frame_complete {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.apu);
+            self.frames += 1;
+            self.frame_number += 1;
+            if let Some(region) = self.pending_region.take() {
+                self.apply_region(region);
+            }
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.joypad2, &mut self.apu);
+            for i in 0..self.frozen_addresses.len() {
+                let frozen = self.frozen_addresses[i];
+                self.mem_write(frozen.addr, frozen.value);
+            }
+            while let Some(write) = self.scheduled_writes.front().copied() {
+                if write.frame > self.frame_number {
+                    break;
+                }
+                self.scheduled_writes.pop_front();
+                self.mem_write(write.addr, write.value);
+            }
         }
 
         if self.ppu.poll_nmi_interrupt().is_some() {
             self.nmi_interrupt = Some(1);
         }
 
-        if self.apu.poll_frame_interrupt() {
+        if self.apu.irq_pending() || self.mapper.irq_pending() {
             self.irq_interrupt = Some(1);
         }
     }
 
+    pub fn ppu(&self) -> &NesPPU {
+        &self.ppu
+    }
+
+    /// CPU cycles elapsed since this bus was created.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn game_genie_code_count(&self) -> usize {
+        self.game_genie_codes.len()
+    }
+
+    /// Number of frames completed since this bus was created.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Frame the emulated machine is on. It counts like `frame_count` but is saved in
+    /// states, so it goes back with a loaded state, a rewind or a run-ahead rollback;
+    /// scheduled writes are keyed on it.
+    pub fn frame_number(&self) -> u64 {
+        self.frame_number
+    }
+
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
@@ -150,13 +512,20 @@ impl<'call> Bus<'call> {
 
     pub fn mem_read_readonly(&self, addr: u16) -> u8 {
         self.debugger.check_read(addr);
+        self.peek(addr)
+    }
 
+    /// Reads RAM or the cartridge like `mem_read_readonly`, without tripping read
+    /// breakpoints, for debugger views. $4015 shows the APU status without acknowledging
+    /// the frame IRQ; other registers read as 0.
+    pub fn peek(&self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0x07FF;
                 self.cpu_vram[mirror_down_addr as usize]
             }
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4015 => self.apu.peek_status(),
+            CARTRIDGE_SPACE..=0xFFFF => self.read_cartridge(addr),
             _ => 0,
         }
     }
@@ -170,28 +539,58 @@ impl<'call> Bus<'call> {
     pub fn save_state(&self) -> BusState {
         BusState {
             cpu_vram: self.cpu_vram.to_vec(),
+            mapper: self.mapper.save_state(),
             ppu: self.ppu.save_state(),
             apu: self.apu.save_state(),
             cycles: self.cycles,
+            ppu_dot_remainder: self.ppu_dot_remainder,
             nmi_interrupt: self.nmi_interrupt,
             irq_interrupt: self.irq_interrupt,
             joypad1: self.joypad1.save_state(),
             joypad2: self.joypad2.save_state(),
             game_genie_codes: self.game_genie_codes.clone(),
+            frozen_addresses: self.frozen_addresses.clone(),
+            frame_number: self.frame_number,
+            scheduled_writes: self.scheduled_writes.clone(),
             debugger: self.debugger.save_state(),
         }
     }
 
+    /// Checks every part of a state against this machine, so that `load_state` can
+    /// apply it without failing halfway.
+    pub fn validate_state(&self, state: &BusState) -> Result<(), String> {
+        if state.cpu_vram.len() != self.cpu_vram.len() {
+            return Err(format!("CPU RAM is {} bytes, expected {}", state.cpu_vram.len(), self.cpu_vram.len()));
+        }
+        let (_, denominator) = self.region.ppu_dots_per_cpu_cycle();
+        if state.ppu_dot_remainder >= denominator {
+            return Err(format!(
+                "PPU dot remainder {} is out of range for {} timing",
+                state.ppu_dot_remainder,
+                self.region.name()
+            ));
+        }
+        self.mapper.validate_state(&state.mapper)?;
+        self.ppu.validate_state(&state.ppu)?;
+        state.apu.validate()
+    }
+
     pub fn load_state(&mut self, state: &BusState) {
         self.cpu_vram.copy_from_slice(&state.cpu_vram);
+        self.mapper.load_state(&state.mapper);
         self.ppu.load_state(&state.ppu);
         self.apu.load_state(&state.apu);
         self.cycles = state.cycles;
+        self.ppu_dot_remainder = state.ppu_dot_remainder;
         self.nmi_interrupt = state.nmi_interrupt;
         self.irq_interrupt = state.irq_interrupt;
+        self.pending_oam_dma = None;
         self.joypad1.load_state(&state.joypad1);
         self.joypad2.load_state(&state.joypad2);
         self.game_genie_codes = state.game_genie_codes.clone();
+        self.frozen_addresses = state.frozen_addresses.clone();
+        self.frame_number = state.frame_number;
+        self.scheduled_writes = state.scheduled_writes.clone();
         self.debugger.load_state(&state.debugger);
     }
 }
@@ -207,16 +606,32 @@ impl<'a> Mem for Bus<'a> {
             }
             0x2000..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0x2007;
-                match mirror_down_addr {
-                    0x2002 => self.ppu.read_status(),
+                let data = match mirror_down_addr {
+                    0x2002 => {
+                        // The NMI a read suppresses may already be latched here
+                        if self.ppu.vblank_just_started() {
+                            self.nmi_interrupt = None;
+                        }
+                        self.ppu.read_status()
+                    }
+                    0x2004 => self.ppu.read_oam_data(),
                     0x2007 => self.ppu.read_data(),
-                    _ => 0,
+                    _ => self.ppu.read_open_bus(),
+                };
+                if self.log_ppu_registers {
+                    self.log_ppu_access("read ", mirror_down_addr, data);
                 }
+                data
             }
             0x4015 => self.apu.mem_read(addr),
             0x4016 => self.joypad1.read(),
-            0x4017 => self.joypad2.read(),
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            // Reads of 0x4017 are port 2 only; the APU frame counter is write-only
+            0x4017 => match (&mut self.zapper, &self.keyboard) {
+                (Some(zapper), _) => zapper.read(&self.ppu, self.frames),
+                (None, Some(keyboard)) => self.joypad2.read() | keyboard.read(),
+                (None, None) => self.joypad2.read(),
+            },
+            CARTRIDGE_SPACE..=0xFFFF => self.read_cartridge(addr),
             _ => 0,
         }
     }
@@ -231,6 +646,10 @@ impl<'a> Mem for Bus<'a> {
             }
             0x2000..=PPU_REGISTERS_MIRRORS_END => {
                 let mirror_down_addr = addr & 0x2007;
+                if self.log_ppu_registers {
+                    self.log_ppu_access("write", mirror_down_addr, data);
+                }
+                self.ppu.drive_open_bus(data, 0xFF);
                 match mirror_down_addr {
                     0x2000 => self.ppu.write_to_ctrl(data),
                     0x2001 => self.ppu.write_to_mask(data),
@@ -242,6 +661,8 @@ impl<'a> Mem for Bus<'a> {
                     _ => { /* Unimplemented */ }
                 }
             }
+            // Writes to 0x4017 set the APU frame counter and never reach controller 2;
+            // both controllers are strobed through 0x4016 alone
             0x4000..=0x4013 | 0x4015 | 0x4017 => {
                 self.apu.mem_write(addr, data);
             }
@@ -249,9 +670,308 @@ impl<'a> Mem for Bus<'a> {
             0x4016 => {
                 self.joypad1.write(data);
                 self.joypad2.write(data);
+                if let Some(keyboard) = &mut self.keyboard {
+                    keyboard.write(data);
+                }
             }
-            0x8000..=0xFFFF => { /* Cannot write to ROM */ }
+            CARTRIDGE_SPACE..=0xFFFF => self.mapper.write(addr, data),
             _ => { /* Ignoring write */ }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Mirroring;
+    use crate::joypad::JoypadButton;
+
+    fn test_bus() -> Bus<'static> {
+        let rom = Rom::from_raw(&[0xEA; 0x8000], None, Mirroring::HORIZONTAL).unwrap();
+        Bus::new(rom, |_, _, _, _| {})
+    }
+
+    #[test]
+    fn power_on_keeps_the_solo_channel() {
+        let mut bus = test_bus();
+        bus.apu.set_solo(Some(2));
+        bus.power_on();
+        assert_eq!(bus.apu.solo(), Some(2));
+    }
+
+    #[test]
+    fn status_read_as_vblank_starts_drops_the_latched_nmi() {
+        let mut bus = test_bus();
+        bus.mem_write(0x2000, 0x80);
+        while !bus.ppu().vblank_just_started() {
+            bus.tick(1);
+        }
+        assert_eq!(bus.mem_read(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.poll_nmi_status(), None);
+    }
+
+    // Answers every read with the low address byte and records the writes it is given
+    struct RecordingMapper(std::rc::Rc<std::cell::RefCell<Vec<(u16, u8)>>>);
+
+    impl Mapper for RecordingMapper {
+        fn read(&self, addr: u16) -> u8 {
+            addr as u8
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.0.borrow_mut().push((addr, data));
+        }
+    }
+
+    #[test]
+    fn cartridge_space_goes_through_the_mapper() {
+        let mut bus = test_bus();
+        let writes = std::rc::Rc::default();
+        bus.mapper = Box::new(RecordingMapper(std::rc::Rc::clone(&writes)));
+        for (addr, data) in [(0x8000, 0x01), (0xC123, 0x02), (0xFFFF, 0x03), (0x6000, 0x04)] {
+            bus.mem_write(addr, data);
+        }
+        assert_eq!(*writes.borrow(), [(0x8000, 0x01), (0xC123, 0x02), (0xFFFF, 0x03), (0x6000, 0x04)]);
+        assert_eq!(bus.mem_read(0x8000), 0x00);
+        assert_eq!(bus.mem_read(0xFFFC), 0xFC);
+        // Work RAM and the registers below cartridge space stay on the bus
+        bus.mem_write(0x0000, 0x05);
+        bus.mem_write(0x4015, 0x00);
+        assert_eq!(writes.borrow().len(), 4);
+    }
+
+    #[derive(Default)]
+    struct BoardSignals {
+        scanlines: Vec<u16>,
+        cpu_cycles: usize,
+        irq: bool,
+    }
+
+    // Records the scanline and CPU cycle notifications, with an IRQ line set by the test
+    struct CountingMapper(std::rc::Rc<std::cell::RefCell<BoardSignals>>);
+
+    impl Mapper for CountingMapper {
+        fn read(&self, _addr: u16) -> u8 {
+            0xEA
+        }
+
+        fn write(&mut self, _addr: u16, _data: u8) {}
+
+        fn notify_scanline(&mut self, scanline: u16) {
+            self.0.borrow_mut().scanlines.push(scanline);
+        }
+
+        fn notify_cpu_cycles(&mut self, cycles: usize) {
+            self.0.borrow_mut().cpu_cycles += cycles;
+        }
+
+        fn irq_pending(&self) -> bool {
+            self.0.borrow().irq
+        }
+    }
+
+    #[test]
+    fn mapper_sees_241_scanlines_per_frame_at_any_step_size() {
+        // A frame ends after the pre-render line
+        let expected: Vec<u16> = (0..240).chain([261]).collect();
+        for rendering in [true, false] {
+            for step in [1, 3, 7, 21] {
+                let mut bus = test_bus();
+                let signals = std::rc::Rc::<std::cell::RefCell<BoardSignals>>::default();
+                bus.mapper = Box::new(CountingMapper(std::rc::Rc::clone(&signals)));
+                bus.mem_write(0x2001, if rendering { 0x18 } else { 0x00 });
+                let frame = bus.frame_count() + 1;
+                while bus.frame_count() < frame {
+                    bus.tick(step);
+                }
+
+                *signals.borrow_mut() = BoardSignals::default();
+                let cycles = bus.cycles();
+                while bus.frame_count() < frame + 1 {
+                    bus.tick(step);
+                }
+                let signals = signals.borrow();
+                let context = format!("rendering {} in steps of {}", rendering, step);
+                if rendering {
+                    assert_eq!(signals.scanlines, expected, "{}", context);
+                } else {
+                    assert!(signals.scanlines.is_empty(), "{}", context);
+                }
+                assert_eq!(signals.cpu_cycles, bus.cycles() - cycles, "{}", context);
+            }
+        }
+    }
+
+    #[test]
+    fn mapper_irq_line_raises_the_bus_irq() {
+        let mut bus = test_bus();
+        let signals = std::rc::Rc::<std::cell::RefCell<BoardSignals>>::default();
+        bus.mapper = Box::new(CountingMapper(std::rc::Rc::clone(&signals)));
+        bus.tick(1);
+        assert_eq!(bus.poll_irq_status(), None);
+
+        signals.borrow_mut().irq = true;
+        bus.tick(1);
+        assert!(bus.poll_irq_status().is_some());
+        // A level, so it is raised again for as long as the board holds it
+        bus.tick(1);
+        assert!(bus.poll_irq_status().is_some());
+
+        signals.borrow_mut().irq = false;
+        bus.tick(1);
+        assert_eq!(bus.poll_irq_status(), None);
+    }
+
+    #[test]
+    fn write_only_ppu_registers_read_the_io_latch() {
+        let mut bus = test_bus();
+        for (written, value) in [(0x2003, 0x5A), (0x3FFD, 0xC3), (0x2001, 0x00), (0x2006, 0x21)] {
+            bus.mem_write(written, value);
+            for addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006, 0x3FF8, 0x2E15] {
+                assert_eq!(bus.mem_read(addr), value, "{:#06X} after writing {:#04X} to {:#06X}", addr, value, written);
+            }
+        }
+        // $2002 drives only its three flag bits; the rest of the byte is the latch
+        bus.mem_write(0x2003, 0x1F);
+        assert_eq!(bus.mem_read(0x2002) & 0x1F, 0x1F);
+        assert_eq!(bus.mem_read(0x2000) & 0x1F, 0x1F);
+    }
+
+    #[test]
+    fn palette_reads_take_bits_7_and_6_from_the_latch() {
+        let mut bus = test_bus();
+        let point_at_palette = |bus: &mut Bus| {
+            bus.mem_write(0x2006, 0x3F);
+            bus.mem_write(0x2006, 0x00);
+        };
+        point_at_palette(&mut bus);
+        bus.mem_write(0x2007, 0xFD);
+        for latch in [0xC0, 0x40, 0x80, 0x00, 0x3F] {
+            point_at_palette(&mut bus);
+            bus.mem_write(0x2003, latch);
+            assert_eq!(bus.mem_read(0x2007), latch & 0xC0 | 0x3D, "latch {:#04X}", latch);
+            // The six palette bits are left on the bus
+            assert_eq!(bus.mem_read(0x2000), latch & 0xC0 | 0x3D);
+        }
+        // Greyscale masks the colour the read returns, not the latch bits
+        point_at_palette(&mut bus);
+        bus.mem_write(0x2001, 0xC1);
+        assert_eq!(bus.mem_read(0x2007), 0xC0 | 0x30);
+    }
+
+    #[test]
+    fn overlapping_game_genie_codes_resolve_by_precedence() {
+        let code = |new_data, compare_data| GameGenieCode { address: 0x9000, new_data, compare_data };
+        let cases = [
+            (vec![code(0x11, None), code(0x22, Some(0xEA))], 0x22),
+            (vec![code(0x33, Some(0x00)), code(0x11, None), code(0x44, None)], 0x11),
+            (vec![code(0x33, Some(0x00))], 0xEA),
+            (vec![code(0x55, Some(0xEA)), code(0x66, Some(0xEA)), code(0x77, None)], 0x55),
+        ];
+        let mut bus = test_bus();
+        for (codes, expected) in cases {
+            bus.set_game_genie_codes(codes.clone());
+            assert_eq!(bus.mem_read(0x9000), expected, "{:?}", codes);
+            assert_eq!(bus.mem_read(0x9001), 0xEA);
+        }
+    }
+
+    #[test]
+    fn frame_counter_writes_and_port_2_reads_stay_apart() {
+        let mut bus = test_bus();
+        let pressed = JoypadButton::BUTTON_A | JoypadButton::START | JoypadButton::LEFT;
+        bus.joypad1.set_buttons(JoypadButton::BUTTON_B);
+        bus.joypad2.set_buttons(pressed);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+
+        // A $4017 write mid-sequence sets the frame counter and neither strobes nor
+        // shifts controller 2
+        let mut bits = 0u8;
+        for button in 0..8 {
+            if button == 3 {
+                bus.mem_write(0x4017, 0xC0);
+                bus.tick(4);
+            }
+            let before = format!("{:?}", bus.apu.debug_snapshot());
+            let data = bus.mem_read(0x4017);
+            assert_eq!(format!("{:?}", bus.apu.debug_snapshot()), before);
+            assert_eq!(data & 0xFE, 0x40);
+            bits |= (data & 1) << button;
+        }
+        assert_eq!(bits, pressed.bits());
+        assert_eq!(bus.mem_read(0x4017), 0x41);
+        assert_eq!(bus.apu.debug_snapshot().frame_counter_steps, 5);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+        assert_eq!(bus.mem_read(0x4016), 0x41);
+    }
+
+    // Cycles the DMA due after a `tick(0)` takes, and the DMC bytes it fetched
+    fn dma_cycles(bus: &mut Bus) -> (usize, u16) {
+        let (cycles, remaining) = (bus.cycles(), bus.apu.debug_snapshot().dmc_bytes_remaining);
+        bus.tick(0);
+        (bus.cycles() - cycles, remaining - bus.apu.debug_snapshot().dmc_bytes_remaining)
+    }
+
+    // Starts a DMC sample of `length` bytes at the fastest rate; its first fetch is due
+    // straight away
+    fn start_dmc(bus: &mut Bus, length: u8) {
+        bus.mem_write(0x4010, 0x0F);
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, length);
+        bus.mem_write(0x4015, 0x10);
+        assert!(bus.apu.dmc_fetch_address().is_some());
+    }
+
+    #[test]
+    fn oam_dma_takes_513_or_514_cycles() {
+        // Starting after an odd number of cycles, the halt is a get cycle and the first
+        // read needs no alignment
+        for (before, expected) in [(1, 513), (2, 514), (7, 513), (10, 514)] {
+            let mut bus = test_bus();
+            for i in 0..=255u8 {
+                bus.mem_write(0x0200 + i as u16, i);
+            }
+            bus.tick(before);
+            bus.mem_write(0x4014, 0x02);
+            assert_eq!(dma_cycles(&mut bus), (expected, 0), "after {} cycles", before);
+            for i in 0..=255u8 {
+                bus.mem_write(0x2003, i);
+                assert_eq!(bus.mem_read(0x2004) & 0xE3, i & 0xE3);
+            }
+        }
+    }
+
+    #[test]
+    fn lone_dmc_fetch_stalls_3_or_4_cycles() {
+        for (before, expected) in [(1, 4), (2, 3), (7, 4), (10, 3)] {
+            let mut bus = test_bus();
+            bus.tick(before);
+            start_dmc(&mut bus, 0x00);
+            assert_eq!(dma_cycles(&mut bus), (expected, 1), "after {} cycles", before);
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_during_oam_dma_costs_2_cycles() {
+        // Due as the transfer starts
+        for (before, expected) in [(1, 515), (2, 516)] {
+            let mut bus = test_bus();
+            bus.tick(before);
+            start_dmc(&mut bus, 0x00);
+            bus.mem_write(0x4014, 0x02);
+            assert_eq!(dma_cycles(&mut bus), (expected, 1), "after {} cycles", before);
+        }
+
+        // Due part way through, once the first byte has played out
+        for before in 0..200 {
+            let mut bus = test_bus();
+            start_dmc(&mut bus, 0x01);
+            bus.tick(0);
+            bus.tick(before);
+            let odd = bus.cycles() % 2 == 1;
+            bus.mem_write(0x4014, 0x02);
+            let expected = if odd { 513 } else { 514 } + 2;
+            assert_eq!(dma_cycles(&mut bus), (expected, 1), "{} cycles after the first fetch", before);
+        }
+    }
+}
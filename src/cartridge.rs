@@ -1,3 +1,6 @@
+use crate::nsf::NsfHeader;
+use crate::region::Region;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mirroring {
     VERTICAL,
@@ -5,11 +8,25 @@ pub enum Mirroring {
     FOURSCREEN,
 }
 
+#[derive(Clone)]
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
+    pub chr_is_ram: bool,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    /// Region the header says the game was made for, when it says anything at all.
+    /// Most dumps leave the TV system flag clear whatever their origin, so None is common.
+    pub region_hint: Option<Region>,
+    /// iNES flags 7 bit 0: a VS System arcade board. It runs as a plain NES; the coin
+    /// slots, DIP switches and arcade palettes are not emulated.
+    pub vs_system: bool,
+    /// iNES flags 7 bit 1: a PlayChoice-10 cartridge. Its INST-ROM and PROM follow the
+    /// CHR data and are not used; only the game itself is loaded.
+    pub playchoice10: bool,
+    /// Set for an NSF tune, which plays on a virtual player board instead of the
+    /// mapper number.
+    pub nsf: Option<NsfHeader>,
 }
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
@@ -18,7 +35,7 @@ const CHR_ROM_PAGE_SIZE: usize = 8192;  // 8 KiB
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
@@ -29,6 +46,10 @@ impl Rom {
             return Err("NES2.0 format is not supported".to_string());
         }
 
+        if mapper != 0 {
+            return Err(format!("Mapper {} is not supported", mapper));
+        }
+
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -37,51 +58,159 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
+        // iNES flags 9 bit 0: TV system, set for PAL. Clear is the default, not a claim of NTSC.
+        let region_hint = (raw[9] & 1 != 0).then_some(Region::Pal);
+        let vs_system = raw[7] & 0b01 != 0;
+        let playchoice10 = raw[7] & 0b10 != 0;
+
         let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        if prg_rom_size == 0 {
+            return Err("ROM header declares no PRG ROM".to_string());
+        }
         let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        // A CHR size of zero means the cart carries 8 KiB of CHR RAM instead
+        let chr_is_ram = chr_rom_size == 0;
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
         let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
 
+        let expected_len = chr_rom_start + chr_rom_size;
+        if raw.len() < expected_len {
+            return Err(format!(
+                "ROM file is truncated: header needs {} bytes but file has {}",
+                expected_len,
+                raw.len()
+            ));
+        }
+        // Anything past the CHR is ignored. On PlayChoice-10 dumps that is the 8 KiB
+        // INST-ROM and the 32 bytes of PROM, which many dumps leave out anyway.
+
+        let chr_rom = if chr_is_ram {
+            vec![0; CHR_ROM_PAGE_SIZE]
+        } else {
+            raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec()
+        };
+
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            chr_rom,
+            chr_is_ram,
             mapper,
             screen_mirroring,
+            region_hint,
+            vs_system,
+            playchoice10,
+            nsf: None,
         })
     }
 
-    // ADD THE FOLLOWING METHODS
-    
-    pub fn read(&self, addr: u16) -> u8 {
-        match self.mapper {
-            0 => { // Mapper 0 (NROM)
-                let mut mapped_addr = addr as usize;
-                if addr >= 0x8000 {
-                    mapped_addr -= 0x8000;
-                    // Mirror PRG ROM if it's only 16KB
-                    if self.prg_rom.len() == PRG_ROM_PAGE_SIZE {
-                        mapped_addr %= PRG_ROM_PAGE_SIZE;
-                    }
-                }
-                self.prg_rom[mapped_addr]
-            },
-            _ => {
-                // For now, we'll just panic for unsupported mappers.
-                // Later you could implement more complex mappers here.
-                panic!("Mapper {} not supported yet", self.mapper);
-            }
+    /// Builds a mapper 0 `Rom` from bare PRG/CHR binaries that have no iNES header.
+    /// A missing CHR image is treated as 8 KiB of CHR RAM.
+    pub fn from_raw(prg: &[u8], chr: Option<&[u8]>, screen_mirroring: Mirroring) -> Result<Rom, String> {
+        if prg.is_empty() || prg.len() > 2 * PRG_ROM_PAGE_SIZE {
+            return Err(format!(
+                "Raw PRG must be 1 byte to 32 KiB for mapper 0 (got {} bytes)",
+                prg.len()
+            ));
         }
+
+        let chr_is_ram = chr.is_none();
+        let chr_rom = match chr {
+            Some(chr) if chr.len() != CHR_ROM_PAGE_SIZE => {
+                return Err(format!(
+                    "Raw CHR must be 8 KiB for mapper 0 (got {} bytes)",
+                    chr.len()
+                ));
+            }
+            Some(chr) => chr.to_vec(),
+            None => vec![0; CHR_ROM_PAGE_SIZE],
+        };
+
+        Ok(Rom {
+            prg_rom: prg.to_vec(),
+            chr_rom,
+            chr_is_ram,
+            mapper: 0,
+            screen_mirroring,
+            region_hint: None,
+            vs_system: false,
+            playchoice10: false,
+            nsf: None,
+        })
     }
 
-    // You will need this write method later for more complex mappers.
-    // For Mapper 0, writes to the ROM are ignored.
-    pub fn write(&mut self, _addr: u16, _data: u8) {
-        match self.mapper {
-            0 => { /* Mapper 0 is not writable */ },
-            _ => panic!("Mapper {} not supported yet", self.mapper),
+    /// Builds a `Rom` from an NSF file: its banks as PRG ROM and 8 KiB of CHR RAM that
+    /// nothing draws from.
+    pub fn from_nsf(raw: &[u8]) -> Result<Rom, String> {
+        let (header, prg_rom) = NsfHeader::parse(raw)?;
+        Ok(Rom {
+            prg_rom,
+            chr_rom: vec![0; CHR_ROM_PAGE_SIZE],
+            chr_is_ram: true,
+            mapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            region_hint: header.region_hint,
+            vs_system: false,
+            playchoice10: false,
+            nsf: Some(header),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper;
+
+    // An iNES image whose PRG and CHR bytes count 0 to 250 over and over, then `trailer`
+    fn ines(prg_banks: u8, chr_banks: u8, flags7: u8, trailer: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, prg_banks, chr_banks, 0, flags7, 0, 0, 0, 0, 0, 0, 0, 0];
+        let len = prg_banks as usize * PRG_ROM_PAGE_SIZE + chr_banks as usize * CHR_ROM_PAGE_SIZE;
+        raw.extend((0..len).map(|i| (i % 251) as u8));
+        raw.extend_from_slice(trailer);
+        raw
+    }
+
+    #[test]
+    fn prg_of_any_size_mirrors_through_the_window() {
+        let mut roms = vec![Rom::new(&ines(1, 1, 0, &[])).unwrap()];
+        for len in [0x2000, 0x6000, 0x8000, 1] {
+            let prg: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            roms.push(Rom::from_raw(&prg, None, Mirroring::VERTICAL).unwrap());
         }
+        for rom in roms {
+            let len = rom.prg_rom.len();
+            let board = mapper::new_mapper(&rom);
+            for offset in 0..0x8000 {
+                assert_eq!(board.read(0x8000 + offset as u16), rom.prg_rom[offset % len], "{} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn playchoice_chr_comes_before_the_inst_rom() {
+        // 8 KiB INST-ROM and 32 bytes of PROM after the CHR
+        let trailer = [0xA5; CHR_ROM_PAGE_SIZE + 32];
+        let raw = ines(2, 1, 0b10, &trailer);
+        let rom = Rom::new(&raw).unwrap();
+        assert!(rom.playchoice10 && !rom.vs_system);
+        let chr_start = 16 + 2 * PRG_ROM_PAGE_SIZE;
+        assert_eq!(rom.prg_rom, raw[16..chr_start]);
+        assert_eq!(rom.chr_rom, raw[chr_start..chr_start + CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.chr_rom[0], (2 * PRG_ROM_PAGE_SIZE % 251) as u8);
+
+        // Dumps that leave the INST-ROM out load the same game
+        let bare = Rom::new(&ines(2, 1, 0b10, &[])).unwrap();
+        assert_eq!(bare.chr_rom, rom.chr_rom);
+        assert!(Rom::new(&ines(2, 1, 0b01, &trailer)).unwrap().vs_system);
+    }
+
+    #[test]
+    fn prg_sizes_outside_the_board_are_rejected() {
+        assert!(Rom::new(&ines(0, 1, 0, &[])).is_err());
+        assert!(Rom::from_raw(&[], None, Mirroring::VERTICAL).is_err());
+        assert!(Rom::from_raw(&[0; 0x8001], None, Mirroring::VERTICAL).is_err());
     }
-}
\ No newline at end of file
+}
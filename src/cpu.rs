@@ -1,6 +1,8 @@
 use crate::bus::{Bus, Mem, BusState};
+use crate::rewind::SnapshotMemory;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use log::error;
+use std::collections::{HashMap, VecDeque};
 use std::cell::Cell;
 use serde::{Serialize, Deserialize};
 
@@ -32,6 +34,17 @@ const BREAK_COMMAND_2: u8 = 0b0010_0000;
 const OVERFLOW_FLAG: u8 = 0b0100_0000;
 const NEGATIVE_FLAG: u8 = 0b1000_0000;
 
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_BRK_VECTOR: u16 = 0xFFFE;
+// Reset, NMI, IRQ and BRK all take 7 cycles to reach the handler
+const INTERRUPT_CYCLES: usize = 7;
+// ORed into A by *XAA before the ANDs. Real chips differ, and the value drifts with
+// temperature; $EE is the one most NES CPUs show and most test ROMs accept.
+const XAA_MAGIC: u8 = 0xEE;
+/// Instructions kept by the trace history while it is on.
+pub const TRACE_HISTORY_LEN: usize = 100;
+
 pub struct CPU<'call> {
     pub register_a: u8,
     pub register_x: u8,
@@ -41,6 +54,8 @@ pub struct CPU<'call> {
     pub program_counter: u16,
     pub bus: Bus<'call>,
     pub last_instruction_trace: String,
+    // Trace lines of the last TRACE_HISTORY_LEN instructions, oldest first; None while off
+    trace_history: Option<VecDeque<String>>,
 }
 pub struct OpCode {
     pub code: u8,
@@ -67,6 +82,35 @@ pub struct EmulatorSnapshot {
     bus: BusState,
 }
 
+impl EmulatorSnapshot {
+    /// Moves the RAM arrays out of the snapshot, leaving the small register state behind.
+    pub fn take_memory(&mut self) -> SnapshotMemory {
+        let mut mem = SnapshotMemory::default();
+        self.bus.take_memory(&mut mem);
+        mem
+    }
+
+    /// Puts back memory previously removed by `take_memory`.
+    pub fn restore_memory(&mut self, mem: SnapshotMemory) {
+        self.bus.restore_memory(mem);
+    }
+
+    /// The CPU, bus and PPU registers by name, formatted for display.
+    pub(crate) fn registers(&self) -> Vec<(&'static str, String)> {
+        let cpu = &self.cpu;
+        let mut registers = vec![
+            ("A", format!("${:02X}", cpu.register_a)),
+            ("X", format!("${:02X}", cpu.register_x)),
+            ("Y", format!("${:02X}", cpu.register_y)),
+            ("SP", format!("${:02X}", cpu.stack_pointer)),
+            ("P", format!("${:02X}", cpu.status)),
+            ("PC", format!("${:04X}", cpu.program_counter)),
+        ];
+        registers.extend(self.bus.registers());
+        registers
+    }
+}
+
 impl OpCode {
     fn new(code: u8, name: &'static str, bytes: u8, cycles: u8, mode: AddressingMode) -> Self {
         OpCode {
@@ -340,6 +384,9 @@ lazy_static! {
         OpCode::new(0x8B, "*XAA", 2, 2, AddressingMode::Immediate),
         OpCode::new(0x9B, "*XAS", 3, 5, AddressingMode::Absolute_Y),
     ];
+
+    pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> =
+        CPU_OPCODES.iter().map(|op| (op.code, op)).collect();
 }
 
 impl<'call> CPU<'call> {
@@ -354,6 +401,7 @@ impl<'call> CPU<'call> {
             program_counter: 0,
             bus,
             last_instruction_trace: String::new(),
+            trace_history: None,
         }
     }
     // --- Private Helper Methods now use the Bus ---
@@ -484,16 +532,24 @@ impl<'call> CPU<'call> {
         self.set_flag(NEGATIVE_FLAG, (result & 0b1000_0000) != 0);
     }
 
+    /// Power-cycles the console: the same state every time, whatever ran before.
+    pub fn power_on(&mut self) {
+        self.bus.power_on();
+        self.reset();
+    }
+
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
         self.stack_pointer = 0xFD;
         self.status = INTERRUPT_DISABLE | BREAK_COMMAND_2;
-        self.program_counter = self.bus.mem_read_u16(0xFFFC);
+        self.program_counter = self.bus.mem_read_u16(RESET_VECTOR);
+        self.bus.tick(INTERRUPT_CYCLES);
     }
 
-    fn branch(&mut self, condition: bool) {
+    // Returns true when the branch was taken and PC now points at the target
+    fn branch(&mut self, condition: bool) -> bool {
         if condition {
             // Add 1 cycle for taking the branch
             self.bus.tick(1); 
@@ -509,37 +565,62 @@ impl<'call> CPU<'call> {
 
             self.program_counter = target_addr;
         }
+        condition
     }
 
     fn adc(&mut self, mode: &AddressingMode) {
         let value = self.get_operand(mode);
-        let sum = self.register_a as u16 + value as u16 + self.get_flag(CARRY_FLAG) as u16;
-        self.set_flag(CARRY_FLAG, sum > 0xFF);
-        let result = sum as u8;
-        self.set_flag(
-            OVERFLOW_FLAG,
-            (self.register_a ^ result) & (value ^ result) & 0x80 != 0,
-        );
-        self.register_a = result;
-        self.update_zero_and_negative_flags(self.register_a);
+        self.add_to_register_a(value);
     }
 
     fn sbc(&mut self, mode: &AddressingMode) {
+        // A - M - (1 - C) is A + !M + C
         let value = self.get_operand(mode);
-        let addend = !value;
-        let sum = self.register_a as u16 + addend as u16 + self.get_flag(CARRY_FLAG) as u16;
+        self.add_to_register_a(!value);
+    }
+
+    // Shared by ADC, SBC and the RRA/ISB combos, which add the value they have just
+    // written rather than reading the operand a second time
+    fn add_to_register_a(&mut self, value: u8) {
+        let sum = self.register_a as u16 + value as u16 + self.get_flag(CARRY_FLAG) as u16;
         self.set_flag(CARRY_FLAG, sum > 0xFF);
         let result = sum as u8;
         self.set_flag(
             OVERFLOW_FLAG,
-            (self.register_a ^ result) & (addend ^ result) & 0x80 != 0,
+            (self.register_a ^ result) & (value ^ result) & 0x80 != 0,
         );
         self.register_a = result;
         self.update_zero_and_negative_flags(self.register_a);
     }
 
+    // The unstable stores *AXA, *SXA, *SYA and *XAS write `register` ANDed with one more
+    // than the high byte of the base address, i.e. before indexing. When indexing crosses
+    // a page, the value also replaces the high byte of the address written to: the CPU
+    // puts the ANDed byte on the bus while it is still fixing up the address. This is the
+    // behaviour of most 2A03s when no DMA lands on the instruction; a DMA on the write
+    // cycle drops the AND on hardware, which is not emulated.
+    fn store_and_high(&mut self, mode: &AddressingMode, register: u8) {
+        let addr = self.get_operand_address(mode);
+        let index = match mode {
+            AddressingMode::Absolute_X => self.register_x,
+            _ => self.register_y,
+        };
+        let base = addr.wrapping_sub(index as u16);
+        let value = register & ((base >> 8) as u8).wrapping_add(1);
+        let target = if base & 0xFF00 != addr & 0xFF00 {
+            (value as u16) << 8 | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.bus.mem_write(target, value);
+    }
+
     fn compare(&mut self, mode: &AddressingMode, register: u8) {
         let value = self.get_operand(mode);
+        self.compare_value(register, value);
+    }
+
+    fn compare_value(&mut self, register: u8, value: u8) {
         self.set_flag(CARRY_FLAG, register >= value);
         self.update_zero_and_negative_flags(register.wrapping_sub(value));
     }
@@ -547,25 +628,11 @@ impl<'call> CPU<'call> {
     pub fn run_with_callback<F>(&mut self, mut callback: F, tracing_enabled: &Cell<bool>)    where
         F: FnMut(&mut CPU) -> bool,
     {
-        let ref opcodes: HashMap<u8, &'static OpCode> =
-            CPU_OPCODES.iter().map(|op| (op.code, op)).collect();
-
         loop {
-            if self.bus.poll_nmi_status().is_some() {
-                self.interrupt_nmi();
-                self.bus.tick(7);
+            if self.service_interrupts() {
                 continue;
             }
 
-            if self.bus.poll_irq_status().is_some() {
-                // Only trigger IRQ if the interrupt disable flag is clear
-                if !self.get_flag(INTERRUPT_DISABLE) {
-                    self.interrupt_irq();
-                    self.bus.tick(7); // IRQs take 7 cycles
-                    continue;
-                }
-            }
-
             if tracing_enabled.get() {
                 self.last_instruction_trace = self.trace(); // ONLY generate trace if enabled
                 println!("{}", self.last_instruction_trace);
@@ -573,436 +640,517 @@ impl<'call> CPU<'call> {
                 self.last_instruction_trace.clear();
             }
             //println!("{}", self.trace());
+            self.bus.debugger.check_execute(self.program_counter);
+            if self.bus.debugger.has_opcode_breakpoints() {
+                let code = self.bus.mem_read_readonly(self.program_counter);
+                if let Some(opcode) = OPCODES_MAP.get(&code) {
+                    self.bus.debugger.check_opcode(self.program_counter, opcode.name);
+                }
+            }
             if !callback(self) {
                 break; // If callback returns false, stop this CPU loop.
             }
             
-            let code = self.bus.mem_read(self.program_counter);
-            let opcode_ref = opcodes
-                .get(&code)
-                .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
+            self.execute_instruction();
+        }
+    }
 
-            let pc_state = self.program_counter;
+    /// Keeps the trace lines of the last `TRACE_HISTORY_LEN` instructions while on, for
+    /// seeing how a game got somewhere. Off by default, since formatting every
+    /// instruction costs more than running it. Turning it off drops the history.
+    pub fn set_trace_history(&mut self, enabled: bool) {
+        if enabled != self.trace_history.is_some() {
+            self.trace_history = enabled.then(|| VecDeque::with_capacity(TRACE_HISTORY_LEN));
+        }
+    }
 
-            let mode = &opcode_ref.mode;
-            let name = opcode_ref.name;
-            
-            match name {
-                "BRK" => {
-                    self.program_counter += 2; 
-                    self.stack_push_u16(self.program_counter);
-                    let mut status = self.status;
-                    status |= BREAK_COMMAND | BREAK_COMMAND_2; 
-                    self.stack_push(status);
-                    self.set_flag(INTERRUPT_DISABLE, true);
-                    self.program_counter = self.bus.mem_read_u16(0xFFFE);
-                }
-                "NOP" => {}
+    pub fn trace_history_enabled(&self) -> bool {
+        self.trace_history.is_some()
+    }
 
-                /* Load/Store */
-                "LDA" => {
-                    self.register_a = self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                "LDX" => {
-                    self.register_x = self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                "LDY" => {
-                    self.register_y = self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
-                "STA" => {
-                    self.set_operand(mode, self.register_a);
-                }
-                "STX" => {
-                    self.set_operand(mode, self.register_x);
-                }
-                "STY" => {
-                    self.set_operand(mode, self.register_y);
-                }
+    /// The recorded trace lines, oldest first; empty while the history is off.
+    pub fn trace_history(&self) -> impl Iterator<Item = &str> {
+        self.trace_history.iter().flatten().map(String::as_str)
+    }
 
-                /* Arithmetic */
-                "ADC" => self.adc(mode),
-                "SBC" => self.sbc(mode),
-                "AND" => {
-                    self.register_a &= self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                "EOR" => {
-                    self.register_a ^= self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                "ORA" => {
-                    self.register_a |= self.get_operand(mode);
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+    /// Formats the recorded history, e.g. once the game has jammed or hit a breakpoint.
+    /// `None` while the history is off.
+    pub fn trace_history_text(&self) -> Option<String> {
+        let history = self.trace_history.as_ref()?;
+        let mut text = format!("Last {} instruction(s), oldest first:", history.len());
+        for line in history {
+            text.push('\n');
+            text.push_str(line);
+        }
+        Some(text)
+    }
 
-                /* Shifts */
-                "ASL" => {
-                    let mut val = self.get_operand(mode);
-                    self.set_flag(CARRY_FLAG, val & 0x80 != 0);
-                    val <<= 1;
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
-                "LSR" => {
-                    let mut val = self.get_operand(mode);
-                    self.set_flag(CARRY_FLAG, val & 0x01 != 0);
-                    val >>= 1;
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
-                "ROL" => {
-                    let mut val = self.get_operand(mode);
-                    let c = self.get_flag(CARRY_FLAG);
-                    self.set_flag(CARRY_FLAG, val & 0x80 != 0);
-                    val <<= 1;
-                    if c {
-                        val |= 1;
-                    };
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
-                "ROR" => {
-                    let mut val = self.get_operand(mode);
-                    let c = self.get_flag(CARRY_FLAG);
-                    self.set_flag(CARRY_FLAG, val & 0x01 != 0);
-                    val >>= 1;
-                    if c {
-                        val |= 0x80;
-                    };
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
+    fn record_trace_history(&mut self) {
+        let line = self.trace();
+        if let Some(history) = &mut self.trace_history {
+            if history.len() == TRACE_HISTORY_LEN {
+                history.pop_front();
+            }
+            history.push_back(line);
+        }
+    }
 
-                /* INC/DEC */
-                "INC" => {
-                    let mut val = self.get_operand(mode);
-                    val = val.wrapping_add(1);
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
-                "INX" => {
-                    self.register_x = self.register_x.wrapping_add(1);
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                "INY" => {
-                    self.register_y = self.register_y.wrapping_add(1);
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
-                "DEC" => {
-                    let mut val = self.get_operand(mode);
-                    val = val.wrapping_sub(1);
-                    self.set_operand(mode, val);
-                    self.update_zero_and_negative_flags(val);
-                }
-                "DEX" => {
-                    self.register_x = self.register_x.wrapping_sub(1);
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                "DEY" => {
-                    self.register_y = self.register_y.wrapping_sub(1);
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
+    /// Services a pending interrupt or executes exactly one instruction.
+    pub fn step(&mut self) {
+        if !self.service_interrupts() {
+            self.execute_instruction();
+        }
+    }
 
-                /* Compare */
-                "CMP" => self.compare(mode, self.register_a),
-                "CPX" => self.compare(mode, self.register_x),
-                "CPY" => self.compare(mode, self.register_y),
+    // Returns true if an NMI or IRQ was taken instead of the next instruction
+    fn service_interrupts(&mut self) -> bool {
+        if self.bus.poll_nmi_status().is_some() {
+            self.interrupt_nmi();
+            return true;
+        }
 
-                /* Jumps */
-                "JMP" => self.program_counter = self.get_operand_address(mode),
-                "JSR" => {
-                    self.stack_push_u16(self.program_counter + 2);
-                    self.program_counter = self.get_operand_address(mode);
-                }
-                "RTS" => self.program_counter = self.stack_pull_u16().wrapping_add(1),
-                "RTI" => {
-                    self.status = self.stack_pull();
-                    self.program_counter = self.stack_pull_u16();
-                }
+        if self.bus.poll_irq_status().is_some() {
+            // Only trigger IRQ if the interrupt disable flag is clear
+            if !self.get_flag(INTERRUPT_DISABLE) {
+                self.interrupt_irq();
+                return true;
+            }
+        }
+        false
+    }
 
-                /* Branches */
-                "BCC" => self.branch(!self.get_flag(CARRY_FLAG)),
-                "BCS" => self.branch(self.get_flag(CARRY_FLAG)),
-                "BEQ" => self.branch(self.get_flag(ZERO_FLAG)),
-                "BNE" => self.branch(!self.get_flag(ZERO_FLAG)),
-                "BMI" => self.branch(self.get_flag(NEGATIVE_FLAG)),
-                "BPL" => self.branch(!self.get_flag(NEGATIVE_FLAG)),
-                "BVC" => self.branch(!self.get_flag(OVERFLOW_FLAG)),
-                "BVS" => self.branch(self.get_flag(OVERFLOW_FLAG)),
-
-                /* Flags */
-                "CLC" => self.set_flag(CARRY_FLAG, false),
-                "CLD" => self.set_flag(DECIMAL_MODE, false),
-                "CLI" => self.set_flag(INTERRUPT_DISABLE, false),
-                "CLV" => self.set_flag(OVERFLOW_FLAG, false),
-                "SEC" => self.set_flag(CARRY_FLAG, true),
-                "SED" => self.set_flag(DECIMAL_MODE, true),
-                "SEI" => self.set_flag(INTERRUPT_DISABLE, true),
-
-                /* Stack */
-                "PHA" => self.stack_push(self.register_a),
-                "PHP" => {
-                    self.stack_push(self.status | BREAK_COMMAND | BREAK_COMMAND_2);
-                }
-                "PLA" => {
-                    self.register_a = self.stack_pull();
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                "PLP" => {
-                    let temp = self.stack_pull();
-                    self.status = (temp & 0b11001111) | (self.status & 0b00110000);                }
-
-                /* Transfers */
-                "TAX" => {
-                    self.register_x = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                "TAY" => {
-                    self.register_y = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_y);
-                }
-                "TSX" => {
-                    self.register_x = self.stack_pointer;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                "TXA" => {
-                    self.register_a = self.register_x;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
-                "TXS" => self.stack_pointer = self.register_x,
-                "TYA" => {
-                    self.register_a = self.register_y;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+    fn execute_instruction(&mut self) {
+        if self.trace_history.is_some() {
+            self.record_trace_history();
+        }
+        let code = self.bus.mem_read(self.program_counter);
+        let opcode_ref = OPCODES_MAP
+            .get(&code)
+            .unwrap_or_else(|| panic!("OpCode {:x} is not recognized", code));
 
-                /* Other */
-                "BIT" => {
-                    let val = self.get_operand(mode);
-                    self.set_flag(ZERO_FLAG, (self.register_a & val) == 0);
-                    self.set_flag(NEGATIVE_FLAG, val & NEGATIVE_FLAG != 0);
-                    self.set_flag(OVERFLOW_FLAG, val & OVERFLOW_FLAG != 0);
-                }
-                "*NOP" => { }
+        // Set by any instruction that loads PC itself. Comparing PC before/after is not
+        // enough: `JMP *` or a taken branch to itself leaves PC unchanged but must not advance.
+        let mut pc_updated = false;
 
-                "*KIL" => { panic!("KIL instruction executed."); }
+        let mode = &opcode_ref.mode;
+        let name = opcode_ref.name;
+        
+        match name {
+            "BRK" => {
+                self.program_counter += 2; 
+                // The interrupt sequence ticks its own 7 cycles
+                self.interrupt(IRQ_BRK_VECTOR, true);
+                return;
+            }
+            "NOP" => {}
 
-                "*SBC" => {
-                    self.sbc(mode);
-                }
+            /* Load/Store */
+            "LDA" => {
+                self.register_a = self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            "LDX" => {
+                self.register_x = self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            "LDY" => {
+                self.register_y = self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+            "STA" => {
+                self.set_operand(mode, self.register_a);
+            }
+            "STX" => {
+                self.set_operand(mode, self.register_x);
+            }
+            "STY" => {
+                self.set_operand(mode, self.register_y);
+            }
 
-                "*AAC" => {
-                    let value = self.get_operand(mode);
-                    self.register_a &= value;
-                    self.update_zero_and_negative_flags(self.register_a);
-                    if self.get_flag(NEGATIVE_FLAG) {
-                        self.set_flag(CARRY_FLAG, true);
-                    }
-                }
-                
-                "*SAX" => {
-                    let value = self.register_a & self.register_x;
-                    self.set_operand(mode, value);
-                }
+            /* Arithmetic */
+            "ADC" => self.adc(mode),
+            "SBC" => self.sbc(mode),
+            "AND" => {
+                self.register_a &= self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            "EOR" => {
+                self.register_a ^= self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            "ORA" => {
+                self.register_a |= self.get_operand(mode);
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                "*ARR" => {
-                    let value = self.get_operand(mode);
-                    self.register_a &= value;
-                    self.register_a = (self.register_a >> 1) | (if self.get_flag(CARRY_FLAG) { 0x80 } else { 0 });
-                    self.update_zero_and_negative_flags(self.register_a);
+            /* Shifts */
+            "ASL" => {
+                let mut val = self.get_operand(mode);
+                self.set_flag(CARRY_FLAG, val & 0x80 != 0);
+                val <<= 1;
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
+            "LSR" => {
+                let mut val = self.get_operand(mode);
+                self.set_flag(CARRY_FLAG, val & 0x01 != 0);
+                val >>= 1;
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
+            "ROL" => {
+                let mut val = self.get_operand(mode);
+                let c = self.get_flag(CARRY_FLAG);
+                self.set_flag(CARRY_FLAG, val & 0x80 != 0);
+                val <<= 1;
+                if c {
+                    val |= 1;
+                };
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
+            "ROR" => {
+                let mut val = self.get_operand(mode);
+                let c = self.get_flag(CARRY_FLAG);
+                self.set_flag(CARRY_FLAG, val & 0x01 != 0);
+                val >>= 1;
+                if c {
+                    val |= 0x80;
+                };
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
 
-                    let bit6 = (self.register_a & 0b0100_0000) != 0;
-                    let bit5 = (self.register_a & 0b0010_0000) != 0;
+            /* INC/DEC */
+            "INC" => {
+                let mut val = self.get_operand(mode);
+                val = val.wrapping_add(1);
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
+            "INX" => {
+                self.register_x = self.register_x.wrapping_add(1);
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            "INY" => {
+                self.register_y = self.register_y.wrapping_add(1);
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+            "DEC" => {
+                let mut val = self.get_operand(mode);
+                val = val.wrapping_sub(1);
+                self.set_operand(mode, val);
+                self.update_zero_and_negative_flags(val);
+            }
+            "DEX" => {
+                self.register_x = self.register_x.wrapping_sub(1);
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            "DEY" => {
+                self.register_y = self.register_y.wrapping_sub(1);
+                self.update_zero_and_negative_flags(self.register_y);
+            }
 
-                    match (bit6, bit5) {
-                        (true, true)   => { self.set_flag(CARRY_FLAG, true); self.set_flag(OVERFLOW_FLAG, false); },
-                        (false, false) => { self.set_flag(CARRY_FLAG, false); self.set_flag(OVERFLOW_FLAG, false); },
-                        (false, true)  => { self.set_flag(CARRY_FLAG, false); self.set_flag(OVERFLOW_FLAG, true); },
-                        (true, false)  => { self.set_flag(CARRY_FLAG, true); self.set_flag(OVERFLOW_FLAG, true); },
-                    }
-                }
+            /* Compare */
+            "CMP" => self.compare(mode, self.register_a),
+            "CPX" => self.compare(mode, self.register_x),
+            "CPY" => self.compare(mode, self.register_y),
 
-                "*ASR" => {
-                    let value = self.get_operand(mode);
-                    self.register_a &= value;
-                    self.set_flag(CARRY_FLAG, (self.register_a & 0x01) != 0);
-                    self.register_a >>= 1;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            /* Jumps */
+            "JMP" => {
+                pc_updated = true;
+                self.program_counter = self.get_operand_address(mode);
+            }
+            "JSR" => {
+                pc_updated = true;
+                // Return address is the last byte of the JSR operand; RTS adds 1
+                self.stack_push_u16(self.program_counter + 2);
+                self.program_counter = self.get_operand_address(mode);
+                self.bus.debugger.on_jsr(self.program_counter);
+            }
+            "RTS" => {
+                pc_updated = true;
+                self.program_counter = self.stack_pull_u16().wrapping_add(1);
+            }
+            "RTI" => {
+                pc_updated = true;
+                self.status = self.stack_pull();
+                self.program_counter = self.stack_pull_u16();
+            }
 
-                "*ATX" => {
-                    let value = self.get_operand(mode);
-                    self.register_a &= value;
-                    self.register_x = self.register_a;
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
-                
-                "*AXA" => {
-                    let addr = self.get_operand_address(mode);
-                    let value = self.register_a & self.register_x & 7;
-                    self.bus.mem_write(addr, value);
-                }
+            /* Branches */
+            "BCC" => pc_updated = self.branch(!self.get_flag(CARRY_FLAG)),
+            "BCS" => pc_updated = self.branch(self.get_flag(CARRY_FLAG)),
+            "BEQ" => pc_updated = self.branch(self.get_flag(ZERO_FLAG)),
+            "BNE" => pc_updated = self.branch(!self.get_flag(ZERO_FLAG)),
+            "BMI" => pc_updated = self.branch(self.get_flag(NEGATIVE_FLAG)),
+            "BPL" => pc_updated = self.branch(!self.get_flag(NEGATIVE_FLAG)),
+            "BVC" => pc_updated = self.branch(!self.get_flag(OVERFLOW_FLAG)),
+            "BVS" => pc_updated = self.branch(self.get_flag(OVERFLOW_FLAG)),
+
+            /* Flags */
+            "CLC" => self.set_flag(CARRY_FLAG, false),
+            "CLD" => self.set_flag(DECIMAL_MODE, false),
+            "CLI" => self.set_flag(INTERRUPT_DISABLE, false),
+            "CLV" => self.set_flag(OVERFLOW_FLAG, false),
+            "SEC" => self.set_flag(CARRY_FLAG, true),
+            "SED" => self.set_flag(DECIMAL_MODE, true),
+            "SEI" => self.set_flag(INTERRUPT_DISABLE, true),
+
+            /* Stack */
+            "PHA" => self.stack_push(self.register_a),
+            "PHP" => {
+                self.stack_push(self.status | BREAK_COMMAND | BREAK_COMMAND_2);
+            }
+            "PLA" => {
+                self.register_a = self.stack_pull();
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            "PLP" => {
+                let temp = self.stack_pull();
+                self.status = (temp & 0b11001111) | (self.status & 0b00110000);                }
+
+            /* Transfers */
+            "TAX" => {
+                self.register_x = self.register_a;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            "TAY" => {
+                self.register_y = self.register_a;
+                self.update_zero_and_negative_flags(self.register_y);
+            }
+            "TSX" => {
+                self.register_x = self.stack_pointer;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            "TXA" => {
+                self.register_a = self.register_x;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+            "TXS" => self.stack_pointer = self.register_x,
+            "TYA" => {
+                self.register_a = self.register_y;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                "*AXS" => {
-                    let value = self.get_operand(mode);
-                    let start_val = self.register_a & self.register_x;
-                    let (result, borrow) = start_val.overflowing_sub(value);
-                    self.register_x = result;
-                    self.set_flag(CARRY_FLAG, !borrow);
-                    self.update_zero_and_negative_flags(self.register_x);
-                }
+            /* Other */
+            "BIT" => {
+                let val = self.get_operand(mode);
+                self.set_flag(ZERO_FLAG, (self.register_a & val) == 0);
+                self.set_flag(NEGATIVE_FLAG, val & NEGATIVE_FLAG != 0);
+                self.set_flag(OVERFLOW_FLAG, val & OVERFLOW_FLAG != 0);
+            }
+            "*NOP" => { }
 
-                "*DCP" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut value = self.bus.mem_read(addr);
-                    value = value.wrapping_sub(1);
-                    self.bus.mem_write(addr, value);
-                    self.compare(mode, self.register_a);
+            "*KIL" => {
+                if let Some(history) = self.trace_history_text() {
+                    error!("{}", history);
                 }
+                panic!("KIL instruction executed at {:#06X}.", self.program_counter);
+            }
 
-                "*ISB" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut value = self.bus.mem_read(addr);
-                    value = value.wrapping_add(1);
-                    self.bus.mem_write(addr, value);
-                    self.sbc(&opcode_ref.mode); 
-                }
-                
-                "*LAR" => {
-                    let value = self.get_operand(mode);
-                    let result = self.stack_pointer & value;
-                    self.register_a = result;
-                    self.register_x = result;
-                    self.stack_pointer = result;
-                    self.update_zero_and_negative_flags(result);
-                }
+            "*SBC" => {
+                self.sbc(mode);
+            }
 
-                "*LAX" => {
-                    let value = self.get_operand(mode);
-                    self.register_a = value;
-                    self.register_x = value;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            "*AAC" => {
+                let value = self.get_operand(mode);
+                self.register_a &= value;
+                self.update_zero_and_negative_flags(self.register_a);
+                // C is a copy of N, so a positive result clears it
+                self.set_flag(CARRY_FLAG, self.get_flag(NEGATIVE_FLAG));
+            }
+            
+            "*SAX" => {
+                let value = self.register_a & self.register_x;
+                self.set_operand(mode, value);
+            }
 
-                "*RLA" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut data = self.bus.mem_read(addr);
-                    let carry = self.get_flag(CARRY_FLAG);
-                    self.set_flag(CARRY_FLAG, (data & 0x80) != 0);
-                    data <<= 1;
-                    if carry {
-                        data |= 1;
-                    }
-                    self.bus.mem_write(addr, data);
-                    self.register_a &= data;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+            "*ARR" => {
+                let value = self.get_operand(mode);
+                self.register_a &= value;
+                self.register_a = (self.register_a >> 1) | (if self.get_flag(CARRY_FLAG) { 0x80 } else { 0 });
+                self.update_zero_and_negative_flags(self.register_a);
 
-                "*RRA" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut data = self.bus.mem_read(addr);
-                    let carry = self.get_flag(CARRY_FLAG);
-                    self.set_flag(CARRY_FLAG, (data & 0x01) != 0);
-                    data >>= 1;
-                    if carry {
-                        data |= 0x80;
-                    }
-                    self.bus.mem_write(addr, data);
-                    self.adc(&opcode_ref.mode); 
-                }
-                
-                "*SLO" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut data = self.bus.mem_read(addr);
-                    self.set_flag(CARRY_FLAG, (data & 0x80) != 0);
-                    data <<= 1;
-                    self.bus.mem_write(addr, data);
-                    self.register_a |= data;
-                    self.update_zero_and_negative_flags(self.register_a);
-                }
+                let bit6 = (self.register_a & 0b0100_0000) != 0;
+                let bit5 = (self.register_a & 0b0010_0000) != 0;
 
-                "*SRE" => {
-                    let addr = self.get_operand_address(mode);
-                    let mut data = self.bus.mem_read(addr);
-                    self.set_flag(CARRY_FLAG, (data & 0x01) != 0);
-                    data >>= 1;
-                    self.bus.mem_write(addr, data);
-                    self.register_a ^= data;
-                    self.update_zero_and_negative_flags(self.register_a);
+                match (bit6, bit5) {
+                    (true, true)   => { self.set_flag(CARRY_FLAG, true); self.set_flag(OVERFLOW_FLAG, false); },
+                    (false, false) => { self.set_flag(CARRY_FLAG, false); self.set_flag(OVERFLOW_FLAG, false); },
+                    (false, true)  => { self.set_flag(CARRY_FLAG, false); self.set_flag(OVERFLOW_FLAG, true); },
+                    (true, false)  => { self.set_flag(CARRY_FLAG, true); self.set_flag(OVERFLOW_FLAG, true); },
                 }
+            }
 
-                "*SXA" => {
-                    let addr = self.get_operand_address(mode);
-                    let high = (addr >> 8) as u8;
-                    let value = self.register_x & high.wrapping_add(1);
-                    self.bus.mem_write(addr, value);
-                }
+            "*ASR" => {
+                let value = self.get_operand(mode);
+                self.register_a &= value;
+                self.set_flag(CARRY_FLAG, (self.register_a & 0x01) != 0);
+                self.register_a >>= 1;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                "*SYA" => {
-                    let addr = self.get_operand_address(mode);
-                    let high = (addr >> 8) as u8;
-                    let value = self.register_y & high.wrapping_add(1);
-                    self.bus.mem_write(addr, value);
-                }
+            "*ATX" => {
+                let value = self.get_operand(mode);
+                self.register_a &= value;
+                self.register_x = self.register_a;
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+            
+            "*AXA" => self.store_and_high(mode, self.register_a & self.register_x),
+
+            "*AXS" => {
+                let value = self.get_operand(mode);
+                let start_val = self.register_a & self.register_x;
+                let (result, borrow) = start_val.overflowing_sub(value);
+                self.register_x = result;
+                self.set_flag(CARRY_FLAG, !borrow);
+                self.update_zero_and_negative_flags(self.register_x);
+            }
+
+            "*DCP" => {
+                let addr = self.get_operand_address(mode);
+                let mut value = self.bus.mem_read(addr);
+                value = value.wrapping_sub(1);
+                self.bus.mem_write(addr, value);
+                self.compare_value(self.register_a, value);
+            }
 
-                "*XAA" => {
-                    let value = self.get_operand(mode);
-                    self.register_a &= self.register_x & value;
-                    self.update_zero_and_negative_flags(self.register_a);
+            "*ISB" => {
+                let addr = self.get_operand_address(mode);
+                let mut value = self.bus.mem_read(addr);
+                value = value.wrapping_add(1);
+                self.bus.mem_write(addr, value);
+                self.add_to_register_a(!value);
+            }
+            
+            // The one stable member of the group: A, X and SP all become SP AND memory
+            "*LAR" => {
+                let value = self.get_operand(mode);
+                let result = self.stack_pointer & value;
+                self.register_a = result;
+                self.register_x = result;
+                self.stack_pointer = result;
+                self.update_zero_and_negative_flags(result);
+            }
+
+            "*LAX" => {
+                let value = self.get_operand(mode);
+                self.register_a = value;
+                self.register_x = value;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            "*RLA" => {
+                let addr = self.get_operand_address(mode);
+                let mut data = self.bus.mem_read(addr);
+                let carry = self.get_flag(CARRY_FLAG);
+                self.set_flag(CARRY_FLAG, (data & 0x80) != 0);
+                data <<= 1;
+                if carry {
+                    data |= 1;
                 }
+                self.bus.mem_write(addr, data);
+                self.register_a &= data;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
 
-                "*XAS" => {
-                    self.stack_pointer = self.register_a & self.register_x;
-                    let addr = self.get_operand_address(mode);
-                    let high = (addr >> 8) as u8;
-                    let value = self.stack_pointer & high.wrapping_add(1);
-                    self.bus.mem_write(addr, value);
+            "*RRA" => {
+                let addr = self.get_operand_address(mode);
+                let mut data = self.bus.mem_read(addr);
+                let carry = self.get_flag(CARRY_FLAG);
+                self.set_flag(CARRY_FLAG, (data & 0x01) != 0);
+                data >>= 1;
+                if carry {
+                    data |= 0x80;
                 }
-                _ => todo!(),
+                self.bus.mem_write(addr, data);
+                self.add_to_register_a(data);
+            }
+            
+            "*SLO" => {
+                let addr = self.get_operand_address(mode);
+                let mut data = self.bus.mem_read(addr);
+                self.set_flag(CARRY_FLAG, (data & 0x80) != 0);
+                data <<= 1;
+                self.bus.mem_write(addr, data);
+                self.register_a |= data;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            "*SRE" => {
+                let addr = self.get_operand_address(mode);
+                let mut data = self.bus.mem_read(addr);
+                self.set_flag(CARRY_FLAG, (data & 0x01) != 0);
+                data >>= 1;
+                self.bus.mem_write(addr, data);
+                self.register_a ^= data;
+                self.update_zero_and_negative_flags(self.register_a);
+            }
+
+            "*SXA" => self.store_and_high(mode, self.register_x),
+
+            "*SYA" => self.store_and_high(mode, self.register_y),
+
+            "*XAA" => {
+                let value = self.get_operand(mode);
+                self.register_a = (self.register_a | XAA_MAGIC) & self.register_x & value;
+                self.update_zero_and_negative_flags(self.register_a);
             }
-            self.bus.tick(opcode_ref.cycles as usize);
 
-            if pc_state == self.program_counter {
-                self.program_counter += opcode_ref.bytes as u16;
+            "*XAS" => {
+                self.stack_pointer = self.register_a & self.register_x;
+                self.store_and_high(mode, self.stack_pointer);
             }
+            _ => todo!(),
+        }
+        self.bus.tick(opcode_ref.cycles as usize);
+
+        if !pc_updated {
+            self.program_counter += opcode_ref.bytes as u16;
         }
     }
 
     fn interrupt_nmi(&mut self){
-        self.stack_push_u16(self.program_counter);
-        let mut status = self.status;
-        status &= !(BREAK_COMMAND); // Clear bit 4
-        status |= BREAK_COMMAND_2;  // Set bit 5
-        self.stack_push(status);
-        
-        self.set_flag(INTERRUPT_DISABLE, true);
-
-        // self.bus.tick(2); // NMI takes extra cycles
-        self.program_counter = self.bus.mem_read_u16(0xFFFA);
+        self.interrupt(NMI_VECTOR, false);
     }
     
     fn interrupt_irq(&mut self){
+        self.interrupt(IRQ_BRK_VECTOR, false);
+    }
+
+    // Shared 7-cycle interrupt entry: two fetch cycles, push PCH, push PCL, push P, then
+    // the two vector reads. An NMI raised before P is pushed hijacks an IRQ/BRK sequence:
+    // the pushed B flag still reflects BRK, but execution continues at the NMI handler.
+    fn interrupt(&mut self, vector: u16, brk: bool) {
         self.stack_push_u16(self.program_counter);
+        self.bus.tick(4);
+
+        let mut vector = vector;
+        if vector == IRQ_BRK_VECTOR && self.bus.poll_nmi_status().is_some() {
+            vector = NMI_VECTOR;
+        }
+
         let mut status = self.status;
-        status &= !(BREAK_COMMAND); // Clear bit 4
+        if brk {
+            status |= BREAK_COMMAND; // Set bit 4
+        } else {
+            status &= !(BREAK_COMMAND); // Clear bit 4
+        }
         status |= BREAK_COMMAND_2;  // Set bit 5
         self.stack_push(status);
-
+        
         self.set_flag(INTERRUPT_DISABLE, true);
 
-        // self.bus.tick(2); // IRQ takes extra cycles
-        self.program_counter = self.bus.mem_read_u16(0xFFFE);
+        self.bus.tick(INTERRUPT_CYCLES - 4);
+        self.program_counter = self.bus.mem_read_u16(vector);
     }
 
 
     pub fn trace(&self) -> String {
-        let opcodes: std::collections::HashMap<u8, &'static OpCode> =
-            CPU_OPCODES.iter().map(|op| (op.code, op)).collect();
-
         let code = self.bus.mem_read_readonly(self.program_counter);
-        let opcode = opcodes.get(&code).unwrap();
+        let opcode = OPCODES_MAP.get(&code).unwrap();
         let pc = self.program_counter;
 
         let mut hex_dump = vec![code];
@@ -1096,4 +1244,433 @@ impl<'call> CPU<'call> {
         self.load_state(&snapshot.cpu);
         self.bus.load_state(&snapshot.bus);
     }
-}
\ No newline at end of file
+
+    /// Runs `frames` frames ahead with the current input, then puts the machine back as
+    /// it was, so only their side effects outside the machine, such as the picture and
+    /// sound, are kept. `before_frame` is called with each frame's index before it runs.
+    pub fn run_ahead(&mut self, frames: u32, mut before_frame: impl FnMut(u32)) {
+        let snapshot = self.save_snapshot();
+        for i in 0..frames {
+            before_frame(i);
+            let target = self.bus.frame_count() + 1;
+            while self.bus.frame_count() < target {
+                self.step();
+            }
+        }
+        self.load_snapshot(&snapshot);
+    }
+
+    /// Loads a snapshot from outside the running session, such as a state file. The
+    /// snapshot is validated first; on error the machine is left exactly as it was.
+    pub fn try_load_snapshot(&mut self, snapshot: &EmulatorSnapshot) -> Result<(), String> {
+        self.bus.validate_state(&snapshot.bus)?;
+        self.load_snapshot(snapshot);
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::ScheduledWrite;
+    use crate::cartridge::{Mirroring, Rom};
+
+    const PROGRAM: u16 = 0xF000;
+    const NMI_HANDLER: u16 = 0x9000;
+    const IRQ_HANDLER: u16 = 0x9100;
+
+    // A 32 KiB NROM board of NOPs with `program` at `PROGRAM`, where reset starts
+    fn test_cpu(program: &[u8]) -> CPU<'static> {
+        let mut prg = vec![0xEA; 0x8000];
+        let start = (PROGRAM - 0x8000) as usize;
+        prg[start..start + program.len()].copy_from_slice(program);
+        let vectors = [NMI_HANDLER, PROGRAM, IRQ_HANDLER];
+        for (i, vector) in vectors.iter().enumerate() {
+            prg[0x7FFA + i * 2..0x7FFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+        }
+        let rom = Rom::from_raw(&prg, None, Mirroring::HORIZONTAL).unwrap();
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    // Turns on the vblank NMI and runs NOPs until the PPU sets the vblank flag during
+    // CPU cycle `cycle` from now (1 is the next cycle), then points PC at the program
+    fn nmi_in_cycle(cpu: &mut CPU, cycle: usize) {
+        cpu.bus.mem_write(0x2000, 0x80);
+        let dots_to_vblank = |cpu: &CPU| match cpu.bus.ppu().scanline() {
+            240 => 341 - cpu.bus.ppu().cycle(),
+            _ => usize::MAX,
+        };
+        while dots_to_vblank(cpu) > 3 * cycle + 6 {
+            cpu.program_counter = 0x8000;
+            cpu.step();
+        }
+        while dots_to_vblank(cpu) > 3 * cycle {
+            cpu.bus.tick(1);
+        }
+        cpu.program_counter = PROGRAM;
+    }
+
+    fn stack_byte(cpu: &CPU, depth: u8) -> u8 {
+        cpu.bus.peek(0x0100 + cpu.stack_pointer.wrapping_add(depth) as u16)
+    }
+
+    fn stack_word(cpu: &CPU, depth: u8) -> u16 {
+        u16::from_le_bytes([stack_byte(cpu, depth), stack_byte(cpu, depth + 1)])
+    }
+
+    // Takes the pending NMI and returns the PC it pushed, checking the frame it left
+    fn take_nmi(cpu: &mut CPU) -> u16 {
+        let cycles = cpu.bus.cycles();
+        cpu.step();
+        assert_eq!(cpu.program_counter, NMI_HANDLER, "NMI vector not fetched");
+        assert_eq!(cpu.bus.cycles() - cycles, INTERRUPT_CYCLES);
+        assert_eq!(stack_byte(cpu, 1) & (BREAK_COMMAND | BREAK_COMMAND_2), BREAK_COMMAND_2);
+        assert!(cpu.get_flag(INTERRUPT_DISABLE));
+        stack_word(cpu, 2)
+    }
+
+    #[test]
+    fn nmi_before_jsr_is_taken_first() {
+        let mut cpu = test_cpu(&[0x20, 0x10, 0xF0]); // JSR $F010
+        nmi_in_cycle(&mut cpu, 1);
+        cpu.bus.tick(1);
+        assert_eq!(take_nmi(&mut cpu), PROGRAM);
+    }
+
+    #[test]
+    fn nmi_during_jsr_is_taken_after_it() {
+        // Interrupts are polled between instructions, so an NMI raised on any cycle of
+        // the JSR sees the subroutine's address, with JSR's own return address below it
+        for cycle in 1..=6 {
+            let mut cpu = test_cpu(&[0x20, 0x10, 0xF0]); // JSR $F010
+            nmi_in_cycle(&mut cpu, cycle);
+            cpu.step();
+            assert_eq!(cpu.program_counter, 0xF010);
+            assert_eq!(take_nmi(&mut cpu), 0xF010, "NMI in cycle {}", cycle);
+            assert_eq!(stack_word(&cpu, 4), PROGRAM + 2, "NMI in cycle {}", cycle);
+        }
+    }
+
+    #[test]
+    fn nmi_during_branch_pushes_the_next_instruction() {
+        // (program, Z flag, cycles, address pushed by the NMI)
+        let cases: [(&[u8], bool, usize, u16); 4] = [
+            (&[0xF0, 0x0E], true, 3, 0xF010),  // BEQ taken
+            (&[0xD0, 0x0E], true, 2, 0xF002),  // BNE not taken
+            (&[0xF0, 0xFE], true, 3, PROGRAM), // BEQ to itself
+            (&[0xF0, 0x80], true, 4, 0xEF82),  // BEQ taken back across a page
+        ];
+        for (program, zero, cycles, pushed) in cases {
+            for cycle in 1..=cycles {
+                let mut cpu = test_cpu(program);
+                nmi_in_cycle(&mut cpu, cycle);
+                cpu.set_flag(ZERO_FLAG, zero);
+                let start = cpu.bus.cycles();
+                cpu.step();
+                assert_eq!(cpu.bus.cycles() - start, cycles, "{:02X?}", program);
+                assert_eq!(take_nmi(&mut cpu), pushed, "{:02X?} with NMI in cycle {}", program, cycle);
+            }
+        }
+    }
+
+    #[test]
+    fn reset_takes_seven_cycles() {
+        let mut cpu = test_cpu(&[]);
+        let cycles = cpu.bus.cycles();
+        cpu.reset();
+        assert_eq!(cpu.bus.cycles() - cycles, INTERRUPT_CYCLES);
+        assert_eq!(cpu.program_counter, PROGRAM);
+    }
+
+    #[test]
+    fn irq_entry_takes_seven_cycles() {
+        // Wait out the first 4-step sequence with IRQs masked, until the frame IRQ is up
+        let mut cpu = test_cpu(&[]);
+        while cpu.bus.peek(0x4015) & 0x40 == 0 {
+            cpu.program_counter = 0x8000;
+            cpu.step();
+        }
+        cpu.set_flag(INTERRUPT_DISABLE, false);
+        cpu.program_counter = PROGRAM;
+
+        let cycles = cpu.bus.cycles();
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(cpu.bus.cycles() - cycles, INTERRUPT_CYCLES);
+        assert_eq!(stack_byte(&cpu, 1) & (BREAK_COMMAND | BREAK_COMMAND_2), BREAK_COMMAND_2);
+        assert_eq!(stack_word(&cpu, 2), PROGRAM);
+        assert!(cpu.get_flag(INTERRUPT_DISABLE));
+    }
+
+    #[test]
+    fn nmi_entry_takes_seven_cycles() {
+        let mut cpu = test_cpu(&[]);
+        nmi_in_cycle(&mut cpu, 1);
+        cpu.bus.tick(1);
+        assert_eq!(take_nmi(&mut cpu), PROGRAM);
+    }
+
+    #[test]
+    fn nmi_before_brk_pushes_p_hijacks_the_vector() {
+        // An NMI raised during the first four cycles of BRK, before P is pushed, sends
+        // it to the NMI handler with B still set in the pushed flags
+        for cycle in 1..=4 {
+            let mut cpu = test_cpu(&[0x00, 0x00]); // BRK
+            nmi_in_cycle(&mut cpu, cycle);
+            let cycles = cpu.bus.cycles();
+            cpu.step();
+            assert_eq!(cpu.program_counter, NMI_HANDLER, "NMI in cycle {}", cycle);
+            assert_eq!(cpu.bus.cycles() - cycles, INTERRUPT_CYCLES);
+            assert_eq!(stack_byte(&cpu, 1) & BREAK_COMMAND, BREAK_COMMAND, "NMI in cycle {}", cycle);
+            assert_eq!(stack_word(&cpu, 2), PROGRAM + 2, "NMI in cycle {}", cycle);
+        }
+
+        // Any later and BRK reaches its own handler, with the NMI taken after it
+        let mut cpu = test_cpu(&[0x00, 0x00]);
+        nmi_in_cycle(&mut cpu, 6);
+        cpu.step();
+        assert_eq!(cpu.program_counter, IRQ_HANDLER);
+        assert_eq!(stack_byte(&cpu, 1) & BREAK_COMMAND, BREAK_COMMAND);
+        assert_eq!(take_nmi(&mut cpu), IRQ_HANDLER);
+    }
+
+    #[test]
+    fn trace_history_text_lists_the_last_instructions_oldest_first() {
+        let mut cpu = test_cpu(&[0xA9, 0x01, 0xE8, 0xC8]); // LDA #$01 / INX / INY
+        assert_eq!(cpu.trace_history_text(), None);
+
+        cpu.set_trace_history(true);
+        let mut traces = Vec::new();
+        for _ in 0..3 {
+            traces.push(cpu.trace());
+            cpu.step();
+        }
+        let text = cpu.trace_history_text().unwrap();
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("Last 3 instruction(s), oldest first:"));
+        assert_eq!(lines.collect::<Vec<_>>(), traces);
+    }
+
+    #[test]
+    fn scheduled_write_lands_on_its_frame_with_run_ahead() {
+        let mut cpu = test_cpu(&[0x4C, 0x00, 0xF0]); // JMP $F000
+        cpu.bus.schedule_write(ScheduledWrite { frame: 5, addr: 0x0300, value: 0x42 });
+
+        while cpu.bus.frame_number() < 8 {
+            let frame = cpu.bus.frame_number() + 1;
+            while cpu.bus.frame_number() < frame {
+                cpu.step();
+            }
+            let expected = if frame >= 5 { 0x42 } else { 0 };
+            assert_eq!(cpu.bus.peek(0x0300), expected, "frame {}", frame);
+
+            // The speculative frames reach frame 5 early, but must not use the write up
+            cpu.run_ahead(2, |_| {});
+            assert_eq!(cpu.bus.frame_number(), frame);
+            assert_eq!(cpu.bus.peek(0x0300), expected, "frame {} after run-ahead", frame);
+        }
+    }
+
+    const RAM_PROGRAM: u16 = 0x0600;
+    const OPERANDS: [u8; 8] = [0x00, 0x01, 0x3C, 0x40, 0x7F, 0x80, 0x81, 0xFF];
+    const ARITHMETIC_FLAGS: u8 = CARRY_FLAG | ZERO_FLAG | OVERFLOW_FLAG | NEGATIVE_FLAG;
+
+    // Copies `program` into RAM and runs its first instruction with the given registers
+    fn run_in_ram(cpu: &mut CPU, program: &[u8], a: u8, x: u8, y: u8, carry: bool) {
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.bus.mem_write(RAM_PROGRAM + i as u16, byte);
+        }
+        cpu.register_a = a;
+        cpu.register_x = x;
+        cpu.register_y = y;
+        cpu.status = if carry { CARRY_FLAG } else { 0 } | INTERRUPT_DISABLE | BREAK_COMMAND_2;
+        cpu.program_counter = RAM_PROGRAM;
+        cpu.step();
+    }
+
+    fn zero_and_negative(value: u8) -> u8 {
+        (if value == 0 { ZERO_FLAG } else { 0 }) | (value & NEGATIVE_FLAG)
+    }
+
+    // Binary-mode ADC as documented: the sum, and the carry and overflow flags
+    fn reference_adc(a: u8, value: u8, carry: bool) -> (u8, u8) {
+        let sum = a as u16 + value as u16 + carry as u16;
+        let result = sum as u8;
+        let overflow = (a ^ result) & (value ^ result) & 0x80 != 0;
+        let flags = (if sum > 0xFF { CARRY_FLAG } else { 0 }) | (if overflow { OVERFLOW_FLAG } else { 0 });
+        (result, flags | zero_and_negative(result))
+    }
+
+    // The read-modify-write combos on zero page $10: the byte written back, then A and
+    // the flags the second half leaves. V is carried over where that half does not touch it.
+    fn reference_combo(opcode: u8, a: u8, memory: u8, carry: bool) -> (u8, u8, u8) {
+        let carry_bit = carry as u8;
+        match opcode {
+            // *SLO
+            0x07 => {
+                let written = memory << 1;
+                (written, a | written, (memory >> 7) | zero_and_negative(a | written))
+            }
+            // *RLA
+            0x27 => {
+                let written = memory << 1 | carry_bit;
+                (written, a & written, (memory >> 7) | zero_and_negative(a & written))
+            }
+            // *SRE
+            0x47 => {
+                let written = memory >> 1;
+                (written, a ^ written, (memory & 1) | zero_and_negative(a ^ written))
+            }
+            // *RRA
+            0x67 => {
+                let written = memory >> 1 | carry_bit << 7;
+                let (result, flags) = reference_adc(a, written, memory & 1 != 0);
+                (written, result, flags)
+            }
+            // *DCP
+            0xC7 => {
+                let written = memory.wrapping_sub(1);
+                let carry = if a >= written { CARRY_FLAG } else { 0 };
+                (written, a, carry | zero_and_negative(a.wrapping_sub(written)))
+            }
+            // *ISB
+            0xE7 => {
+                let written = memory.wrapping_add(1);
+                let (result, flags) = reference_adc(a, !written, carry);
+                (written, result, flags)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn read_modify_write_combos_match_the_reference() {
+        let mut cpu = test_cpu(&[]);
+        for opcode in [0x07, 0x27, 0x47, 0x67, 0xC7, 0xE7] {
+            for a in OPERANDS {
+                for memory in OPERANDS {
+                    for carry in [false, true] {
+                        cpu.bus.mem_write(0x0010, memory);
+                        run_in_ram(&mut cpu, &[opcode, 0x10], a, 0, 0, carry);
+                        let (written, result, flags) = reference_combo(opcode, a, memory, carry);
+                        let flags = match opcode {
+                            0x67 | 0xE7 => flags,
+                            _ => flags & !OVERFLOW_FLAG,
+                        };
+                        let context = format!("{:#04X} A={:#04X} M={:#04X} C={}", opcode, a, memory, carry);
+                        assert_eq!(cpu.bus.peek(0x0010), written, "memory, {}", context);
+                        assert_eq!(cpu.register_a, result, "A, {}", context);
+                        assert_eq!(cpu.status & ARITHMETIC_FLAGS, flags, "flags, {}", context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn immediate_combos_match_the_reference() {
+        let mut cpu = test_cpu(&[]);
+        for a in OPERANDS {
+            for value in OPERANDS {
+                for carry in [false, true] {
+                    let context = format!("A={:#04X} #{:#04X} C={}", a, value, carry);
+                    let and = a & value;
+
+                    // *ANC: AND, then N copied into C
+                    run_in_ram(&mut cpu, &[0x0B, value], a, 0, 0, carry);
+                    assert_eq!(cpu.register_a, and, "ANC, {}", context);
+                    assert_eq!(cpu.status & ARITHMETIC_FLAGS, zero_and_negative(and) | and >> 7, "ANC, {}", context);
+
+                    // *ALR: AND, then LSR
+                    run_in_ram(&mut cpu, &[0x4B, value], a, 0, 0, carry);
+                    assert_eq!(cpu.register_a, and >> 1, "ALR, {}", context);
+                    assert_eq!(cpu.status & ARITHMETIC_FLAGS, zero_and_negative(and >> 1) | (and & 1), "ALR, {}", context);
+
+                    // *ARR: AND, then ROR; C from bit 6, V from bit 6 XOR bit 5
+                    let rotated = and >> 1 | (carry as u8) << 7;
+                    let arr_flags = zero_and_negative(rotated)
+                        | (rotated >> 6 & 1)
+                        | if (rotated >> 6 ^ rotated >> 5) & 1 != 0 { OVERFLOW_FLAG } else { 0 };
+                    run_in_ram(&mut cpu, &[0x6B, value], a, 0, 0, carry);
+                    assert_eq!(cpu.register_a, rotated, "ARR, {}", context);
+                    assert_eq!(cpu.status & ARITHMETIC_FLAGS, arr_flags, "ARR, {}", context);
+
+                    // *SBX: X = (A AND X) - imm, compared like CMP, without borrow in
+                    for x in [0x00, 0x0F, 0xF0, 0xFF] {
+                        let masked = a & x;
+                        run_in_ram(&mut cpu, &[0xCB, value], a, x, 0, carry);
+                        let sbx_flags = zero_and_negative(masked.wrapping_sub(value)) | (masked >= value) as u8;
+                        assert_eq!(cpu.register_a, a, "SBX, {}", context);
+                        assert_eq!(cpu.register_x, masked.wrapping_sub(value), "SBX X={:#04X}, {}", x, context);
+                        assert_eq!(cpu.status & ARITHMETIC_FLAGS, sbx_flags, "SBX X={:#04X}, {}", x, context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unstable_stores_and_with_the_base_high_byte_plus_one() {
+        // *AXA absolute,Y and (indirect),Y through the pointer at $10, *SXA, *SYA and *XAS
+        let mut cpu = test_cpu(&[]);
+        for opcode in [0x9F, 0x93, 0x9E, 0x9C, 0x9B] {
+            let indirect = opcode == 0x93;
+            let index_x = opcode == 0x9C;
+            let register = |a: u8, x: u8, y: u8| match opcode {
+                0x9E => x,
+                0x9C => y,
+                _ => a & x,
+            };
+            for (base, index) in [(0x0400u16, 0x10u8), (0x04F8, 0x04), (0x03F0, 0x20), (0x02C0, 0x7F)] {
+                for (a, x, y) in [(0xFF, 0xFF, 0xFF), (0x36, 0x5B, 0x07), (0x0E, 0xFD, 0x0A)] {
+                    let (x, y) = if index_x { (index, y) } else { (x, index) };
+                    let [low, high] = base.to_le_bytes();
+                    let program = if indirect { [opcode, 0x10, 0x00] } else { [opcode, low, high] };
+                    cpu.bus.mem_write(0x0010, low);
+                    cpu.bus.mem_write(0x0011, high);
+                    for addr in 0x0200..0x0600 {
+                        cpu.bus.mem_write(addr, 0xA5);
+                    }
+                    run_in_ram(&mut cpu, &program, a, x, y, false);
+
+                    let value = register(a, x, y) & (high + 1);
+                    let addr = base + index as u16;
+                    // A page crossing writes through the ANDed value as the high byte
+                    let target = if addr >> 8 != base >> 8 { (value as u16) << 8 | (addr & 0xFF) } else { addr };
+                    let context = format!("{:#04X} base {:#06X} index {:#04X} A={:#04X} X={:#04X} Y={:#04X}", opcode, base, index, a, x, y);
+                    assert_eq!(cpu.bus.peek(target), value, "{}", context);
+                    if target != addr && (0x0200..0x0600).contains(&addr) {
+                        assert_eq!(cpu.bus.peek(addr), 0xA5, "indexed address written, {}", context);
+                    }
+                    if opcode == 0x9B {
+                        assert_eq!(cpu.stack_pointer, a & x, "{}", context);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn las_and_xaa_match_their_formulas() {
+        let mut cpu = test_cpu(&[]);
+        for value in OPERANDS {
+            for stack_pointer in [0xFD, 0x5D, 0x00, 0x80] {
+                // *LAS: A, X and SP all become SP AND memory
+                cpu.bus.mem_write(0x0410, value);
+                cpu.stack_pointer = stack_pointer;
+                run_in_ram(&mut cpu, &[0xBB, 0x00, 0x04], 0x12, 0x34, 0x10, false);
+                let result = stack_pointer & value;
+                assert_eq!((cpu.register_a, cpu.register_x, cpu.stack_pointer), (result, result, result));
+                assert_eq!(cpu.status & (ZERO_FLAG | NEGATIVE_FLAG), zero_and_negative(result));
+            }
+            for (a, x) in [(0x00, 0xFF), (0x11, 0xFF), (0xFF, 0x0F), (0x01, 0x81)] {
+                // *XAA: (A OR magic) AND X AND immediate
+                run_in_ram(&mut cpu, &[0x8B, value], a, x, 0, false);
+                let result = (a | XAA_MAGIC) & x & value;
+                assert_eq!(cpu.register_a, result, "XAA A={:#04X} X={:#04X} #{:#04X}", a, x, value);
+                assert_eq!(cpu.status & (ZERO_FLAG | NEGATIVE_FLAG), zero_and_negative(result));
+            }
+        }
+    }
+}
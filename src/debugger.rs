@@ -1,17 +1,22 @@
 // src/debugger.rs
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use log::info;
 use serde::{Serialize, Deserialize}; // Import
 
+use crate::cpu::{AddressingMode, OPCODES_MAP};
+
 /// Defines the conditions for a breakpoint.
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)] // Add Serialize/Deserialize
 pub struct Breakpoint {
     pub on_read: bool,
     pub on_write: bool,
-    // We could add on_execute here, but that requires CPU integration.
-    // For memory/bus, read/write is what we need.
+    /// Hit when the CPU is about to execute the instruction at this address.
+    pub on_execute: bool,
+    /// Removed automatically the first time it is hit.
+    pub one_shot: bool,
 }
 
 impl Breakpoint {
@@ -19,19 +24,34 @@ impl Breakpoint {
     pub fn on_read() -> Self {
         Self {
             on_read: true,
-            on_write: false,
+            ..Self::default()
         }
     }
     pub fn on_write() -> Self {
         Self {
-            on_read: false,
             on_write: true,
+            ..Self::default()
         }
     }
     pub fn on_rw() -> Self {
         Self {
             on_read: true,
             on_write: true,
+            ..Self::default()
+        }
+    }
+    pub fn on_execute() -> Self {
+        Self {
+            on_execute: true,
+            ..Self::default()
+        }
+    }
+    /// One-shot execute breakpoint, for "run to here".
+    pub fn run_to() -> Self {
+        Self {
+            on_execute: true,
+            one_shot: true,
+            ..Self::default()
         }
     }
 }
@@ -40,7 +60,9 @@ impl Breakpoint {
 #[derive(Serialize, Deserialize)]
 pub struct DebuggerState {
     breakpoints: HashMap<u16, Breakpoint>,
+    opcode_breakpoints: BTreeSet<String>,
     paused: bool,
+    break_on_next_jsr: bool,
 }
 // --- END STRUCT ---
 
@@ -50,10 +72,17 @@ pub struct DebuggerState {
 #[derive(Debug)]
 pub struct Debugger {
     breakpoints: HashMap<u16, Breakpoint>,
+    /// Mnemonics that break whenever an instruction with that name is about to run,
+    /// stored upper-case with the `*` prefix of unofficial opcodes.
+    opcode_breakpoints: BTreeSet<String>,
+    /// When set, the next JSR plants a one-shot execute breakpoint at its target.
+    break_on_next_jsr: bool,
     /// A shared, thread-safe flag.
     /// The debugger sets this to `true` when a breakpoint is hit.
     /// The main emulator loop should check this and pause.
     pub paused: Arc<AtomicBool>,
+    /// Set with `paused` when a breakpoint, rather than the user, paused the game.
+    breakpoint_hit: AtomicBool,
 }
 
 impl Debugger {
@@ -61,19 +90,25 @@ impl Debugger {
     pub fn new() -> Self {
         Debugger {
             breakpoints: HashMap::new(),
+            opcode_breakpoints: BTreeSet::new(),
+            break_on_next_jsr: false,
             paused: Arc::new(AtomicBool::new(false)),
+            breakpoint_hit: AtomicBool::new(false),
         }
     }
 
     /// Adds a new breakpoint at a specific address.
     pub fn add_breakpoint(&mut self, addr: u16, bp: Breakpoint) {
-        println!("[DEBUG] Breakpoint added at {:#06X} (Read: {}, Write: {})", addr, bp.on_read, bp.on_write);
+        info!(
+            "Breakpoint added at {:#06X} (Read: {}, Write: {}, Execute: {}, One-shot: {})",
+            addr, bp.on_read, bp.on_write, bp.on_execute, bp.one_shot
+        );
         self.breakpoints.insert(addr, bp);
     }
 
     /// Removes a breakpoint from an address.
     pub fn remove_breakpoint(&mut self, addr: u16) -> Option<Breakpoint> {
-        println!("[DEBUG] Breakpoint removed from {:#06X}", addr);
+        info!("Breakpoint removed from {:#06X}", addr);
         self.breakpoints.remove(&addr)
     }
     
@@ -82,13 +117,55 @@ impl Debugger {
         self.breakpoints.keys().cloned().collect()
     }
 
+    pub fn get_breakpoint(&self, addr: u16) -> Option<Breakpoint> {
+        self.breakpoints.get(&addr).copied()
+    }
+
+    /// Breaks before every instruction named `name`, e.g. "BRK" or "*KIL".
+    pub fn add_opcode_breakpoint(&mut self, name: &str) {
+        let name = name.to_ascii_uppercase();
+        info!("Opcode breakpoint added on {}", name);
+        self.opcode_breakpoints.insert(name);
+    }
+
+    pub fn remove_opcode_breakpoint(&mut self, name: &str) -> bool {
+        let removed = self.opcode_breakpoints.remove(&name.to_ascii_uppercase());
+        if removed {
+            info!("Opcode breakpoint removed from {}", name.to_ascii_uppercase());
+        }
+        removed
+    }
+
+    pub fn get_opcode_breakpoints(&self) -> Vec<String> {
+        self.opcode_breakpoints.iter().cloned().collect()
+    }
+
+    /// Lets the CPU skip decoding the next opcode when no opcode breakpoints are set.
+    pub fn has_opcode_breakpoints(&self) -> bool {
+        !self.opcode_breakpoints.is_empty()
+    }
+
+    /// Checks if the instruction `name` about to execute at `pc` should trigger a breakpoint.
+    pub fn check_opcode(&self, pc: u16, name: &str) {
+        if self.opcode_breakpoints.contains(name) {
+            info!("Opcode Breakpoint HIT: {} at {:#06X}", name, pc);
+            self.hit();
+        }
+    }
+
+    /// Arms a break at the target of the next JSR the CPU executes.
+    pub fn break_on_next_jsr(&mut self) {
+        info!("Will break at the target of the next JSR");
+        self.break_on_next_jsr = true;
+    }
+
     /// Checks if a memory read at `addr` should trigger a breakpoint.
     /// This should be called by `bus_read` *before* the read happens.
     pub fn check_read(&self, addr: u16) {
         if let Some(bp) = self.breakpoints.get(&addr) {
             if bp.on_read {
-                println!("[DEBUG] Read Breakpoint HIT at {:#06X}", addr);
-                self.paused.store(true, Ordering::SeqCst);
+                info!("Read Breakpoint HIT at {:#06X}", addr);
+                self.hit();
             }
         }
     }
@@ -98,23 +175,57 @@ impl Debugger {
     pub fn check_write(&self, addr: u16, value: u8) {
         if let Some(bp) = self.breakpoints.get(&addr) {
             if bp.on_write {
-                println!("[DEBUG] Write Breakpoint HIT at {:#06X} (Value: {:#04X})", addr, value);
-                self.paused.store(true, Ordering::SeqCst);
+                info!("Write Breakpoint HIT at {:#06X} (Value: {:#04X})", addr, value);
+                self.hit();
+            }
+        }
+    }
+
+    /// Checks if executing the instruction at `pc` should trigger a breakpoint.
+    /// Called by the CPU before each instruction; one-shot breakpoints are removed on hit.
+    pub fn check_execute(&mut self, pc: u16) {
+        if let Some(bp) = self.breakpoints.get(&pc).copied().filter(|bp| bp.on_execute) {
+            info!("Execute Breakpoint HIT at {:#06X}", pc);
+            self.hit();
+            if bp.one_shot {
+                self.breakpoints.remove(&pc);
             }
         }
     }
 
+    fn hit(&self) {
+        self.breakpoint_hit.store(true, Ordering::SeqCst);
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether a breakpoint has paused the game since the last call.
+    pub fn take_breakpoint_hit(&self) -> bool {
+        self.breakpoint_hit.swap(false, Ordering::SeqCst)
+    }
+
+    /// Called by the CPU for every JSR with the subroutine address.
+    pub fn on_jsr(&mut self, target: u16) {
+        if self.break_on_next_jsr {
+            self.break_on_next_jsr = false;
+            self.add_breakpoint(target, Breakpoint::run_to());
+        }
+    }
+
     // --- ADD THESE METHODS ---
     pub fn save_state(&self) -> DebuggerState {
         DebuggerState {
             breakpoints: self.breakpoints.clone(),
+            opcode_breakpoints: self.opcode_breakpoints.clone(),
             paused: self.paused.load(Ordering::SeqCst),
+            break_on_next_jsr: self.break_on_next_jsr,
         }
     }
 
     pub fn load_state(&mut self, state: &DebuggerState) {
         self.breakpoints = state.breakpoints.clone();
+        self.opcode_breakpoints = state.opcode_breakpoints.clone();
         self.paused.store(state.paused, Ordering::SeqCst);
+        self.break_on_next_jsr = state.break_on_next_jsr;
     }
     // --- END METHODS ---
 }
@@ -123,4 +234,78 @@ impl Default for Debugger {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+/// One decoded instruction of a disassembly listing.
+#[derive(Clone, Debug)]
+pub struct DisassembledLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// Mnemonic and operand, e.g. `LDA ($00),Y`; branches show their target address.
+    pub text: String,
+}
+
+/// Decodes the instruction at `addr`, reading memory through `read`. Bytes that are not
+/// an opcode come out as a one-byte `.db`.
+pub fn disassemble(read: &impl Fn(u16) -> u8, addr: u16) -> DisassembledLine {
+    let code = read(addr);
+    let Some(opcode) = OPCODES_MAP.get(&code) else {
+        return DisassembledLine { addr, bytes: vec![code], text: format!(".db ${:02X}", code) };
+    };
+
+    let bytes: Vec<u8> = (0..opcode.bytes as u16).map(|i| read(addr.wrapping_add(i))).collect();
+    let byte = || bytes[1];
+    let word = || u16::from_le_bytes([bytes[1], bytes[2]]);
+    let operand = match opcode.mode {
+        AddressingMode::Immediate => format!("#${:02X}", byte()),
+        AddressingMode::ZeroPage => format!("${:02X}", byte()),
+        AddressingMode::ZeroPage_X => format!("${:02X},X", byte()),
+        AddressingMode::ZeroPage_Y => format!("${:02X},Y", byte()),
+        AddressingMode::Absolute => format!("${:04X}", word()),
+        AddressingMode::Absolute_X => format!("${:04X},X", word()),
+        AddressingMode::Absolute_Y => format!("${:04X},Y", word()),
+        AddressingMode::Indirect => format!("(${:04X})", word()),
+        AddressingMode::Indirect_X => format!("(${:02X},X)", byte()),
+        AddressingMode::Indirect_Y => format!("(${:02X}),Y", byte()),
+        AddressingMode::Relative => {
+            let target = addr.wrapping_add(2).wrapping_add(byte() as i8 as u16);
+            format!("${:04X}", target)
+        }
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Implied => String::new(),
+    };
+    let text = format!("{} {}", opcode.name, operand).trim_end().to_string();
+    DisassembledLine { addr, bytes, text }
+}
+
+/// Disassembles up to `before` instructions leading to `pc`, then `after` from `pc` on.
+///
+/// 6502 code cannot be decoded backwards, so the listing starts from the furthest
+/// address before `pc` whose instructions run exactly into it. Data or a jump target
+/// just before `pc` can still make the leading lines wrong; the line at `pc` and those
+/// after it are always decoded from `pc`.
+pub fn disassemble_around(read: &impl Fn(u16) -> u8, pc: u16, before: usize, after: usize) -> Vec<DisassembledLine> {
+    let mut lines = Vec::new();
+    for back in (1..=before as u16 * 3).rev() {
+        let mut addr = pc.wrapping_sub(back);
+        let mut walked = Vec::new();
+        while addr != pc && pc.wrapping_sub(addr) <= back {
+            let line = disassemble(read, addr);
+            addr = addr.wrapping_add(line.bytes.len() as u16);
+            walked.push(line);
+        }
+        if addr == pc {
+            let skip = walked.len().saturating_sub(before);
+            lines.extend(walked.into_iter().skip(skip));
+            break;
+        }
+    }
+
+    let mut addr = pc;
+    for _ in 0..after {
+        let line = disassemble(read, addr);
+        addr = addr.wrapping_add(line.bytes.len() as u16);
+        lines.push(line);
+    }
+    lines
+}
@@ -1,311 +1,2784 @@
 use std::sync::atomic::Ordering;
 use std::sync::{mpsc, Arc, Mutex};
 use std::io::{self, Write};
-use crate::debugger::Breakpoint; 
+use nesemu::debugger::{self, Breakpoint, DisassembledLine};
 
-use std::collections::HashMap;
 use std::time::{Duration, Instant};
 use std::fs::{self, File}; 
 use std::io::Read;
 use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::mouse::MouseButton;
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::audio::AudioSpecDesired;
-
-use crate::bus::Bus;
-use crate::cartridge::Rom;
-use crate::cpu::{CPU, EmulatorSnapshot};
-use crate::render::frame::Frame;
-use crate::render;
-use crate::apu;
-use crate::ppu;
-use crate::joypad;
-use crate::gamegenie::GameGenieCode;
-use crate::bus::Mem;
-
-const AUDIO_SAMPLE_RATE: i32 = 44100;
+use sdl2::render::{Texture, TextureCreator, WindowCanvas};
+use sdl2::video::{Window, WindowContext};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioQueue, AudioSpecDesired};
+use sdl2::AudioSubsystem;
+
+use nesemu::bus::{Bus, ExpansionDevice, FrozenAddress, ScheduledWrite};
+use nesemu::cartridge::{Mirroring, Rom};
+use nesemu::cpu::{CPU, CPU_OPCODES, EmulatorSnapshot};
+use nesemu::render::frame::Frame;
+use nesemu::render;
+use nesemu::render::overlay;
+use nesemu::render::filter::{FilterKind, VideoFilter};
+use nesemu::perf::{AudioQueueStats, PerfStats};
+use nesemu::region::Region;
+use nesemu::rewind::RewindBuffer;
+use nesemu::netplay::{self, NetplayListener, NetplaySession};
+use nesemu::nsf;
+use nesemu::movie::{self, Fm2Header, Movie, MovieMode};
+
+use crate::bindings::{self, Action, Bindings};
+use nesemu::Player;
+use nesemu::apu;
+use nesemu::apulog::{self, ApuLogFormat, ApuLogWriter, ApuWrite};
+use nesemu::audioring::{self, SampleConsumer, SampleProducer};
+use nesemu::ppu;
+use nesemu::joypad;
+use nesemu::gamegenie::GameGenieCode;
+use nesemu::bus::Mem;
+use log::{debug, error, info, warn};
+
 const AUDIO_BUFFER_SIZE: u16 = 1024;
+// Queue depth, in samples, that rate control steers toward: two device buffers
+const AUDIO_TARGET_SAMPLES: u32 = AUDIO_BUFFER_SIZE as u32 * 2;
+// Past this depth the queue is flushed; only a long stall gets it this far
+const AUDIO_MAX_SAMPLES: u32 = AUDIO_TARGET_SAMPLES * 4;
+// The callback backend asks the device for small buffers and keeps about this much
+// sound waiting in its ring
+const AUDIO_CALLBACK_BUFFER_SIZE: u16 = 512;
+const AUDIO_CALLBACK_LATENCY: Duration = Duration::from_millis(30);
+// A quarter of a second at the highest sample rate, well past the flush depth
+const AUDIO_RING_CAPACITY: usize = 24_000;
+// Underruns in one second that count as a problem worth a log line, and how often to repeat it
+const AUDIO_UNDERRUN_WARN_COUNT: u32 = 3;
+const AUDIO_UNDERRUN_WARN_INTERVAL: Duration = Duration::from_secs(30);
+// Rewind keeps ~10 seconds of history regardless of the capture interval
+const REWIND_HISTORY_FRAMES: u32 = 600;
+const REWIND_MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+// Run-ahead turns itself off after this many consecutive host frames over budget
+const RUN_AHEAD_MAX_OVERRUNS: u32 = 60;
+const NETPLAY_ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+// How long on-screen notes stay visible
+const OSD_DURATION: Duration = Duration::from_secs(3);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const APU_VIEWER_INTERVAL: Duration = Duration::from_millis(100);
+const SPECTRUM_INTERVAL: Duration = Duration::from_millis(250);
+// Instructions listed before and from PC in the GUI debugger
+const DISASSEMBLY_BEFORE: usize = 8;
+const DISASSEMBLY_AFTER: usize = 16;
+const JSR_OPCODE: u8 = 0x20;
+// Filters render at the window's integer scale, within these bounds
+const FILTER_MIN_SCALE: usize = 2;
+const FILTER_MAX_SCALE: usize = 4;
+
+pub enum EmulatorCommand {
+    LoadRom(String),
+    LoadRawRom {
+        prg_path: String,
+        chr_path: Option<String>,
+        mirroring: Mirroring,
+    },
+    /// Plays an NSF tune on the virtual player board, starting at its default song.
+    LoadNsf(String),
+    /// Restarts the loaded NSF at another song, numbered from 0.
+    NsfSelectSong(u8),
+    SetGameGenieCodes(Vec<GameGenieCode>),
+    /// Replaces the addresses rewritten with a fixed value after every frame.
+    SetFrozenAddresses(Vec<FrozenAddress>),
+    /// Writes `value` to `addr` at the start of frame `frame` of `Bus::frame_number`, before
+    /// the NMI handler runs; a frame already past gets the write at the next frame start.
+    /// The write goes through the normal bus path, so PPU, APU and mapper registers react
+    /// as they would to the game's own write. Writes are kept per game and applied in
+    /// frame order, then in the order they were sent; see `Bus::schedule_write`.
+    ScheduledWrite { frame: u64, addr: u16, value: u8 },
+    Pause,
+    SetTracing(bool),
+    /// Keeps the last `cpu::TRACE_HISTORY_LEN` instructions, printed when a breakpoint
+    /// hits, the game jams or the console asks with `dump-history`.
+    SetTraceHistory(bool),
+    /// Prints every CPU access to the PPU registers with the scanline and cycle it happened on.
+    SetPpuLogging(bool),
+    SetPerfOverlay(bool),
+    /// Draws both controllers with their pressed buttons in the corner of the frame.
+    SetInputDisplay(bool),
+    SetRewind { enabled: bool, interval_frames: u32 },
+    SaveState(String),
+    LoadState(String),
+    StopEmulation,
+    SetVsync(bool),
+    /// Sleeps out the rest of each frame when on; independent of vsync.
+    SetFrameLimit(bool),
+    /// Switches NTSC/PAL/Dendy timing; a running game changes over at the end of its frame.
+    SetRegion(Region),
+    /// Emulates OAMADDR clearing and $2004 reads during rendering; see `NesPPU::set_oam_quirks`.
+    SetOamQuirks(bool),
+    /// Debug overrides for drawing the background and sprites; `None` follows the game's
+    /// $2001 bits. See `NesPPU::set_layer_visibility`.
+    SetLayerVisibility { background: Option<bool>, sprites: Option<bool> },
+    /// Connects the mouse-driven Zapper (in place of controller 2) or the Family BASIC
+    /// keyboard, or unplugs both.
+    SetExpansionDevice(ExpansionDevice),
+    SetPauseOnFocusLoss(bool),
+    /// Pauses each newly loaded game at its reset vector, before its first instruction.
+    SetBreakOnLoad(bool),
+    ExportNametablePng(String),
+    ExportPalettePng(String),
+    /// Number of frames to run ahead of the displayed frame; 0 disables run-ahead.
+    SetRunAhead(u32),
+    /// Frames per pressed/released phase, shared by held and sticky turbo.
+    SetTurboRate(u32),
+    /// Autofire on the normal buttons: which ones pulse while held, and how many of
+    /// every `period` frames they are pressed for.
+    SetAutofire { buttons: joypad::JoypadButton, period: u32, duty: u32 },
+    /// How Left+Right and Up+Down held together reach the game, for both controllers.
+    SetOpposingDirections(joypad::OpposingDirections),
+    /// How a game controller's left stick drives controller 1's d-pad.
+    SetAnalogStick { deadzone: f32, sensitivity: f32, eight_way: bool },
+    NetplayHost(u16),
+    NetplayConnect(String),
+    NetplayDisconnect,
+    /// Reopens audio output on the named device, or the system default for None.
+    SetAudioDevice(Option<String>),
+    /// Volume of one APU channel (`apu::CHANNEL_NAMES` index), 0.0 to 1.0.
+    SetChannelVolume { channel: usize, volume: f32 },
+    /// Plays only this channel, or every channel again with None; see `Apu::set_solo`.
+    SetSolo(Option<usize>),
+    SetFastForwardAudio(FastForwardAudio),
+    /// Silences the audio device while paused instead of letting it hold the last sound.
+    SetMuteOnPause(bool),
+    /// Band-limited synthesis when on; the cheaper point sampler when off.
+    SetBandLimitedAudio(bool),
+    /// The console's 90 Hz/440 Hz/14 kHz output filters when on; one gentle high-pass when off.
+    SetHardwareFilters(bool),
+    /// Holds the triangle at its midpoint while its period is ultrasonic, to avoid clicks.
+    SetSilenceUltrasonicTriangle(bool),
+    /// Reopens the audio device at this many samples per second; the APU follows whatever
+    /// rate the device actually grants.
+    SetSampleRate(u32),
+    /// Reopens the audio device with the queue or the callback backend.
+    SetAudioBackend(AudioBackend),
+    SetVideoFilter(FilterKind),
+    /// Starts a new input movie at the next frame boundary, replacing any current one.
+    MovieRecord,
+    /// Power-cycles the console and records a movie from there, saved as FM2 to `path`
+    /// when stopped. With `checksums` the file also carries periodic state checksums.
+    StartMovie { path: String, checksums: bool },
+    /// Loads an FM2 movie for this ROM, power-cycles and plays it back to the end.
+    PlayMovie(String),
+    /// Switches the movie to playback from its current frame.
+    MoviePlay,
+    MovieStop,
+    MovieSeek(usize),
+    MovieGetInput(usize),
+    /// Replaces a recorded frame's (controller 1, controller 2) input.
+    MovieSetInput { frame: usize, input: [u8; 2] },
+    SetBindings(Bindings),
+    /// Ignores hotkeys in the game window, e.g. while a GUI text field has focus.
+    SetHotkeysSuppressed(bool),
+    /// Resumes a game stopped by a breakpoint or the pause command.
+    DebugContinue,
+    /// While paused, runs one instruction and pauses again.
+    DebugStep,
+    /// While paused, runs one instruction, or a whole subroutine call for a JSR.
+    DebugStepOver,
+    AddBreakpoint { addr: u16, breakpoint: Breakpoint },
+    RemoveBreakpoint(u16),
+    /// Asks for an `EmulatorStatus::Debugger` reply with the current CPU state.
+    DebugRefresh,
+    /// Asks for an `EmulatorStatus::Memory` reply with `len` bytes from `start`.
+    DebugReadMemory { start: u16, len: u16 },
+    /// Sends `EmulatorStatus::Apu` a few times a second while on, for the APU viewer.
+    SetApuViewer(bool),
+    /// Sends the recent audio output a few times a second while on, for the spectrum view.
+    SetSpectrumViewer(bool),
+    /// Records every APU register write with its CPU cycle to `path`, replacing any log
+    /// already being recorded.
+    StartApuLog { path: String, format: ApuLogFormat },
+    StopApuLog,
+}
+
+/// What happens to the sound while the frame limiter is off and the game runs faster
+/// than real time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FastForwardAudio {
+    /// Nothing is played until the game is back to normal speed.
+    #[default]
+    Mute,
+    /// Every frame's sound is squeezed into real time, raising the pitch with the speed.
+    Pitch,
+    /// Whole frames of sound are played at normal pitch and the surplus is dropped.
+    Skip,
+}
+
+impl FastForwardAudio {
+    pub const ALL: [FastForwardAudio; 3] = [FastForwardAudio::Mute, FastForwardAudio::Pitch, FastForwardAudio::Skip];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FastForwardAudio::Mute => "Mute",
+            FastForwardAudio::Pitch => "Raise pitch",
+            FastForwardAudio::Skip => "Skip ahead (normal pitch)",
+        }
+    }
+}
+
+/// How samples reach the audio device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AudioBackend {
+    /// The game loop pushes each frame's sound onto SDL's queue.
+    #[default]
+    Queue,
+    /// SDL's callback pulls sound from a lock-free ring as the device needs it, for
+    /// lower latency. Harder to debug, since the callback runs on SDL's own thread.
+    Callback,
+}
+
+impl AudioBackend {
+    pub const ALL: [AudioBackend; 2] = [AudioBackend::Queue, AudioBackend::Callback];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            AudioBackend::Queue => "Queue (push)",
+            AudioBackend::Callback => "Callback (pull, lower latency)",
+        }
+    }
+}
+
+/// What the audio device is opened with.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct AudioFormat {
+    sample_rate: u32,
+    backend: AudioBackend,
+}
+
+// Runs on SDL's audio thread, filling with silence whatever the ring cannot cover
+struct RingPlayer {
+    consumer: SampleConsumer,
+}
+
+impl AudioCallback for RingPlayer {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let filled = self.consumer.pop_into(out);
+        out[filled..].fill(0.0);
+    }
+}
+
+/// The open audio device, behind whichever backend was chosen. Depths and targets are
+/// in samples.
+enum AudioOutput {
+    Queue(AudioQueue<f32>),
+    Callback {
+        device: AudioDevice<RingPlayer>,
+        producer: SampleProducer,
+    },
+}
+
+impl AudioOutput {
+    fn open(audio: &AudioSubsystem, device: Option<&str>, format: AudioFormat) -> Result<Self, String> {
+        let buffer_size = match format.backend {
+            AudioBackend::Queue => AUDIO_BUFFER_SIZE,
+            AudioBackend::Callback => AUDIO_CALLBACK_BUFFER_SIZE,
+        };
+        let desired_spec = AudioSpecDesired {
+            freq: Some(format.sample_rate as i32),
+            channels: Some(1),
+            samples: Some(buffer_size),
+        };
+
+        let output = match format.backend {
+            AudioBackend::Queue => AudioOutput::Queue(audio.open_queue::<f32, _>(device, &desired_spec)?),
+            AudioBackend::Callback => {
+                let (producer, consumer) = audioring::sample_ring(AUDIO_RING_CAPACITY);
+                let device = audio.open_playback(device, &desired_spec, |_| RingPlayer { consumer })?;
+                AudioOutput::Callback { device, producer }
+            }
+        };
+        output.resume();
+        Ok(output)
+    }
+
+    fn backend(&self) -> AudioBackend {
+        match self {
+            AudioOutput::Queue(_) => AudioBackend::Queue,
+            AudioOutput::Callback { .. } => AudioBackend::Callback,
+        }
+    }
+
+    // Samples per second the device actually plays, which SDL may set apart from the request
+    fn sample_rate(&self) -> u32 {
+        let freq = match self {
+            AudioOutput::Queue(queue) => queue.spec().freq,
+            AudioOutput::Callback { device, .. } => device.spec().freq,
+        };
+        freq.max(1) as u32
+    }
+
+    fn queued_samples(&self) -> u32 {
+        match self {
+            // Queue size is in bytes of f32 samples
+            AudioOutput::Queue(queue) => queue.size() / 4,
+            AudioOutput::Callback { producer, .. } => producer.len() as u32,
+        }
+    }
+
+    /// Depth rate control steers toward.
+    fn target_samples(&self) -> u32 {
+        match self {
+            AudioOutput::Queue(_) => AUDIO_TARGET_SAMPLES,
+            AudioOutput::Callback { .. } => (self.sample_rate() as f64 * AUDIO_CALLBACK_LATENCY.as_secs_f64()) as u32,
+        }
+    }
+
+    /// Depth past which waiting sound is flushed to resync with the picture.
+    fn max_samples(&self) -> u32 {
+        match self {
+            AudioOutput::Queue(_) => AUDIO_MAX_SAMPLES,
+            AudioOutput::Callback { .. } => self.target_samples() * 4,
+        }
+    }
+
+    /// Time from queuing a sample to hearing it: what is waiting plus the device's buffer.
+    fn latency(&self) -> Duration {
+        let buffer = match self {
+            AudioOutput::Queue(queue) => queue.spec().samples,
+            AudioOutput::Callback { device, .. } => device.spec().samples,
+        };
+        let samples = self.queued_samples() + buffer as u32;
+        Duration::from_secs_f64(samples as f64 / self.sample_rate() as f64)
+    }
+
+    /// Hands samples to the device. A full ring drops the surplus, which only a
+    /// stalled device lets happen.
+    fn queue(&mut self, samples: &[f32]) {
+        match self {
+            AudioOutput::Queue(queue) => {
+                queue.queue(samples);
+            }
+            AudioOutput::Callback { producer, .. } => {
+                producer.push(samples);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            AudioOutput::Queue(queue) => queue.clear(),
+            // Locking keeps the callback out while its ring is emptied
+            AudioOutput::Callback { device, .. } => device.lock().consumer.clear(),
+        }
+    }
+
+    fn pause(&self) {
+        match self {
+            AudioOutput::Queue(queue) => queue.pause(),
+            AudioOutput::Callback { device, .. } => device.pause(),
+        }
+    }
+
+    fn resume(&self) {
+        match self {
+            AudioOutput::Queue(queue) => queue.resume(),
+            AudioOutput::Callback { device, .. } => device.resume(),
+        }
+    }
+}
+
+/// Pending single-step request made from the SDL window while paused.
+#[derive(Clone, Copy)]
+enum StepRequest {
+    None,
+    Instruction,
+    Frame(u64),
+}
+
+/// What the frame callback does with a completed frame. Run-ahead emulates frames whose
+/// picture or sound must be thrown away.
+#[derive(Clone, Copy)]
+enum FrameOutput {
+    Normal,
+    AudioOnly,
+    VideoOnly,
+    Discard,
+}
+
+/// PNG export requested from the GUI, written once the current frame completes.
+#[derive(Clone, Copy)]
+enum ExportKind {
+    Nametables,
+    Palette,
+}
+
+/// Dynamic rate control for the audio queue. Frames are paced by the video clock, which
+/// never quite matches the sound card's, so the queue slowly drains or piles up. Each
+/// frame the APU's output rate is nudged by up to `apu::MAX_RATE_ADJUST` in proportion
+/// to how far the queue is from its target depth.
+struct AudioRateControl {
+    // Depth averaged over recent frames; SDL takes a whole device buffer at a time
+    average_depth: f64,
+}
+
+impl AudioRateControl {
+    fn new() -> Self {
+        AudioRateControl { average_depth: AUDIO_TARGET_SAMPLES as f64 }
+    }
+
+    /// Takes the depth after this frame's samples were queued and returns the rate ratio
+    /// for the next frame: the full speed-up on an empty queue, the full slow-down at
+    /// twice the target.
+    fn update(&mut self, queued_samples: u32, target_samples: u32) -> f64 {
+        self.average_depth += (queued_samples as f64 - self.average_depth) * 0.05;
+        let fill = (self.average_depth / (2 * target_samples.max(1)) as f64).min(1.0);
+        1.0 + (1.0 - 2.0 * fill) * apu::MAX_RATE_ADJUST
+    }
+
+    fn reset(&mut self, target_samples: u32) {
+        self.average_depth = target_samples as f64;
+    }
+}
+
+/// The streaming texture shown in the window. With a filter selected it holds the
+/// filter's upscaled output and is recreated whenever that size changes.
+struct VideoOutput<'a> {
+    creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    filter: Option<Box<dyn VideoFilter>>,
+    filtered: Vec<u8>,
+}
+
+impl<'a> VideoOutput<'a> {
+    fn new(creator: &'a TextureCreator<WindowContext>, kind: FilterKind) -> Result<Self, String> {
+        let texture = creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, Frame::WIDTH as u32, Frame::HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
+        Ok(VideoOutput {
+            creator,
+            texture,
+            filter: kind.create(),
+            filtered: Vec::new(),
+        })
+    }
+
+    fn set_filter(&mut self, kind: FilterKind) {
+        self.filter = kind.create();
+    }
+
+    fn upload(&mut self, frame: &Frame, scale: usize) {
+        let (data, width) = match self.filter.as_mut() {
+            Some(filter) => {
+                filter.apply(frame, scale, &mut self.filtered);
+                (&self.filtered[..], Frame::WIDTH * scale)
+            }
+            None => (&frame.data[..], Frame::WIDTH),
+        };
+        let height = data.len() / (width * 3);
+
+        let query = self.texture.query();
+        if query.width as usize != width || query.height as usize != height {
+            self.texture = self
+                .creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, width as u32, height as u32)
+                .unwrap();
+        }
+        self.texture.update(None, data, width * 3).unwrap();
+    }
+}
+
+/// Live counters for the GUI statistics panel, sent once a second while a ROM is loaded.
+#[derive(Clone)]
+pub struct EmulatorStats {
+    pub frames: u64,
+    pub cpu_cycles: usize,
+    pub scanline: u16,
+    pub audio_queue_samples: u32,
+    /// Sound waiting plus the device's buffer, as time.
+    pub audio_latency: Duration,
+    /// Frames that finished after their deadline, so the display missed a refresh.
+    pub dropped_frames: u64,
+    /// Times the audio queue overflowed and was flushed to resync with video.
+    pub audio_resyncs: u64,
+    /// Output rate set by audio rate control, relative to nominal.
+    pub audio_rate: f64,
+    /// Underruns and the queue's depth range over the last second.
+    pub audio_queue_stats: AudioQueueStats,
+    pub paused: bool,
+    pub turbo_held: joypad::JoypadButton,
+    pub turbo_sticky: joypad::JoypadButton,
+    pub cheats: usize,
+}
+
+/// Where an NSF tune is, sent once a second while one is playing.
+#[derive(Clone, Debug)]
+pub struct NsfStatus {
+    pub title: String,
+    /// Current song, numbered from 0.
+    pub song: u8,
+    pub songs: u8,
+    /// Emulated time since the song was started.
+    pub elapsed: Duration,
+}
+
+/// CPU registers and timing, published into a `SharedCpuSnapshot` once a frame so the
+/// GUI can show them on every repaint without a command round trip. It is taken at the
+/// first instruction after each frame ends, and when the game pauses, so while running
+/// it lags the game by up to a frame; fine for display.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuSnapshot {
+    pub program_counter: u16,
+    pub register_a: u8,
+    pub register_x: u8,
+    pub register_y: u8,
+    pub stack_pointer: u8,
+    pub status: u8,
+    pub cpu_cycles: usize,
+    pub frames: u64,
+    pub scanline: u16,
+    /// PPU dot within the scanline.
+    pub dot: usize,
+}
+
+impl CpuSnapshot {
+    fn of(cpu: &CPU) -> Self {
+        CpuSnapshot {
+            program_counter: cpu.program_counter,
+            register_a: cpu.register_a,
+            register_x: cpu.register_x,
+            register_y: cpu.register_y,
+            stack_pointer: cpu.stack_pointer,
+            status: cpu.status,
+            cpu_cycles: cpu.bus.cycles(),
+            frames: cpu.bus.frame_number(),
+            scanline: cpu.bus.ppu().scanline(),
+            dot: cpu.bus.ppu().cycle(),
+        }
+    }
+}
+
+/// Written by the emulator thread, read by the GUI; None until a game has run a frame.
+pub type SharedCpuSnapshot = Arc<Mutex<Option<CpuSnapshot>>>;
+
+/// CPU state for the GUI debugger, sent whenever the game pauses and on request.
+#[derive(Clone)]
+pub struct DebuggerView {
+    pub paused: bool,
+    pub registers: CpuSnapshot,
+    /// Instructions around PC, which is the line whose address matches `registers.program_counter`.
+    pub disassembly: Vec<DisassembledLine>,
+    pub breakpoints: Vec<(u16, Breakpoint)>,
+}
+
+#[derive(Clone, Copy)]
+pub struct MovieStatus {
+    pub frame: usize,
+    pub len: usize,
+    pub mode: MovieMode,
+    /// First frame that did not match the checksums stored in the movie.
+    pub desync: Option<usize>,
+}
+
+/// Feedback sent from the emulator thread back to the GUI.
+pub enum EmulatorStatus {
+    GameGenieCodesApplied(usize),
+    /// Freeze list taken from a loaded save state.
+    FrozenAddresses(Vec<FrozenAddress>),
+    Error(String),
+    RomLoaded,
+    Stopped,
+    RunAheadDisabled,
+    /// Human-readable netplay state, or None once the session has ended.
+    Netplay(Option<String>),
+    AudioDevices(Vec<String>),
+    /// The device audio is actually playing on, after any fallback.
+    AudioDevice(Option<String>),
+    Stats(EmulatorStats),
+    /// Movie position and mode, or None when no movie is active.
+    Movie(Option<MovieStatus>),
+    MovieInput { frame: usize, input: [u8; 2] },
+    /// The controls key was pressed in the game window.
+    ShowControls,
+    Debugger(DebuggerView),
+    Memory { start: u16, bytes: Vec<u8> },
+    Apu(apu::ApuDebugInfo),
+    /// The APU's most recent output samples and the rate they were produced at.
+    AudioSamples { samples: Vec<f32>, sample_rate: u32 },
+    Nsf(NsfStatus),
+    /// Whether an APU write log is being recorded; sent when one starts, stops or fails.
+    ApuLogging(bool),
+    /// The soloed channel changed from the game window's keys.
+    Solo(Option<usize>),
+}
+
+pub fn run_emulator(
+    rx: mpsc::Receiver<EmulatorCommand>,
+    status_tx: mpsc::Sender<EmulatorStatus>,
+    cpu_snapshot: SharedCpuSnapshot,
+) {
+    if let Err(e) = emulator_main(rx, &status_tx, &cpu_snapshot) {
+        error!("Emulator thread failed: {}", e);
+        let _ = status_tx.send(EmulatorStatus::Error(format!("Emulator failed to start: {}", e)));
+    }
+}
+
+fn emulator_main(
+    rx: mpsc::Receiver<EmulatorCommand>,
+    status_tx: &mpsc::Sender<EmulatorStatus>,
+    cpu_snapshot: &SharedCpuSnapshot,
+) -> Result<(), String> {
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let audio_subsystem = sdl_context.audio()?;
+    let game_controller_subsystem = sdl_context.game_controller()?;
+
+    let window = video_subsystem
+        .window("JazzNess Emulator", 256 * 2, 240 * 2)
+        .position_centered()
+        .hidden()
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let event_pump = Rc::new(RefCell::new(sdl_context.event_pump()?));
+
+    // Rate asked of the audio device; the rate it grants is in the device's spec
+    let audio_format = Rc::new(Cell::new(AudioFormat {
+        sample_rate: apu::DEFAULT_SAMPLE_RATE,
+        backend: AudioBackend::default(),
+    }));
+    let audio_output = Rc::new(RefCell::new(AudioOutput::open(&audio_subsystem, None, audio_format.get())?));
+    let audio_device: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    let _ = status_tx.send(EmulatorStatus::AudioDevices(audio_device_names(&audio_subsystem)));
+    // Short on-screen note and when it was posted
+    let osd_message: Rc<RefCell<Option<(String, Instant)>>> = Rc::new(RefCell::new(None));
+
+    let bindings = Rc::new(RefCell::new(Bindings::new()));
+    let hotkeys_suppressed = Rc::new(Cell::new(false));
+    let turbo = Rc::new(RefCell::new(joypad::Turbo::new()));
+    let autofire = Rc::new(RefCell::new(joypad::Autofire::new()));
+    let opposing_directions = Rc::new(Cell::new(joypad::OpposingDirections::default()));
+    let analog_stick = Rc::new(RefCell::new(joypad::AnalogToDpad::new()));
+    // Open controllers; SDL stops reporting a controller's events once it is dropped
+    let game_controllers: Rc<RefCell<Vec<GameController>>> = Rc::new(RefCell::new(Vec::new()));
+    let channel_volumes = Rc::new(Cell::new([1.0f32; 5]));
+    let solo: Rc<Cell<Option<usize>>> = Rc::new(Cell::new(None));
+    let fast_forward_audio = Rc::new(Cell::new(FastForwardAudio::default()));
+    let mute_on_pause = Rc::new(Cell::new(true));
+    let band_limited_audio = Rc::new(Cell::new(true));
+    let hardware_filters = Rc::new(Cell::new(true));
+    let silence_ultrasonic_triangle = Rc::new(Cell::new(true));
+
+    let rx = Arc::new(Mutex::new(rx));
+    let console_rx = Rc::new(spawn_console_reader());
+    let overlay_enabled = Rc::new(Cell::new(false));
+    let input_display = Rc::new(Cell::new(false));
+    let rewind_enabled = Rc::new(Cell::new(true));
+    let rewind_interval = Rc::new(Cell::new(2u32));
+    let vsync_enabled = Rc::new(Cell::new(true));
+    let frame_limit_enabled = Rc::new(Cell::new(true));
+    let region = Rc::new(Cell::new(Region::Ntsc));
+    let oam_quirks = Rc::new(Cell::new(false));
+    let layer_visibility: Rc<Cell<(Option<bool>, Option<bool>)>> = Rc::new(Cell::new((None, None)));
+    let expansion_device = Rc::new(Cell::new(ExpansionDevice::None));
+    let pause_on_focus_loss = Rc::new(Cell::new(false));
+    let break_on_load = Rc::new(Cell::new(false));
+    let trace_history = Rc::new(Cell::new(false));
+    let apu_viewer = Rc::new(Cell::new(false));
+    let spectrum_viewer = Rc::new(Cell::new(false));
+    let video_filter = Rc::new(Cell::new(FilterKind::None));
+    let run_ahead_frames = Rc::new(Cell::new(0u32));
+    // Kept across a canvas rebuild; closed when the game it is recording ends
+    let apu_log: Rc<RefCell<Option<ApuLogWriter>>> = Rc::new(RefCell::new(None));
+    // A ROM load received mid-game is parked here so the outer loop picks it up.
+    let pending_command: Rc<RefCell<Option<EmulatorCommand>>> = Rc::new(RefCell::new(None));
+    // A session interrupted to rebuild the canvas is parked here and resumed from its snapshot.
+    let resume_session: Rc<RefCell<Option<(Rom, String, EmulatorSnapshot)>>> = Rc::new(RefCell::new(None));
+    // The canvas is kept from one game to the next and only rebuilt around its window when
+    // vsync changes, since vsync can only be chosen when the renderer is created
+    let mut canvas_vsync = vsync_enabled.get();
+    let mut game_canvas = Some(build_canvas(window, canvas_vsync)?);
+
+
+    loop {
+
+        let resume = resume_session.borrow_mut().take();
+        let (rom, game_name, resume_snapshot) = match resume {
+            Some((rom, game_name, snapshot)) => (rom, game_name, Some(snapshot)),
+            None => {
+                let pending = pending_command.borrow_mut().take();
+                let command = match pending {
+                    Some(cmd) => cmd,
+                    None => match rx.lock().unwrap().recv() {
+                        Ok(cmd) => cmd,
+                        Err(_) => {
+                            debug!("Command channel closed, exiting thread.");
+                            break;
+                        }
+                    },
+                };
+
+                let (loaded, game_name) = match command {
+                    EmulatorCommand::LoadRom(rom_path) => {
+                        info!("Loading ROM: {}", rom_path);
+                        (load_ines_rom(&rom_path), game_name_from_path(&rom_path))
+                    }
+                    EmulatorCommand::LoadRawRom { prg_path, chr_path, mirroring } => {
+                        info!("Loading raw PRG: {}", prg_path);
+                        (load_raw_rom(&prg_path, chr_path.as_deref(), mirroring), game_name_from_path(&prg_path))
+                    }
+                    EmulatorCommand::LoadNsf(nsf_path) => {
+                        info!("Loading NSF: {}", nsf_path);
+                        let loaded = load_nsf(&nsf_path);
+                        // The tune's own title names the window when it has one
+                        let name = match &loaded {
+                            Ok(Rom { nsf: Some(header), .. }) if !header.title.is_empty() => header.title.clone(),
+                            _ => game_name_from_path(&nsf_path),
+                        };
+                        (loaded, name)
+                    }
+                    EmulatorCommand::NsfSelectSong(_) => {
+                        debug!("Ignoring song selection, no NSF loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetFrozenAddresses(_) => {
+                        debug!("Ignoring frozen addresses, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::ScheduledWrite { .. } => {
+                        debug!("Ignoring scheduled write, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetGameGenieCodes(_) => {
+                        debug!("Ignoring cheat codes, no ROM loaded.");
+                        let _ = status_tx.send(EmulatorStatus::Error(
+                            "No ROM is loaded. Cheats cannot be applied.".to_string(),
+                        ));
+                        continue;
+                    }
+                    EmulatorCommand::Pause => {
+                        debug!("Ignoring pause, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetTracing(_) => {
+                        debug!("Ignoring trace command, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetTraceHistory(enabled) => {
+                        trace_history.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetPpuLogging(_) => {
+                        debug!("Ignoring PPU logging command, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetPerfOverlay(enabled) => {
+                        overlay_enabled.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetInputDisplay(enabled) => {
+                        input_display.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetRewind { enabled, interval_frames } => {
+                        rewind_enabled.set(enabled);
+                        rewind_interval.set(interval_frames.max(1));
+                        continue;
+                    }
+                    EmulatorCommand::SaveState(_) | EmulatorCommand::LoadState(_) => {
+                         debug!("Ignoring save/load state, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::StopEmulation => {
+                        debug!("Ignoring stop, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::ExportNametablePng(_) | EmulatorCommand::ExportPalettePng(_) => {
+                        debug!("Ignoring PNG export, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::SetRunAhead(frames) => {
+                        run_ahead_frames.set(frames);
+                        continue;
+                    }
+                    EmulatorCommand::SetAutofire { buttons, period, duty } => {
+                        let mut autofire = autofire.borrow_mut();
+                        autofire.set_buttons(buttons);
+                        autofire.set_pulse(period, duty);
+                        continue;
+                    }
+                    EmulatorCommand::SetOpposingDirections(mode) => {
+                        opposing_directions.set(mode);
+                        continue;
+                    }
+                    EmulatorCommand::SetAnalogStick { deadzone, sensitivity, eight_way } => {
+                        let mut stick = analog_stick.borrow_mut();
+                        stick.set_deadzone(deadzone);
+                        stick.set_sensitivity(sensitivity);
+                        stick.set_eight_way(eight_way);
+                        continue;
+                    }
+                    EmulatorCommand::SetFastForwardAudio(mode) => {
+                        fast_forward_audio.set(mode);
+                        continue;
+                    }
+                    EmulatorCommand::SetMuteOnPause(enabled) => {
+                        mute_on_pause.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetBandLimitedAudio(enabled) => {
+                        band_limited_audio.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetHardwareFilters(enabled) => {
+                        hardware_filters.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetSilenceUltrasonicTriangle(enabled) => {
+                        silence_ultrasonic_triangle.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetSampleRate(rate) => {
+                        let format = AudioFormat { sample_rate: rate, ..audio_format.get() };
+                        if format != audio_format.get() {
+                            audio_format.set(format);
+                            let device = audio_device.borrow().clone();
+                            select_audio_device(&audio_subsystem, &audio_output, &audio_device, device, format, &osd_message, status_tx);
+                        }
+                        continue;
+                    }
+                    EmulatorCommand::SetAudioBackend(backend) => {
+                        let format = AudioFormat { backend, ..audio_format.get() };
+                        if format != audio_format.get() {
+                            audio_format.set(format);
+                            let device = audio_device.borrow().clone();
+                            select_audio_device(&audio_subsystem, &audio_output, &audio_device, device, format, &osd_message, status_tx);
+                        }
+                        continue;
+                    }
+                    EmulatorCommand::SetChannelVolume { channel, volume } => {
+                        let mut volumes = channel_volumes.get();
+                        if let Some(slot) = volumes.get_mut(channel) {
+                            *slot = volume;
+                        }
+                        channel_volumes.set(volumes);
+                        continue;
+                    }
+                    EmulatorCommand::SetSolo(channel) => {
+                        solo.set(channel);
+                        continue;
+                    }
+                    EmulatorCommand::SetTurboRate(frames) => {
+                        turbo.borrow_mut().set_rate(frames);
+                        continue;
+                    }
+                    EmulatorCommand::NetplayHost(_) | EmulatorCommand::NetplayConnect(_) => {
+                        let _ = status_tx.send(EmulatorStatus::Error(
+                            "Load the same ROM on both sides before starting netplay.".to_string(),
+                        ));
+                        continue;
+                    }
+                    EmulatorCommand::NetplayDisconnect => {
+                        continue;
+                    }
+                    EmulatorCommand::SetAudioDevice(device) => {
+                        select_audio_device(&audio_subsystem, &audio_output, &audio_device, device, audio_format.get(), &osd_message, status_tx);
+                        continue;
+                    }
+                    EmulatorCommand::SetVsync(enabled) => {
+                        vsync_enabled.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetFrameLimit(enabled) => {
+                        frame_limit_enabled.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetRegion(new_region) => {
+                        region.set(new_region);
+                        continue;
+                    }
+                    EmulatorCommand::SetOamQuirks(enabled) => {
+                        oam_quirks.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetLayerVisibility { background, sprites } => {
+                        layer_visibility.set((background, sprites));
+                        continue;
+                    }
+                    EmulatorCommand::SetExpansionDevice(device) => {
+                        expansion_device.set(device);
+                        continue;
+                    }
+                    EmulatorCommand::SetPauseOnFocusLoss(enabled) => {
+                        pause_on_focus_loss.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetBreakOnLoad(enabled) => {
+                        break_on_load.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetApuViewer(enabled) => {
+                        apu_viewer.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::SetSpectrumViewer(enabled) => {
+                        spectrum_viewer.set(enabled);
+                        continue;
+                    }
+                    EmulatorCommand::StartApuLog { .. } => {
+                        let _ = status_tx.send(EmulatorStatus::Error(
+                            "No ROM is loaded. Load a game before recording its APU writes.".to_string(),
+                        ));
+                        continue;
+                    }
+                    EmulatorCommand::StopApuLog => {
+                        continue;
+                    }
+                    EmulatorCommand::SetVideoFilter(kind) => {
+                        video_filter.set(kind);
+                        continue;
+                    }
+                    EmulatorCommand::SetBindings(new_bindings) => {
+                        *bindings.borrow_mut() = new_bindings;
+                        continue;
+                    }
+                    EmulatorCommand::SetHotkeysSuppressed(suppressed) => {
+                        hotkeys_suppressed.set(suppressed);
+                        continue;
+                    }
+                    EmulatorCommand::MovieRecord
+                    | EmulatorCommand::StartMovie { .. }
+                    | EmulatorCommand::PlayMovie(_)
+                    | EmulatorCommand::MoviePlay
+                    | EmulatorCommand::MovieStop
+                    | EmulatorCommand::MovieSeek(_)
+                    | EmulatorCommand::MovieGetInput(_)
+                    | EmulatorCommand::MovieSetInput { .. } => {
+                        debug!("Ignoring movie command, no ROM loaded.");
+                        continue;
+                    }
+                    EmulatorCommand::DebugContinue
+                    | EmulatorCommand::DebugStep
+                    | EmulatorCommand::DebugStepOver
+                    | EmulatorCommand::AddBreakpoint { .. }
+                    | EmulatorCommand::RemoveBreakpoint(_)
+                    | EmulatorCommand::DebugRefresh
+                    | EmulatorCommand::DebugReadMemory { .. } => {
+                        debug!("Ignoring debugger command, no ROM loaded.");
+                        continue;
+                    }
+                };
+
+                // A failed load leaves the thread idle and waiting for the next command
+                let rom = match loaded {
+                    Ok(rom) => rom,
+                    Err(e) => {
+                        error!("{}", e);
+                        // A failed swap from a running game leaves its window up
+                        if let Some(canvas) = game_canvas.as_mut() {
+                            canvas.window_mut().hide();
+                        }
+                        let _ = status_tx.send(EmulatorStatus::Error(e));
+                        let _ = status_tx.send(EmulatorStatus::Stopped);
+                        continue;
+                    }
+                };
+                let _ = status_tx.send(EmulatorStatus::RomLoaded);
+                (rom, game_name, None)
+            }
+        };
+
+        let mut canvas = game_canvas.take().ok_or("Game window was lost while rebuilding the canvas")?;
+        if canvas_vsync != vsync_enabled.get() {
+            canvas_vsync = vsync_enabled.get();
+            canvas = build_canvas(canvas.into_window(), canvas_vsync)?;
+        }
+        let window_canvas = Rc::new(RefCell::new(canvas));
+
+        let texture_creator = window_canvas.borrow().texture_creator();
+        let video = Rc::new(RefCell::new(VideoOutput::new(&texture_creator, video_filter.get())?));
+
+        let base_title = format!("JazzNess — {}", game_name);
+        {
+            let mut canvas = window_canvas.borrow_mut();
+            let window = canvas.window_mut();
+            let _ = window.set_title(&base_title);
+            window.show();
+        }
+
+        let frame = Rc::new(RefCell::new(Frame::new()));
+
+        let window_canvas_clone_loop = Rc::clone(&window_canvas);
+        let video_clone = Rc::clone(&video);
+        let frame_clone = Rc::clone(&frame);
+        let audio_output_clone = Rc::clone(&audio_output);
+        let osd_message_loop = Rc::clone(&osd_message);
+        let overlay_enabled_loop = Rc::clone(&overlay_enabled);
+        let input_display_loop = Rc::clone(&input_display);
+        // Position of the active movie, drawn in the corner of every frame
+        let movie_counter: Rc<Cell<Option<MovieStatus>>> = Rc::new(Cell::new(None));
+        let movie_counter_loop = Rc::clone(&movie_counter);
+        let frame_limit_loop = Rc::clone(&frame_limit_enabled);
+        let fast_forward_audio_loop = Rc::clone(&fast_forward_audio);
+        // Fraction of a sample carried between frames when decimating for raised pitch
+        let mut pitch_phase = 0.0f64;
+        let mut rate_control = AudioRateControl::new();
+        let rewind_capture_time = Rc::new(Cell::new(Duration::ZERO));
+        let rewind_capture_time_loop = Rc::clone(&rewind_capture_time);
+        let run_ahead_time = Rc::new(Cell::new(Duration::ZERO));
+        let run_ahead_time_loop = Rc::clone(&run_ahead_time);
+        let frame_output = Rc::new(Cell::new(FrameOutput::Normal));
+        let frame_output_loop = Rc::clone(&frame_output);
+        let dropped_frames = Rc::new(Cell::new(0u64));
+        let dropped_frames_loop = Rc::clone(&dropped_frames);
+        let audio_resyncs = Rc::new(Cell::new(0u64));
+        let audio_resyncs_loop = Rc::clone(&audio_resyncs);
+        let audio_queue_stats = Rc::new(Cell::new(AudioQueueStats::new()));
+        let audio_queue_stats_loop = Rc::clone(&audio_queue_stats);
+        let mut last_underrun_warning: Option<Instant> = None;
+        let apu_log_loop = Rc::clone(&apu_log);
+        let status_tx_loop = status_tx.clone();
+        let mut perf = PerfStats::new();
+        // Buttons actually held on the keyboard, before turbo is mixed in; latched into the
+        // joypads once per frame by the CPU callback
+        let genuine_buttons = Cell::new(joypad::JoypadButton::empty());
+        let player2_buttons = Cell::new(joypad::JoypadButton::empty());
+        let turbo_loop = Rc::clone(&turbo);
+        let autofire_loop = Rc::clone(&autofire);
+        // During netplay or a movie the joypads are fed once per frame by the session or the
+        // movie, never straight from the keyboard
+        let external_input = Cell::new(false);
+
+        let game_loop = move |ppu: &ppu::NesPPU,
+                              joypad: &mut joypad::Joypad,
+                              joypad2: &mut joypad::Joypad,
+                              apu: &mut apu::Apu| {
+            let output = frame_output_loop.get();
+
+            // Pulse turbo on real frames only, so run-ahead does not speed it up
+            if matches!(output, FrameOutput::Normal | FrameOutput::AudioOnly) {
+                turbo_loop.borrow_mut().tick();
+                autofire_loop.borrow_mut().tick();
+            }
+            if matches!(output, FrameOutput::Normal | FrameOutput::VideoOnly) {
+                perf.begin_frame();
+                perf.target_fps = ppu.region().frame_rate();
+                let frame_start_time = perf.frame_start();
+
+                render::render(ppu, &mut frame_clone.borrow_mut());
+                perf.timings.rewind_capture = rewind_capture_time_loop.get();
+                perf.timings.run_ahead = run_ahead_time_loop.get();
+                if overlay_enabled_loop.get() {
+                    overlay::draw_text_block(&mut frame_clone.borrow_mut(), 2, 2, &perf.overlay_lines());
+                }
+                if let Some(status) = movie_counter_loop.get() {
+                    let line = match status.mode {
+                        MovieMode::Recording => format!("REC {}", status.frame),
+                        MovieMode::Playback => format!("PLAY {}/{}", status.frame, status.len),
+                    };
+                    let lines = [line];
+                    let x = Frame::WIDTH - overlay::text_block_width(&lines) - 2;
+                    overlay::draw_text_block(&mut frame_clone.borrow_mut(), x, 2, &lines);
+                }
+                if input_display_loop.get() {
+                    let y = Frame::HEIGHT - overlay::CONTROLLER_HEIGHT - 2;
+                    let x = Frame::WIDTH - 2 * (overlay::CONTROLLER_WIDTH + 2);
+                    let mut frame = frame_clone.borrow_mut();
+                    overlay::draw_controller(&mut frame, x, y, "1", joypad.buttons());
+                    overlay::draw_controller(&mut frame, x + overlay::CONTROLLER_WIDTH + 2, y, "2", joypad2.buttons());
+                }
+                let osd = osd_message_loop.borrow();
+                if let Some((message, _)) = osd.as_ref().filter(|(_, posted)| posted.elapsed() < OSD_DURATION) {
+                    overlay::draw_text_block(&mut frame_clone.borrow_mut(), 2, Frame::HEIGHT - 9, std::slice::from_ref(message));
+                }
+                drop(osd);
+                let render_done = Instant::now();
+
+                present_frame(&window_canvas_clone_loop, &video_clone, &frame_clone.borrow());
+                let present_done = Instant::now();
+
+                // Refresh the FPS readout in the title once per second
+                if perf.end_frame(render_done - frame_start_time, present_done - render_done) {
+                    let title = format!(
+                        "{} — {:.1} fps, {:.0}%",
+                        base_title, perf.average_fps, perf.speed_percent
+                    );
+                    let _ = window_canvas_clone_loop.borrow_mut().window_mut().set_title(&title);
+                }
+            }
+
+            let mut audio_samples = apu.take_samples();
+            // Run-ahead replays frames that are thrown away; only the real ones are logged
+            let mut apu_log = apu_log_loop.borrow_mut();
+            let logged = match apu_log.as_mut() {
+                Some(log) if matches!(output, FrameOutput::Normal | FrameOutput::AudioOnly) => log.write(apu.logged_writes()),
+                _ => Ok(()),
+            };
+            if let Err(e) = logged {
+                error!("{}", e);
+                *apu_log = None;
+                apu.set_write_logging(false);
+                let _ = status_tx_loop.send(EmulatorStatus::Error(e));
+                let _ = status_tx_loop.send(EmulatorStatus::ApuLogging(false));
+            }
+            drop(apu_log);
+            apu.clear_logged_writes();
+            // A reopened device may run at a new rate; later frames are made to match it
+            apu.set_sample_rate(audio_output_clone.borrow().sample_rate());
+            if matches!(output, FrameOutput::Normal | FrameOutput::AudioOnly) && !audio_samples.is_empty() {
+                let speed = perf.speed_percent / 100.0;
+                let fast_forward = !frame_limit_loop.get() && speed > 1.0;
+                let mut queue = audio_output_clone.borrow_mut();
+                let target = queue.target_samples();
+                // Fast-forward drains or floods the queue on purpose, so only normal
+                // speed says anything about the device keeping up
+                if !fast_forward && perf.audio_queue.record(queue.queued_samples()) {
+                    let stats = perf.audio_queue;
+                    let warned_recently = last_underrun_warning.is_some_and(|t| t.elapsed() < AUDIO_UNDERRUN_WARN_INTERVAL);
+                    if stats.window_underruns >= AUDIO_UNDERRUN_WARN_COUNT && !warned_recently {
+                        last_underrun_warning = Some(Instant::now());
+                        warn!(
+                            "Audio underran {} times in the last second (queue {}-{} samples, {} resyncs so far)",
+                            stats.window_underruns, stats.min_samples, stats.max_samples, audio_resyncs_loop.get()
+                        );
+                    }
+                }
+                match fast_forward_audio_loop.get() {
+                    _ if !fast_forward => {}
+                    FastForwardAudio::Mute => audio_samples.clear(),
+                    FastForwardAudio::Pitch => {
+                        // Keep one sample for every `speed` produced
+                        audio_samples.retain(|_| {
+                            pitch_phase += 1.0;
+                            let keep = pitch_phase >= speed;
+                            if keep {
+                                pitch_phase -= speed;
+                            }
+                            keep
+                        });
+                    }
+                    // Drop this frame's sound while earlier frames are still queued,
+                    // rather than let it pile up until the queue is flushed
+                    FastForwardAudio::Skip if queue.queued_samples() > target => audio_samples.clear(),
+                    FastForwardAudio::Skip => {}
+                }
+                if !audio_samples.is_empty() {
+                    if queue.queued_samples() > queue.max_samples() {
+                        queue.clear();
+                        audio_resyncs_loop.set(audio_resyncs_loop.get() + 1);
+                    }
+                    queue.queue(&audio_samples);
+                }
+                // Fast-forward manages the queue its own way; rate control starts over after it
+                let ratio = if fast_forward {
+                    rate_control.reset(target);
+                    1.0
+                } else {
+                    rate_control.update(queue.queued_samples(), target)
+                };
+                apu.set_rate_adjust(ratio);
+            }
+            let queue = audio_output_clone.borrow();
+            perf.audio_queue_samples = queue.queued_samples();
+            perf.audio_latency = queue.latency();
+            drop(queue);
+            perf.audio_rate = apu.rate_adjust();
+            perf.audio_resyncs = audio_resyncs_loop.get();
+            audio_queue_stats_loop.set(perf.audio_queue);
+
+            // With run-ahead on, the CPU callback throttles once per host frame instead
+            if matches!(output, FrameOutput::Normal) {
+                let target_frame_time = ppu.region().frame_time();
+                let elapsed_time = perf.frame_start().elapsed();
+                if elapsed_time < target_frame_time {
+                    if frame_limit_loop.get() {
+                        std::thread::sleep(target_frame_time - elapsed_time);
+                    }
+                } else {
+                    dropped_frames_loop.set(dropped_frames_loop.get() + 1);
+                }
+            }
+            if matches!(output, FrameOutput::Normal | FrameOutput::VideoOnly) {
+                perf.resume();
+            }
+        };
+
+        let mut session_rom = Some(rom.clone());
+        let movie_header = Fm2Header {
+            rom_filename: game_name.clone(),
+            rom_checksum: movie::rom_checksum(&rom),
+        };
+        let rom_region_hint = rom.region_hint;
+        let arcade_board = match (rom.vs_system, rom.playchoice10) {
+            (true, _) => Some("VS System"),
+            (false, true) => Some("PlayChoice-10"),
+            (false, false) => None,
+        };
+        let rom_crc = apulog::rom_crc(&rom);
+        let nsf_header = rom.nsf.clone();
+        let bus = Bus::new(rom, game_loop);
+        
+        let paused_flag = bus.debugger.paused.clone();
+
+        let mut cpu = CPU::new(bus);
+        cpu.bus.set_region(region.get());
+        cpu.bus.set_oam_quirks(oam_quirks.get());
+        let (background, sprites) = layer_visibility.get();
+        cpu.bus.set_layer_visibility(background, sprites);
+        cpu.bus.apu.set_band_limited(band_limited_audio.get());
+        cpu.bus.apu.set_hardware_filters(hardware_filters.get());
+        cpu.bus.apu.set_silence_ultrasonic_triangle(silence_ultrasonic_triangle.get());
+        cpu.bus.apu.set_write_logging(apu_log.borrow().is_some());
+        cpu.bus.apu.set_sample_rate(audio_output.borrow().sample_rate());
+        for (channel, volume) in channel_volumes.get().into_iter().enumerate() {
+            cpu.bus.apu.set_channel_volume(channel, volume);
+        }
+        cpu.bus.apu.set_solo(solo.get());
+        cpu.set_trace_history(trace_history.get());
+        cpu.reset();
+        if resume_snapshot.is_none() {
+            warn_region_mismatch(rom_region_hint, region.get(), &osd_message);
+            if let Some(board) = arcade_board {
+                warn!("{} ROM: running it as a plain NES; the arcade hardware's I/O is not emulated.", board);
+                *osd_message.borrow_mut() = Some((format!("{} I/O NOT EMULATED", board.to_uppercase()), Instant::now()));
+            }
+        }
+        cpu.bus.set_expansion_device(expansion_device.get());
+        if let Some(header) = nsf_header.as_ref().filter(|_| resume_snapshot.is_none()) {
+            if header.expansion_chips != 0 {
+                warn!("NSF uses expansion sound chips ({:#04X}); only the APU channels will play.", header.expansion_chips);
+            }
+            nsf::start_song(&mut cpu, header.starting_song);
+        }
+        // Song playing on the NSF board and the CPU cycle it was started on
+        let mut nsf_song = nsf_header.as_ref().map(|header| (header.starting_song, cpu.bus.cycles()));
+        if let Some(snapshot) = resume_snapshot {
+            cpu.load_snapshot(&snapshot);
+        } else if break_on_load.get() {
+            // The run loop checks the pause flag before executing anything, so the
+            // game stops with PC on its reset vector
+            info!("Paused at reset vector ${:04X}.", cpu.program_counter);
+            paused_flag.store(true, Ordering::SeqCst);
+            *osd_message.borrow_mut() = Some((format!("PAUSED AT RESET ${:04X}", cpu.program_counter), Instant::now()));
+        }
+
+        let tracing_enabled = Rc::new(Cell::new(false));
+        let rx_clone = Arc::clone(&rx);
+        let event_pump_clone = Rc::clone(&event_pump);
+        let bindings_callback = Rc::clone(&bindings);
+        let hotkeys_suppressed_callback = Rc::clone(&hotkeys_suppressed);
+        let turbo_callback = Rc::clone(&turbo);
+        let autofire_callback = Rc::clone(&autofire);
+        let opposing_directions_callback = Rc::clone(&opposing_directions);
+        let analog_stick_callback = Rc::clone(&analog_stick);
+        let game_controllers_callback = Rc::clone(&game_controllers);
+        let game_controller_subsystem_callback = game_controller_subsystem.clone();
+        let channel_volumes_callback = Rc::clone(&channel_volumes);
+        let solo_callback = Rc::clone(&solo);
+        let fast_forward_audio_callback = Rc::clone(&fast_forward_audio);
+        let mute_on_pause_callback = Rc::clone(&mute_on_pause);
+        let band_limited_audio_callback = Rc::clone(&band_limited_audio);
+        let hardware_filters_callback = Rc::clone(&hardware_filters);
+        let silence_ultrasonic_triangle_callback = Rc::clone(&silence_ultrasonic_triangle);
+        let audio_format_callback = Rc::clone(&audio_format);
+        // Whether the audio device is currently stopped for a pause
+        let mut audio_muted = false;
+        // Keys held for each controller; their filtered state feeds genuine_buttons and player2_buttons
+        let mut dpad1 = joypad::DpadFilter::new(opposing_directions.get());
+        let mut dpad2 = joypad::DpadFilter::new(opposing_directions.get());
+        let window_canvas_clone_callback = Rc::clone(&window_canvas);
+
+        let tracing_enabled_clone = Rc::clone(&tracing_enabled);
+        let pending_command_clone = Rc::clone(&pending_command);
+        let status_tx_clone = status_tx.clone();
+        let console_rx_clone = Rc::clone(&console_rx);
+        let step_request = Cell::new(StepRequest::None);
+        let prompt_shown = Cell::new(false);
+        let overlay_enabled_callback = Rc::clone(&overlay_enabled);
+        let input_display_callback = Rc::clone(&input_display);
+        let rewind_enabled_callback = Rc::clone(&rewind_enabled);
+        let rewind_interval_callback = Rc::clone(&rewind_interval);
+        let frame_callback = Rc::clone(&frame);
+        let video_callback = Rc::clone(&video);
+        let video_filter_callback = Rc::clone(&video_filter);
+        let audio_output_callback = Rc::clone(&audio_output);
+        let audio_subsystem_callback = audio_subsystem.clone();
+        let audio_device_callback = Rc::clone(&audio_device);
+        let osd_message_callback = Rc::clone(&osd_message);
+        let vsync_enabled_callback = Rc::clone(&vsync_enabled);
+        let frame_limit_callback = Rc::clone(&frame_limit_enabled);
+        let region_callback = Rc::clone(&region);
+        let oam_quirks_callback = Rc::clone(&oam_quirks);
+        let layer_visibility_callback = Rc::clone(&layer_visibility);
+        let expansion_device_callback = Rc::clone(&expansion_device);
+        let resume_session_callback = Rc::clone(&resume_session);
+        let pause_on_focus_loss_callback = Rc::clone(&pause_on_focus_loss);
+        let break_on_load_callback = Rc::clone(&break_on_load);
+        let trace_history_callback = Rc::clone(&trace_history);
+        let apu_viewer_callback = Rc::clone(&apu_viewer);
+        let spectrum_viewer_callback = Rc::clone(&spectrum_viewer);
+        let apu_log_callback = Rc::clone(&apu_log);
+        // Set only when the pause came from losing focus, so regaining it never undoes a user pause
+        let auto_paused = Cell::new(false);
+        let run_ahead_callback = Rc::clone(&run_ahead_frames);
+        let mut last_real_frame = 0u64;
+        // Frame whose input has been pumped and latched
+        let mut last_input_frame = u64::MAX;
+        let mut host_frame_start = Instant::now();
+        let mut run_ahead_overruns = 0u32;
+        let mut pending_exports: Vec<(ExportKind, String)> = Vec::new();
+        let mut export_after_frame = 0u64;
+        let rewind_held = Cell::new(false);
+        let mut rewind_buffer_interval = rewind_interval.get();
+        let mut rewind_buffer = RewindBuffer::new(
+            (REWIND_HISTORY_FRAMES / rewind_buffer_interval) as usize,
+            REWIND_MEMORY_BUDGET,
+        );
+        let mut last_capture_frame = 0u64;
+        let mut netplay: Option<NetplaySession> = None;
+        // Hosting, while no client has connected yet
+        let mut netplay_listener: Option<NetplayListener> = None;
+        let mut last_netplay_frame = 0u64;
+        let mut stats_sent = Instant::now();
+        let mut apu_sent = Instant::now();
+        let mut spectrum_sent = Instant::now();
+        let cpu_snapshot_callback = Arc::clone(cpu_snapshot);
+        let mut snapshot_frame = None;
+        let mut movie: Option<Movie> = None;
+        let mut last_movie_frame = 0u64;
+        // Where the movie is saved when stopped; only movies started from power-on have one
+        let mut movie_path: Option<String> = None;
+        // A movie played from a file ends at its last frame instead of turning into a recording
+        let mut movie_from_file = false;
+        cpu.run_with_callback(move |cpu| { 
+
+            if snapshot_frame != Some(cpu.bus.frame_count()) {
+                snapshot_frame = Some(cpu.bus.frame_count());
+                *cpu_snapshot_callback.lock().unwrap() = Some(CpuSnapshot::of(cpu));
+            }
+
+            // Exports wait for a completed frame so they never capture a half-updated nametable
+            if !pending_exports.is_empty() && cpu.bus.frame_count() > export_after_frame {
+                for (kind, path) in pending_exports.drain(..) {
+                    if let Err(e) = export_png(cpu.bus.ppu(), kind, &path) {
+                        error!("{}", e);
+                        let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                    }
+                }
+            }
+
+            // Re-pause once a requested instruction/frame step has completed
+            match step_request.get() {
+                StepRequest::Instruction => {
+                    step_request.set(StepRequest::None);
+                    paused_flag.store(true, Ordering::SeqCst);
+                }
+                StepRequest::Frame(target) if cpu.bus.frame_count() >= target => {
+                    step_request.set(StepRequest::None);
+                    paused_flag.store(true, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+
+            loop {
+                let paused = paused_flag.load(Ordering::SeqCst);
+                let rewinding = rewind_held.get() && !paused;
+
+                // Whatever paused the game, drop the queued sound and stop the device so
+                // it goes quiet rather than looping its last buffer
+                let mute = paused && mute_on_pause_callback.get();
+                if mute != audio_muted {
+                    audio_muted = mute;
+                    let mut queue = audio_output_callback.borrow_mut();
+                    if mute {
+                        queue.pause();
+                        queue.clear();
+                    } else {
+                        queue.resume();
+                    }
+                }
+
+                if stats_sent.elapsed() >= STATS_INTERVAL {
+                    stats_sent = Instant::now();
+                    let turbo = turbo_callback.borrow();
+                    let _ = status_tx_clone.send(EmulatorStatus::Stats(EmulatorStats {
+                        frames: cpu.bus.frame_number(),
+                        cpu_cycles: cpu.bus.cycles(),
+                        scanline: cpu.bus.ppu().scanline(),
+                        audio_queue_samples: audio_output_callback.borrow().queued_samples(),
+                        audio_latency: audio_output_callback.borrow().latency(),
+                        dropped_frames: dropped_frames.get(),
+                        audio_resyncs: audio_resyncs.get(),
+                        audio_rate: cpu.bus.apu.rate_adjust(),
+                        audio_queue_stats: audio_queue_stats.get(),
+                        paused,
+                        turbo_held: turbo.held(),
+                        turbo_sticky: turbo.sticky(),
+                        cheats: cpu.bus.game_genie_code_count(),
+                    }));
+                    if let Some(active) = &movie {
+                        let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(active))));
+                    }
+                    if let (Some(header), Some((song, started))) = (&nsf_header, nsf_song) {
+                        let seconds = cpu.bus.cycles().saturating_sub(started) as f64 / cpu.bus.ppu().region().cpu_clock_hz();
+                        let _ = status_tx_clone.send(EmulatorStatus::Nsf(NsfStatus {
+                            title: header.title.clone(),
+                            song,
+                            songs: header.songs,
+                            elapsed: Duration::from_secs_f64(seconds),
+                        }));
+                    }
+                }
+                if apu_viewer_callback.get() && apu_sent.elapsed() >= APU_VIEWER_INTERVAL {
+                    apu_sent = Instant::now();
+                    let _ = status_tx_clone.send(EmulatorStatus::Apu(cpu.bus.apu.debug_snapshot()));
+                }
+                if spectrum_viewer_callback.get() && spectrum_sent.elapsed() >= SPECTRUM_INTERVAL {
+                    spectrum_sent = Instant::now();
+                    let _ = status_tx_clone.send(EmulatorStatus::AudioSamples {
+                        samples: cpu.bus.apu.recent_samples(),
+                        sample_rate: cpu.bus.apu.sample_rate(),
+                    });
+                }
+                if paused && !prompt_shown.get() {
+                    *cpu_snapshot_callback.lock().unwrap() = Some(CpuSnapshot::of(cpu));
+                    print_debug_prompt(cpu);
+                    let _ = status_tx_clone.send(EmulatorStatus::Debugger(debugger_view(cpu, paused)));
+                    prompt_shown.set(true);
+                }
+
+                match rx_clone.lock().unwrap().try_recv() {
+                    Ok(cmd @ (EmulatorCommand::LoadRom(_) | EmulatorCommand::LoadRawRom { .. } | EmulatorCommand::LoadNsf(_))) => {
+                        info!("Received new ROM, stopping current emulation.");
+                        *pending_command_clone.borrow_mut() = Some(cmd);
+                        paused_flag.store(false, Ordering::SeqCst);
+                        return false; 
+                    },
+
+                    Ok(EmulatorCommand::StopEmulation) => {
+                        info!("Closing ROM, returning to idle.");
+                        paused_flag.store(false, Ordering::SeqCst);
+                        return false;
+                    },
+
+                    Ok(EmulatorCommand::SetExpansionDevice(device)) => {
+                        debug!("Expansion device set to {}.", device.name());
+                        expansion_device_callback.set(device);
+                        cpu.bus.set_expansion_device(device);
+                        if device == ExpansionDevice::FamilyKeyboard {
+                            *osd_message_callback.borrow_mut() =
+                                Some(("KEYBOARD ON: KEYS TYPE ON THE FAMICOM".to_string(), Instant::now()));
+                        }
+                    },
+
+                    Ok(EmulatorCommand::StartApuLog { path, format }) => {
+                        match ApuLogWriter::create(&path, format, rom_crc, cpu.bus.ppu().region()) {
+                            Ok(log) => {
+                                info!("Recording APU writes to {}.", path);
+                                // Anything recorded before now belongs to the log being replaced
+                                let previous = apu_log_callback.borrow_mut().replace(log);
+                                if let Some(previous) = previous {
+                                    finish_apu_log(previous, cpu.bus.apu.logged_writes(), &status_tx_clone);
+                                }
+                                cpu.bus.apu.clear_logged_writes();
+                                cpu.bus.apu.set_write_logging(true);
+                                let _ = status_tx_clone.send(EmulatorStatus::ApuLogging(true));
+                            }
+                            Err(e) => {
+                                error!("{}", e);
+                                let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                            }
+                        }
+                    },
+
+                    Ok(EmulatorCommand::StopApuLog) => {
+                        let log = apu_log_callback.borrow_mut().take();
+                        if let Some(log) = log {
+                            finish_apu_log(log, cpu.bus.apu.logged_writes(), &status_tx_clone);
+                        }
+                        cpu.bus.apu.set_write_logging(false);
+                    },
+
+                    Ok(EmulatorCommand::NsfSelectSong(song)) => {
+                        match &nsf_header {
+                            Some(header) if song < header.songs => {
+                                debug!("Starting NSF song {} of {}.", song + 1, header.songs);
+                                nsf::start_song(cpu, song);
+                                nsf_song = Some((song, cpu.bus.cycles()));
+                            }
+                            Some(_) => debug!("Ignoring out-of-range NSF song {}.", song),
+                            None => debug!("Ignoring song selection, the game is not an NSF."),
+                        }
+                    },
+
+                    Ok(EmulatorCommand::SetFrameLimit(enabled)) => {
+                        debug!("Frame limiter set to: {}", enabled);
+                        frame_limit_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetRegion(new_region)) => {
+                        debug!("Region set to: {}", new_region.name());
+                        region_callback.set(new_region);
+                        cpu.bus.set_region(new_region);
+                        warn_region_mismatch(rom_region_hint, new_region, &osd_message_callback);
+                    },
+
+                    Ok(EmulatorCommand::SetOamQuirks(enabled)) => {
+                        debug!("OAM quirks set to: {}", enabled);
+                        oam_quirks_callback.set(enabled);
+                        cpu.bus.set_oam_quirks(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetLayerVisibility { background, sprites }) => {
+                        debug!("Layer overrides set to background {:?}, sprites {:?}.", background, sprites);
+                        layer_visibility_callback.set((background, sprites));
+                        cpu.bus.set_layer_visibility(background, sprites);
+                    },
+
+                    Ok(EmulatorCommand::SetVsync(enabled)) => {
+                        if enabled != vsync_enabled_callback.get() {
+                            debug!("VSync set to: {}, rebuilding canvas.", enabled);
+                            vsync_enabled_callback.set(enabled);
+                            if let Some(rom) = session_rom.take() {
+                                *resume_session_callback.borrow_mut() =
+                                    Some((rom, game_name.clone(), cpu.save_snapshot()));
+                            }
+                            return false;
+                        }
+                    },
+                    
+                    Ok(EmulatorCommand::SetGameGenieCodes(codes)) => {
+                        debug!("Applying Game Genie codes.");
+                        let count = codes.len();
+                        cpu.bus.set_game_genie_codes(codes);
+                        let _ = status_tx_clone.send(EmulatorStatus::GameGenieCodesApplied(count));
+                    },
+
+                    Ok(EmulatorCommand::SetFrozenAddresses(frozen)) => {
+                        debug!("Freezing {} address(es).", frozen.len());
+                        cpu.bus.set_frozen_addresses(frozen);
+                    },
+
+                    Ok(EmulatorCommand::ScheduledWrite { frame, addr, value }) => {
+                        debug!("Scheduled write of {:#04X} to {:#06X} at frame {}.", value, addr, frame);
+                        cpu.bus.schedule_write(ScheduledWrite { frame, addr, value });
+                    },
+     
+                    Ok(EmulatorCommand::Pause) => {
+                        debug!("Pausing emulator via command.");
+                        paused_flag.store(true, Ordering::SeqCst);
+                        auto_paused.set(false);
+                    },
+
+                    Ok(EmulatorCommand::DebugContinue) => {
+                        paused_flag.store(false, Ordering::SeqCst);
+                        auto_paused.set(false);
+                    },
+
+                    Ok(EmulatorCommand::DebugStep) if paused => {
+                        step_request.set(StepRequest::Instruction);
+                        paused_flag.store(false, Ordering::SeqCst);
+                    },
+
+                    Ok(EmulatorCommand::DebugStepOver) if paused => {
+                        // Run a JSR to the instruction after it; anything else is a plain step
+                        let pc = cpu.program_counter;
+                        let return_addr = pc.wrapping_add(3);
+                        if cpu.bus.peek(pc) == JSR_OPCODE && cpu.bus.debugger.get_breakpoint(return_addr).is_none() {
+                            cpu.bus.debugger.add_breakpoint(return_addr, Breakpoint::run_to());
+                        } else {
+                            step_request.set(StepRequest::Instruction);
+                        }
+                        paused_flag.store(false, Ordering::SeqCst);
+                    },
+
+                    Ok(EmulatorCommand::DebugStep | EmulatorCommand::DebugStepOver) => {
+                        debug!("Ignoring step command, the game is running.");
+                    },
+
+                    Ok(EmulatorCommand::AddBreakpoint { addr, breakpoint }) => {
+                        cpu.bus.debugger.add_breakpoint(addr, breakpoint);
+                        let _ = status_tx_clone.send(EmulatorStatus::Debugger(debugger_view(cpu, paused)));
+                    },
+
+                    Ok(EmulatorCommand::RemoveBreakpoint(addr)) => {
+                        cpu.bus.debugger.remove_breakpoint(addr);
+                        let _ = status_tx_clone.send(EmulatorStatus::Debugger(debugger_view(cpu, paused)));
+                    },
+
+                    Ok(EmulatorCommand::DebugRefresh) => {
+                        let _ = status_tx_clone.send(EmulatorStatus::Debugger(debugger_view(cpu, paused)));
+                    },
+
+                    Ok(EmulatorCommand::DebugReadMemory { start, len }) => {
+                        let bytes = (0..len).map(|i| cpu.bus.peek(start.wrapping_add(i))).collect();
+                        let _ = status_tx_clone.send(EmulatorStatus::Memory { start, bytes });
+                    },
+
+                    Ok(EmulatorCommand::SetPauseOnFocusLoss(enabled)) => {
+                        pause_on_focus_loss_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetBreakOnLoad(enabled)) => {
+                        break_on_load_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetApuViewer(enabled)) => {
+                        apu_viewer_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetSpectrumViewer(enabled)) => {
+                        spectrum_viewer_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetBindings(new_bindings)) => {
+                        *bindings_callback.borrow_mut() = new_bindings;
+                    },
+
+                    Ok(EmulatorCommand::SetHotkeysSuppressed(suppressed)) => {
+                        hotkeys_suppressed_callback.set(suppressed);
+                    },
+
+                    Ok(EmulatorCommand::SetVideoFilter(kind)) => {
+                        debug!("Video filter set to {}.", kind.name());
+                        video_filter_callback.set(kind);
+                        video_callback.borrow_mut().set_filter(kind);
+                        if paused {
+                            present_frame(&window_canvas_clone_callback, &video_callback, &frame_callback.borrow());
+                        }
+                    },
+
+                    Ok(EmulatorCommand::SetAutofire { buttons, period, duty }) => {
+                        let mut autofire = autofire_callback.borrow_mut();
+                        let toggled = autofire.buttons() ^ buttons;
+                        autofire.set_buttons(buttons);
+                        autofire.set_pulse(period, duty);
+                        debug!("Autofire set to {:#04X}, {} of {} frames.", buttons.bits(), autofire.duty(), autofire.period());
+                        let changes: Vec<String> = [(joypad::JoypadButton::BUTTON_A, "A"), (joypad::JoypadButton::BUTTON_B, "B")]
+                            .iter()
+                            .filter(|(button, _)| toggled.contains(*button))
+                            .map(|&(button, name)| format!("AUTOFIRE {} {}", name, if buttons.contains(button) { "ON" } else { "OFF" }))
+                            .collect();
+                        if !changes.is_empty() {
+                            *osd_message_callback.borrow_mut() = Some((changes.join(", "), Instant::now()));
+                        }
+                    },
+
+                    Ok(EmulatorCommand::SetFastForwardAudio(mode)) => {
+                        debug!("Fast-forward audio set to {}.", mode.name());
+                        fast_forward_audio_callback.set(mode);
+                    },
+
+                    Ok(EmulatorCommand::SetMuteOnPause(enabled)) => {
+                        mute_on_pause_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetBandLimitedAudio(enabled)) => {
+                        debug!("Band-limited audio set to {}.", enabled);
+                        band_limited_audio_callback.set(enabled);
+                        cpu.bus.apu.set_band_limited(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetHardwareFilters(enabled)) => {
+                        debug!("Hardware audio filters set to {}.", enabled);
+                        hardware_filters_callback.set(enabled);
+                        cpu.bus.apu.set_hardware_filters(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetSilenceUltrasonicTriangle(enabled)) => {
+                        debug!("Ultrasonic triangle silencing set to {}.", enabled);
+                        silence_ultrasonic_triangle_callback.set(enabled);
+                        cpu.bus.apu.set_silence_ultrasonic_triangle(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetSampleRate(rate)) => {
+                        let format = AudioFormat { sample_rate: rate, ..audio_format_callback.get() };
+                        if format != audio_format_callback.get() {
+                            audio_format_callback.set(format);
+                            let device = audio_device_callback.borrow().clone();
+                            select_audio_device(
+                                &audio_subsystem_callback,
+                                &audio_output_callback,
+                                &audio_device_callback,
+                                device,
+                                format,
+                                &osd_message_callback,
+                                &status_tx_clone,
+                            );
+                        }
+                    },
+
+                    Ok(EmulatorCommand::SetAudioBackend(backend)) => {
+                        let format = AudioFormat { backend, ..audio_format_callback.get() };
+                        if format != audio_format_callback.get() {
+                            debug!("Audio backend set to {}.", backend.name());
+                            audio_format_callback.set(format);
+                            let device = audio_device_callback.borrow().clone();
+                            select_audio_device(
+                                &audio_subsystem_callback,
+                                &audio_output_callback,
+                                &audio_device_callback,
+                                device,
+                                format,
+                                &osd_message_callback,
+                                &status_tx_clone,
+                            );
+                        }
+                    },
+
+                    Ok(EmulatorCommand::SetChannelVolume { channel, volume }) => {
+                        let mut volumes = channel_volumes_callback.get();
+                        if let Some(slot) = volumes.get_mut(channel) {
+                            *slot = volume;
+                        }
+                        channel_volumes_callback.set(volumes);
+                        cpu.bus.apu.set_channel_volume(channel, volume);
+                    },
+
+                    Ok(EmulatorCommand::SetSolo(channel)) => {
+                        solo_callback.set(channel);
+                        cpu.bus.apu.set_solo(channel);
+                    },
+
+                    Ok(EmulatorCommand::SetOpposingDirections(mode)) => {
+                        debug!("Opposing directions set to {}.", mode.name());
+                        opposing_directions_callback.set(mode);
+                        dpad1.set_mode(mode);
+                        dpad2.set_mode(mode);
+                        genuine_buttons.set(dpad1.buttons() | analog_stick_callback.borrow().buttons());
+                        player2_buttons.set(dpad2.buttons());
+                    },
 
-pub enum EmulatorCommand {
-    LoadRom(String),
-    SetGameGenieCodes(Vec<GameGenieCode>),
-    Pause,
-    SetTracing(bool),
-    SaveState(String),
-    LoadState(String),
-}
+                    Ok(EmulatorCommand::SetAnalogStick { deadzone, sensitivity, eight_way }) => {
+                        let mut stick = analog_stick_callback.borrow_mut();
+                        stick.set_deadzone(deadzone);
+                        stick.set_sensitivity(sensitivity);
+                        stick.set_eight_way(eight_way);
+                        genuine_buttons.set(dpad1.buttons() | stick.buttons());
+                    },
 
-pub fn run_emulator(rx: mpsc::Receiver<EmulatorCommand>) {
-
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
-    let audio_subsystem = sdl_context.audio().unwrap();
-
-    let window_canvas = Rc::new(RefCell::new(
-        video_subsystem
-            .window("JazzNess Emulator", 256 * 2, 240 * 2)
-            .position_centered()
-            .hidden()
-            .build()
-            .unwrap()
-            .into_canvas()
-            .present_vsync()
-            .build()
-            .unwrap()
-    ));
-
-    let texture_creator = window_canvas.borrow().texture_creator();
-    let texture = Rc::new(RefCell::new(
-        texture_creator
-            .create_texture_streaming(PixelFormatEnum::RGB24, 256, 240)
-            .unwrap(),
-    ));
-
-    let event_pump = Rc::new(RefCell::new(sdl_context.event_pump().unwrap()));
-
-    let desired_spec = AudioSpecDesired {
-        freq: Some(AUDIO_SAMPLE_RATE),
-        channels: Some(1),
-        samples: Some(AUDIO_BUFFER_SIZE),
-    };
+                    Ok(EmulatorCommand::SetTurboRate(frames)) => {
+                        turbo_callback.borrow_mut().set_rate(frames);
+                    },
 
-    let audio_queue = Rc::new(RefCell::new(
-        audio_subsystem.open_queue::<f32, _>(None, &desired_spec).unwrap()
-    ));
-    audio_queue.borrow().resume();
-
-    let mut key_map_init = HashMap::new();
-    key_map_init.insert(Keycode::S, joypad::JoypadButton::BUTTON_A);
-    key_map_init.insert(Keycode::A, joypad::JoypadButton::BUTTON_B);
-    key_map_init.insert(Keycode::Backspace, joypad::JoypadButton::SELECT);
-    key_map_init.insert(Keycode::Return, joypad::JoypadButton::START);
-    key_map_init.insert(Keycode::Up, joypad::JoypadButton::UP);
-    key_map_init.insert(Keycode::Down, joypad::JoypadButton::DOWN);
-    key_map_init.insert(Keycode::Left, joypad::JoypadButton::LEFT);
-    key_map_init.insert(Keycode::Right, joypad::JoypadButton::RIGHT);
-    let key_map = Arc::new(key_map_init);
+                    Ok(EmulatorCommand::SetRunAhead(frames)) => {
+                        debug!("Run-ahead set to {} frame(s).", frames);
+                        run_ahead_callback.set(frames);
+                        run_ahead_overruns = 0;
+                        host_frame_start = Instant::now();
+                    },
 
-    let rx = Arc::new(Mutex::new(rx));
+                    Ok(cmd @ (EmulatorCommand::ExportNametablePng(_) | EmulatorCommand::ExportPalettePng(_))) => {
+                        let (kind, path) = match cmd {
+                            EmulatorCommand::ExportNametablePng(path) => (ExportKind::Nametables, path),
+                            EmulatorCommand::ExportPalettePng(path) => (ExportKind::Palette, path),
+                            _ => unreachable!(),
+                        };
+                        // No frame will complete while paused, so export the current state right away
+                        if paused {
+                            if let Err(e) = export_png(cpu.bus.ppu(), kind, &path) {
+                                error!("{}", e);
+                                let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                            }
+                        } else {
+                            export_after_frame = cpu.bus.frame_count();
+                            pending_exports.push((kind, path));
+                        }
+                    },
 
+                    Ok(EmulatorCommand::SetTracing(enabled)) => {
+                        debug!("CPU Tracing set to: {}", enabled);
+                        tracing_enabled_clone.set(enabled);
+                    },
 
-    loop {
+                    Ok(EmulatorCommand::SetTraceHistory(enabled)) => {
+                        debug!("Instruction history set to: {}", enabled);
+                        trace_history_callback.set(enabled);
+                        cpu.set_trace_history(enabled);
+                    },
 
-        let command = match rx.lock().unwrap().recv() {
-            Ok(cmd) => cmd,
-            Err(_) => {
-                println!("Emulator Thread: Command channel closed, exiting thread.");
-                break;
-            }
-        };
+                    Ok(EmulatorCommand::SetPpuLogging(enabled)) => {
+                        debug!("PPU register logging set to: {}", enabled);
+                        cpu.bus.set_ppu_register_logging(enabled);
+                    },
 
-        let rom_path = match command {
-            EmulatorCommand::LoadRom(path) => path,
-            EmulatorCommand::SetGameGenieCodes(_) => {
-                println!("Emulator Thread: Ignoring cheat codes, no ROM loaded.");
-                continue;
-            }
-            EmulatorCommand::Pause => {
-                println!("Emulator Thread: Ignoring pause, no ROM loaded.");
-                continue;
-            }
-            EmulatorCommand::SetTracing(_) => {
-                println!("Emulator Thread: Ignoring trace command, no ROM loaded.");
-                continue;
-            }
-            EmulatorCommand::SaveState(_) | EmulatorCommand::LoadState(_) => {
-                 println!("Emulator Thread: Ignoring save/load state, no ROM loaded.");
-                continue;
-            }
-        };
+                    Ok(EmulatorCommand::SetPerfOverlay(enabled)) => {
+                        overlay_enabled_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetInputDisplay(enabled)) => {
+                        input_display_callback.set(enabled);
+                    },
+
+                    Ok(EmulatorCommand::SetRewind { enabled, interval_frames }) => {
+                        rewind_enabled_callback.set(enabled);
+                        rewind_interval_callback.set(interval_frames.max(1));
+                        if !enabled {
+                            rewind_buffer.clear();
+                        }
+                    },
+                    
+                    Ok(EmulatorCommand::SaveState(path)) => {
+                        info!("Saving state to {}", path);
+                        let snapshot = cpu.save_snapshot();
+                        match fs::File::create(&path) {
+                            Ok(file) => {
+                                if let Err(e) = bincode::serialize_into(file, &snapshot) {
+                                    let msg = format!("Failed to serialize and save state: {}", e);
+                                    error!("{}", msg);
+                                    let _ = status_tx_clone.send(EmulatorStatus::Error(msg));
+                                } else {
+                                    info!("State saved successfully.");
+                                }
+                            },
+                            Err(e) => {
+                                let msg = format!("Failed to create save file '{}': {}", path, e);
+                                error!("{}", msg);
+                                let _ = status_tx_clone.send(EmulatorStatus::Error(msg));
+                            }
+                        }
+                    },
+     
+                    Ok(EmulatorCommand::LoadState(_)) if netplay.is_some() => {
+                        let _ = status_tx_clone.send(EmulatorStatus::Error(
+                            "Loading a state would desync netplay. Disconnect first.".to_string(),
+                        ));
+                    },
+
+                    Ok(EmulatorCommand::LoadState(_)) if movie.is_some() => {
+                        let _ = status_tx_clone.send(EmulatorStatus::Error(
+                            "Loading a state would break the movie. Stop it or seek instead.".to_string(),
+                        ));
+                    },
 
-        println!("Emulator Thread: Loading ROM: {}", rom_path);
-        window_canvas.borrow_mut().window_mut().show();
+                    Ok(EmulatorCommand::NetplayHost(_) | EmulatorCommand::NetplayConnect(_)) if movie.is_some() => {
+                        let _ = status_tx_clone.send(EmulatorStatus::Error(
+                            "Stop the movie before starting netplay.".to_string(),
+                        ));
+                    },
 
-        let mut file = File::open(&rom_path)
-            .expect(&format!("Failed to open ROM file: {}", rom_path));
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
+                    Ok(EmulatorCommand::MovieRecord
+                    | EmulatorCommand::StartMovie { .. }
+                    | EmulatorCommand::PlayMovie(_)) if netplay.is_some() || netplay_listener.is_some() => {
+                        let _ = status_tx_clone.send(EmulatorStatus::Error(
+                            "Movies cannot be recorded during netplay.".to_string(),
+                        ));
+                    },
 
-        let rom = Rom::new(&buffer).unwrap();
-        let frame = Rc::new(RefCell::new(Frame::new()));
-        let target_frame_time = Duration::from_millis(1000 / 60);
+                    Ok(EmulatorCommand::MovieRecord) => {
+                        info!("Recording a new movie from the next frame.");
+                        let new_movie = Movie::new();
+                        let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(&new_movie))));
+                        movie = Some(new_movie);
+                        movie_path = None;
+                        movie_from_file = false;
+                        external_input.set(true);
+                        last_movie_frame = cpu.bus.frame_count();
+                    },
 
-        let window_canvas_clone_loop = Rc::clone(&window_canvas);
-        let texture_clone = Rc::clone(&texture);
-        let frame_clone = Rc::clone(&frame);
-        let audio_queue_clone = Rc::clone(&audio_queue);
+                    Ok(EmulatorCommand::StartMovie { path, checksums }) => {
+                        info!("Power-cycling and recording a movie to {}.", path);
+                        cpu.power_on();
+                        let mut new_movie = Movie::new();
+                        new_movie.set_record_checksums(checksums);
+                        let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(&new_movie))));
+                        movie = Some(new_movie);
+                        movie_path = Some(path);
+                        movie_from_file = false;
+                        external_input.set(true);
+                        // Frame 0 starts right now, so apply its input before the first instruction
+                        last_movie_frame = cpu.bus.frame_count().wrapping_sub(1);
+                    },
 
-        let game_loop = move |ppu: &ppu::NesPPU, _joypad: &mut joypad::Joypad, apu: &mut apu::Apu| {
-            let frame_start_time = Instant::now();
+                    Ok(EmulatorCommand::PlayMovie(path)) => {
+                        let loaded = fs::read_to_string(&path)
+                            .map_err(|e| format!("Failed to read movie {}: {}", path, e))
+                            .and_then(|text| Movie::from_fm2(&text))
+                            .and_then(|(loaded, header)| {
+                                if header.rom_checksum == movie_header.rom_checksum {
+                                    Ok(loaded)
+                                } else {
+                                    Err(format!(
+                                        "{} was recorded on a different ROM ({}).",
+                                        path, header.rom_filename
+                                    ))
+                                }
+                            });
 
-            render::render(ppu, &mut frame_clone.borrow_mut());
-            texture_clone
-                .borrow_mut()
-                .update(None, &frame_clone.borrow().data, Frame::WIDTH * 3)
-                .unwrap();
+                        match loaded {
+                            Ok(new_movie) => {
+                                info!("Power-cycling and playing {} frames from {}.", new_movie.len(), path);
+                                cpu.power_on();
+                                let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(&new_movie))));
+                                movie = Some(new_movie);
+                                movie_path = None;
+                                movie_from_file = true;
+                                external_input.set(true);
+                                last_movie_frame = cpu.bus.frame_count().wrapping_sub(1);
+                            }
+                            Err(e) => {
+                                error!("{}", e);
+                                let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                            }
+                        }
+                    },
+
+                    Ok(EmulatorCommand::MovieStop) => {
+                        info!("Movie stopped.");
+                        if let (Some(path), Some(active)) = (movie_path.take(), &movie) {
+                            match fs::write(&path, active.to_fm2(&movie_header)) {
+                                Ok(()) => {
+                                    info!("Saved {} movie frames to {}", active.len(), path);
+                                    *osd_message_callback.borrow_mut() =
+                                        Some((format!("MOVIE SAVED - {} FRAMES", active.len()), Instant::now()));
+                                }
+                                Err(e) => {
+                                    let message = format!("Failed to save movie to {}: {}", path, e);
+                                    error!("{}", message);
+                                    let _ = status_tx_clone.send(EmulatorStatus::Error(message));
+                                }
+                            }
+                        }
+                        movie = None;
+                        movie_from_file = false;
+                        movie_counter.set(None);
+                        external_input.set(netplay.is_some());
+                        let _ = status_tx_clone.send(EmulatorStatus::Movie(None));
+                    },
+
+                    Ok(cmd @ (EmulatorCommand::MoviePlay
+                    | EmulatorCommand::MovieSeek(_)
+                    | EmulatorCommand::MovieGetInput(_)
+                    | EmulatorCommand::MovieSetInput { .. })) => {
+                        let Some(active) = movie.as_mut() else {
+                            let _ = status_tx_clone.send(EmulatorStatus::Error("No movie is active.".to_string()));
+                            continue;
+                        };
+
+                        // Frames replayed by a seek are neither shown nor heard
+                        let mut seek_to = None;
+                        let result = match cmd {
+                            EmulatorCommand::MoviePlay => {
+                                active.set_mode(MovieMode::Playback);
+                                Ok(())
+                            }
+                            EmulatorCommand::MovieSeek(frame) => {
+                                seek_to = Some(frame);
+                                Ok(())
+                            }
+                            EmulatorCommand::MovieGetInput(frame) => match active.input(frame) {
+                                Some(input) => {
+                                    let _ = status_tx_clone.send(EmulatorStatus::MovieInput { frame, input });
+                                    Ok(())
+                                }
+                                None => Err(format!("Frame {} has not been recorded yet.", frame)),
+                            },
+                            EmulatorCommand::MovieSetInput { frame, input } => {
+                                // An edit behind the console invalidates its state, so replay up to here again
+                                let result = active.set_input(frame, input);
+                                if result.is_ok() && frame < active.frame() {
+                                    seek_to = Some(active.frame());
+                                }
+                                result
+                            }
+                            _ => unreachable!(),
+                        };
+
+                        let result = match (result, seek_to) {
+                            (Ok(()), Some(frame)) => {
+                                let output = frame_output.get();
+                                frame_output.set(FrameOutput::Discard);
+                                let seeked = active.seek(cpu, frame);
+                                frame_output.set(output);
+                                // The keyframe carries the debugger's pause flag from when it was taken
+                                paused_flag.store(paused, Ordering::SeqCst);
+                                if seeked.is_ok() {
+                                    // Apply the input of the frame we landed on before the next instruction
+                                    last_movie_frame = cpu.bus.frame_count().wrapping_sub(1);
+                                    render::render(cpu.bus.ppu(), &mut frame_callback.borrow_mut());
+                                    present_frame(&window_canvas_clone_callback, &video_callback, &frame_callback.borrow());
+                                }
+                                seeked
+                            }
+                            (result, _) => result,
+                        };
+
+                        if let Err(e) = result {
+                            error!("{}", e);
+                            let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                        }
+                        let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(active))));
+                    },
+
+                    Ok(EmulatorCommand::NetplayHost(port)) => {
+                        // The client is picked up between frames, further down the loop
+                        match NetplayListener::bind(port, NETPLAY_ACCEPT_TIMEOUT, netplay::DEFAULT_INPUT_DELAY) {
+                            Ok(listener) => {
+                                info!("Waiting for a netplay client on port {}...", port);
+                                let _ = status_tx_clone.send(EmulatorStatus::Netplay(Some(format!("Waiting on port {}...", port))));
+                                netplay_listener = Some(listener);
+                            }
+                            Err(e) => start_netplay(Err(e), &mut netplay, &external_input, &status_tx_clone),
+                        }
+                    },
+
+                    Ok(EmulatorCommand::NetplayConnect(addr)) => {
+                        info!("Connecting to netplay host {}...", addr);
+                        let result = NetplaySession::connect(&addr, netplay::DEFAULT_INPUT_DELAY)
+                            .and_then(|mut session| {
+                                let state = session.recv_blob()?;
+                                let snapshot: EmulatorSnapshot = bincode::deserialize(&state)
+                                    .map_err(|e| format!("Invalid state from netplay host: {}", e))?;
+                                cpu.try_load_snapshot(&snapshot)
+                                    .map_err(|e| format!("Invalid state from netplay host: {}", e))?;
+                                Ok(session)
+                            });
+                        start_netplay(result, &mut netplay, &external_input, &status_tx_clone);
+                        last_netplay_frame = cpu.bus.frame_count();
+                    },
+
+                    Ok(EmulatorCommand::SetAudioDevice(device)) => {
+                        select_audio_device(
+                            &audio_subsystem_callback,
+                            &audio_output_callback,
+                            &audio_device_callback,
+                            device,
+                            audio_format_callback.get(),
+                            &osd_message_callback,
+                            &status_tx_clone,
+                        );
+                    },
+
+                    Ok(EmulatorCommand::NetplayDisconnect) => {
+                        if netplay_listener.take().is_some() {
+                            info!("Stopped waiting for a netplay client.");
+                            let _ = status_tx_clone.send(EmulatorStatus::Netplay(None));
+                        }
+                        if netplay.take().is_some() {
+                            info!("Netplay disconnected.");
+                            external_input.set(false);
+                            let _ = status_tx_clone.send(EmulatorStatus::Netplay(None));
+                        }
+                    },
+
+                    Ok(EmulatorCommand::LoadState(path)) => {
+                        info!("Loading state from {}", path);
+                        match fs::File::open(&path) {
+                            Ok(file) => {
+                                // Decode and validate in full before anything is applied, so a
+                                // truncated or foreign file leaves the running game alone
+                                let result = bincode::deserialize_from(file)
+                                    .map_err(|e| format!("Failed to deserialize state: {}", e))
+                                    .and_then(|snapshot: EmulatorSnapshot| {
+                                        cpu.try_load_snapshot(&snapshot)
+                                            .map_err(|e| format!("State file '{}' is not usable: {}", path, e))
+                                    });
+                                match result {
+                                    Ok(()) => {
+                                        info!("State loaded successfully.");
+                                        let frozen = cpu.bus.frozen_addresses().to_vec();
+                                        let _ = status_tx_clone.send(EmulatorStatus::FrozenAddresses(frozen));
+                                    }
+                                    Err(msg) => {
+                                        error!("{}", msg);
+                                        let _ = status_tx_clone.send(EmulatorStatus::Error(msg));
+                                    }
+                                }
+                            },
+                            Err(e) => {
+                                let msg = format!("Failed to open save file '{}': {}", path, e);
+                                error!("{}", msg);
+                                let _ = status_tx_clone.send(EmulatorStatus::Error(msg));
+                            }
+                        }
+                    },
+     
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        info!("Menu closed, stopping program.");
+                        window_canvas_clone_callback.borrow_mut().window_mut().hide();
+                        std::process::exit(0);
+                    },
+                    Err(mpsc::TryRecvError::Empty) => { }
+                }
+
+                // While running, SDL events are pumped once per frame, just before the
+                // controllers are latched; paused or rewinding, on every pass
+                if paused || rewinding || cpu.bus.frame_count() != last_input_frame {
+                    for event in event_pump_clone.borrow_mut().poll_iter() {
+                        // With the Family BASIC keyboard plugged in, every key it has is
+                        // typed on it and skips hotkeys and controller bindings
+                        if let Event::KeyDown { keycode: Some(key), .. } | Event::KeyUp { keycode: Some(key), .. } = event
+                            && let Some((keyboard, (row, column, bit))) = cpu.bus.keyboard_mut().zip(bindings::family_keyboard_key(key))
+                        {
+                            keyboard.set_key(row, column, bit, matches!(event, Event::KeyDown { .. }));
+                            continue;
+                        }
+                        // Hotkeys are matched first and swallow their key; only keys left
+                        // over are translated to controller input
+                        let action = match &event {
+                            Event::KeyDown { keycode: Some(key), keymod, .. } => {
+                                let bindings = bindings_callback.borrow();
+                                let hotkey = if hotkeys_suppressed_callback.get() { None } else { bindings.hotkey(*key, *keymod) };
+                                hotkey.or_else(|| bindings.action(*key))
+                            }
+                            Event::KeyUp { keycode: Some(key), .. } => {
+                                let bindings = bindings_callback.borrow();
+                                // A held hotkey must still see its release after suppression starts
+                                if bindings.hotkey_release(*key) == Some(Action::Rewind) {
+                                    rewind_held.set(false);
+                                }
+                                bindings.action(*key)
+                            }
+                            _ => None,
+                        };
+                        match event {
+                            Event::Quit { .. } => {
+                                info!("Quit event, hiding window and stopping emulation.");
+                                paused_flag.store(false, Ordering::SeqCst);
+                                window_canvas_clone_callback.borrow_mut().window_mut().hide();
+                                return false; 
+                            },
+                            Event::KeyDown { .. } if action == Some(Action::CloseGame) => {
+                                info!("Close key pressed, hiding window and stopping emulation.");
+                                paused_flag.store(false, Ordering::SeqCst);
+                                window_canvas_clone_callback.borrow_mut().window_mut().hide();
+                                return false;
+                            },
+                            Event::KeyDown { repeat: false, .. } if action == Some(Action::ShowControls) => {
+                                let _ = status_tx_clone.send(EmulatorStatus::ShowControls);
+                            }
+                            Event::AudioDeviceAdded { iscapture: false, .. } => {
+                                let _ = status_tx_clone.send(EmulatorStatus::AudioDevices(
+                                    audio_device_names(&audio_subsystem_callback),
+                                ));
+                            }
+                            Event::AudioDeviceRemoved { iscapture: false, .. } => {
+                                let devices = audio_device_names(&audio_subsystem_callback);
+                                let selected = audio_device_callback.borrow().clone();
+                                // Re-selecting a vanished device falls back to the default
+                                if let Some(name) = selected.filter(|name| !devices.contains(name)) {
+                                    warn!("Audio device '{}' was removed.", name);
+                                    select_audio_device(
+                                        &audio_subsystem_callback,
+                                        &audio_output_callback,
+                                        &audio_device_callback,
+                                        Some(name),
+                                        audio_format_callback.get(),
+                                        &osd_message_callback,
+                                        &status_tx_clone,
+                                    );
+                                }
+                                let _ = status_tx_clone.send(EmulatorStatus::AudioDevices(devices));
+                            }
+                            Event::KeyDown { repeat: false, .. } if action == Some(Action::Pause) => {
+                                let now_paused = !paused_flag.load(Ordering::SeqCst);
+                                debug!("{} via keyboard.", if now_paused { "Paused" } else { "Resumed" });
+                                paused_flag.store(now_paused, Ordering::SeqCst);
+                                step_request.set(StepRequest::None);
+                                auto_paused.set(false);
+                            }
+                            Event::Window { win_event: WindowEvent::FocusLost, .. }
+                                if pause_on_focus_loss_callback.get() && !paused_flag.load(Ordering::SeqCst) =>
+                            {
+                                debug!("Paused on focus loss.");
+                                paused_flag.store(true, Ordering::SeqCst);
+                                auto_paused.set(true);
+                                audio_output_callback.borrow_mut().clear();
+                            }
+                            Event::Window { win_event: WindowEvent::FocusGained, .. } if auto_paused.get() => {
+                                debug!("Resumed on focus gain.");
+                                auto_paused.set(false);
+                                paused_flag.store(false, Ordering::SeqCst);
+                            }
+                            Event::MouseMotion { x, y, .. } => {
+                                let aim = window_to_frame(&window_canvas_clone_callback.borrow(), x, y);
+                                if let Some(zapper) = cpu.bus.zapper_mut() {
+                                    zapper.set_aim(aim);
+                                }
+                            }
+                            Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => {
+                                if let Some(zapper) = cpu.bus.zapper_mut() {
+                                    zapper.set_trigger(true);
+                                }
+                            }
+                            Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                                if let Some(zapper) = cpu.bus.zapper_mut() {
+                                    zapper.set_trigger(false);
+                                }
+                            }
+                            Event::ControllerDeviceAdded { which, .. } => {
+                                match game_controller_subsystem_callback.open(which) {
+                                    Ok(controller) => {
+                                        info!("Game controller connected: {}", controller.name());
+                                        game_controllers_callback.borrow_mut().push(controller);
+                                    }
+                                    Err(e) => warn!("Failed to open game controller {}: {}", which, e),
+                                }
+                            }
+                            Event::ControllerDeviceRemoved { which, .. } => {
+                                game_controllers_callback.borrow_mut().retain(|c| c.instance_id() != which);
+                            }
+                            Event::ControllerAxisMotion { axis: axis @ (Axis::LeftX | Axis::LeftY), value, .. } => {
+                                let mut stick = analog_stick_callback.borrow_mut();
+                                let position = value as f32 / i16::MAX as f32;
+                                if axis == Axis::LeftX {
+                                    stick.set_x(position);
+                                } else {
+                                    stick.set_y(position);
+                                }
+                                genuine_buttons.set(dpad1.buttons() | stick.buttons());
+                            }
+                            Event::Window { win_event: WindowEvent::Leave, .. } => {
+                                if let Some(zapper) = cpu.bus.zapper_mut() {
+                                    zapper.set_aim(None);
+                                }
+                            }
+                            Event::KeyDown { repeat: false, .. } if action == Some(Action::Rewind) => {
+                                rewind_held.set(rewind_enabled_callback.get() && netplay.is_none() && movie.is_none());
+                            }
+                            Event::KeyDown { repeat: false, .. } if action == Some(Action::PerfOverlay) => {
+                                overlay_enabled_callback.set(!overlay_enabled_callback.get());
+                            }
+                            Event::KeyDown { repeat: false, .. } if paused && action == Some(Action::StepInstruction) => {
+                                step_request.set(StepRequest::Instruction);
+                                paused_flag.store(false, Ordering::SeqCst);
+                            }
+                            Event::KeyDown { repeat: false, .. } if paused && action == Some(Action::StepFrame) => {
+                                step_request.set(StepRequest::Frame(cpu.bus.frame_count() + 1));
+                                paused_flag.store(false, Ordering::SeqCst);
+                            }
+                            Event::KeyDown { repeat: false, .. } if action.and_then(|a| a.solo()).is_some() => {
+                                let channel = action.and_then(|a| a.solo()).unwrap();
+                                solo_callback.set(channel);
+                                cpu.bus.apu.set_solo(channel);
+                                let note = match channel {
+                                    Some(channel) => format!("SOLO {}", apu::CHANNEL_NAMES[channel].to_uppercase()),
+                                    None => "ALL CHANNELS".to_string(),
+                                };
+                                *osd_message_callback.borrow_mut() = Some((note, Instant::now()));
+                                let _ = status_tx_clone.send(EmulatorStatus::Solo(channel));
+                            }
+                            Event::KeyDown { repeat: false, .. } if action.and_then(|a| a.sticky_turbo_button()).is_some() => {
+                                let button = action.and_then(|a| a.sticky_turbo_button()).unwrap();
+                                let on = turbo_callback.borrow_mut().toggle_sticky(button);
+                                debug!("Sticky turbo {}.", if on { "on" } else { "off" });
+                            }
+                            Event::KeyDown { .. } | Event::KeyUp { .. } if action.is_some() => {
+                                let pressed = matches!(event, Event::KeyDown { .. });
+                                let action = action.unwrap();
+                                if let Some(button) = action.joypad_button() {
+                                    dpad1.set_pressed(button, pressed);
+                                    genuine_buttons.set(dpad1.buttons() | analog_stick_callback.borrow().buttons());
+                                } else if let Some(button) = action
+                                    .player2_button()
+                                    .filter(|_| cpu.bus.expansion_device() != ExpansionDevice::FamilyKeyboard)
+                                {
+                                    dpad2.set_pressed(button, pressed);
+                                    player2_buttons.set(dpad2.buttons());
+                                } else if let Some(button) = action.turbo_button() {
+                                    turbo_callback.borrow_mut().set_held(button, pressed);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
 
-            let mut canvas_guard = window_canvas_clone_loop.borrow_mut();
-            canvas_guard.copy(&texture_clone.borrow(), None, None).unwrap();
-            canvas_guard.present();
+                // While rewinding, step backwards through history instead of running the CPU
+                if rewind_held.get() && !paused_flag.load(Ordering::SeqCst) {
+                    if let Some(snapshot) = rewind_buffer.pop() {
+                        cpu.load_snapshot(&snapshot);
+                        render::render(cpu.bus.ppu(), &mut frame_callback.borrow_mut());
+                        present_frame(&window_canvas_clone_callback, &video_callback, &frame_callback.borrow());
+                    }
+                    // Rewind is silent
+                    audio_output_callback.borrow_mut().clear();
+                    std::thread::sleep(cpu.bus.ppu().region().frame_time());
+                    continue;
+                }
+
+                if paused_flag.load(Ordering::SeqCst) {
+                    while let Ok(line) = console_rx_clone.try_recv() {
+                        if !handle_debug_command(cpu, &line) {
+                            debug!("Quitting from debugger.");
+                            window_canvas_clone_callback.borrow_mut().window_mut().hide();
+                            std::process::exit(0); 
+                        }
+                        prompt_shown.set(false);
+                    }
+                }
 
-            let audio_samples = apu.take_samples();
-            if !audio_samples.is_empty() {
-                if audio_queue_clone.borrow().size() > (AUDIO_BUFFER_SIZE * 2) as u32 {
-                    audio_queue_clone.borrow().clear();
+                if !paused_flag.load(Ordering::SeqCst) {
+                    prompt_shown.set(false);
+                    break;
                 }
-                audio_queue_clone.borrow().queue(&audio_samples);
+                std::thread::sleep(Duration::from_millis(10));
             }
 
-            let elapsed_time = frame_start_time.elapsed();
-            if elapsed_time < target_frame_time {
-                std::thread::sleep(target_frame_time - elapsed_time);
+            // Latch the controllers once per frame, from the events pumped above and before
+            // the NMI handler runs its first instruction
+            if cpu.bus.frame_count() != last_input_frame {
+                last_input_frame = cpu.bus.frame_count();
+                if !external_input.get() {
+                    cpu.bus.joypad1.set_buttons(player1_buttons(&turbo_callback.borrow(), &autofire_callback.borrow(), genuine_buttons.get()));
+                    cpu.bus.joypad2.set_buttons(player2_buttons.get());
+                }
             }
-        };
 
-        let bus = Bus::new(rom, game_loop);
-        
-        let paused_flag = bus.debugger.paused.clone();
+            // Lockstep: each frame waits for both players' input before it starts
+            if let Some(session) = netplay.as_mut().filter(|_| cpu.bus.frame_count() != last_netplay_frame) {
+                last_netplay_frame = cpu.bus.frame_count();
+                let local = player1_buttons(&turbo_callback.borrow(), &autofire_callback.borrow(), genuine_buttons.get()).bits();
+                let result = session.advance(local).and_then(|(player1, player2)| {
+                    cpu.bus.joypad1.set_buttons(joypad::JoypadButton::from_bits_truncate(player1));
+                    cpu.bus.joypad2.set_buttons(joypad::JoypadButton::from_bits_truncate(player2));
+                    session.check_sync(cpu)
+                });
+                if let Err(e) = result {
+                    error!("{}", e);
+                    let _ = status_tx_clone.send(EmulatorStatus::Error(e));
+                    let _ = status_tx_clone.send(EmulatorStatus::Netplay(None));
+                    netplay = None;
+                    external_input.set(false);
+                }
+            }
 
-        let mut cpu = CPU::new(bus);
-        cpu.reset();
+            // Hosting: check for the client once a frame, so the game keeps running until it
+            // connects. The client then starts from the host's exact state.
+            if let Some(listener) = netplay_listener.as_mut().filter(|_| cpu.bus.frame_count() != last_netplay_frame) {
+                last_netplay_frame = cpu.bus.frame_count();
+                let result = listener.poll().transpose().map(|result| {
+                    result.and_then(|mut session| {
+                        let state = bincode::serialize(&cpu.save_snapshot()).map_err(|e| e.to_string())?;
+                        session.send_blob(&state)?;
+                        Ok(session)
+                    })
+                });
+                if let Some(result) = result {
+                    netplay_listener = None;
+                    start_netplay(result, &mut netplay, &external_input, &status_tx_clone);
+                }
+            }
 
-        let instruction_counter = Cell::new(0u32);
-        let tracing_enabled = Rc::new(Cell::new(false));
-        let rx_clone = Arc::clone(&rx);
-        let event_pump_clone = Rc::clone(&event_pump);
-        let key_map_clone = Arc::clone(&key_map); 
-        let window_canvas_clone_callback = Rc::clone(&window_canvas);
+            // Capture a rewind snapshot every N completed frames
+            if rewind_enabled_callback.get() && !rewind_held.get() && netplay.is_none() && movie.is_none() {
+                let interval = rewind_interval_callback.get();
+                if interval != rewind_buffer_interval {
+                    rewind_buffer_interval = interval;
+                    rewind_buffer = RewindBuffer::new(
+                        (REWIND_HISTORY_FRAMES / interval) as usize,
+                        REWIND_MEMORY_BUDGET,
+                    );
+                }
 
-        let tracing_enabled_clone = Rc::clone(&tracing_enabled);
-        cpu.run_with_callback(move |cpu| { 
- 
-            while paused_flag.load(Ordering::SeqCst) {
-                if !handle_debug_prompt(cpu) {
-                    println!("Emulator Thread: Quitting from debugger.");
-                    window_canvas_clone_callback.borrow_mut().window_mut().hide();
-                    std::process::exit(0); 
+                // Run-ahead advances the frame counter by several frames at a time
+                let frame_no = cpu.bus.frame_count();
+                if frame_no >= last_capture_frame + interval as u64 {
+                    last_capture_frame = frame_no;
+                    let capture_start = Instant::now();
+                    rewind_buffer.push(cpu.save_snapshot());
+                    rewind_capture_time.set(capture_start.elapsed());
                 }
             }
- 
-            match rx_clone.lock().unwrap().try_recv() {
-                Ok(EmulatorCommand::LoadRom(_new_path)) => {
-                    println!("Emulator Thread: Received new ROM, stopping current emulation.");
-                    window_canvas_clone_callback.borrow_mut().window_mut().hide();
-                    return false; 
-                },
-                
-                Ok(EmulatorCommand::SetGameGenieCodes(codes)) => {
-                    println!("Emulator Thread: Applying Game Genie codes.");
-                    cpu.bus.set_game_genie_codes(codes);
-                },
- 
-                Ok(EmulatorCommand::Pause) => {
-                    println!("[DEBUG] Pausing emulator via command.");
-                    paused_flag.store(true, Ordering::SeqCst);
-                },
-
-                Ok(EmulatorCommand::SetTracing(enabled)) => {
-                    println!("[DEBUG] CPU Tracing set to: {}", enabled);
-                    tracing_enabled_clone.set(enabled);
-                },
-                
-                Ok(EmulatorCommand::SaveState(path)) => {
-                    println!("[DEBUG] Saving state to {}", path);
-                    let snapshot = cpu.save_snapshot();
-                    match fs::File::create(&path) {
-                        Ok(file) => {
-                            if let Err(e) = bincode::serialize_into(file, &snapshot) {
-                                println!("[ERROR] Failed to serialize and save state: {}", e);
-                            } else {
-                                println!("[DEBUG] State saved successfully.");
-                            }
-                        },
-                        Err(e) => println!("[ERROR] Failed to create save file '{}': {}", path, e),
-                    }
-                },
- 
-                Ok(EmulatorCommand::LoadState(path)) => {
-                    println!("[DEBUG] Loading state from {}", path);
-                    match fs::File::open(&path) {
-                        Ok(file) => {
-                            match bincode::deserialize_from(file) {
-                                Ok(snapshot) => {
-                                    cpu.load_snapshot(&snapshot);
-                                    println!("[DEBUG] State loaded successfully.");
-                                },
-                                Err(e) => println!("[ERROR] Failed to deserialize state: {}", e),
-                            }
-                        },
-                        Err(e) => println!("[ERROR] Failed to open save file '{}': {}", path, e),
-                    }
-                },
- 
-                Err(mpsc::TryRecvError::Disconnected) => {
-                    println!("Emulator Thread: Menu closed, stopping program.");
-                    window_canvas_clone_callback.borrow_mut().window_mut().hide();
-                    std::process::exit(0);
-                },
-                Err(mpsc::TryRecvError::Empty) => { }
+
+            // Run-ahead: after each real frame, save state, emulate the next frames with the
+            // current input, show only the last of them, then restore the real state
+            let run_ahead = run_ahead_callback.get();
+            if run_ahead == 0 || netplay.is_some() || movie.is_some() {
+                frame_output.set(FrameOutput::Normal);
+            } else if cpu.bus.frame_count() != last_real_frame
+                && !rewind_held.get()
+                && !paused_flag.load(Ordering::SeqCst)
+            {
+                let run_ahead_start = Instant::now();
+                cpu.run_ahead(run_ahead, |i| {
+                    frame_output.set(if i + 1 == run_ahead { FrameOutput::VideoOnly } else { FrameOutput::Discard });
+                });
+                frame_output.set(FrameOutput::AudioOnly);
+                last_real_frame = cpu.bus.frame_count();
+                run_ahead_time.set(run_ahead_start.elapsed());
+
+                let target_frame_time = cpu.bus.ppu().region().frame_time();
+                let host_frame_time = host_frame_start.elapsed();
+                if host_frame_time > target_frame_time {
+                    run_ahead_overruns += 1;
+                    dropped_frames.set(dropped_frames.get() + 1);
+                } else {
+                    run_ahead_overruns = 0;
+                    if frame_limit_callback.get() {
+                        std::thread::sleep(target_frame_time - host_frame_time);
+                    }
+                }
+                host_frame_start = Instant::now();
+
+                if run_ahead_overruns >= RUN_AHEAD_MAX_OVERRUNS {
+                    warn!("Run-ahead cannot sustain full speed, disabling it.");
+                    run_ahead_callback.set(0);
+                    run_ahead_overruns = 0;
+                    frame_output.set(FrameOutput::Normal);
+                    let _ = status_tx_clone.send(EmulatorStatus::RunAheadDisabled);
+                }
             }
- 
-            let count = instruction_counter.get();
-            instruction_counter.set(count + 1);
-            if count < 1000 { return true; }
-            instruction_counter.set(0);
- 
-            for event in event_pump_clone.borrow_mut().poll_iter() {
-                match event {
-                    Event::Quit { .. }
-                    | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                        println!("Emulator Thread: Quit event, hiding window and stopping emulation.");
-                        window_canvas_clone_callback.borrow_mut().window_mut().hide();
-                        return false; 
-                    },
-                    Event::KeyDown { keycode, .. } => {
-                        if let Some(keycode) = keycode {
-                            if let Some(button) = key_map_clone.get(&keycode) {
-                                cpu.bus.joypad1.set_button_pressed_status(*button, true);
-                            }
-                        }
+
+            // Movie: each frame's input is recorded into, or played back from, the movie.
+            // This runs last so a seek made while paused applies its frame's input before
+            // the next instruction executes.
+            let mut movie_finished = false;
+            if let Some(active) = movie.as_mut().filter(|_| cpu.bus.frame_count() != last_movie_frame) {
+                last_movie_frame = cpu.bus.frame_count();
+                let live = [player1_buttons(&turbo_callback.borrow(), &autofire_callback.borrow(), genuine_buttons.get()).bits(), player2_buttons.get().bits()];
+                let had_desync = active.desync().is_some();
+                let mut input = active.next_frame(cpu, live);
+
+                if let Some(frame) = active.desync().filter(|_| !had_desync) {
+                    let message = format!("Movie desynced at frame {}: the console state no longer matches the recording.", frame);
+                    error!("{}", message);
+                    *osd_message_callback.borrow_mut() = Some((format!("DESYNC AT FRAME {}", frame), Instant::now()));
+                    let _ = status_tx_clone.send(EmulatorStatus::Error(message));
+                }
+                if input.is_none() && !movie_from_file {
+                    info!("Movie playback reached the end, recording from here.");
+                    active.set_mode(MovieMode::Recording);
+                    let _ = status_tx_clone.send(EmulatorStatus::Movie(Some(movie_status(active))));
+                    input = active.next_frame(cpu, live);
+                }
+
+                match input {
+                    Some([player1, player2]) => {
+                        cpu.bus.joypad1.set_buttons(joypad::JoypadButton::from_bits_truncate(player1));
+                        cpu.bus.joypad2.set_buttons(joypad::JoypadButton::from_bits_truncate(player2));
+                        movie_counter.set(Some(movie_status(active)));
                     }
-                    Event::KeyUp { keycode, .. } => {
-                        if let Some(keycode) = keycode {
-                            if let Some(button) = key_map_clone.get(&keycode) {
-                                cpu.bus.joypad1.set_button_pressed_status(*button, false);
-                            }
-                        }
+                    None => {
+                        info!("Movie playback finished after {} frames.", active.len());
+                        *osd_message_callback.borrow_mut() = Some(("MOVIE FINISHED".to_string(), Instant::now()));
+                        movie_finished = true;
                     }
-                    _ => {}
+                }
+            }
+            // The player takes over from the movie's last frame
+            if movie_finished {
+                movie = None;
+                movie_from_file = false;
+                movie_counter.set(None);
+                external_input.set(netplay.is_some());
+                let _ = status_tx_clone.send(EmulatorStatus::Movie(None));
+                if netplay.is_none() {
+                    cpu.bus.joypad1.set_buttons(player1_buttons(&turbo_callback.borrow(), &autofire_callback.borrow(), genuine_buttons.get()));
+                    cpu.bus.joypad2.set_buttons(player2_buttons.get());
                 }
             }
  
             true 
         }, &tracing_enabled); 
 
-        audio_queue.borrow().clear();
+        audio_output.borrow_mut().clear();
+        // The session may have ended while paused with the device stopped
+        audio_output.borrow().resume();
+
+        // A rebuilt canvas carries on with the same log; any other ending closes it
+        let open_log = apu_log.borrow_mut().take();
+        if let Some(mut log) = open_log {
+            if resume_session.borrow().is_some() {
+                if let Err(e) = log.write(cpu.bus.apu.logged_writes()) {
+                    error!("{}", e);
+                }
+                *apu_log.borrow_mut() = Some(log);
+            } else {
+                finish_apu_log(log, cpu.bus.apu.logged_writes(), status_tx);
+            }
+        }
+
+        // Every other handle to the canvas lived in the CPU and its callbacks
+        drop(cpu);
+        let mut canvas = Rc::try_unwrap(window_canvas)
+            .map_err(|_| "Game canvas is still in use after emulation stopped")?
+            .into_inner();
+        let resuming = resume_session.borrow().is_some();
+        // A ROM swap keeps the window up, so the next game replaces this one without a flicker
+        let swapping = pending_command.borrow().is_some();
+        if !resuming && !swapping {
+            canvas.window_mut().hide();
+        }
+        game_canvas = Some(canvas);
+
+        // Only report idle when no replacement ROM is about to be loaded
+        if !swapping && !resuming {
+            let _ = status_tx.send(EmulatorStatus::Stopped);
+        }
+    }
+
+    Ok(())
+}
+
+
+fn build_canvas(window: Window, vsync: bool) -> Result<WindowCanvas, String> {
+    let mut builder = window.into_canvas();
+    if vsync {
+        builder = builder.present_vsync();
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+fn present_frame(canvas: &RefCell<WindowCanvas>, video: &RefCell<VideoOutput>, frame: &Frame) {
+    let mut canvas = canvas.borrow_mut();
+    let mut video = video.borrow_mut();
+
+    let output_height = canvas.output_size().map(|(_, h)| h as usize).unwrap_or(Frame::HEIGHT);
+    let scale = (output_height / Frame::HEIGHT).clamp(FILTER_MIN_SCALE, FILTER_MAX_SCALE);
+    video.upload(frame, scale);
+
+    canvas.copy(&video.texture, None, None).unwrap();
+    canvas.present();
+}
+
+/// Maps a mouse position in the game window to the pixel of the NES picture under it.
+/// The picture is stretched over the whole window, so this is a plain rescale.
+fn window_to_frame(canvas: &WindowCanvas, x: i32, y: i32) -> Option<(usize, usize)> {
+    let (width, height) = canvas.window().size();
+    if x < 0 || y < 0 || x as u32 >= width || y as u32 >= height {
+        return None;
+    }
+    Some((
+        x as usize * Frame::WIDTH / width as usize,
+        y as usize * Frame::HEIGHT / height as usize,
+    ))
+}
+
+fn audio_device_names(audio: &AudioSubsystem) -> Vec<String> {
+    let count = audio.num_audio_playback_devices().unwrap_or(0);
+    (0..count)
+        .filter_map(|index| audio.audio_playback_device_name(index).ok())
+        .collect()
+}
+
+// Reopens the shared output on `device`; if it cannot be opened, falls back to the
+// default device and leaves a note on screen instead of failing
+fn select_audio_device(
+    audio: &AudioSubsystem,
+    queue: &RefCell<AudioOutput>,
+    selected: &RefCell<Option<String>>,
+    device: Option<String>,
+    format: AudioFormat,
+    osd_message: &RefCell<Option<(String, Instant)>>,
+    status_tx: &mpsc::Sender<EmulatorStatus>,
+) {
+    let opened = match AudioOutput::open(audio, device.as_deref(), format) {
+        Ok(new_queue) => Ok((new_queue, device)),
+        Err(e) => {
+            error!("Failed to open audio device {:?}: {}", device, e);
+            *osd_message.borrow_mut() = Some(("AUDIO DEVICE LOST - USING DEFAULT".to_string(), Instant::now()));
+            AudioOutput::open(audio, None, format).map(|new_queue| (new_queue, None))
+        }
+    };
+
+    match opened {
+        Ok((new_queue, device)) => {
+            info!(
+                "Audio output on {} at {} Hz ({:?} backend).",
+                device.as_deref().unwrap_or("default device"),
+                new_queue.sample_rate(),
+                new_queue.backend()
+            );
+            *queue.borrow_mut() = new_queue;
+            *selected.borrow_mut() = device.clone();
+            let _ = status_tx.send(EmulatorStatus::AudioDevice(device));
+        }
+        Err(e) => {
+            let _ = status_tx.send(EmulatorStatus::Error(format!("No audio output available: {}", e)));
+        }
+    }
+}
+
+fn movie_status(movie: &Movie) -> MovieStatus {
+    MovieStatus {
+        frame: movie.frame(),
+        len: movie.len(),
+        mode: movie.mode(),
+        desync: movie.desync(),
+    }
+}
+
+fn start_netplay(
+    result: Result<NetplaySession, String>,
+    netplay: &mut Option<NetplaySession>,
+    external_input: &Cell<bool>,
+    status_tx: &mpsc::Sender<EmulatorStatus>,
+) {
+    match result {
+        Ok(session) => {
+            let player = match session.local_player() {
+                Player::One => 1,
+                Player::Two => 2,
+            };
+            info!("Netplay connected as player {}.", player);
+            let _ = status_tx.send(EmulatorStatus::Netplay(Some(format!("Connected as player {}", player))));
+            *netplay = Some(session);
+            external_input.set(true);
+        }
+        Err(e) => {
+            error!("{}", e);
+            let _ = status_tx.send(EmulatorStatus::Error(e));
+            let _ = status_tx.send(EmulatorStatus::Netplay(None));
+        }
+    }
+}
+
+fn export_png(ppu: &ppu::NesPPU, kind: ExportKind, path: &str) -> Result<(), String> {
+    let (data, width, height) = match kind {
+        ExportKind::Nametables => (
+            render::render_nametables(ppu),
+            render::NAMETABLES_WIDTH,
+            render::NAMETABLES_HEIGHT,
+        ),
+        ExportKind::Palette => (
+            render::render_palette(ppu),
+            render::PALETTE_WIDTH,
+            render::PALETTE_HEIGHT,
+        ),
+    };
+
+    image::save_buffer(path, &data, width as u32, height as u32, image::ColorType::Rgb8)
+        .map_err(|e| format!("Failed to write PNG '{}': {}", path, e))?;
+    info!("Exported PNG to {}", path);
+    Ok(())
+}
+
+/// Controller 1 as the game sees it: autofire pulses the held buttons, then turbo adds its own pulse.
+fn player1_buttons(turbo: &joypad::Turbo, autofire: &joypad::Autofire, genuine: joypad::JoypadButton) -> joypad::JoypadButton {
+    turbo.apply(autofire.apply(genuine))
+}
+
+/// Tells the user when the ROM header asks for a different region than the one selected.
+fn warn_region_mismatch(hint: Option<Region>, selected: Region, osd_message: &RefCell<Option<(String, Instant)>>) {
+    if let Some(hint) = hint.filter(|&hint| hint != selected) {
+        warn!("ROM header suggests {} but the console is running as {}.", hint.name(), selected.name());
+        *osd_message.borrow_mut() = Some((format!("ROM IS {}, RUNNING {}", hint.name(), selected.name()), Instant::now()));
+    }
+}
+
+fn game_name_from_path(path: &str) -> String {
+    std::path::Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("Unknown")
+        .to_string()
+}
+
+fn load_ines_rom(rom_path: &str) -> Result<Rom, String> {
+    let mut file = File::open(rom_path)
+        .map_err(|e| format!("Failed to open ROM file '{}': {}", rom_path, e))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .map_err(|e| format!("Failed to read ROM file '{}': {}", rom_path, e))?;
+
+    Rom::new(&buffer).map_err(|e| format!("Failed to load ROM '{}': {}", rom_path, e))
+}
+
+// Writes the APU's remaining buffered writes, closes the log and tells the GUI it has stopped
+fn finish_apu_log(mut log: ApuLogWriter, pending: &[ApuWrite], status_tx: &mpsc::Sender<EmulatorStatus>) {
+    match log.write(pending).and_then(|()| log.finish()) {
+        Ok(writes) => info!("APU log closed after {} writes.", writes),
+        Err(e) => {
+            error!("{}", e);
+            let _ = status_tx.send(EmulatorStatus::Error(e));
+        }
     }
+    let _ = status_tx.send(EmulatorStatus::ApuLogging(false));
 }
 
+fn load_nsf(nsf_path: &str) -> Result<Rom, String> {
+    let raw = fs::read(nsf_path)
+        .map_err(|e| format!("Failed to read NSF file '{}': {}", nsf_path, e))?;
+    Rom::from_nsf(&raw).map_err(|e| format!("Failed to load NSF '{}': {}", nsf_path, e))
+}
+
+fn load_raw_rom(prg_path: &str, chr_path: Option<&str>, mirroring: Mirroring) -> Result<Rom, String> {
+    let prg = fs::read(prg_path)
+        .map_err(|e| format!("Failed to read PRG file '{}': {}", prg_path, e))?;
+    let chr = match chr_path {
+        Some(path) => Some(
+            fs::read(path).map_err(|e| format!("Failed to read CHR file '{}': {}", path, e))?,
+        ),
+        None => None,
+    };
+    Rom::from_raw(&prg, chr.as_deref(), mirroring)
+}
+
+// Reads stdin on its own thread so a paused emulator can keep servicing the SDL window
+fn spawn_console_reader() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        loop {
+            let mut input = String::new();
+            match io::stdin().read_line(&mut input) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(input).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
+
+fn debugger_view(cpu: &CPU, paused: bool) -> DebuggerView {
+    let read = |addr| cpu.bus.peek(addr);
+    let mut breakpoints: Vec<(u16, Breakpoint)> = cpu
+        .bus
+        .debugger
+        .get_breakpoints()
+        .into_iter()
+        .filter_map(|addr| cpu.bus.debugger.get_breakpoint(addr).map(|bp| (addr, bp)))
+        .collect();
+    breakpoints.sort_by_key(|&(addr, _)| addr);
+    DebuggerView {
+        paused,
+        registers: CpuSnapshot::of(cpu),
+        disassembly: debugger::disassemble_around(&read, cpu.program_counter, DISASSEMBLY_BEFORE, DISASSEMBLY_AFTER),
+        breakpoints,
+    }
+}
 
-fn handle_debug_prompt(cpu: &mut CPU) -> bool {
-    println!("[DEBUG] Breakpoint HIT. Last instruction executed:");
+fn print_debug_prompt(cpu: &CPU) {
+    // Only a breakpoint gets the whole history; a step or a user pause would repeat it
+    if cpu.bus.debugger.take_breakpoint_hit()
+        && let Some(history) = cpu.trace_history_text()
+    {
+        println!("[DEBUG] {}", history);
+    }
+    println!("[DEBUG] Emulator paused. Last instruction executed:");
     if cpu.last_instruction_trace.is_empty() {
         println!("{}", cpu.trace());
     } else {
         println!("{}", cpu.last_instruction_trace);
     }
 
-    print!("[DEBUG] (c)ontinue, (q)uit, (bp add|rem|list <addr>), (r <addr>), (w <addr> <val>): ");
+    println!("[DEBUG] Window keys: Space = resume, N = step instruction, F = step frame");
+    print!("[DEBUG] (c)ontinue, (q)uit, (bp add|rem|list <addr>), (run <addr>), (bp-jsr), (bp-op [rem] <name>), (apu), (dump-history), (r <addr>), (w <addr> <val>): ");
     io::stdout().flush().unwrap(); 
+}
 
-    let mut input = String::new();
-    if io::stdin().read_line(&mut input).is_err() {
-        println!("[DEBUG] Error reading input.");
-        return true; 
+fn print_apu_state(info: &apu::ApuDebugInfo) {
+    println!("[DEBUG] APU state:");
+    println!("  {:<9} {:>3} {:>6} {:>6} {:>4} {:>4} {:>4} {:>6} {:>4}", "channel", "on", "period", "timer", "len", "vol", "duty", "sweep", "out");
+    for (name, ch) in apu::CHANNEL_NAMES.iter().zip(&info.channels) {
+        println!(
+            "  {:<9} {:>3} {:>6} {:>6} {:>4} {:>4} {:>4} {:>6} {:>4}",
+            name, if ch.enabled { "yes" } else { "no" }, ch.period, ch.timer, ch.length_counter, ch.volume, ch.duty, ch.sweep_target, ch.output
+        );
     }
+    println!(
+        "  DMC {} bytes left at {:#06X}; frame counter {}-step at cycle {}; IRQ frame {} DMC {}",
+        info.dmc_bytes_remaining, info.dmc_address, info.frame_counter_steps, info.frame_counter_cycle, info.frame_interrupt, info.dmc_interrupt
+    );
+}
 
+fn handle_debug_command(cpu: &mut CPU, input: &str) -> bool {
     let parts: Vec<&str> = input.trim().split_whitespace().collect();
 
     match parts.as_slice() {
@@ -320,6 +2793,7 @@ fn handle_debug_prompt(cpu: &mut CPU) -> bool {
         ["bp", "add", addr_str, "r"] => parse_and_add_bp(&mut cpu.bus, addr_str, Breakpoint::on_read()),
         ["bp", "add", addr_str, "w"] => parse_and_add_bp(&mut cpu.bus, addr_str, Breakpoint::on_write()),
         ["bp", "add", addr_str, "rw"] => parse_and_add_bp(&mut cpu.bus, addr_str, Breakpoint::on_rw()),
+        ["bp", "add", addr_str, "x"] => parse_and_add_bp(&mut cpu.bus, addr_str, Breakpoint::on_execute()),
         ["bp", "add", addr_str] => {
              println!("[DEBUG] Defaulting to Read/Write breakpoint.");
              parse_and_add_bp(&mut cpu.bus, addr_str, Breakpoint::on_rw())
@@ -332,10 +2806,55 @@ fn handle_debug_prompt(cpu: &mut CPU) -> bool {
         ["bp", "list"] => {
             println!("[DEBUG] Active Breakpoints:");
             for addr in cpu.bus.debugger.get_breakpoints() {
-                println!("  - {:#06X}", addr);
+                let bp = cpu.bus.debugger.get_breakpoint(addr).unwrap_or_default();
+                let kinds: String = [(bp.on_read, 'r'), (bp.on_write, 'w'), (bp.on_execute, 'x')]
+                    .iter()
+                    .filter_map(|&(set, c)| set.then_some(c))
+                    .collect();
+                println!("  - {:#06X} {}{}", addr, kinds, if bp.one_shot { " (one-shot)" } else { "" });
             }
+            for name in cpu.bus.debugger.get_opcode_breakpoints() {
+                println!("  - opcode {}", name);
+            }
+        }
+
+        // Run to an address: temporary execute breakpoint, then resume
+        ["run", addr_str] => {
+            if let Some(addr) = parse_address(addr_str) {
+                cpu.bus.debugger.add_breakpoint(addr, Breakpoint::run_to());
+                println!("[DEBUG] ...running to {:#06X}", addr);
+                cpu.bus.debugger.paused.store(false, Ordering::SeqCst);
+            }
+        }
+        // Break on an instruction by mnemonic anywhere, e.g. `bp-op BRK` or `bp-op *KIL`
+        ["bp-op", "rem", name] => {
+            if !cpu.bus.debugger.remove_opcode_breakpoint(name) {
+                println!("[DEBUG] No opcode breakpoint on '{}'", name);
+            }
+        }
+        ["bp-op", name] => {
+            if CPU_OPCODES.iter().any(|op| op.name.eq_ignore_ascii_case(name)) {
+                cpu.bus.debugger.add_opcode_breakpoint(name);
+            } else {
+                println!("[DEBUG] Unknown opcode '{}'; unofficial ones start with '*', e.g. *KIL", name);
+            }
+        }
+        ["bp-jsr"] => {
+            cpu.bus.debugger.break_on_next_jsr();
+            println!("[DEBUG] ...resuming");
+            cpu.bus.debugger.paused.store(false, Ordering::SeqCst);
         }
         
+        ["apu"] => print_apu_state(&cpu.bus.apu.debug_snapshot()),
+
+        ["dump-history"] => {
+            if let Some(history) = cpu.trace_history_text() {
+                println!("[DEBUG] {}", history);
+            } else {
+                println!("[DEBUG] Instruction history is off; turn on Debug > Keep Instruction History");
+            }
+        }
+
         ["r" | "read", addr_str] => {
             if let Some(addr) = parse_address(addr_str) {
                 let val = cpu.bus.mem_read_readonly(addr);
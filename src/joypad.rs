@@ -21,6 +21,7 @@ pub struct JoypadState {
     strobe: bool,
     button_index: u8,
     button_status: u8, // Store the raw bits
+    shift_register: u8,
 }
 // --- END STRUCT ---
 
@@ -28,6 +29,8 @@ pub struct Joypad {
     strobe: bool,     
     button_index: u8,  
     button_status: JoypadButton,
+    // Buttons latched when strobe went low; reads shift out of this, not the live state
+    shift_register: u8,
 }
 
 impl Joypad {
@@ -36,6 +39,7 @@ impl Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::empty(),
+            shift_register: 0,
         }
     }
 
@@ -43,31 +47,47 @@ impl Joypad {
         self.button_status.set(button, pressed);
     }
 
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.button_status
+    }
+
     pub fn write(&mut self, data: u8) {
-        self.strobe = data & 1 == 1;
+        let strobe = data & 1 == 1;
+        if self.strobe && !strobe {
+            // Falling edge: the buttons are captured now and later presses wait for the next strobe
+            self.shift_register = self.button_status.bits();
+            self.button_index = 0;
+        }
+        self.strobe = strobe;
         if self.strobe {
             self.button_index = 0;
         }
     }
 
     pub fn read(&mut self) -> u8 {
-        if self.button_index > 7 {
-            return 0x41;
-        }
-        
-        let response = (self.button_status.bits() >> self.button_index) & 1;
-
-        if !self.strobe {
+        let data = self.peek();
+        if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
-        0x40 | response
+        data
     }
 
+    /// The value the next `read` returns, without advancing the shift register.
+    /// While strobe is high the register keeps reloading, so every read reports the
+    /// live A button; after all 8 buttons have been shifted out, reads return 1.
     pub fn peek(&self) -> u8 {
-        if self.button_index > 7 {
-            return 1;
-        }
-        (self.button_status.bits() >> self.button_index) & 1
+        let response = if self.strobe {
+            self.button_status.bits() & 1
+        } else if self.button_index > 7 {
+            1
+        } else {
+            (self.shift_register >> self.button_index) & 1
+        };
+        0x40 | response
     }
 
     // --- ADD THESE METHODS ---
@@ -76,6 +96,7 @@ impl Joypad {
             strobe: self.strobe,
             button_index: self.button_index,
             button_status: self.button_status.bits(),
+            shift_register: self.shift_register,
         }
     }
 
@@ -83,6 +104,450 @@ impl Joypad {
         self.strobe = state.strobe;
         self.button_index = state.button_index;
         self.button_status = JoypadButton::from_bits_truncate(state.button_status);
+        self.shift_register = state.shift_register;
     }
     // --- END METHODS ---
-}
\ No newline at end of file
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the controller reports when both keys of a d-pad axis are held, which a real
+/// d-pad cannot do and which puts many games into glitched states.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OpposingDirections {
+    /// Both directions are reported.
+    Allow,
+    /// Neither direction is reported while both are held.
+    Block,
+    /// Only the direction pressed most recently is reported.
+    #[default]
+    LastPressed,
+}
+
+impl OpposingDirections {
+    pub const ALL: [OpposingDirections; 3] =
+        [OpposingDirections::Allow, OpposingDirections::Block, OpposingDirections::LastPressed];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpposingDirections::Allow => "Allow",
+            OpposingDirections::Block => "Block",
+            OpposingDirections::LastPressed => "Last Pressed Wins",
+        }
+    }
+}
+
+/// Turns the keys held for one controller into its buttons, resolving Left+Right and
+/// Up+Down according to an `OpposingDirections` mode.
+pub struct DpadFilter {
+    mode: OpposingDirections,
+    held: JoypadButton,
+    // Direction of each axis pressed most recently
+    last_pressed: JoypadButton,
+}
+
+impl DpadFilter {
+    const AXES: [JoypadButton; 2] = [
+        JoypadButton::LEFT.union(JoypadButton::RIGHT),
+        JoypadButton::UP.union(JoypadButton::DOWN),
+    ];
+
+    pub fn new(mode: OpposingDirections) -> Self {
+        DpadFilter {
+            mode,
+            held: JoypadButton::empty(),
+            last_pressed: JoypadButton::empty(),
+        }
+    }
+
+    pub fn mode(&self) -> OpposingDirections {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: OpposingDirections) {
+        self.mode = mode;
+    }
+
+    pub fn set_pressed(&mut self, button: JoypadButton, pressed: bool) {
+        self.held.set(button, pressed);
+        if pressed {
+            for axis in Self::AXES.into_iter().filter(|axis| axis.contains(button)) {
+                self.last_pressed = (self.last_pressed - axis) | button;
+            }
+        }
+    }
+
+    /// Held buttons after the opposing-direction rule is applied.
+    pub fn buttons(&self) -> JoypadButton {
+        let mut buttons = self.held;
+        for axis in Self::AXES.into_iter().filter(|&axis| self.held.contains(axis)) {
+            match self.mode {
+                OpposingDirections::Allow => {}
+                OpposingDirections::Block => buttons -= axis,
+                OpposingDirections::LastPressed => buttons -= axis - self.last_pressed,
+            }
+        }
+        buttons
+    }
+}
+
+/// Maps an analog stick onto the d-pad. Deflection inside the deadzone radius presses
+/// nothing; outside it the stick angle picks one of 4 or 8 directions. A direction is
+/// kept until the angle moves `HYSTERESIS_DEGREES` past the edge of its sector, so a
+/// stick resting on a boundary does not flicker between neighbours.
+pub struct AnalogToDpad {
+    deadzone: f32,
+    sensitivity: f32,
+    eight_way: bool,
+    x: f32,
+    y: f32,
+    // Index into DIRECTIONS of the direction being pressed
+    direction: Option<usize>,
+}
+
+impl AnalogToDpad {
+    pub const DEFAULT_DEADZONE: f32 = 0.3;
+    pub const DEFAULT_SENSITIVITY: f32 = 1.0;
+    const HYSTERESIS_DEGREES: f32 = 10.0;
+    // Counter-clockwise from right in 45 degree steps
+    const DIRECTIONS: [JoypadButton; 8] = [
+        JoypadButton::RIGHT,
+        JoypadButton::UP.union(JoypadButton::RIGHT),
+        JoypadButton::UP,
+        JoypadButton::UP.union(JoypadButton::LEFT),
+        JoypadButton::LEFT,
+        JoypadButton::DOWN.union(JoypadButton::LEFT),
+        JoypadButton::DOWN,
+        JoypadButton::DOWN.union(JoypadButton::RIGHT),
+    ];
+
+    pub fn new() -> Self {
+        AnalogToDpad {
+            deadzone: Self::DEFAULT_DEADZONE,
+            sensitivity: Self::DEFAULT_SENSITIVITY,
+            eight_way: true,
+            x: 0.0,
+            y: 0.0,
+            direction: None,
+        }
+    }
+
+    /// Sets the deadzone radius as a fraction of full deflection, up to 0.9.
+    pub fn set_deadzone(&mut self, deadzone: f32) {
+        self.deadzone = deadzone.clamp(0.0, 0.9);
+        self.update();
+    }
+
+    /// Sets how much the deflection is scaled before the deadzone test; higher values
+    /// register a press with less stick travel.
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity.clamp(0.5, 2.0);
+        self.update();
+    }
+
+    /// Chooses between 8 directions and cardinal directions only.
+    pub fn set_eight_way(&mut self, eight_way: bool) {
+        self.eight_way = eight_way;
+        self.direction = None;
+        self.update();
+    }
+
+    /// Horizontal axis from -1.0 (left) to 1.0 (right).
+    pub fn set_x(&mut self, x: f32) {
+        self.x = x.clamp(-1.0, 1.0);
+        self.update();
+    }
+
+    /// Vertical axis from -1.0 (up) to 1.0 (down), the way SDL reports it.
+    pub fn set_y(&mut self, y: f32) {
+        self.y = y.clamp(-1.0, 1.0);
+        self.update();
+    }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.direction.map_or(JoypadButton::empty(), |d| Self::DIRECTIONS[d])
+    }
+
+    fn update(&mut self) {
+        if self.x.hypot(self.y) * self.sensitivity < self.deadzone {
+            self.direction = None;
+            return;
+        }
+        let angle = (-self.y).atan2(self.x).to_degrees().rem_euclid(360.0);
+        let sector = if self.eight_way { 45.0 } else { 90.0 };
+        if let Some(current) = self.direction {
+            let offset = (angle - current as f32 * 45.0 + 180.0).rem_euclid(360.0) - 180.0;
+            if offset.abs() <= sector / 2.0 + Self::HYSTERESIS_DEGREES {
+                return;
+            }
+        }
+        let step = (sector / 45.0) as usize;
+        self.direction = Some((angle / sector).round() as usize * step % Self::DIRECTIONS.len());
+    }
+}
+
+impl Default for AnalogToDpad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Autofire for the A/B buttons. Held turbo pulses while its key is down; sticky turbo
+/// is toggled on and off and keeps pulsing in between. Both share the same pulse rate.
+pub struct Turbo {
+    held: JoypadButton,
+    sticky: JoypadButton,
+    rate: u32,
+    frame: u32,
+}
+
+impl Turbo {
+    pub const DEFAULT_RATE: u32 = 2;
+
+    pub fn new() -> Self {
+        Turbo {
+            held: JoypadButton::empty(),
+            sticky: JoypadButton::empty(),
+            rate: Self::DEFAULT_RATE,
+            frame: 0,
+        }
+    }
+
+    /// Sets how many frames each pressed/released phase of the pulse lasts.
+    pub fn set_rate(&mut self, frames: u32) {
+        self.rate = frames.max(1);
+    }
+
+    pub fn set_held(&mut self, button: JoypadButton, held: bool) {
+        self.held.set(button, held);
+    }
+
+    /// Flips sticky turbo for `button`, returning whether it is now on.
+    pub fn toggle_sticky(&mut self, button: JoypadButton) -> bool {
+        self.sticky.toggle(button);
+        self.sticky.contains(button)
+    }
+
+    pub fn held(&self) -> JoypadButton {
+        self.held
+    }
+
+    pub fn sticky(&self) -> JoypadButton {
+        self.sticky
+    }
+
+    /// Advances the pulse by one emulated frame.
+    pub fn tick(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Combines genuine presses with the turbo pulse. A genuinely held button stays
+    /// pressed, and a button whose turbo is off falls back to its genuine state.
+    pub fn apply(&self, genuine: JoypadButton) -> JoypadButton {
+        let pulse_on = (self.frame / self.rate).is_multiple_of(2);
+        if pulse_on {
+            genuine | self.held | self.sticky
+        } else {
+            genuine
+        }
+    }
+}
+
+impl Default for Turbo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Autofire on the normal buttons. While a button with autofire on is held, it is
+/// pressed for `duty` frames out of every `period` and released for the rest.
+pub struct Autofire {
+    buttons: JoypadButton,
+    period: u32,
+    duty: u32,
+    frame: u32,
+}
+
+impl Autofire {
+    pub const DEFAULT_PERIOD: u32 = 4;
+    pub const DEFAULT_DUTY: u32 = 2;
+
+    pub fn new() -> Self {
+        Autofire {
+            buttons: JoypadButton::empty(),
+            period: Self::DEFAULT_PERIOD,
+            duty: Self::DEFAULT_DUTY,
+            frame: 0,
+        }
+    }
+
+    /// Buttons that pulse while held.
+    pub fn buttons(&self) -> JoypadButton {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.buttons = buttons;
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub fn duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Sets the pulse length in frames and how many of them are pressed. The duty is
+    /// kept between 1 and `period - 1` so the button is never stuck on or off.
+    pub fn set_pulse(&mut self, period: u32, duty: u32) {
+        self.period = period.max(2);
+        self.duty = duty.clamp(1, self.period - 1);
+    }
+
+    /// Advances the pulse by one emulated frame.
+    pub fn tick(&mut self) {
+        self.frame = self.frame.wrapping_add(1);
+    }
+
+    /// Releases held autofire buttons during the off part of the pulse. Buttons without
+    /// autofire pass through unchanged.
+    pub fn apply(&self, pressed: JoypadButton) -> JoypadButton {
+        if self.frame % self.period < self.duty {
+            pressed
+        } else {
+            pressed - self.buttons
+        }
+    }
+}
+
+impl Default for Autofire {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(joypad: &mut Joypad, count: usize) -> Vec<u8> {
+        (0..count).map(|_| joypad.read() & 1).collect()
+    }
+
+    #[test]
+    fn strobe_held_high_reports_the_live_a_button() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::BUTTON_B | JoypadButton::START);
+        joypad.write(1);
+        assert_eq!(read_bits(&mut joypad, 4), [0, 0, 0, 0]);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        assert_eq!(read_bits(&mut joypad, 4), [1, 1, 1, 1]);
+        assert_eq!(joypad.read(), 0x41);
+    }
+
+    #[test]
+    fn reads_after_eight_bits_return_1() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::BUTTON_A | JoypadButton::SELECT | JoypadButton::RIGHT);
+        joypad.write(1);
+        joypad.write(0);
+        // Presses after the strobe falls wait for the next one
+        joypad.set_buttons(JoypadButton::all());
+        assert_eq!(read_bits(&mut joypad, 12), [1, 0, 1, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn restrobing_mid_read_starts_over() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(JoypadButton::BUTTON_B | JoypadButton::UP);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_bits(&mut joypad, 3), [0, 1, 0]);
+        joypad.set_buttons(JoypadButton::BUTTON_A | JoypadButton::DOWN);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_bits(&mut joypad, 8), [1, 0, 0, 0, 0, 1, 0, 0]);
+        // A write of 0 with no rising edge first does not reload the register
+        joypad.write(0);
+        assert_eq!(joypad.read() & 1, 1);
+    }
+
+    #[test]
+    fn reading_all_8_bits_twice_gives_matching_reports() {
+        // Games that dodge the DMC's extra reads strobe and read all 8 bits twice, and
+        // retry until the two reports match
+        let held = JoypadButton::BUTTON_A | JoypadButton::START | JoypadButton::LEFT;
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(held);
+        joypad.write(1);
+        joypad.write(0);
+        // Presses after the strobe falls do not reach the report already latched
+        joypad.set_buttons(JoypadButton::all());
+        let first = read_bits(&mut joypad, 8);
+        joypad.set_buttons(held);
+        joypad.write(1);
+        joypad.write(0);
+        joypad.set_buttons(JoypadButton::empty());
+        let second = read_bits(&mut joypad, 8);
+        assert_eq!(first, [1, 0, 0, 1, 0, 0, 1, 0]);
+        assert_eq!(first, second);
+    }
+
+    // Points the stick `degrees` counter-clockwise from right, `magnitude` from centre
+    fn point(stick: &mut AnalogToDpad, degrees: f32, magnitude: f32) -> u8 {
+        let radians = degrees.to_radians();
+        stick.set_x(magnitude * radians.cos());
+        stick.set_y(-magnitude * radians.sin());
+        stick.buttons().bits()
+    }
+
+    #[test]
+    fn stick_inside_the_deadzone_presses_nothing() {
+        let mut stick = AnalogToDpad::new();
+        assert_eq!(point(&mut stick, 0.0, 0.29), 0);
+        assert_eq!(point(&mut stick, 0.0, 0.31), JoypadButton::RIGHT.bits());
+        assert_eq!(point(&mut stick, 90.0, 0.29), 0);
+        stick.set_sensitivity(2.0);
+        assert_eq!(point(&mut stick, 90.0, 0.16), JoypadButton::UP.bits());
+        stick.set_deadzone(0.5);
+        assert_eq!(point(&mut stick, 90.0, 0.24), 0);
+    }
+
+    #[test]
+    fn diagonals_press_two_directions_in_eight_way_mode() {
+        let mut stick = AnalogToDpad::new();
+        let up_right = (JoypadButton::UP | JoypadButton::RIGHT).bits();
+        let down_left = (JoypadButton::DOWN | JoypadButton::LEFT).bits();
+        assert_eq!(point(&mut stick, 45.0, 1.0), up_right);
+        assert_eq!(point(&mut stick, 225.0, 1.0), down_left);
+        stick.set_x(0.0);
+        stick.set_y(0.0);
+        stick.set_eight_way(false);
+        assert_eq!(point(&mut stick, 40.0, 1.0), JoypadButton::RIGHT.bits());
+        stick.set_x(0.0);
+        stick.set_y(0.0);
+        assert_eq!(point(&mut stick, 60.0, 1.0), JoypadButton::UP.bits());
+    }
+
+    #[test]
+    fn direction_holds_until_the_stick_passes_the_hysteresis_band() {
+        let mut stick = AnalogToDpad::new();
+        let right = JoypadButton::RIGHT.bits();
+        let up_right = (JoypadButton::UP | JoypadButton::RIGHT).bits();
+        assert_eq!(point(&mut stick, 20.0, 1.0), right);
+        // The sector edge is at 22.5 degrees and the band reaches 10 past it
+        assert_eq!(point(&mut stick, 30.0, 1.0), right);
+        assert_eq!(point(&mut stick, 35.0, 1.0), up_right);
+        assert_eq!(point(&mut stick, 15.0, 1.0), up_right);
+        assert_eq!(point(&mut stick, 10.0, 1.0), right);
+        assert_eq!(point(&mut stick, 350.0, 1.0), right);
+        // Returning to the deadzone forgets the direction
+        assert_eq!(point(&mut stick, 60.0, 0.1), 0);
+        assert_eq!(point(&mut stick, 60.0, 1.0), up_right);
+    }
+}
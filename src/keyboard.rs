@@ -0,0 +1,78 @@
+// src/keyboard.rs
+
+/// Rows in the key matrix. Row 9 can be selected but has no keys.
+const ROWS: usize = 9;
+
+/// $4016 write bits.
+const RESET_ROW: u8 = 0x01;
+const SELECT_COLUMN: u8 = 0x02;
+const ENABLE: u8 = 0x04;
+
+/// $4017 bits carrying the four keys of the selected row and column.
+const KEY_BITS: u8 = 0x1E;
+
+/// Family BASIC keyboard on the Famicom expansion port.
+///
+/// Writes to $4016 pick one of 9 rows and one of 2 columns, and $4017 bits 1-4 report
+/// four keys of that half-row, reading 0 while a key is held. Bit 1 selects the column;
+/// its 1-to-0 edge moves on to the next row, and bit 0 returns to row 0.
+pub struct FamilyKeyboard {
+    row: usize,
+    column: usize,
+    enabled: bool,
+    // Held keys as $4017 bits, by row and column
+    held: [[u8; 2]; ROWS],
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> Self {
+        FamilyKeyboard {
+            row: 0,
+            column: 0,
+            enabled: false,
+            held: [[0; 2]; ROWS],
+        }
+    }
+
+    /// Presses or releases the key reported on $4017 bit `bit` (1 to 4) for `row` and
+    /// `column`, following the matrix on the nesdev wiki.
+    pub fn set_key(&mut self, row: usize, column: usize, bit: u8, pressed: bool) {
+        if row < ROWS && column < 2 && (1..=4).contains(&bit) {
+            let mask = 1 << bit;
+            if pressed {
+                self.held[row][column] |= mask;
+            } else {
+                self.held[row][column] &= !mask;
+            }
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        let column = ((data & SELECT_COLUMN) != 0) as usize;
+        if self.column == 1 && column == 0 {
+            self.row = (self.row + 1) % (ROWS + 1);
+        }
+        self.column = column;
+        if data & RESET_ROW != 0 {
+            self.row = 0;
+        }
+        self.enabled = data & ENABLE != 0;
+    }
+
+    /// The keyboard's bits of $4017; the controller 2 bit is left to the caller.
+    pub fn read(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.held.get(self.row) {
+            Some(row) => !row[self.column] & KEY_BITS,
+            None => KEY_BITS,
+        }
+    }
+}
+
+impl Default for FamilyKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
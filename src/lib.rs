@@ -0,0 +1,213 @@
+//! JazzNess emulator core.
+//!
+//! The [`Nes`] facade is the supported, stable entry point for embedding the
+//! emulator in another front-end: its method names and signatures only change
+//! with a minor version bump. The individual hardware modules are public so the
+//! bundled SDL/egui front-end can reach into them for debugging tools, but their
+//! APIs follow the needs of that front-end and may change at any time.
+
+pub mod apu;
+pub mod apulog;
+pub mod audioring;
+pub mod blip;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod gamegenie;
+pub mod joypad;
+pub mod keyboard;
+pub mod mapper;
+pub mod movie;
+pub mod netplay;
+pub mod nsf;
+pub mod palette;
+pub mod perf;
+pub mod ppu;
+pub mod region;
+pub mod render;
+pub mod rewind;
+pub mod spectrum;
+pub mod statediff;
+pub mod zapper;
+
+use crate::bus::Bus;
+use crate::cartridge::Rom;
+use crate::cpu::{CPU, EmulatorSnapshot};
+use crate::joypad::JoypadButton;
+use crate::region::Region;
+use crate::render::frame::Frame;
+
+/// Controller port, for [`Nes::set_input`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Player {
+    One,
+    Two,
+}
+
+/// A complete NES that is driven one frame at a time by the caller.
+///
+/// The caller owns timing: call [`Nes::step_frame`] ~60 times a second, display
+/// the returned frame and queue the samples from [`Nes::take_audio`].
+pub struct Nes {
+    cpu: Option<CPU<'static>>,
+    frame: Frame,
+    region: Region,
+}
+
+impl Nes {
+    /// Creates a console with no cartridge inserted.
+    pub fn new() -> Self {
+        Nes {
+            cpu: None,
+            frame: Frame::new(),
+            region: Region::Ntsc,
+        }
+    }
+
+    /// Inserts an iNES image and resets the console. The console keeps the region chosen
+    /// with [`Nes::set_region`], whatever the image's header says.
+    pub fn load_rom(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let rom = Rom::new(&bytes.to_vec())?;
+        // The facade renders on demand, so the bus needs no per-frame callback
+        let bus = Bus::new(rom, |_, _, _, _| {});
+        let mut cpu = CPU::new(bus);
+        cpu.bus.set_region(self.region);
+        cpu.reset();
+        self.cpu = Some(cpu);
+        self.frame = Frame::new();
+        Ok(())
+    }
+
+    /// Runs until the PPU finishes the next frame and returns the rendered picture.
+    /// Without a cartridge this returns a blank frame.
+    pub fn step_frame(&mut self) -> &Frame {
+        if let Some(cpu) = self.cpu.as_mut() {
+            let target = cpu.bus.frame_count() + 1;
+            while cpu.bus.frame_count() < target {
+                cpu.step();
+            }
+            render::render(cpu.bus.ppu(), &mut self.frame);
+        }
+        &self.frame
+    }
+
+    /// Runs until the PPU next enters vblank and returns the picture it has just drawn.
+    ///
+    /// [`Nes::step_frame`] stops where the PPU wraps from the pre-render line to a new
+    /// frame, by which time the game's NMI handler has already run for the frame. This
+    /// stops on the scanline where vblank begins (241 on NTSC and PAL, 291 on Dendy),
+    /// after the last visible line but before the CPU takes the NMI, so the machine can
+    /// be inspected at the same point every frame. Without a cartridge this returns a
+    /// blank frame.
+    pub fn run_to_vblank(&mut self) -> &Frame {
+        if let Some(cpu) = self.cpu.as_mut() {
+            // An instruction, or an OAM DMA, may cross several scanlines at once. A pending
+            // region switch takes effect at the frame wrap, so the PPU's own region decides.
+            loop {
+                let before = cpu.bus.ppu().scanline();
+                cpu.step();
+                let vblank = cpu.bus.ppu().region().vblank_scanline();
+                if before < vblank && cpu.bus.ppu().scanline() >= vblank {
+                    break;
+                }
+            }
+            render::render(cpu.bus.ppu(), &mut self.frame);
+        }
+        &self.frame
+    }
+
+    /// Sets the full button state of one controller.
+    pub fn set_input(&mut self, player: Player, buttons: JoypadButton) {
+        if let Some(cpu) = self.cpu.as_mut() {
+            match player {
+                Player::One => cpu.bus.joypad1.set_buttons(buttons),
+                Player::Two => cpu.bus.joypad2.set_buttons(buttons),
+            }
+        }
+    }
+
+    /// Timing the console runs with; NTSC unless changed.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches between NTSC, PAL and Dendy timing without reloading the game. A running
+    /// game switches once its current frame is finished; the audio sample rate stays the same.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        if let Some(cpu) = self.cpu.as_mut() {
+            cpu.bus.set_region(region);
+        }
+    }
+
+    /// Drains the mono f32 samples generated since the last call.
+    pub fn take_audio(&mut self) -> Vec<f32> {
+        match self.cpu.as_mut() {
+            Some(cpu) => cpu.bus.apu.take_samples(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Serializes the whole machine state. The format matches the front-end's `.state` files.
+    pub fn save_state(&self) -> Result<Vec<u8>, String> {
+        let cpu = self.cpu.as_ref().ok_or("No ROM is loaded")?;
+        bincode::serialize(&cpu.save_snapshot()).map_err(|e| e.to_string())
+    }
+
+    /// Restores a state produced by [`Nes::save_state`] for the same cartridge. A state
+    /// that fails to decode or validate is rejected without touching the machine.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let cpu = self.cpu.as_mut().ok_or("No ROM is loaded")?;
+        let snapshot: EmulatorSnapshot = bincode::deserialize(data).map_err(|e| e.to_string())?;
+        cpu.try_load_snapshot(&snapshot)
+    }
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A one-bank NROM image of NOPs; the reset vector points at its start
+    fn nop_rom(mapper: u8) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, mapper << 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[0x3FFC..0x3FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw.extend(prg);
+        raw.extend(vec![0; 0x2000]);
+        raw
+    }
+
+    #[test]
+    fn failed_loads_leave_the_console_usable() {
+        let mut nes = Nes::new();
+        let good = nop_rom(0);
+        let failures = [
+            (b"not a rom".to_vec(), "iNES"),
+            (good[..0x3000].to_vec(), "truncated"),
+            (nop_rom(1), "Mapper 1 is not supported"),
+        ];
+        for (bytes, message) in &failures {
+            let error = nes.load_rom(bytes).unwrap_err();
+            assert!(error.contains(message), "{:?} lacks {:?}", error, message);
+            assert!(nes.save_state().is_err(), "no game after a failed load");
+        }
+
+        nes.load_rom(&good).unwrap();
+        nes.step_frame();
+        let state = nes.save_state().unwrap();
+        // A failed load or a corrupt state leaves the running game in place
+        for (bytes, _) in &failures {
+            assert!(nes.load_rom(bytes).is_err());
+            assert!(nes.load_state(bytes).is_err());
+        }
+        nes.step_frame();
+        nes.load_state(&state).unwrap();
+    }
+}
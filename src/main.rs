@@ -1,39 +1,207 @@
 use eframe::egui;
 use native_dialog::FileDialog;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
-mod apu;
-mod bus;
-mod cartridge;
-mod cpu;
-mod debugger;
+mod bindings;
 mod emulator;
-mod gamegenie;
-mod joypad;
-mod palette;
-mod ppu;
-mod render;
+mod settings;
 
-use crate::emulator::EmulatorCommand;
-use crate::gamegenie::{parse_game_genie_code, GameGenieCode};
+use crate::bindings::{Action, Bindings, Category};
+use crate::emulator::{
+    AudioBackend, CpuSnapshot, DebuggerView, EmulatorCommand, EmulatorStats, EmulatorStatus, FastForwardAudio, MovieStatus, NsfStatus,
+    SharedCpuSnapshot,
+};
+use crate::settings::Settings;
+use nesemu::apu::{ApuDebugInfo, CHANNEL_NAMES, SAMPLE_RATES};
+use nesemu::apulog::ApuLogFormat;
+use nesemu::bus::{ExpansionDevice, FrozenAddress};
+use nesemu::cartridge::Mirroring;
+use nesemu::cpu::TRACE_HISTORY_LEN;
+use nesemu::debugger::Breakpoint;
+use nesemu::render::filter::FilterKind;
+use nesemu::gamegenie::{parse_game_genie_code, GameGenieCode};
+use nesemu::joypad::{JoypadButton, OpposingDirections};
+use nesemu::movie::MovieMode;
+use nesemu::region::Region;
+use nesemu::spectrum::magnitude_spectrum;
+use nesemu::statediff::StateDiff;
+use std::time::{Duration, Instant};
+use log::{debug, error};
+use sdl2::keyboard::Keycode;
+
+struct CheatEntry {
+    code: String,
+    description: String,
+    enabled: bool,
+}
 
 struct JazzNessApp {
     emulator_tx: Option<mpsc::Sender<EmulatorCommand>>,
+    emulator_status_rx: Option<mpsc::Receiver<EmulatorStatus>>,
     emulator_thread: Option<thread::JoinHandle<()>>,
-    game_genie_codes: Vec<String>,
+    game_running: bool,
+    cheats: Vec<CheatEntry>,
+    new_cheat_code: String,
+    cheat_status: Option<String>,
+    frozen: Vec<FrozenAddress>,
+    new_freeze_addr: String,
+    new_freeze_value: String,
+    new_write_frame: String,
+    new_write_addr: String,
+    new_write_value: String,
     cpu_tracing_enabled: bool,
+    trace_history: bool,
+    ppu_logging_enabled: bool,
+    perf_overlay_enabled: bool,
+    vsync_enabled: bool,
+    frame_limit_enabled: bool,
+    region: Region,
+    oam_quirks: bool,
+    // Debug overrides for the background and sprite layers; None follows the game
+    background_layer: Option<bool>,
+    sprite_layer: Option<bool>,
+    expansion_device: ExpansionDevice,
+    pause_on_focus_loss: bool,
+    break_on_load: bool,
+    video_filter: FilterKind,
+    input_display: bool,
+    rewind_enabled: bool,
+    rewind_interval: u32,
+    run_ahead_enabled: bool,
+    run_ahead_frames: u32,
+    turbo_rate: u32,
+    netplay_port: String,
+    netplay_address: String,
+    netplay_status: Option<String>,
+    audio_devices: Vec<String>,
+    audio_device: Option<String>,
+    // Percent per APU channel, in `apu::CHANNEL_NAMES` order
+    channel_volumes: [u32; 5],
+    solo: Option<usize>,
+    stats: Option<EmulatorStats>,
+    movie: Option<MovieStatus>,
+    movie_checksums: bool,
+    movie_seek_frame: usize,
+    movie_edit_frame: usize,
+    movie_edit_input: [u8; 2],
+    bindings: Bindings,
+    settings: Settings,
+    show_controls: bool,
+    show_configure_controls: bool,
+    /// Last comparison from Debug > Compare Save States, with the two file names.
+    state_diff: Option<(String, String, StateDiff)>,
+    show_state_diff: bool,
+    show_debugger: bool,
+    /// Latest CPU state and memory page from the emulator, for the Debugger window.
+    debugger_view: Option<DebuggerView>,
+    /// Registers published by the emulator once a frame, read on every repaint.
+    cpu_snapshot: SharedCpuSnapshot,
+    debugger_memory: Option<(u16, Vec<u8>)>,
+    debugger_memory_start: u16,
+    debugger_goto: String,
+    debugger_bp_addr: String,
+    debugger_bp: Breakpoint,
+    debugger_refreshed: Instant,
+    show_apu_viewer: bool,
+    /// Whether the emulator has been asked to stream APU state for the viewer.
+    apu_viewer_active: bool,
+    apu_info: Option<ApuDebugInfo>,
+    show_spectrum: bool,
+    /// Whether the emulator has been asked to stream audio samples for the spectrum.
+    spectrum_active: bool,
+    /// Level in dB per FFT bin and the sample rate the bins are spaced by.
+    spectrum: Option<(Vec<f32>, u32)>,
+    /// Song and play time of the NSF tune being played, if one is.
+    nsf: Option<NsfStatus>,
+    apu_log_format: ApuLogFormat,
+    /// Whether the emulator is recording APU register writes.
+    apu_logging: bool,
+    /// Action waiting for a key press in the Configure Controls window.
+    rebinding: Option<Action>,
+    /// Whether egui had keyboard focus last frame, mirrored to the emulator's hotkeys.
+    hotkeys_suppressed: bool,
     current_rom_path: Option<String>, // Store the path of the loaded ROM
+    raw_rom_mirroring: Mirroring,
 }
 
 impl Default for JazzNessApp {
     fn default() -> Self {
         Self {
             emulator_tx: None,
+            emulator_status_rx: None,
             emulator_thread: None,
-            game_genie_codes: vec!["".to_string(); 6],
+            game_running: false,
+            cheats: Vec::new(),
+            new_cheat_code: String::new(),
+            cheat_status: None,
+            frozen: Vec::new(),
+            new_freeze_addr: String::new(),
+            new_freeze_value: String::new(),
+            new_write_frame: String::new(),
+            new_write_addr: String::new(),
+            new_write_value: String::new(),
             cpu_tracing_enabled: false,
+            trace_history: false,
+            ppu_logging_enabled: false,
+            perf_overlay_enabled: false,
+            vsync_enabled: true,
+            frame_limit_enabled: true,
+            region: Region::Ntsc,
+            oam_quirks: false,
+            background_layer: None,
+            sprite_layer: None,
+            expansion_device: ExpansionDevice::None,
+            pause_on_focus_loss: false,
+            break_on_load: false,
+            video_filter: FilterKind::None,
+            input_display: false,
+            rewind_enabled: true,
+            rewind_interval: 2,
+            run_ahead_enabled: false,
+            run_ahead_frames: 1,
+            turbo_rate: nesemu::joypad::Turbo::DEFAULT_RATE,
+            netplay_port: "7845".to_string(),
+            netplay_address: "127.0.0.1:7845".to_string(),
+            netplay_status: None,
+            audio_devices: Vec::new(),
+            audio_device: None,
+            channel_volumes: [100; 5],
+            solo: None,
+            stats: None,
+            movie: None,
+            movie_checksums: true,
+            movie_seek_frame: 0,
+            movie_edit_frame: 0,
+            movie_edit_input: [0; 2],
+            bindings: Bindings::new(),
+            settings: Settings::load(),
+            show_controls: false,
+            show_configure_controls: false,
+            state_diff: None,
+            show_state_diff: false,
+            show_debugger: false,
+            debugger_view: None,
+            debugger_memory: None,
+            debugger_memory_start: 0,
+            debugger_goto: String::new(),
+            debugger_bp_addr: String::new(),
+            debugger_bp: Breakpoint::on_execute(),
+            debugger_refreshed: Instant::now(),
+            cpu_snapshot: Arc::new(Mutex::new(None)),
+            show_apu_viewer: false,
+            apu_viewer_active: false,
+            show_spectrum: false,
+            spectrum_active: false,
+            spectrum: None,
+            nsf: None,
+            apu_log_format: ApuLogFormat::default(),
+            apu_logging: false,
+            apu_info: None,
+            rebinding: None,
+            hotkeys_suppressed: false,
             current_rom_path: None, // Initially no ROM is loaded
+            raw_rom_mirroring: Mirroring::HORIZONTAL,
         }
     }
 }
@@ -42,41 +210,229 @@ impl JazzNessApp {
     fn start_emulator(&mut self, rom_path: String) {
         // Store the ROM path
         self.current_rom_path = Some(rom_path.clone());
+        self.send_load_command(EmulatorCommand::LoadRom(rom_path));
+    }
+
+    fn start_emulator_raw(&mut self, prg_path: String, chr_path: Option<String>) {
+        // Save states are named after the PRG file
+        self.current_rom_path = Some(prg_path.clone());
+        self.send_load_command(EmulatorCommand::LoadRawRom {
+            prg_path,
+            chr_path,
+            mirroring: self.raw_rom_mirroring.clone(),
+        });
+    }
+
+    fn start_emulator_nsf(&mut self, nsf_path: String) {
+        self.current_rom_path = Some(nsf_path.clone());
+        self.send_load_command(EmulatorCommand::LoadNsf(nsf_path));
+    }
 
+    fn send_load_command(&mut self, command: EmulatorCommand) {
         if let Some(tx) = self.emulator_tx.take() {
             if let Some(handle) = self.emulator_thread.take() {
-                if tx.send(EmulatorCommand::LoadRom(rom_path.clone())).is_err() {
+                if let Err(mpsc::SendError(command)) = tx.send(command) {
                     handle.join().expect("Failed to join emulator thread");
-                    self.spawn_new_emulator_thread(rom_path);
+                    self.spawn_new_emulator_thread(command);
                 } else {
                     self.emulator_tx = Some(tx);
+                    self.emulator_thread = Some(handle);
                 }
             }
         } else {
-            self.spawn_new_emulator_thread(rom_path);
+            self.spawn_new_emulator_thread(command);
         }
     }
 
-    fn spawn_new_emulator_thread(&mut self, rom_path: String) {
+    fn spawn_new_emulator_thread(&mut self, load_command: EmulatorCommand) {
         let (tx, rx) = mpsc::channel();
+        let (status_tx, status_rx) = mpsc::channel();
+        let cpu_snapshot = Arc::clone(&self.cpu_snapshot);
         let emulator_handle = thread::spawn(move || {
-            emulator::run_emulator(rx);
+            emulator::run_emulator(rx, status_tx, cpu_snapshot);
         });
 
-        tx.send(EmulatorCommand::LoadRom(rom_path))
+        tx.send(EmulatorCommand::SetBindings(self.bindings.clone()))
+            .expect("Failed to send key bindings");
+        tx.send(EmulatorCommand::SetHotkeysSuppressed(self.hotkeys_suppressed))
+            .expect("Failed to send hotkey state");
+        tx.send(self.autofire_command())
+            .expect("Failed to send autofire settings");
+        tx.send(EmulatorCommand::SetOpposingDirections(self.settings.opposing_directions))
+            .expect("Failed to send d-pad settings");
+        tx.send(self.analog_stick_command())
+            .expect("Failed to send analog stick settings");
+        tx.send(EmulatorCommand::SetFastForwardAudio(self.settings.fast_forward_audio))
+            .expect("Failed to send fast-forward audio setting");
+        tx.send(EmulatorCommand::SetMuteOnPause(self.settings.mute_on_pause))
+            .expect("Failed to send mute-on-pause setting");
+        tx.send(EmulatorCommand::SetBandLimitedAudio(self.settings.band_limited_audio))
+            .expect("Failed to send audio synthesis setting");
+        tx.send(EmulatorCommand::SetHardwareFilters(self.settings.hardware_filters))
+            .expect("Failed to send audio filter setting");
+        tx.send(EmulatorCommand::SetSilenceUltrasonicTriangle(self.settings.silence_ultrasonic_triangle))
+            .expect("Failed to send triangle silencing setting");
+        tx.send(EmulatorCommand::SetSampleRate(self.settings.sample_rate))
+            .expect("Failed to send sample rate setting");
+        tx.send(EmulatorCommand::SetAudioBackend(self.settings.audio_backend))
+            .expect("Failed to send audio backend setting");
+        tx.send(EmulatorCommand::SetApuViewer(self.apu_viewer_active))
+            .expect("Failed to send APU viewer state");
+        tx.send(EmulatorCommand::SetSpectrumViewer(self.spectrum_active))
+            .expect("Failed to send spectrum viewer state");
+        tx.send(load_command)
             .expect("Failed to send initial ROM load command");
 
         self.emulator_tx = Some(tx);
+        self.emulator_status_rx = Some(status_rx);
         self.emulator_thread = Some(emulator_handle);
     }
 
     fn send_command(&self, command: EmulatorCommand) {
         if let Some(tx) = &self.emulator_tx {
             if let Err(e) = tx.send(command) {
-                eprintln!("Failed to send command to emulator thread: {}", e);
+                error!("Failed to send command to emulator thread: {}", e);
             }
         } else {
-            println!("No emulator running, ignoring command.");
+            debug!("No emulator running, ignoring command.");
+        }
+    }
+
+    fn autofire_command(&self) -> EmulatorCommand {
+        EmulatorCommand::SetAutofire {
+            buttons: self.settings.autofire_buttons,
+            period: self.settings.autofire_period,
+            duty: self.settings.autofire_duty,
+        }
+    }
+
+    // Pushes the autofire settings to the emulator and writes them to disk
+    fn apply_autofire(&mut self) {
+        self.send_command(self.autofire_command());
+        if let Err(e) = self.settings.save() {
+            error!("{}", e);
+        }
+    }
+
+    fn analog_stick_command(&self) -> EmulatorCommand {
+        EmulatorCommand::SetAnalogStick {
+            deadzone: self.settings.stick_deadzone,
+            sensitivity: self.settings.stick_sensitivity,
+            eight_way: self.settings.stick_eight_way,
+        }
+    }
+
+    // Pushes the analog stick settings to the emulator and writes them to disk
+    fn apply_analog_stick(&mut self) {
+        self.send_command(self.analog_stick_command());
+        if let Err(e) = self.settings.save() {
+            error!("{}", e);
+        }
+    }
+
+    // Sends every enabled code that parses; invalid rows are flagged in the UI instead
+    fn apply_cheats(&mut self) {
+        let codes: Vec<GameGenieCode> = self
+            .cheats
+            .iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| parse_game_genie_code(cheat.code.trim()).ok())
+            .collect();
+
+        if self.game_running {
+            self.cheat_status = None;
+            self.send_command(EmulatorCommand::SetGameGenieCodes(codes));
+        } else {
+            self.cheat_status = Some("No ROM is loaded. Cheats cannot be applied.".to_string());
+        }
+    }
+
+    fn poll_emulator_status(&mut self) {
+        let Some(status_rx) = &self.emulator_status_rx else {
+            return;
+        };
+        while let Ok(status) = status_rx.try_recv() {
+            match status {
+                EmulatorStatus::GameGenieCodesApplied(count) => {
+                    self.cheat_status = Some(format!("{} code(s) active", count));
+                }
+                EmulatorStatus::Error(message) => {
+                    native_dialog::MessageDialog::new()
+                        .set_type(native_dialog::MessageType::Error)
+                        .set_title("Emulator Error")
+                        .set_text(&message)
+                        .show_alert()
+                        .unwrap();
+                }
+                EmulatorStatus::RomLoaded => {
+                    self.game_running = true;
+                    // A freshly loaded game starts with nothing frozen
+                    self.frozen.clear();
+                    self.nsf = None;
+                    self.apu_logging = false;
+                }
+                EmulatorStatus::FrozenAddresses(frozen) => {
+                    self.frozen = frozen;
+                }
+                EmulatorStatus::Stopped => {
+                    self.game_running = false;
+                    self.netplay_status = None;
+                    self.stats = None;
+                    self.movie = None;
+                    self.debugger_view = None;
+                    self.debugger_memory = None;
+                    self.apu_info = None;
+                    self.nsf = None;
+                    self.apu_logging = false;
+                }
+                EmulatorStatus::RunAheadDisabled => {
+                    self.run_ahead_enabled = false;
+                }
+                EmulatorStatus::Netplay(status) => {
+                    self.netplay_status = status;
+                }
+                EmulatorStatus::AudioDevices(devices) => {
+                    self.audio_devices = devices;
+                }
+                EmulatorStatus::AudioDevice(device) => {
+                    self.audio_device = device;
+                }
+                EmulatorStatus::Stats(stats) => {
+                    self.stats = Some(stats);
+                }
+                EmulatorStatus::Movie(status) => {
+                    self.movie = status;
+                }
+                EmulatorStatus::MovieInput { frame, input } => {
+                    self.movie_edit_frame = frame;
+                    self.movie_edit_input = input;
+                }
+                EmulatorStatus::ShowControls => {
+                    self.show_controls = true;
+                }
+                EmulatorStatus::Debugger(view) => {
+                    self.debugger_view = Some(view);
+                }
+                EmulatorStatus::Memory { start, bytes } => {
+                    self.debugger_memory = Some((start, bytes));
+                }
+                EmulatorStatus::Apu(info) => {
+                    self.apu_info = Some(info);
+                }
+                // Only streamed while the spectrum window is open, so the FFT costs nothing otherwise
+                EmulatorStatus::AudioSamples { samples, sample_rate } => {
+                    self.spectrum = Some((magnitude_spectrum(&samples), sample_rate));
+                }
+                EmulatorStatus::Nsf(status) => {
+                    self.nsf = Some(status);
+                }
+                EmulatorStatus::ApuLogging(active) => {
+                    self.apu_logging = active;
+                }
+                EmulatorStatus::Solo(channel) => {
+                    self.solo = channel;
+                }
+            }
         }
     }
 
@@ -89,12 +445,200 @@ impl JazzNessApp {
         }
         "jazzness.state".to_string()
     }
+
+    // Asks for two state files and opens the diff window on them
+    fn compare_state_files(&mut self) {
+        let pick = || {
+            FileDialog::new()
+                .add_filter("Save State", &["state"])
+                .show_open_single_file()
+                .ok()
+                .flatten()
+                .and_then(|path| path.to_str().map(str::to_string))
+        };
+        let Some(left) = pick() else { return };
+        let Some(right) = pick() else { return };
+
+        match StateDiff::from_files(&left, &right) {
+            Ok(diff) => {
+                let name = |path: &str| {
+                    std::path::Path::new(path).file_name().map_or(path.to_string(), |n| n.to_string_lossy().into_owned())
+                };
+                self.state_diff = Some((name(&left), name(&right), diff));
+                self.show_state_diff = true;
+            }
+            Err(e) => {
+                error!("{}", e);
+                native_dialog::MessageDialog::new()
+                    .set_type(native_dialog::MessageType::Error)
+                    .set_title("Compare Save States")
+                    .set_text(&e)
+                    .show_alert()
+                    .unwrap();
+            }
+        }
+    }
+
+    fn debugger_window(&mut self, ctx: &egui::Context) {
+        if !self.show_debugger {
+            return;
+        }
+        // A game sends its state as it pauses; poll so the window also follows a running one
+        if self.game_running && self.debugger_refreshed.elapsed() >= DEBUGGER_REFRESH {
+            self.debugger_refreshed = Instant::now();
+            self.send_command(EmulatorCommand::DebugRefresh);
+            self.send_command(EmulatorCommand::DebugReadMemory {
+                start: self.debugger_memory_start,
+                len: DEBUGGER_MEMORY_BYTES,
+            });
+        }
+        ctx.request_repaint_after(DEBUGGER_REFRESH);
+
+        let mut open = true;
+        let mut commands = Vec::new();
+        egui::Window::new("Debugger")
+            .open(&mut open)
+            .default_width(680.0)
+            .show(ctx, |ui| {
+                if !self.game_running {
+                    ui.label("Load a ROM to debug it.");
+                    return;
+                }
+                let Some(view) = &self.debugger_view else {
+                    ui.label("Waiting for the emulator...");
+                    return;
+                };
+
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(view.paused, egui::Button::new("Continue")).clicked() {
+                        commands.push(EmulatorCommand::DebugContinue);
+                    }
+                    if ui.add_enabled(!view.paused, egui::Button::new("Break")).clicked() {
+                        commands.push(EmulatorCommand::Pause);
+                    }
+                    if ui.add_enabled(view.paused, egui::Button::new("Step")).clicked() {
+                        commands.push(EmulatorCommand::DebugStep);
+                    }
+                    if ui
+                        .add_enabled(view.paused, egui::Button::new("Step Over"))
+                        .on_hover_text("Runs a JSR until it returns")
+                        .clicked()
+                    {
+                        commands.push(EmulatorCommand::DebugStepOver);
+                    }
+                    ui.label(if view.paused { "Paused" } else { "Running" });
+                });
+                ui.separator();
+                // The published snapshot is fresher than the polled view while the game runs
+                let registers = match *self.cpu_snapshot.lock().unwrap() {
+                    Some(snapshot) if !view.paused => snapshot,
+                    _ => view.registers,
+                };
+                show_cpu_registers(ui, &registers);
+                ui.separator();
+
+                ui.columns(2, |columns| {
+                    columns[0].label("Disassembly (click a line to toggle a breakpoint)");
+                    for line in &view.disassembly {
+                        let breakpoint = view.breakpoints.iter().find(|(addr, _)| *addr == line.addr).map(|(_, bp)| *bp);
+                        let marker = match (line.addr == view.registers.program_counter, breakpoint.is_some()) {
+                            (true, _) => '>',
+                            (false, true) => '*',
+                            (false, false) => ' ',
+                        };
+                        let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                        let mut text = egui::RichText::new(format!("{} {:04X}  {:<8}  {}", marker, line.addr, bytes.join(" "), line.text))
+                            .monospace();
+                        if line.addr == view.registers.program_counter {
+                            text = text.color(egui::Color32::YELLOW);
+                        } else if breakpoint.is_some() {
+                            text = text.color(egui::Color32::LIGHT_RED);
+                        }
+                        if columns[0].add(egui::Label::new(text).sense(egui::Sense::click())).clicked() {
+                            commands.push(match breakpoint {
+                                Some(_) => EmulatorCommand::RemoveBreakpoint(line.addr),
+                                None => EmulatorCommand::AddBreakpoint { addr: line.addr, breakpoint: Breakpoint::on_execute() },
+                            });
+                        }
+                    }
+
+                    let ui = &mut columns[1];
+                    ui.label("Breakpoints");
+                    egui::Grid::new("debugger_breakpoints").num_columns(3).striped(true).show(ui, |ui| {
+                        for &(addr, bp) in &view.breakpoints {
+                            ui.monospace(format!("${:04X}", addr));
+                            let kinds: String = [(bp.on_read, 'r'), (bp.on_write, 'w'), (bp.on_execute, 'x')]
+                                .iter()
+                                .map(|&(set, c)| if set { c } else { '-' })
+                                .collect();
+                            ui.monospace(if bp.one_shot { format!("{} once", kinds) } else { kinds });
+                            if ui.small_button("Remove").clicked() {
+                                commands.push(EmulatorCommand::RemoveBreakpoint(addr));
+                            }
+                            ui.end_row();
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::TextEdit::singleline(&mut self.debugger_bp_addr).hint_text("$8000").desired_width(60.0));
+                        ui.checkbox(&mut self.debugger_bp.on_read, "R");
+                        ui.checkbox(&mut self.debugger_bp.on_write, "W");
+                        ui.checkbox(&mut self.debugger_bp.on_execute, "X");
+                    });
+                    let bp = self.debugger_bp;
+                    let addr = parse_hex_address(&self.debugger_bp_addr);
+                    let valid = addr.is_some() && (bp.on_read || bp.on_write || bp.on_execute);
+                    if ui.add_enabled(valid, egui::Button::new("Add Breakpoint")).clicked() {
+                        commands.push(EmulatorCommand::AddBreakpoint { addr: addr.unwrap(), breakpoint: bp });
+                        self.debugger_bp_addr.clear();
+                    }
+                });
+                ui.separator();
+
+                let mut start = self.debugger_memory_start;
+                ui.horizontal(|ui| {
+                    ui.label("Memory");
+                    let goto = ui.add(egui::TextEdit::singleline(&mut self.debugger_goto).hint_text("$0000").desired_width(60.0));
+                    let entered = goto.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    if (ui.button("Go").clicked() || entered) && parse_hex_address(&self.debugger_goto).is_some() {
+                        // Rows stay aligned to 16 bytes
+                        start = parse_hex_address(&self.debugger_goto).unwrap() & 0xFFF0;
+                    }
+                    if ui.button("<").clicked() {
+                        start = start.wrapping_sub(DEBUGGER_MEMORY_BYTES);
+                    }
+                    if ui.button(">").clicked() {
+                        start = start.wrapping_add(DEBUGGER_MEMORY_BYTES);
+                    }
+                });
+                if start != self.debugger_memory_start {
+                    self.debugger_memory_start = start;
+                    commands.push(EmulatorCommand::DebugReadMemory { start, len: DEBUGGER_MEMORY_BYTES });
+                }
+                match &self.debugger_memory {
+                    Some((shown, bytes)) if *shown == self.debugger_memory_start => show_memory(ui, *shown, bytes),
+                    _ => {
+                        ui.label("Reading memory...");
+                    }
+                }
+            });
+        self.show_debugger = open;
+        for command in commands {
+            self.send_command(command);
+        }
+    }
+
+    fn get_default_movie_path(&self) -> String {
+        let state_path = self.get_default_state_path();
+        format!("{}.fm2", state_path.trim_end_matches(".state"))
+    }
 }
 
 impl eframe::App for JazzNessApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.poll_emulator_status();
+
         // Check if an emulator is running (for enabling/disabling menu items)
-        let is_running = self.emulator_tx.is_some();
+        let is_running = self.game_running;
 
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
@@ -123,6 +667,54 @@ impl eframe::App for JazzNessApp {
                             }
                         }
                     }
+
+                    ui.menu_button("Open Raw PRG/CHR", |ui| {
+                        ui.label("Mirroring");
+                        ui.radio_value(&mut self.raw_rom_mirroring, Mirroring::HORIZONTAL, "Horizontal");
+                        ui.radio_value(&mut self.raw_rom_mirroring, Mirroring::VERTICAL, "Vertical");
+                        ui.separator();
+
+                        if ui.button("Choose PRG...").clicked() {
+                            ui.close_menu();
+                            let prg = FileDialog::new()
+                                .set_location("~")
+                                .add_filter("Raw PRG", &["prg", "bin"])
+                                .show_open_single_file();
+
+                            if let Ok(Some(prg_path)) = prg {
+                                // Cancelling the CHR dialog loads the PRG with blank CHR
+                                let chr = FileDialog::new()
+                                    .set_location("~")
+                                    .add_filter("Raw CHR", &["chr", "bin"])
+                                    .show_open_single_file();
+                                let chr_path = match chr {
+                                    Ok(Some(path)) => path.to_str().map(|s| s.to_string()),
+                                    _ => None,
+                                };
+
+                                if let Some(prg_str) = prg_path.to_str() {
+                                    self.start_emulator_raw(prg_str.to_string(), chr_path);
+                                }
+                            }
+                        }
+                    });
+
+                    if ui.button("Open NSF...").clicked() {
+                        ui.close_menu();
+                        let result = FileDialog::new()
+                            .set_location("~")
+                            .add_filter("NSF Music", &["nsf"])
+                            .show_open_single_file();
+
+                        if let Some(path_str) = result.ok().flatten().as_deref().and_then(|path| path.to_str()) {
+                            self.start_emulator_nsf(path_str.to_string());
+                        }
+                    }
+
+                    if ui.add_enabled(is_running, egui::Button::new("Close ROM")).clicked() {
+                        ui.close_menu();
+                        self.send_command(EmulatorCommand::StopEmulation);
+                    }
                     
                     ui.separator();
 
@@ -169,83 +761,972 @@ impl eframe::App for JazzNessApp {
                     ui.label("Game Genie Codes");
                     ui.separator();
 
-                    for code_str in self.game_genie_codes.iter_mut() {
+                    let mut cheats_changed = false;
+                    let mut remove_idx = None;
+
+                    for (i, cheat) in self.cheats.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            cheats_changed |= ui.checkbox(&mut cheat.enabled, "").changed();
+
+                            let code_edit = ui.add(
+                                egui::TextEdit::singleline(&mut cheat.code)
+                                    .hint_text("AAPPZK")
+                                    .desired_width(80.0),
+                            );
+                            // Only re-apply once editing is finished, not on every keystroke
+                            cheats_changed |= code_edit.lost_focus();
+
+                            ui.add(
+                                egui::TextEdit::singleline(&mut cheat.description)
+                                    .hint_text("Description")
+                                    .desired_width(120.0),
+                            );
+
+                            if ui.button("Remove").clicked() {
+                                remove_idx = Some(i);
+                            }
+                        });
+
+                        if let Err(e) = parse_game_genie_code(cheat.code.trim()) {
+                            ui.colored_label(egui::Color32::RED, format!("'{}': {}", cheat.code, e));
+                        }
+                    }
+
+                    if let Some(i) = remove_idx {
+                        self.cheats.remove(i);
+                        cheats_changed = true;
+                    }
+
+                    ui.horizontal(|ui| {
                         ui.add(
-                            egui::TextEdit::singleline(code_str)
+                            egui::TextEdit::singleline(&mut self.new_cheat_code)
                                 .hint_text("AAPPZK")
-                                .desired_width(100.0),
+                                .desired_width(80.0),
                         );
+                        if ui.button("Add code").clicked() && !self.new_cheat_code.trim().is_empty() {
+                            self.cheats.push(CheatEntry {
+                                code: self.new_cheat_code.trim().to_string(),
+                                description: String::new(),
+                                enabled: true,
+                            });
+                            self.new_cheat_code.clear();
+                            cheats_changed = true;
+                        }
+                    });
+
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        if ui.button("Disable all").clicked() {
+                            for cheat in self.cheats.iter_mut() {
+                                cheat.enabled = false;
+                            }
+                            cheats_changed = true;
+                        }
+                        if ui.button("Clear all").clicked() {
+                            self.cheats.clear();
+                            cheats_changed = true;
+                        }
+                    });
+
+                    if cheats_changed {
+                        self.apply_cheats();
+                    }
+
+                    if let Some(status) = &self.cheat_status {
+                        ui.label(status);
                     }
 
                     ui.separator();
+                    ui.label("Frozen Addresses");
+
+                    let mut frozen_changed = false;
+                    let mut remove_idx = None;
+
+                    for (i, frozen) in self.frozen.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.monospace(format!("${:04X} = ${:02X}", frozen.addr, frozen.value));
+                            if ui.button("Remove").clicked() {
+                                remove_idx = Some(i);
+                            }
+                        });
+                    }
+
+                    if let Some(i) = remove_idx {
+                        self.frozen.remove(i);
+                        frozen_changed = true;
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_freeze_addr)
+                                .hint_text("$0075")
+                                .desired_width(60.0),
+                        );
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_freeze_value)
+                                .hint_text("$09")
+                                .desired_width(40.0),
+                        );
+                        let addr = parse_hex_address(&self.new_freeze_addr);
+                        let value = parse_hex_byte(&self.new_freeze_value);
+                        let valid = addr.is_some() && value.is_some();
+                        if ui.add_enabled(valid && self.game_running, egui::Button::new("Freeze")).clicked() {
+                            let frozen = FrozenAddress { addr: addr.unwrap(), value: value.unwrap() };
+                            // Freezing an address again just changes its value
+                            self.frozen.retain(|f| f.addr != frozen.addr);
+                            self.frozen.push(frozen);
+                            self.new_freeze_addr.clear();
+                            self.new_freeze_value.clear();
+                            frozen_changed = true;
+                        }
+                    });
+
+                    if frozen_changed {
+                        self.send_command(EmulatorCommand::SetFrozenAddresses(self.frozen.clone()));
+                    }
+
+                    ui.separator();
+                    ui.label("Scheduled Write")
+                        .on_hover_text("Written once at the start of the given frame, through the normal bus");
+
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_write_frame)
+                                .hint_text("frame")
+                                .desired_width(60.0),
+                        );
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_write_addr)
+                                .hint_text("$0075")
+                                .desired_width(60.0),
+                        );
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.new_write_value)
+                                .hint_text("$03")
+                                .desired_width(40.0),
+                        );
+                        let frame = self.new_write_frame.trim().parse::<u64>().ok();
+                        let addr = parse_hex_address(&self.new_write_addr);
+                        let value = parse_hex_byte(&self.new_write_value);
+                        if let (Some(frame), Some(addr), Some(value)) = (frame, addr, value) {
+                            if ui.add_enabled(self.game_running, egui::Button::new("Schedule")).clicked() {
+                                self.send_command(EmulatorCommand::ScheduledWrite { frame, addr, value });
+                                self.new_write_frame.clear();
+                                self.new_write_addr.clear();
+                                self.new_write_value.clear();
+                            }
+                        } else {
+                            ui.add_enabled(false, egui::Button::new("Schedule"));
+                        }
+                    });
+
+                    ui.separator();
+                    ui.label("Rewind");
+
+                    let mut rewind_changed = ui
+                        .checkbox(&mut self.rewind_enabled, "Enable Rewind (hold R)")
+                        .changed();
+                    rewind_changed |= ui
+                        .add_enabled(
+                            self.rewind_enabled,
+                            egui::Slider::new(&mut self.rewind_interval, 1..=10).text("frames per snapshot"),
+                        )
+                        .changed();
+
+                    if rewind_changed {
+                        self.send_command(EmulatorCommand::SetRewind {
+                            enabled: self.rewind_enabled,
+                            interval_frames: self.rewind_interval,
+                        });
+                    }
+
+                    ui.separator();
+                    ui.label("Run-Ahead");
+
+                    let mut run_ahead_changed = ui
+                        .checkbox(&mut self.run_ahead_enabled, "Enable Run-Ahead")
+                        .on_hover_text("Turns itself off if full speed cannot be kept")
+                        .changed();
+                    run_ahead_changed |= ui
+                        .add_enabled(
+                            self.run_ahead_enabled,
+                            egui::Slider::new(&mut self.run_ahead_frames, 1..=4).text("frames"),
+                        )
+                        .changed();
+
+                    if run_ahead_changed {
+                        let frames = if self.run_ahead_enabled { self.run_ahead_frames } else { 0 };
+                        self.send_command(EmulatorCommand::SetRunAhead(frames));
+                    }
 
-                    if ui.button("Apply Cheats").clicked() {
-                        let mut parsed_codes = Vec::<GameGenieCode>::new();
-                        let mut error_messages = Vec::<String>::new();
-
-                        for (i, code_str) in self.game_genie_codes.iter().enumerate() {
-                            if !code_str.is_empty() {
-                                match parse_game_genie_code(code_str) {
-                                    Ok(code) => parsed_codes.push(code),
-                                    Err(e) => {
-                                        let msg = format!(
-                                            "Slot {}: Failed to parse '{}' - {}",
-                                            i + 1,
-                                            code_str,
-                                            e
-                                        );
-                                        error_messages.push(msg);
-                                    }
+                    ui.separator();
+                    ui.label("Turbo (hold W/Q, toggle 1/2)");
+
+                    if ui
+                        .add(egui::Slider::new(&mut self.turbo_rate, 1..=8).text("frames per pulse"))
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetTurboRate(self.turbo_rate));
+                    }
+
+                    ui.separator();
+                    ui.menu_button("Region", |ui| {
+                        for region in Region::ALL {
+                            if ui.radio_value(&mut self.region, region, region.name()).clicked() {
+                                self.send_command(EmulatorCommand::SetRegion(region));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+
+                    if ui
+                        .checkbox(&mut self.oam_quirks, "Accurate OAM Access")
+                        .on_hover_text("Clears OAMADDR and changes $2004 reads while rendering, as on a console. Some games glitch with this on.")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetOamQuirks(self.oam_quirks));
+                    }
+
+                    ui.separator();
+                    if ui.button("Configure Controls...").clicked() {
+                        self.show_configure_controls = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Debugger...").clicked() {
+                        self.show_debugger = true;
+                        self.debugger_refreshed = Instant::now() - DEBUGGER_REFRESH;
+                        ui.close_menu();
+                    }
+                });
+                
+                ui.menu_button("Input", |ui| {
+                    ui.label("Autofire (hold the normal key)");
+                    let mut autofire_changed = false;
+                    for (button, label) in [(JoypadButton::BUTTON_A, "Autofire A"), (JoypadButton::BUTTON_B, "Autofire B")] {
+                        let mut enabled = self.settings.autofire_buttons.contains(button);
+                        if ui.checkbox(&mut enabled, label).changed() {
+                            self.settings.autofire_buttons.set(button, enabled);
+                            autofire_changed = true;
+                        }
+                    }
+                    autofire_changed |= ui
+                        .add(egui::Slider::new(&mut self.settings.autofire_period, 2..=16).text("frames per pulse"))
+                        .changed();
+                    self.settings.autofire_duty = self.settings.autofire_duty.clamp(1, self.settings.autofire_period - 1);
+                    autofire_changed |= ui
+                        .add(egui::Slider::new(&mut self.settings.autofire_duty, 1..=self.settings.autofire_period - 1).text("frames pressed"))
+                        .changed();
+                    if autofire_changed {
+                        self.apply_autofire();
+                    }
+
+                    ui.separator();
+                    ui.menu_button("Expansion Device", |ui| {
+                        for device in ExpansionDevice::ALL {
+                            let response = ui.radio_value(&mut self.expansion_device, device, device.name());
+                            let response = match device {
+                                ExpansionDevice::Zapper => {
+                                    response.on_hover_text("Aim with the mouse in the game window, left button fires")
+                                }
+                                ExpansionDevice::FamilyKeyboard => response
+                                    .on_hover_text("Keys type on the Famicom keyboard instead of triggering hotkeys or controller 2"),
+                                ExpansionDevice::None => response,
+                            };
+                            if response.clicked() {
+                                self.send_command(EmulatorCommand::SetExpansionDevice(device));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                    ui.menu_button("Left+Right / Up+Down", |ui| {
+                        for mode in OpposingDirections::ALL {
+                            if ui.radio_value(&mut self.settings.opposing_directions, mode, mode.name()).clicked() {
+                                self.send_command(EmulatorCommand::SetOpposingDirections(mode));
+                                if let Err(e) = self.settings.save() {
+                                    error!("{}", e);
+                                }
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+
+                ui.menu_button("Video", |ui| {
+                    ui.menu_button("Filter", |ui| {
+                        for kind in FilterKind::ALL {
+                            if ui.radio_value(&mut self.video_filter, kind, kind.name()).clicked() {
+                                self.send_command(EmulatorCommand::SetVideoFilter(kind));
+                                ui.close_menu();
+                            }
+                        }
+                    });
+
+                    if ui.checkbox(&mut self.input_display, "Input Display").changed() {
+                        self.send_command(EmulatorCommand::SetInputDisplay(self.input_display));
+                    }
+                });
+
+                ui.menu_button("Audio", |ui| {
+                    ui.menu_button("Output Device", |ui| {
+                        let mut selected = self.audio_device.clone();
+                        ui.radio_value(&mut selected, None, "System Default");
+                        for device in &self.audio_devices {
+                            ui.radio_value(&mut selected, Some(device.clone()), device);
+                        }
+                        if self.audio_devices.is_empty() {
+                            ui.label("Devices are listed once a ROM is loaded.");
+                        }
+
+                        if selected != self.audio_device {
+                            self.audio_device = selected.clone();
+                            self.send_command(EmulatorCommand::SetAudioDevice(selected));
+                            ui.close_menu();
+                        }
+                    });
+
+                    ui.menu_button("Sample Rate", |ui| {
+                        for rate in SAMPLE_RATES {
+                            let label = format!("{} kHz", rate as f32 / 1000.0);
+                            if ui.radio_value(&mut self.settings.sample_rate, rate, label).clicked() {
+                                self.send_command(EmulatorCommand::SetSampleRate(rate));
+                                if let Err(e) = self.settings.save() {
+                                    error!("{}", e);
                                 }
+                                ui.close_menu();
                             }
                         }
+                    })
+                    .response
+                    .on_hover_text("Match your system's output rate to skip its resampling");
 
-                        if !error_messages.is_empty() {
-                            native_dialog::MessageDialog::new()
-                                .set_type(native_dialog::MessageType::Error)
-                                .set_title("Game Genie Error")
-                                .set_text(&error_messages.join("\n"))
-                                .show_alert()
-                                .unwrap();
+                    ui.menu_button("Audio Backend", |ui| {
+                        for backend in AudioBackend::ALL {
+                            if ui.radio_value(&mut self.settings.audio_backend, backend, backend.name()).clicked() {
+                                self.send_command(EmulatorCommand::SetAudioBackend(backend));
+                                if let Err(e) = self.settings.save() {
+                                    error!("{}", e);
+                                }
+                                ui.close_menu();
+                            }
                         }
+                    })
+                    .response
+                    .on_hover_text("The callback keeps about 30 ms of sound buffered; the queue is simpler to debug");
 
-                        if !parsed_codes.is_empty() {
-                            self.send_command(EmulatorCommand::SetGameGenieCodes(parsed_codes));
+                    ui.menu_button("Fast-Forward Audio", |ui| {
+                        for mode in FastForwardAudio::ALL {
+                            if ui.radio_value(&mut self.settings.fast_forward_audio, mode, mode.name()).clicked() {
+                                self.send_command(EmulatorCommand::SetFastForwardAudio(mode));
+                                if let Err(e) = self.settings.save() {
+                                    error!("{}", e);
+                                }
+                                ui.close_menu();
+                            }
                         }
+                    })
+                    .response
+                    .on_hover_text("Used while the frame limiter is off and the game runs faster than normal");
 
-                        if error_messages.is_empty() && !is_running {
-                             native_dialog::MessageDialog::new()
-                                .set_type(native_dialog::MessageType::Warning)
-                                .set_title("Game Genie Warning")
-                                .set_text("No ROM is loaded. Cheats cannot be applied.")
-                                .show_alert()
-                                .unwrap();
+                    if ui
+                        .checkbox(&mut self.settings.mute_on_pause, "Mute While Paused")
+                        .on_hover_text("Off keeps the last sound playing while paused")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetMuteOnPause(self.settings.mute_on_pause));
+                        if let Err(e) = self.settings.save() {
+                            error!("{}", e);
                         }
+                    }
 
+                    if ui
+                        .checkbox(&mut self.settings.band_limited_audio, "Band-Limited Synthesis")
+                        .on_hover_text("Cleaner high notes; off uses less CPU")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetBandLimitedAudio(self.settings.band_limited_audio));
+                        if let Err(e) = self.settings.save() {
+                            error!("{}", e);
+                        }
+                    }
+
+                    if ui
+                        .checkbox(&mut self.settings.hardware_filters, "Console Output Filters")
+                        .on_hover_text("The NES's 90 Hz and 440 Hz high-pass and 14 kHz low-pass; off uses a single gentle high-pass")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetHardwareFilters(self.settings.hardware_filters));
+                        if let Err(e) = self.settings.save() {
+                            error!("{}", e);
+                        }
+                    }
+
+                    if ui
+                        .checkbox(&mut self.settings.silence_ultrasonic_triangle, "Reduce Triangle Popping")
+                        .on_hover_text("Mutes the triangle to its midpoint at periods too high to hear; off plays the ultrasonic tone as the console does")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetSilenceUltrasonicTriangle(
+                            self.settings.silence_ultrasonic_triangle,
+                        ));
+                        if let Err(e) = self.settings.save() {
+                            error!("{}", e);
+                        }
+                    }
+
+                    ui.menu_button("APU Write Log", |ui| {
+                        for format in ApuLogFormat::ALL {
+                            ui.radio_value(&mut self.apu_log_format, format, format.name());
+                        }
+                        ui.separator();
+                        if ui
+                            .add_enabled(is_running, egui::Button::new("Start Recording..."))
+                            .on_hover_text("Every APU register write with its CPU cycle, for converting the music offline")
+                            .clicked()
+                        {
+                            ui.close_menu();
+                            let format = self.apu_log_format;
+                            let default_path = format!(
+                                "{}.{}",
+                                self.get_default_state_path().trim_end_matches(".state"),
+                                format.extension()
+                            );
+                            let result = FileDialog::new()
+                                .set_filename(&default_path)
+                                .add_filter("APU Write Log", &[format.extension()])
+                                .show_save_single_file();
+                            if let Some(path) = result.ok().flatten().as_deref().and_then(|path| path.to_str()) {
+                                self.send_command(EmulatorCommand::StartApuLog { path: path.to_string(), format });
+                            }
+                        }
+                        if ui.add_enabled(self.apu_logging, egui::Button::new("Stop Recording")).clicked() {
+                            ui.close_menu();
+                            self.send_command(EmulatorCommand::StopApuLog);
+                        }
+                    });
+
+                    ui.separator();
+                    ui.label("Channel Volume");
+                    for (channel, name) in CHANNEL_NAMES.iter().enumerate() {
+                        if ui
+                            .add(egui::Slider::new(&mut self.channel_volumes[channel], 0..=100).suffix("%").text(*name))
+                            .changed()
+                        {
+                            let volume = self.channel_volumes[channel] as f32 / 100.0;
+                            self.send_command(EmulatorCommand::SetChannelVolume { channel, volume });
+                        }
+                    }
+
+                    ui.separator();
+                    ui.label("Solo").on_hover_text("Ctrl+1 to Ctrl+5 in the game window; Ctrl+0 plays all channels");
+                    ui.horizontal_wrapped(|ui| {
+                        let mut changed = ui.radio_value(&mut self.solo, None, "Off").changed();
+                        for (channel, name) in CHANNEL_NAMES.iter().enumerate() {
+                            changed |= ui.radio_value(&mut self.solo, Some(channel), *name).changed();
+                        }
+                        if changed {
+                            self.send_command(EmulatorCommand::SetSolo(self.solo));
+                        }
+                    });
+                });
+
+                ui.menu_button("Netplay", |ui| {
+                    ui.label("Both players must load the same ROM first.");
+                    ui.separator();
+
+                    ui.horizontal(|ui| {
+                        ui.label("Port");
+                        ui.add(egui::TextEdit::singleline(&mut self.netplay_port).desired_width(60.0));
+                        if ui.add_enabled(is_running, egui::Button::new("Host")).clicked() {
+                            match self.netplay_port.trim().parse::<u16>() {
+                                Ok(port) => self.send_command(EmulatorCommand::NetplayHost(port)),
+                                Err(_) => self.netplay_status = Some("Invalid port".to_string()),
+                            }
+                        }
+                    });
+
+                    ui.horizontal(|ui| {
+                        ui.label("Address");
+                        ui.add(egui::TextEdit::singleline(&mut self.netplay_address).desired_width(140.0));
+                        if ui.add_enabled(is_running, egui::Button::new("Connect")).clicked() {
+                            self.send_command(EmulatorCommand::NetplayConnect(self.netplay_address.trim().to_string()));
+                        }
+                    });
+
+                    if ui.add_enabled(self.netplay_status.is_some(), egui::Button::new("Disconnect")).clicked() {
+                        self.send_command(EmulatorCommand::NetplayDisconnect);
+                    }
+
+                    if let Some(status) = &self.netplay_status {
+                        ui.label(status);
+                    }
+                });
+
+                ui.menu_button("Movie", |ui| {
+                    let movie_active = is_running && self.movie.is_some();
+                    match &self.movie {
+                        Some(status) => {
+                            let mode = match status.mode {
+                                MovieMode::Recording => "recording",
+                                MovieMode::Playback => "playing",
+                            };
+                            ui.label(format!("Frame {} of {} ({})", status.frame, status.len, mode));
+                            if let Some(frame) = status.desync {
+                                ui.colored_label(egui::Color32::RED, format!("Desynced at frame {}", frame));
+                            }
+                        }
+                        None => {
+                            ui.label("No movie");
+                        }
+                    }
+                    ui.separator();
+
+                    if ui.add_enabled(is_running, egui::Button::new("Record New")).clicked() {
+                        self.send_command(EmulatorCommand::MovieRecord);
+                    }
+                    if ui
+                        .add_enabled(is_running, egui::Button::new("Record FM2 From Power-On..."))
+                        .on_hover_text("Power-cycles the console; the file is written when the movie is stopped")
+                        .clicked()
+                    {
                         ui.close_menu();
+                        let result = FileDialog::new()
+                            .set_filename(&self.get_default_movie_path())
+                            .add_filter("FM2 Movie", &["fm2"])
+                            .show_save_single_file();
+
+                        if let Some(path) = result.ok().flatten().and_then(|path| path.to_str().map(str::to_string)) {
+                            self.send_command(EmulatorCommand::StartMovie { path, checksums: self.movie_checksums });
+                        }
+                    }
+                    ui.checkbox(&mut self.movie_checksums, "Embed State Checksums")
+                        .on_hover_text("Lets playback report the first frame where emulation diverges");
+                    if ui
+                        .add_enabled(is_running, egui::Button::new("Play Movie..."))
+                        .on_hover_text("Power-cycles the console and plays an FM2 movie made on this ROM")
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        let result = FileDialog::new()
+                            .add_filter("FM2 Movie", &["fm2"])
+                            .show_open_single_file();
+
+                        if let Some(path) = result.ok().flatten().and_then(|path| path.to_str().map(str::to_string)) {
+                            self.send_command(EmulatorCommand::PlayMovie(path));
+                        }
+                    }
+                    ui.separator();
+
+                    if ui.add_enabled(movie_active, egui::Button::new("Play From Here")).clicked() {
+                        self.send_command(EmulatorCommand::MoviePlay);
+                    }
+                    if ui.add_enabled(movie_active, egui::Button::new("Stop")).clicked() {
+                        self.send_command(EmulatorCommand::MovieStop);
+                    }
+
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Frame");
+                        ui.add(egui::DragValue::new(&mut self.movie_seek_frame));
+                        if ui.add_enabled(movie_active, egui::Button::new("Seek")).clicked() {
+                            self.send_command(EmulatorCommand::MovieSeek(self.movie_seek_frame));
+                        }
+                    });
+
+                    // Frame input editor: read a recorded frame, toggle buttons, write it back
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Edit frame");
+                        ui.add(egui::DragValue::new(&mut self.movie_edit_frame));
+                        if ui.add_enabled(movie_active, egui::Button::new("Read")).clicked() {
+                            self.send_command(EmulatorCommand::MovieGetInput(self.movie_edit_frame));
+                        }
+                    });
+                    for (player, input) in self.movie_edit_input.iter_mut().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.label(format!("P{}", player + 1));
+                            for (button, name) in MOVIE_BUTTONS {
+                                let mut pressed = *input & button.bits() != 0;
+                                if ui.checkbox(&mut pressed, name).changed() {
+                                    *input ^= button.bits();
+                                }
+                            }
+                        });
+                    }
+                    if ui.add_enabled(movie_active, egui::Button::new("Write")).clicked() {
+                        self.send_command(EmulatorCommand::MovieSetInput {
+                            frame: self.movie_edit_frame,
+                            input: self.movie_edit_input,
+                        });
                     }
                 });
-                
+
                 ui.menu_button("Debug", |ui| {
                     if ui.add_enabled(is_running, egui::Button::new("Pause")).clicked() {
-                        println!("GUI: Sending Pause command.");
+                        debug!("Sending Pause command.");
                         self.send_command(EmulatorCommand::Pause);
                         ui.close_menu();
                     }
 
                     ui.separator();
                     if ui.add_enabled(is_running, egui::Checkbox::new(&mut self.cpu_tracing_enabled, "Enable CPU Trace")).changed() {
-                        println!("GUI: Setting CPU Tracing to {}", self.cpu_tracing_enabled);
+                        debug!("Setting CPU tracing to {}", self.cpu_tracing_enabled);
                         self.send_command(EmulatorCommand::SetTracing(self.cpu_tracing_enabled));
                     }
+
+                    if ui
+                        .checkbox(&mut self.trace_history, "Keep Instruction History")
+                        .on_hover_text(format!(
+                            "Remembers the last {} instructions and prints them when a breakpoint hits or the game jams",
+                            TRACE_HISTORY_LEN
+                        ))
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetTraceHistory(self.trace_history));
+                    }
+
+                    if ui.add_enabled(is_running, egui::Checkbox::new(&mut self.ppu_logging_enabled, "Log PPU Register Access")).changed() {
+                        self.send_command(EmulatorCommand::SetPpuLogging(self.ppu_logging_enabled));
+                    }
+
+                    if ui.add_enabled(is_running, egui::Checkbox::new(&mut self.perf_overlay_enabled, "Performance Overlay (F3)")).changed() {
+                        self.send_command(EmulatorCommand::SetPerfOverlay(self.perf_overlay_enabled));
+                    }
+
+                    if ui
+                        .checkbox(&mut self.break_on_load, "Break on Load")
+                        .on_hover_text("Pause newly loaded games at the reset vector, before their first instruction")
+                        .changed()
+                    {
+                        self.send_command(EmulatorCommand::SetBreakOnLoad(self.break_on_load));
+                    }
+
+                    ui.menu_button("Layers", |ui| {
+                        let mut changed = false;
+                        for (name, layer) in [("Background", &mut self.background_layer), ("Sprites", &mut self.sprite_layer)] {
+                            ui.label(name);
+                            ui.horizontal(|ui| {
+                                changed |= ui.radio_value(layer, None, "Game").changed();
+                                changed |= ui.radio_value(layer, Some(true), "Show").changed();
+                                changed |= ui.radio_value(layer, Some(false), "Hide").changed();
+                            });
+                        }
+                        if changed {
+                            self.send_command(EmulatorCommand::SetLayerVisibility {
+                                background: self.background_layer,
+                                sprites: self.sprite_layer,
+                            });
+                        }
+                    })
+                    .response
+                    .on_hover_text("Draw or hide each layer regardless of what the game writes to $2001");
+
+                    if ui.button("APU Viewer...").clicked() {
+                        self.show_apu_viewer = true;
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Audio Spectrum...").clicked() {
+                        self.show_spectrum = true;
+                        ui.close_menu();
+                    }
+
+                    if ui.checkbox(&mut self.vsync_enabled, "VSync").changed() {
+                        self.send_command(EmulatorCommand::SetVsync(self.vsync_enabled));
+                    }
+
+                    if ui.checkbox(&mut self.frame_limit_enabled, "Frame Limiter").changed() {
+                        self.send_command(EmulatorCommand::SetFrameLimit(self.frame_limit_enabled));
+                    }
+
+                    if ui.checkbox(&mut self.pause_on_focus_loss, "Pause on Focus Loss").changed() {
+                        self.send_command(EmulatorCommand::SetPauseOnFocusLoss(self.pause_on_focus_loss));
+                    }
+
+                    ui.separator();
+
+                    if ui.add_enabled(is_running, egui::Button::new("Export Nametables PNG...")).clicked() {
+                        ui.close_menu();
+                        let result = FileDialog::new()
+                            .set_filename("nametables.png")
+                            .add_filter("PNG Image", &["png"])
+                            .show_save_single_file();
+
+                        if let Ok(Some(path)) = result {
+                            self.send_command(EmulatorCommand::ExportNametablePng(path.to_string_lossy().into_owned()));
+                        }
+                    }
+
+                    if ui.add_enabled(is_running, egui::Button::new("Export Palette PNG...")).clicked() {
+                        ui.close_menu();
+                        let result = FileDialog::new()
+                            .set_filename("palette.png")
+                            .add_filter("PNG Image", &["png"])
+                            .show_save_single_file();
+
+                        if let Ok(Some(path)) = result {
+                            self.send_command(EmulatorCommand::ExportPalettePng(path.to_string_lossy().into_owned()));
+                        }
+                    }
+
+                    ui.separator();
+
+                    if ui
+                        .button("Compare Save States...")
+                        .on_hover_text("Lists the registers and memory that differ between two state files")
+                        .clicked()
+                    {
+                        ui.close_menu();
+                        self.compare_state_files();
+                    }
+                });
+
+                ui.menu_button("Help", |ui| {
+                    if ui.button("Controls (F1)").clicked() {
+                        self.show_controls = true;
+                        ui.close_menu();
+                    }
                 });
             });
         });
 
+        // Typing in a text field must not trigger hotkeys, here or in the game window
+        let wants_keyboard = ctx.wants_keyboard_input();
+        if wants_keyboard != self.hotkeys_suppressed {
+            self.hotkeys_suppressed = wants_keyboard;
+            self.send_command(EmulatorCommand::SetHotkeysSuppressed(wants_keyboard));
+        }
+
+        if !wants_keyboard
+            && self.rebinding.is_none()
+            && self.bindings.hotkey_enabled(Action::ShowControls)
+            && ctx.input(|i| i.key_pressed(egui::Key::F1))
+        {
+            self.show_controls = true;
+        }
+
+        let bindings = &mut self.bindings;
+        let settings = &mut self.settings;
+        let mut bindings_changed = false;
+        let mut stick_changed = false;
+        egui::Window::new("Controls")
+            .open(&mut self.show_controls)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Keys work in the game window.");
+                for category in Category::ALL {
+                    ui.separator();
+                    ui.strong(category.name());
+                    egui::Grid::new(category.name()).num_columns(2).show(ui, |ui| {
+                        for (action, key) in bindings.describe(category) {
+                            ui.label(action);
+                            ui.monospace(key);
+                            ui.end_row();
+                        }
+                    });
+                }
+                ui.separator();
+                ui.collapsing("Analog stick (controller 1 d-pad)", |ui| {
+                    stick_changed |= ui
+                        .add(egui::Slider::new(&mut settings.stick_deadzone, 0.0..=0.9).text("deadzone"))
+                        .changed();
+                    stick_changed |= ui
+                        .add(egui::Slider::new(&mut settings.stick_sensitivity, 0.5..=2.0).text("sensitivity"))
+                        .changed();
+                    stick_changed |= ui
+                        .checkbox(&mut settings.stick_eight_way, "Diagonals (8-way)")
+                        .on_hover_text("Off limits the stick to up, down, left and right")
+                        .changed();
+                });
+                ui.collapsing("Enabled hotkeys", |ui| {
+                    let hotkeys: Vec<_> = bindings.hotkeys().iter().map(|h| (h.action, h.enabled)).collect();
+                    for (action, mut enabled) in hotkeys {
+                        if ui.checkbox(&mut enabled, action.name()).changed() {
+                            bindings.set_hotkey_enabled(action, enabled);
+                            bindings_changed = true;
+                        }
+                    }
+                });
+                if ui.button("Copy to Clipboard").clicked() {
+                    ui.output_mut(|o| o.copied_text = bindings.to_text());
+                }
+            });
+
+        // The next key pressed while a button is waiting becomes its binding; Escape cancels
+        if let Some(action) = self.rebinding {
+            let pressed = ctx.input(|i| {
+                i.events.iter().find_map(|event| match event {
+                    egui::Event::Key { key, pressed: true, repeat: false, .. } => Some(*key),
+                    _ => None,
+                })
+            });
+            match pressed {
+                Some(egui::Key::Escape) => self.rebinding = None,
+                Some(key) => match sdl_keycode(key) {
+                    Some(keycode) => {
+                        self.bindings.set_key(action, keycode);
+                        self.rebinding = None;
+                        bindings_changed = true;
+                    }
+                    None => debug!("{:?} has no SDL key code and cannot be bound", key),
+                },
+                None => {}
+            }
+        }
+
+        let bindings = &mut self.bindings;
+        let rebinding = &mut self.rebinding;
+        egui::Window::new("Configure Controls")
+            .open(&mut self.show_configure_controls)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Click a button, then press the key to use for it. Escape cancels.");
+                for category in [Category::Controller1, Category::Controller2, Category::Turbo] {
+                    ui.separator();
+                    ui.strong(category.name());
+                    egui::Grid::new(("configure", category.name())).num_columns(2).show(ui, |ui| {
+                        for (action, key) in bindings.keys(category) {
+                            ui.label(action.name());
+                            let text = if *rebinding == Some(action) { "Press a key...".to_string() } else { key.name() };
+                            if ui.add(egui::Button::new(egui::RichText::new(text).monospace()).min_size(egui::vec2(110.0, 0.0))).clicked() {
+                                *rebinding = Some(action);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                }
+                ui.separator();
+                if ui.button("Reset to Defaults").clicked() {
+                    bindings.reset_keys();
+                    *rebinding = None;
+                    bindings_changed = true;
+                }
+            });
+        if !self.show_configure_controls {
+            self.rebinding = None;
+        }
+
+        if let Some((left, right, diff)) = &self.state_diff {
+            egui::Window::new("State Diff")
+                .open(&mut self.show_state_diff)
+                .default_height(400.0)
+                .show(ctx, |ui| {
+                    ui.label(format!("{} -> {}", left, right));
+                    if ui.button("Copy to Clipboard").clicked() {
+                        ui.output_mut(|o| o.copied_text = diff.to_string());
+                    }
+                    ui.separator();
+                    if diff.is_empty() {
+                        ui.label("The states are identical.");
+                        return;
+                    }
+                    egui::ScrollArea::vertical().show(ui, |ui| {
+                        show_state_diff(ui, diff);
+                    });
+                });
+        }
+
+        self.debugger_window(ctx);
+
+        egui::Window::new("APU Viewer")
+            .open(&mut self.show_apu_viewer)
+            .resizable(false)
+            .show(ctx, |ui| match &self.apu_info {
+                Some(info) if self.game_running => show_apu_info(ui, info),
+                _ => {
+                    ui.label("Waiting for a running game...");
+                }
+            });
+        if self.show_apu_viewer != self.apu_viewer_active {
+            self.apu_viewer_active = self.show_apu_viewer;
+            self.send_command(EmulatorCommand::SetApuViewer(self.apu_viewer_active));
+        }
+        if self.show_apu_viewer {
+            ctx.request_repaint_after(APU_VIEWER_REFRESH);
+        }
+
+        egui::Window::new("Audio Spectrum")
+            .open(&mut self.show_spectrum)
+            .resizable(false)
+            .show(ctx, |ui| match &self.spectrum {
+                Some((bins, sample_rate)) if self.game_running && !bins.is_empty() => {
+                    show_spectrum(ui, bins, *sample_rate)
+                }
+                _ => {
+                    ui.label("Waiting for a running game...");
+                }
+            });
+        if self.show_spectrum != self.spectrum_active {
+            self.spectrum_active = self.show_spectrum;
+            self.send_command(EmulatorCommand::SetSpectrumViewer(self.spectrum_active));
+            if !self.spectrum_active {
+                self.spectrum = None;
+            }
+        }
+        if self.show_spectrum {
+            ctx.request_repaint_after(SPECTRUM_REFRESH);
+        }
+
+        if bindings_changed {
+            self.send_command(EmulatorCommand::SetBindings(self.bindings.clone()));
+        }
+        if stick_changed {
+            self.apply_analog_stick();
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.label("JazzNess Emulator");
             ui.separator();
             ui.label("Load a ROM using File > Open ROM...");
+
+            if let Some(status) = self.movie.filter(|status| status.mode == MovieMode::Playback) {
+                ui.separator();
+                let progress = if status.len == 0 { 0.0 } else { status.frame as f32 / status.len as f32 };
+                ui.add(egui::ProgressBar::new(progress).text(format!("Movie frame {} of {}", status.frame, status.len)));
+                if let Some(frame) = status.desync {
+                    ui.colored_label(egui::Color32::RED, format!("Desynced at frame {}", frame));
+                }
+                ctx.request_repaint_after(Duration::from_secs(1));
+            }
+
+            if let Some(nsf) = &self.nsf {
+                ui.separator();
+                if !nsf.title.is_empty() {
+                    ui.heading(&nsf.title);
+                }
+                let mut song = None;
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(nsf.song > 0, egui::Button::new("<")).clicked() {
+                        song = Some(nsf.song - 1);
+                    }
+                    ui.label(format!("Song {} of {}", nsf.song + 1, nsf.songs));
+                    if ui.add_enabled(nsf.song + 1 < nsf.songs, egui::Button::new(">")).clicked() {
+                        song = Some(nsf.song + 1);
+                    }
+                    if ui.button("Restart").clicked() {
+                        song = Some(nsf.song);
+                    }
+                    let seconds = nsf.elapsed.as_secs();
+                    ui.monospace(format!("{}:{:02}", seconds / 60, seconds % 60));
+                });
+                if let Some(song) = song {
+                    self.send_command(EmulatorCommand::NsfSelectSong(song));
+                    // Show the new song straight away rather than after the next status
+                    if let Some(nsf) = &mut self.nsf {
+                        nsf.song = song;
+                        nsf.elapsed = Duration::ZERO;
+                    }
+                }
+                ctx.request_repaint_after(Duration::from_secs(1));
+            }
+
+            if let Some(stats) = &self.stats {
+                egui::CollapsingHeader::new("Statistics").show(ui, |ui| {
+                    show_stats(ui, stats);
+                });
+                // Stats arrive once a second; repaint so they stay live without input
+                ctx.request_repaint_after(Duration::from_secs(1));
+            }
         });
     }
 
@@ -257,7 +1738,287 @@ impl eframe::App for JazzNessApp {
     }
 }
 
+// How often the Debugger window asks a running game for its state
+const DEBUGGER_REFRESH: Duration = Duration::from_millis(250);
+// Bytes shown by the Debugger's memory view, 16 per row
+const DEBUGGER_MEMORY_BYTES: u16 = 256;
+// The emulator sends APU state ten times a second while the viewer is open
+const APU_VIEWER_REFRESH: Duration = Duration::from_millis(100);
+// ...and its recent output four times a second while the spectrum is open
+const SPECTRUM_REFRESH: Duration = Duration::from_millis(250);
+// Frequency and level ranges drawn by the spectrum view
+const SPECTRUM_MIN_HZ: f32 = 20.0;
+const SPECTRUM_MIN_DB: f32 = -90.0;
+
+const MOVIE_BUTTONS: [(JoypadButton, &str); 8] = [
+    (JoypadButton::BUTTON_A, "A"),
+    (JoypadButton::BUTTON_B, "B"),
+    (JoypadButton::SELECT, "Sel"),
+    (JoypadButton::START, "St"),
+    (JoypadButton::UP, "U"),
+    (JoypadButton::DOWN, "D"),
+    (JoypadButton::LEFT, "L"),
+    (JoypadButton::RIGHT, "R"),
+];
+
+// SDL key for an egui key, which mostly share names. Modifier keys never reach egui
+// as key events, so they cannot be bound from the GUI.
+fn sdl_keycode(key: egui::Key) -> Option<Keycode> {
+    let name = match key {
+        egui::Key::Enter => "Return",
+        egui::Key::Comma => ",",
+        egui::Key::Minus => "-",
+        egui::Key::Period => ".",
+        egui::Key::Equals => "=",
+        egui::Key::Semicolon => ";",
+        egui::Key::Backslash => "\\",
+        egui::Key::Slash => "/",
+        egui::Key::OpenBracket => "[",
+        egui::Key::CloseBracket => "]",
+        egui::Key::Backtick => "`",
+        other => other.name(),
+    };
+    Keycode::from_name(name)
+}
+
+fn show_state_diff(ui: &mut egui::Ui, diff: &StateDiff) {
+    egui::CollapsingHeader::new(format!("Registers ({})", diff.registers.len()))
+        .default_open(true)
+        .show(ui, |ui| {
+            egui::Grid::new("state_diff_registers").num_columns(3).striped(true).show(ui, |ui| {
+                for reg in &diff.registers {
+                    ui.label(reg.name);
+                    ui.monospace(&reg.left);
+                    ui.monospace(&reg.right);
+                    ui.end_row();
+                }
+            });
+        });
+    for (name, region) in diff.regions() {
+        if region.is_empty() {
+            continue;
+        }
+        egui::CollapsingHeader::new(format!("{} ({})", name, region.bytes.len())).show(ui, |ui| {
+            if let Some((left, right)) = region.size_mismatch {
+                ui.colored_label(egui::Color32::RED, format!("Size differs: {} vs {} bytes", left, right));
+            }
+            egui::Grid::new(("state_diff", name)).num_columns(3).striped(true).show(ui, |ui| {
+                for byte in &region.bytes {
+                    ui.monospace(format!("${:04X}", byte.addr));
+                    ui.monospace(format!("{:02X}", byte.left));
+                    ui.monospace(format!("{:02X}", byte.right));
+                    ui.end_row();
+                }
+            });
+        });
+    }
+}
+
+// "$8000", "0x8000" or "8000"
+fn parse_hex_address(text: &str) -> Option<u16> {
+    let text = text.trim();
+    let digits = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn parse_hex_byte(text: &str) -> Option<u8> {
+    parse_hex_address(text).and_then(|value| u8::try_from(value).ok())
+}
+
+fn show_cpu_registers(ui: &mut egui::Ui, registers: &CpuSnapshot) {
+    // Set flags show their letter, clear ones a dot
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, name)| if registers.status & (0x80 >> i) != 0 { name } else { '.' })
+        .collect();
+
+    egui::Grid::new("debugger_registers").num_columns(8).show(ui, |ui| {
+        ui.label("PC");
+        ui.monospace(format!("${:04X}", registers.program_counter));
+        ui.label("A");
+        ui.monospace(format!("${:02X}", registers.register_a));
+        ui.label("X");
+        ui.monospace(format!("${:02X}", registers.register_x));
+        ui.label("Y");
+        ui.monospace(format!("${:02X}", registers.register_y));
+        ui.end_row();
+        ui.label("SP");
+        ui.monospace(format!("${:02X}", registers.stack_pointer));
+        ui.label("P");
+        ui.monospace(format!("${:02X} {}", registers.status, flags));
+        ui.label("Scanline");
+        ui.monospace(registers.scanline.to_string());
+        ui.label("Frame");
+        ui.monospace(registers.frames.to_string());
+        ui.end_row();
+        ui.label("Cycle");
+        ui.monospace(registers.cpu_cycles.to_string());
+        ui.label("Dot");
+        ui.monospace(registers.dot.to_string());
+        ui.end_row();
+    });
+}
+
+fn show_memory(ui: &mut egui::Ui, start: u16, bytes: &[u8]) {
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        let addr = start.wrapping_add(row as u16 * 16);
+        ui.monospace(format!("{:04X}  {}  {}", addr, hex.join(" "), ascii));
+    }
+}
+
+// Levels against a logarithmic frequency axis, with the loudest bin called out so a
+// channel's pitch can be checked against the note it should play
+fn show_spectrum(ui: &mut egui::Ui, bins: &[f32], sample_rate: u32) {
+    let bin_hz = sample_rate as f32 / (bins.len() * 2) as f32;
+    let max_hz = sample_rate as f32 / 2.0;
+    let (response, painter) = ui.allocate_painter(egui::vec2(512.0, 200.0), egui::Sense::hover());
+    let rect = response.rect;
+    painter.rect_filled(rect, 0.0, egui::Color32::BLACK);
+
+    let x_of = |hz: f32| {
+        let t = (hz / SPECTRUM_MIN_HZ).ln() / (max_hz / SPECTRUM_MIN_HZ).ln();
+        rect.left() + t * rect.width()
+    };
+    let y_of = |db: f32| rect.top() + (db / SPECTRUM_MIN_DB).clamp(0.0, 1.0) * rect.height();
+
+    let grid = egui::Stroke::new(1.0, egui::Color32::from_gray(60));
+    for hz in [100.0, 1000.0, 10000.0] {
+        let x = x_of(hz);
+        painter.line_segment([egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())], grid);
+        painter.text(
+            egui::pos2(x + 2.0, rect.bottom() - 2.0),
+            egui::Align2::LEFT_BOTTOM,
+            format!("{} Hz", hz),
+            egui::FontId::monospace(10.0),
+            egui::Color32::GRAY,
+        );
+    }
+
+    let points: Vec<egui::Pos2> = bins
+        .iter()
+        .enumerate()
+        .skip_while(|(k, _)| (*k as f32) * bin_hz < SPECTRUM_MIN_HZ)
+        .map(|(k, &db)| egui::pos2(x_of(k as f32 * bin_hz), y_of(db)))
+        .collect();
+    painter.add(egui::Shape::line(points, egui::Stroke::new(1.0, egui::Color32::LIGHT_GREEN)));
+
+    let (peak, peak_db) = bins
+        .iter()
+        .enumerate()
+        .skip(1)
+        .fold((0, f32::MIN), |best, (k, &db)| if db > best.1 { (k, db) } else { best });
+    ui.label(format!(
+        "Peak {:.0} Hz at {:.1} dB; {:.1} Hz per bin",
+        peak as f32 * bin_hz,
+        peak_db,
+        bin_hz
+    ));
+}
+
+fn show_apu_info(ui: &mut egui::Ui, info: &ApuDebugInfo) {
+    egui::Grid::new("apu_viewer").num_columns(9).striped(true).show(ui, |ui| {
+        for heading in ["Channel", "On", "Period", "Timer", "Length", "Volume", "Duty", "Sweep", "Output"] {
+            ui.strong(heading);
+        }
+        ui.end_row();
+        for (channel, (name, ch)) in CHANNEL_NAMES.iter().zip(&info.channels).enumerate() {
+            // The DMC's 7-bit level against the 4-bit level of the others
+            let max_output = if channel == 4 { 127.0 } else { 15.0 };
+            ui.label(*name);
+            ui.label(if ch.enabled { "Yes" } else { "No" });
+            ui.monospace(ch.period.to_string());
+            ui.monospace(ch.timer.to_string());
+            ui.monospace(ch.length_counter.to_string());
+            ui.monospace(ch.volume.to_string());
+            ui.monospace(ch.duty.to_string());
+            ui.monospace(ch.sweep_target.to_string());
+            ui.add(egui::ProgressBar::new(ch.output as f32 / max_output).desired_width(80.0).text(ch.output.to_string()));
+            ui.end_row();
+        }
+    });
+    ui.separator();
+    ui.label(format!(
+        "DMC: {} bytes left at ${:04X}{}",
+        info.dmc_bytes_remaining,
+        info.dmc_address,
+        if info.dmc_interrupt { ", IRQ" } else { "" }
+    ));
+    ui.label(format!(
+        "Frame counter: {}-step, cycle {}{}",
+        info.frame_counter_steps,
+        info.frame_counter_cycle,
+        if info.frame_interrupt { ", IRQ" } else { "" }
+    ));
+}
+
+fn show_stats(ui: &mut egui::Ui, stats: &EmulatorStats) {
+    let turbo_buttons = |buttons: JoypadButton| {
+        let names: Vec<&str> = [(JoypadButton::BUTTON_A, "A"), (JoypadButton::BUTTON_B, "B")]
+            .iter()
+            .filter(|(button, _)| buttons.contains(*button))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() { "-".to_string() } else { names.join(" ") }
+    };
+
+    egui::Grid::new("stats_grid").num_columns(2).show(ui, |ui| {
+        ui.label("Frames");
+        ui.label(stats.frames.to_string());
+        ui.end_row();
+        ui.label("CPU cycles");
+        ui.label(stats.cpu_cycles.to_string());
+        ui.end_row();
+        ui.label("Scanline");
+        ui.label(stats.scanline.to_string());
+        ui.end_row();
+        ui.label("Audio queue");
+        ui.label(format!("{} samples", stats.audio_queue_samples));
+        ui.end_row();
+        ui.label("Audio latency");
+        ui.label(format!("{:.0} ms", stats.audio_latency.as_secs_f64() * 1000.0));
+        ui.end_row();
+        ui.label("Dropped frames");
+        ui.label(stats.dropped_frames.to_string());
+        ui.end_row();
+        ui.label("Audio queue last second");
+        ui.label(format!("{}-{} samples", stats.audio_queue_stats.min_samples, stats.audio_queue_stats.max_samples));
+        ui.end_row();
+        ui.label("Audio underruns");
+        ui.label(format!(
+            "{} ({} last second)",
+            stats.audio_queue_stats.underruns, stats.audio_queue_stats.window_underruns
+        ));
+        ui.end_row();
+        ui.label("Audio resyncs");
+        ui.label(stats.audio_resyncs.to_string());
+        ui.end_row();
+        ui.label("Audio rate");
+        ui.label(format!("{:+.2}%", (stats.audio_rate - 1.0) * 100.0));
+        ui.end_row();
+        ui.label("State");
+        ui.label(if stats.paused { "Paused" } else { "Running" });
+        ui.end_row();
+        ui.label("Turbo held / sticky");
+        ui.label(format!("{} / {}", turbo_buttons(stats.turbo_held), turbo_buttons(stats.turbo_sticky)));
+        ui.end_row();
+        ui.label("Cheats");
+        ui.label(stats.cheats.to_string());
+        ui.end_row();
+    });
+}
+
 fn main() {
+    // RUST_LOG overrides this; release builds only report problems unless asked. PPU
+    // register logging is only on when picked from the Debug menu, so it always shows.
+    let default_filter = if cfg!(debug_assertions) { "info" } else { "warn,ppu=info" };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter)).init();
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size(egui::vec2(320.0, 240.0)),
@@ -0,0 +1,96 @@
+// src/mapper.rs
+
+use serde::{Deserialize, Serialize};
+
+use crate::cartridge::Rom;
+use crate::nsf::{NsfBoard, NsfBoardState};
+
+/// A board's registers and RAM, saved with the rest of the machine.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MapperState {
+    /// Boards with nothing to save, such as NROM.
+    None,
+    Nsf(NsfBoardState),
+}
+
+/// Cartridge hardware as seen from the CPU. The bus forwards every access in
+/// 0x4020-0xFFFF here, so bank-switching registers see the writes games make.
+pub trait Mapper {
+    fn read(&self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Called at dot 260 of each visible scanline and of the pre-render line while
+    /// rendering is on, the point where MMC3-style counters see PPU A12 rise.
+    fn notify_scanline(&mut self, _scanline: u16) {}
+
+    /// Called as the CPU runs, with the cycles elapsed since the last call, for boards
+    /// such as the FME-7 and the VRCs whose IRQ counters count CPU (M2) cycles.
+    fn notify_cpu_cycles(&mut self, _cycles: usize) {}
+
+    /// Level of the cartridge's IRQ line. Boards acknowledge their IRQ through their own
+    /// registers, so the bus polls this after every step rather than taking it.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// The board's registers and RAM, for save states, rewind and run-ahead.
+    fn save_state(&self) -> MapperState {
+        MapperState::None
+    }
+
+    /// Checks a state from `save_state` against this board, so that `load_state` can
+    /// apply it without failing halfway.
+    fn validate_state(&self, state: &MapperState) -> Result<(), String> {
+        match state {
+            MapperState::None => Ok(()),
+            _ => Err("State was saved from a different cartridge board".to_string()),
+        }
+    }
+
+    fn load_state(&mut self, _state: &MapperState) {}
+}
+
+/// Creates the mapper named in the cartridge header, or the player board for an NSF.
+/// `Rom::new` already rejects mapper numbers that have no implementation here.
+pub fn new_mapper(rom: &Rom) -> Box<dyn Mapper> {
+    if let Some(header) = &rom.nsf {
+        return Box::new(NsfBoard::new(header, rom.prg_rom.clone()));
+    }
+    match rom.mapper {
+        0 => Box::new(Nrom::new(rom.prg_rom.clone())),
+        mapper => panic!("Mapper {} is not supported", mapper),
+    }
+}
+
+/// Mapper 0: up to 32 KiB of PRG ROM at 0x8000, no registers and no PRG RAM.
+/// Images smaller than 32 KiB are mirrored through the whole window.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Nrom { prg_rom }
+    }
+}
+
+impl Mapper for Nrom {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0xFFFF => {
+                // A 16 KiB bank shows up again at 0xC000-0xFFFF, and odd homebrew sizes wrap
+                // the same way. An empty image reads as open bus rather than panicking.
+                let offset = (addr - 0x8000) as usize;
+                match self.prg_rom.len() {
+                    0 => 0,
+                    len => self.prg_rom[offset % len],
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _addr: u16, _data: u8) {
+        // Nothing on an NROM board listens to writes
+    }
+}
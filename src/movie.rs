@@ -0,0 +1,421 @@
+// src/movie.rs
+
+use std::collections::BTreeMap;
+
+use crate::cartridge::Rom;
+use crate::cpu::{CPU, EmulatorSnapshot};
+use crate::joypad::JoypadButton;
+use crate::netplay::state_crc;
+
+/// A save state is kept at every frame that is a multiple of this, so seeking never
+/// replays more than this many frames.
+pub const KEYFRAME_INTERVAL: usize = 60;
+/// Frames between state checksums when a recording embeds them.
+pub const CHECKSUM_INTERVAL: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovieMode {
+    /// Live input is written into the movie, replacing anything after the current frame.
+    Recording,
+    /// Input comes from the movie; live input is ignored.
+    Playback,
+}
+
+/// Button letters of an FM2 input field, from bit 7 down to bit 0 of `JoypadButton`.
+const FM2_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+/// FM2 comment that carries one embedded state checksum: `comment <tag> <frame> <crc>`.
+const FM2_CHECKSUM_TAG: &str = "jazzness-checksum";
+
+/// What an FM2 file records about the cartridge a movie was made on.
+pub struct Fm2Header {
+    pub rom_filename: String,
+    /// MD5 of the PRG and CHR ROM data, from `rom_checksum`.
+    pub rom_checksum: [u8; 16],
+}
+
+/// Per-frame input log for both controllers, with keyframe states for seeking.
+///
+/// Frame `n` is the `n`th frame emulated since the movie started, and its keyframe is
+/// the state right before that frame's input is applied.
+///
+/// A movie can also carry state checksums. While recording with checksums on, one is
+/// taken every `CHECKSUM_INTERVAL` frames at the same point as the keyframes; playback
+/// compares against them and remembers the first frame that differs.
+pub struct Movie {
+    inputs: Vec<[u8; 2]>,
+    keyframes: BTreeMap<usize, EmulatorSnapshot>,
+    checksums: BTreeMap<usize, u32>,
+    record_checksums: bool,
+    desync: Option<usize>,
+    frame: usize,
+    mode: MovieMode,
+}
+
+impl Movie {
+    /// Creates an empty movie in recording mode. Frame 0 starts at the first
+    /// `next_frame` call, which should come at a frame boundary.
+    pub fn new() -> Self {
+        Movie {
+            inputs: Vec::new(),
+            keyframes: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            record_checksums: false,
+            desync: None,
+            frame: 0,
+            mode: MovieMode::Recording,
+        }
+    }
+
+    /// The frame that will be played next.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Number of frames of recorded input.
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MovieMode) {
+        self.mode = mode;
+    }
+
+    /// Whether recording takes a state checksum every `CHECKSUM_INTERVAL` frames.
+    pub fn set_record_checksums(&mut self, enabled: bool) {
+        self.record_checksums = enabled;
+    }
+
+    /// First frame whose state did not match the checksum stored in the movie.
+    pub fn desync(&self) -> Option<usize> {
+        self.desync
+    }
+
+    /// Call at the start of every frame. While recording, `live` is stored for this
+    /// frame and any later input is discarded; during playback the recorded input is
+    /// returned instead. Returns None once playback runs past the end of the movie.
+    pub fn next_frame(&mut self, cpu: &CPU, live: [u8; 2]) -> Option<[u8; 2]> {
+        let input = match self.mode {
+            MovieMode::Recording => {
+                self.truncate(self.frame);
+                self.inputs.push(live);
+                if self.record_checksums && self.frame.is_multiple_of(CHECKSUM_INTERVAL) {
+                    self.checksums.insert(self.frame, state_crc(cpu));
+                }
+                live
+            }
+            MovieMode::Playback => {
+                let input = *self.inputs.get(self.frame)?;
+                if self.desync.is_none() {
+                    let expected = self.checksums.get(&self.frame);
+                    if expected.is_some_and(|&crc| crc != state_crc(cpu)) {
+                        self.desync = Some(self.frame);
+                    }
+                }
+                input
+            }
+        };
+
+        self.capture_keyframe(cpu);
+        self.frame += 1;
+        Some(input)
+    }
+
+    /// Recorded (controller 1, controller 2) input for `frame`.
+    pub fn input(&self, frame: usize) -> Option<[u8; 2]> {
+        self.inputs.get(frame).copied()
+    }
+
+    /// Replaces the input of an already recorded frame. Keyframes after it no longer
+    /// match the movie and are dropped; if `frame` is behind the current position the
+    /// caller should `seek` back to the current frame to bring the console in line.
+    pub fn set_input(&mut self, frame: usize, input: [u8; 2]) -> Result<(), String> {
+        let len = self.len();
+        let slot = self
+            .inputs
+            .get_mut(frame)
+            .ok_or_else(|| format!("Frame {} is past the end of the movie ({} frames)", frame, len))?;
+        *slot = input;
+        self.keyframes.split_off(&(frame + 1));
+        self.checksums.split_off(&(frame + 1));
+        Ok(())
+    }
+
+    /// Puts `cpu` in the state it had at the start of `frame` by restoring the nearest
+    /// earlier keyframe and replaying the recorded input from there. The console's
+    /// frame callback runs for every replayed frame, so the caller should mute it.
+    pub fn seek(&mut self, cpu: &mut CPU, frame: usize) -> Result<(), String> {
+        if frame > self.len() {
+            return Err(format!("Frame {} is past the end of the movie ({} frames)", frame, self.len()));
+        }
+
+        let (&start, snapshot) = self
+            .keyframes
+            .range(..=frame)
+            .next_back()
+            .ok_or("Movie has no keyframe to seek from")?;
+        cpu.load_snapshot(snapshot);
+
+        for current in start..frame {
+            self.frame = current;
+            self.capture_keyframe(cpu);
+            let [player1, player2] = self.inputs[current];
+            cpu.bus.joypad1.set_buttons(JoypadButton::from_bits_truncate(player1));
+            cpu.bus.joypad2.set_buttons(JoypadButton::from_bits_truncate(player2));
+
+            let target = cpu.bus.frame_count() + 1;
+            while cpu.bus.frame_count() < target {
+                cpu.step();
+            }
+        }
+        self.frame = frame;
+        Ok(())
+    }
+
+    /// Writes the movie as FCEUX FM2 text, with both controllers on the standard ports.
+    /// FM2 marks a movie that starts from power-on by leaving out the `savestate` key,
+    /// so only movies recorded from a fresh power-on should be written this way.
+    pub fn to_fm2(&self, header: &Fm2Header) -> String {
+        let mut text = String::new();
+        text.push_str("version 3\n");
+        text.push_str(&format!("emuVersion {}\n", emu_version()));
+        text.push_str("rerecordCount 0\n");
+        text.push_str("palFlag 0\n");
+        text.push_str(&format!("romFilename {}\n", header.rom_filename));
+        text.push_str(&format!("romChecksum base64:{}\n", base64(&header.rom_checksum)));
+        text.push_str(&format!("guid {}\n", new_guid()));
+        text.push_str("fourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\nFDS 0\nNewPPU 0\n");
+        text.push_str(&format!("comment Recorded with JazzNess {}\n", env!("CARGO_PKG_VERSION")));
+        for (frame, crc) in &self.checksums {
+            text.push_str(&format!("comment {} {} {:08X}\n", FM2_CHECKSUM_TAG, frame, crc));
+        }
+
+        for [player1, player2] in &self.inputs {
+            text.push_str(&format!("|0|{}|{}||\n", fm2_buttons(*player1), fm2_buttons(*player2)));
+        }
+        text
+    }
+
+    /// Reads an FM2 movie made from power-on with standard controllers, ready for
+    /// playback from frame 0. Checksums embedded by `to_fm2` are picked up as well.
+    pub fn from_fm2(text: &str) -> Result<(Movie, Fm2Header), String> {
+        let mut movie = Movie::new();
+        movie.mode = MovieMode::Playback;
+        let mut rom_filename = String::new();
+        let mut rom_checksum = None;
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if let Some(fields) = line.strip_prefix('|') {
+                movie.inputs.push(parse_fm2_input(fields).ok_or_else(|| {
+                    format!("Line {}: cannot read input \"{}\"", line_number + 1, line)
+                })?);
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "romFilename" => rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let digest = value
+                        .strip_prefix("base64:")
+                        .and_then(decode_base64)
+                        .and_then(|bytes| <[u8; 16]>::try_from(bytes).ok())
+                        .ok_or_else(|| format!("Unreadable romChecksum \"{}\"", value))?;
+                    rom_checksum = Some(digest);
+                }
+                "savestate" => return Err("Movies that start from a savestate are not supported".to_string()),
+                "fourscore" | "port2" | "FDS" if value != "0" => {
+                    return Err(format!("Movies using {} are not supported", key));
+                }
+                "comment" => {
+                    let mut words = value.split_whitespace();
+                    if words.next() == Some(FM2_CHECKSUM_TAG) {
+                        let frame = words.next().and_then(|w| w.parse().ok());
+                        let crc = words.next().and_then(|w| u32::from_str_radix(w, 16).ok());
+                        if let (Some(frame), Some(crc)) = (frame, crc) {
+                            movie.checksums.insert(frame, crc);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let rom_checksum = rom_checksum.ok_or("Movie has no romChecksum")?;
+        Ok((movie, Fm2Header { rom_filename, rom_checksum }))
+    }
+
+    fn capture_keyframe(&mut self, cpu: &CPU) {
+        if self.frame.is_multiple_of(KEYFRAME_INTERVAL) {
+            self.keyframes.entry(self.frame).or_insert_with(|| cpu.save_snapshot());
+        }
+    }
+
+    fn truncate(&mut self, frame: usize) {
+        if frame < self.inputs.len() {
+            self.inputs.truncate(frame);
+            self.keyframes.split_off(&(frame + 1));
+            self.checksums.split_off(&frame);
+        }
+    }
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checksum FM2 files use to identify the cartridge: MD5 over the PRG ROM and,
+/// when the board has one, the CHR ROM.
+pub fn rom_checksum(rom: &Rom) -> [u8; 16] {
+    let mut data = rom.prg_rom.clone();
+    if !rom.chr_is_ram {
+        data.extend_from_slice(&rom.chr_rom);
+    }
+    md5(&data)
+}
+
+// Fields after the leading '|': commands, port 0, port 1 and the expansion port.
+// Any character other than '.' or ' ' marks a pressed button.
+fn parse_fm2_input(fields: &str) -> Option<[u8; 2]> {
+    let mut fields = fields.split('|');
+    let commands: u8 = fields.next()?.trim().parse().ok()?;
+    if commands != 0 {
+        // Soft and hard resets in the middle of a movie are not supported
+        return None;
+    }
+
+    let mut input = [0u8; 2];
+    for player in &mut input {
+        let field = fields.next().unwrap_or("");
+        for (i, c) in field.chars().take(8).enumerate() {
+            if c != '.' && c != ' ' {
+                *player |= 0x80 >> i;
+            }
+        }
+    }
+    Some(input)
+}
+
+fn fm2_buttons(buttons: u8) -> String {
+    FM2_BUTTONS
+        .iter()
+        .enumerate()
+        .map(|(i, &letter)| if buttons & (0x80 >> i) != 0 { letter as char } else { '.' })
+        .collect()
+}
+
+// FM2 wants an integer; 1.2.3 becomes 10203
+fn emu_version() -> u32 {
+    env!("CARGO_PKG_VERSION")
+        .split('.')
+        .take(3)
+        .fold(0, |version, part| version * 100 + part.parse::<u32>().unwrap_or(0))
+}
+
+fn new_guid() -> String {
+    let id = rand::random::<u128>();
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:04X}-{:012X}",
+        id >> 96,
+        (id >> 80) & 0xFFFF,
+        (id >> 64) & 0xFFFF,
+        (id >> 48) & 0xFFFF,
+        id & 0xFFFF_FFFF_FFFF
+    )
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut group = 0u32;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        group = (group << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((group >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+// Plain MD5 (RFC 1321); only run once per ROM, so it favours brevity over speed
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let k = ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32;
+            let f = f.wrapping_add(a).wrapping_add(k).wrapping_add(words[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(SHIFTS[(i / 16) * 4 + i % 4]));
+        }
+
+        for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
@@ -0,0 +1,297 @@
+// src/netplay.rs
+
+use std::collections::BTreeMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::Player;
+use crate::cpu::CPU;
+
+/// Frames between reading local input and applying it, to hide network latency.
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+/// Both peers exchange a RAM checksum this often to detect desyncs.
+pub const CRC_INTERVAL_FRAMES: u32 = 60;
+/// How long a peer may stall before the session is dropped.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Every message is a tag byte followed by a frame number and a value, both u32 LE
+const MSG_INPUT: u8 = 1;
+const MSG_CRC: u8 = 2;
+const MESSAGE_LEN: usize = 9;
+/// Largest blob `recv_blob` accepts. A save state is under 32 KiB even with CHR RAM and
+/// an NSF board's PRG RAM; anything much bigger is a corrupt or hostile length.
+const MAX_BLOB_LEN: usize = 256 * 1024;
+
+/// A host waiting for its client. Polling it between frames keeps the game and its
+/// sound running while nobody has connected yet.
+pub struct NetplayListener {
+    listener: TcpListener,
+    port: u16,
+    deadline: Instant,
+    delay: u32,
+}
+
+impl NetplayListener {
+    /// Listens on `port`; `poll` gives up once `accept_timeout` has passed.
+    pub fn bind(port: u16, accept_timeout: Duration, delay: u32) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .map_err(|e| format!("Failed to listen on port {}: {}", port, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(NetplayListener { listener, port, deadline: Instant::now() + accept_timeout, delay })
+    }
+
+    /// Takes the client if one has connected, without blocking; None while still waiting.
+    pub fn poll(&mut self) -> Result<Option<NetplaySession>, String> {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+                NetplaySession::new(stream, Player::One, self.delay).map(Some)
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if Instant::now() > self.deadline {
+                    return Err(format!("No netplay client connected to port {}", self.port));
+                }
+                Ok(None)
+            }
+            Err(e) => Err(format!("Failed to accept netplay client: {}", e)),
+        }
+    }
+}
+
+/// Two-player lockstep session over TCP. The host plays controller 1 and the client
+/// controller 2; each frame only advances once both inputs for it have arrived.
+pub struct NetplaySession {
+    stream: TcpStream,
+    local_player: Player,
+    delay: u32,
+    frame: u32,
+    next_remote_frame: u32,
+    local_inputs: BTreeMap<u32, u8>,
+    remote_inputs: BTreeMap<u32, u8>,
+    local_crcs: BTreeMap<u32, u32>,
+    remote_crcs: BTreeMap<u32, u32>,
+}
+
+impl NetplaySession {
+    pub fn connect(addr: &str, delay: u32) -> Result<Self, String> {
+        let stream = TcpStream::connect(addr)
+            .map_err(|e| format!("Failed to connect to '{}': {}", addr, e))?;
+        Self::new(stream, Player::Two, delay)
+    }
+
+    fn new(stream: TcpStream, local_player: Player, delay: u32) -> Result<Self, String> {
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(PEER_TIMEOUT)).map_err(|e| e.to_string())?;
+
+        // Nobody has input for the first `delay` frames, so both sides start released
+        let initial: BTreeMap<u32, u8> = (0..delay).map(|frame| (frame, 0)).collect();
+        Ok(NetplaySession {
+            stream,
+            local_player,
+            delay,
+            frame: 0,
+            next_remote_frame: delay,
+            local_inputs: initial.clone(),
+            remote_inputs: initial,
+            local_crcs: BTreeMap::new(),
+            remote_crcs: BTreeMap::new(),
+        })
+    }
+
+    pub fn local_player(&self) -> Player {
+        self.local_player
+    }
+
+    /// Number of frames played since the session started.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Sends a length-prefixed blob, used to hand the host's state to the client.
+    pub fn send_blob(&mut self, data: &[u8]) -> Result<(), String> {
+        self.stream
+            .write_all(&(data.len() as u32).to_le_bytes())
+            .and_then(|_| self.stream.write_all(data))
+            .map_err(|e| format!("Netplay send failed: {}", e))
+    }
+
+    /// Receives a blob from `send_blob`. Lengths over `MAX_BLOB_LEN` are refused before
+    /// anything is allocated for them.
+    pub fn recv_blob(&mut self) -> Result<Vec<u8>, String> {
+        let mut len = [0u8; 4];
+        self.stream
+            .read_exact(&mut len)
+            .map_err(|e| format!("Netplay receive failed: {}", e))?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_BLOB_LEN {
+            return Err(format!("Netplay peer sent a {} byte blob, over the {} byte limit", len, MAX_BLOB_LEN));
+        }
+        let mut data = vec![0u8; len];
+        self.stream
+            .read_exact(&mut data)
+            .map_err(|e| format!("Netplay receive failed: {}", e))?;
+        Ok(data)
+    }
+
+    /// Queues `local` for `delay` frames from now and blocks until the remote input for
+    /// the current frame is known. Returns the (controller 1, controller 2) buttons.
+    pub fn advance(&mut self, local: u8) -> Result<(u8, u8), String> {
+        let target = self.frame + self.delay;
+        self.local_inputs.insert(target, local);
+        self.send(MSG_INPUT, target, local as u32)?;
+
+        while !self.remote_inputs.contains_key(&self.frame) {
+            self.receive()?;
+        }
+
+        let local = self.local_inputs.remove(&self.frame).unwrap_or(0);
+        let remote = self.remote_inputs.remove(&self.frame).unwrap_or(0);
+        self.frame += 1;
+
+        Ok(match self.local_player {
+            Player::One => (local, remote),
+            Player::Two => (remote, local),
+        })
+    }
+
+    /// Call once per frame after `advance`. Every `CRC_INTERVAL_FRAMES` the RAM checksum
+    /// is sent to the peer; returns an error as soon as a pair of checksums disagrees.
+    pub fn check_sync(&mut self, cpu: &CPU) -> Result<(), String> {
+        if self.frame.is_multiple_of(CRC_INTERVAL_FRAMES) {
+            let crc = state_crc(cpu);
+            self.local_crcs.insert(self.frame, crc);
+            self.send(MSG_CRC, self.frame, crc)?;
+        }
+
+        let compared: Vec<u32> = self
+            .local_crcs
+            .keys()
+            .filter(|frame| self.remote_crcs.contains_key(frame))
+            .copied()
+            .collect();
+        for frame in compared {
+            let local = self.local_crcs.remove(&frame);
+            let remote = self.remote_crcs.remove(&frame);
+            if local != remote {
+                return Err(format!("Netplay desync detected at frame {}", frame));
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, tag: u8, frame: u32, value: u32) -> Result<(), String> {
+        let mut msg = [0u8; MESSAGE_LEN];
+        msg[0] = tag;
+        msg[1..5].copy_from_slice(&frame.to_le_bytes());
+        msg[5..9].copy_from_slice(&value.to_le_bytes());
+        self.stream
+            .write_all(&msg)
+            .map_err(|e| format!("Netplay send failed: {}", e))
+    }
+
+    fn receive(&mut self) -> Result<(), String> {
+        let mut msg = [0u8; MESSAGE_LEN];
+        self.stream.read_exact(&mut msg).map_err(|e| match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => "Netplay peer timed out".to_string(),
+            ErrorKind::UnexpectedEof => "Netplay peer disconnected".to_string(),
+            _ => format!("Netplay receive failed: {}", e),
+        })?;
+
+        let frame = u32::from_le_bytes(msg[1..5].try_into().unwrap());
+        let value = u32::from_le_bytes(msg[5..9].try_into().unwrap());
+        match msg[0] {
+            MSG_INPUT => {
+                // Inputs are sequence-numbered by frame and must arrive in order
+                if frame != self.next_remote_frame {
+                    return Err(format!(
+                        "Netplay input out of sequence: expected frame {}, got {}",
+                        self.next_remote_frame, frame
+                    ));
+                }
+                self.next_remote_frame += 1;
+                self.remote_inputs.insert(frame, value as u8);
+            }
+            MSG_CRC => {
+                self.remote_crcs.insert(frame, value);
+            }
+            tag => return Err(format!("Unknown netplay message {}", tag)),
+        }
+        Ok(())
+    }
+}
+
+/// Checksum of the console RAM, VRAM, OAM and palette. Register state is left out
+/// because any divergence there shows up in memory within a frame or two.
+pub fn state_crc(cpu: &CPU) -> u32 {
+    let mut snapshot = cpu.save_snapshot();
+    let mem = snapshot.take_memory();
+    let mut crc = !0u32;
+    for data in [&mem.cpu_ram, &mem.vram, &mem.oam, &mem.palette] {
+        crc = crc32_update(crc, data);
+    }
+    !crc
+}
+
+// Bitwise CRC-32 (IEEE); only run once a second, so no table is needed
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connected_pair() -> (NetplaySession, TcpStream) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (NetplaySession::new(stream, Player::One, DEFAULT_INPUT_DELAY).unwrap(), peer)
+    }
+
+    #[test]
+    fn blob_round_trips() {
+        let (mut session, mut peer) = connected_pair();
+        peer.write_all(&3u32.to_le_bytes()).unwrap();
+        peer.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(session.recv_blob().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn oversized_blob_is_refused() {
+        let (mut session, mut peer) = connected_pair();
+        peer.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        assert!(session.recv_blob().unwrap_err().contains("limit"));
+    }
+
+    #[test]
+    fn listener_polls_without_blocking() {
+        let mut listener = NetplayListener::bind(0, Duration::from_secs(10), DEFAULT_INPUT_DELAY).unwrap();
+        assert!(listener.poll().unwrap().is_none());
+
+        let port = listener.listener.local_addr().unwrap().port();
+        let _client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let session = loop {
+            if let Some(session) = listener.poll().unwrap() {
+                break session;
+            }
+            assert!(Instant::now() < deadline, "client was never accepted");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(session.local_player(), Player::One);
+    }
+
+    #[test]
+    fn listener_times_out() {
+        let mut listener = NetplayListener::bind(0, Duration::ZERO, DEFAULT_INPUT_DELAY).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(listener.poll().is_err());
+    }
+}
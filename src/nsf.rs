@@ -0,0 +1,348 @@
+// src/nsf.rs
+
+use serde::{Deserialize, Serialize};
+
+use crate::bus::Mem;
+use crate::cpu::CPU;
+use crate::mapper::{Mapper, MapperState};
+use crate::region::Region;
+
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 4096;
+const PRG_RAM_SIZE: usize = 8192;
+
+// The driver lives in cartridge space that no plain NSF uses, with its registers just after it
+const DRIVER_BASE: u16 = 0x4100;
+const SONG_REGISTER: u16 = 0x4180;
+const REGION_REGISTER: u16 = 0x4181;
+const PLAY_DUE_REGISTER: u16 = 0x4182;
+const INIT_VECTOR: u16 = 0x4183;
+const PLAY_VECTOR: u16 = 0x4185;
+const BANK_REGISTERS: u16 = 0x5FF8;
+const NMI_HANDLER: u16 = 0x4120;
+const IRQ_HANDLER: u16 = 0x411D;
+
+/// The player program, at `DRIVER_BASE`. Reset calls INIT with A = song and X = PAL
+/// flag, then loops waiting for the play timer and calls PLAY each time it expires.
+/// IRQs only acknowledge the APU frame interrupt; NMIs return straight away.
+const DRIVER: [u8; 0x21] = [
+    0xAD, 0x80, 0x41, // $4100  LDA $4180     song
+    0xAE, 0x81, 0x41, // $4103  LDX $4181     0 = NTSC, 1 = PAL
+    0x20, 0x17, 0x41, // $4106  JSR $4117     INIT
+    0xAD, 0x82, 0x41, // $4109  LDA $4182     play timer expired?
+    0xF0, 0xFB,       // $410C  BEQ $4109
+    0x8D, 0x82, 0x41, // $410E  STA $4182     acknowledge it
+    0x20, 0x1A, 0x41, // $4111  JSR $411A     PLAY
+    0x4C, 0x09, 0x41, // $4114  JMP $4109
+    0x6C, 0x83, 0x41, // $4117  JMP ($4183)
+    0x6C, 0x85, 0x41, // $411A  JMP ($4185)
+    0x2C, 0x15, 0x40, // $411D  BIT $4015     IRQ: clear the frame interrupt
+    0x40,             // $4120  RTI           NMI
+];
+
+/// The parts of an NSF header the player needs. Addresses are CPU addresses and
+/// songs are numbered from 0, unlike the file's 1-based starting song.
+#[derive(Clone, Debug)]
+pub struct NsfHeader {
+    pub title: String,
+    pub songs: u8,
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    /// Microseconds between PLAY calls on NTSC and on PAL.
+    pub ntsc_play_period_us: u16,
+    pub pal_play_period_us: u16,
+    /// Banks mapped at $8000-$FFFF before INIT. Tunes that do not bankswitch get the
+    /// 32 KiB image laid out in order.
+    pub initial_banks: [u8; 8],
+    pub region_hint: Option<Region>,
+    /// Expansion sound chip bits from the header; none of them are emulated.
+    pub expansion_chips: u8,
+}
+
+impl NsfHeader {
+    /// Splits an NSF file into its header and the bank image the board maps in, with
+    /// the data placed at its load address.
+    pub fn parse(raw: &[u8]) -> Result<(NsfHeader, Vec<u8>), String> {
+        if raw.len() < HEADER_SIZE || raw[0..5] != NSF_TAG {
+            return Err("File is not in NSF format".to_string());
+        }
+        let word = |offset: usize| u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+        let load_addr = word(0x08);
+        if load_addr < 0x8000 {
+            return Err(format!("NSF load address ${:04X} is below $8000", load_addr));
+        }
+        let songs = raw[0x06];
+        if songs == 0 {
+            return Err("NSF declares no songs".to_string());
+        }
+        let data = &raw[HEADER_SIZE..];
+
+        let bank_bytes: [u8; 8] = raw[0x70..0x78].try_into().unwrap();
+        let bankswitched = bank_bytes.iter().any(|&bank| bank != 0);
+        let (mut image, initial_banks) = if bankswitched {
+            // Data starts at the load address's offset within its 4 KiB bank
+            let mut image = vec![0; load_addr as usize % BANK_SIZE];
+            image.extend_from_slice(data);
+            (image, bank_bytes)
+        } else {
+            let mut image = vec![0; (load_addr - 0x8000) as usize];
+            image.extend_from_slice(data);
+            image.resize(8 * BANK_SIZE, 0);
+            (image, [0, 1, 2, 3, 4, 5, 6, 7])
+        };
+        image.resize(image.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+
+        let title_bytes = &raw[0x0E..0x2E];
+        let title_len = title_bytes.iter().position(|&b| b == 0).unwrap_or(title_bytes.len());
+        let header = NsfHeader {
+            title: String::from_utf8_lossy(&title_bytes[..title_len]).trim().to_string(),
+            songs,
+            starting_song: raw[0x07].clamp(1, songs) - 1,
+            load_addr,
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            ntsc_play_period_us: word(0x6E),
+            pal_play_period_us: word(0x78),
+            initial_banks,
+            // Bit 1 marks a dual-region tune, which plays correctly on either
+            region_hint: (raw[0x7A] & 0b11 == 0b01).then_some(Region::Pal),
+            expansion_chips: raw[0x7B],
+        };
+        Ok((header, image))
+    }
+
+    /// CPU cycles between PLAY calls. Dendy plays at the PAL rate, and a zero period
+    /// in the header falls back to the region's frame rate.
+    pub fn play_period_cycles(&self, region: Region) -> usize {
+        let period_us = match region {
+            Region::Ntsc => self.ntsc_play_period_us,
+            Region::Pal | Region::Dendy => self.pal_play_period_us,
+        };
+        let seconds = match period_us {
+            0 => 1.0 / region.frame_rate(),
+            us => us as f64 / 1_000_000.0,
+        };
+        (seconds * region.cpu_clock_hz()).round().max(1.0) as usize
+    }
+}
+
+/// A virtual NSF player cartridge: 4 KiB PRG banks switched through $5FF8-$5FFF, 8 KiB
+/// of PRG RAM at $6000, and a small driver whose vectors replace the tune's own at
+/// $FFFA-$FFFF. PLAY is paced by a CPU-cycle timer on the board, not by the PPU's NMI.
+pub struct NsfBoard {
+    header: NsfHeader,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    banks: [u8; 8],
+    song: u8,
+    region: Region,
+    play_period: usize,
+    play_timer: usize,
+    play_due: bool,
+}
+
+/// Everything on an `NsfBoard` that changes as a tune plays.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NsfBoardState {
+    prg_ram: Vec<u8>,
+    banks: [u8; 8],
+    song: u8,
+    region: Region,
+    play_period: usize,
+    play_timer: usize,
+    play_due: bool,
+}
+
+impl NsfBoard {
+    pub fn new(header: &NsfHeader, prg_rom: Vec<u8>) -> Self {
+        let region = header.region_hint.unwrap_or_default();
+        NsfBoard {
+            header: header.clone(),
+            prg_rom,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            banks: header.initial_banks,
+            song: header.starting_song,
+            region,
+            play_period: header.play_period_cycles(region),
+            play_timer: 0,
+            play_due: false,
+        }
+    }
+
+    // Everything INIT expects to find fresh, as when the tune was first loaded
+    fn restart(&mut self) {
+        self.banks = self.header.initial_banks;
+        self.prg_ram.fill(0);
+        self.play_period = self.header.play_period_cycles(self.region);
+        self.play_timer = 0;
+        self.play_due = false;
+    }
+}
+
+impl Mapper for NsfBoard {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DRIVER_BASE..=NMI_HANDLER => DRIVER[(addr - DRIVER_BASE) as usize],
+            SONG_REGISTER => self.song,
+            REGION_REGISTER => (self.region != Region::Ntsc) as u8,
+            PLAY_DUE_REGISTER => self.play_due as u8,
+            INIT_VECTOR => self.header.init_addr as u8,
+            0x4184 => (self.header.init_addr >> 8) as u8,
+            PLAY_VECTOR => self.header.play_addr as u8,
+            0x4186 => (self.header.play_addr >> 8) as u8,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0xFFFA => NMI_HANDLER as u8,
+            0xFFFB => (NMI_HANDLER >> 8) as u8,
+            0xFFFC => DRIVER_BASE as u8,
+            0xFFFD => (DRIVER_BASE >> 8) as u8,
+            0xFFFE => IRQ_HANDLER as u8,
+            0xFFFF => (IRQ_HANDLER >> 8) as u8,
+            0x8000..=0xFFF9 => {
+                let offset = (addr - 0x8000) as usize;
+                let bank = self.banks[offset / BANK_SIZE] as usize;
+                let index = (bank * BANK_SIZE + offset % BANK_SIZE) % self.prg_rom.len();
+                self.prg_rom[index]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            SONG_REGISTER => {
+                self.song = data;
+                self.restart();
+            }
+            REGION_REGISTER => {
+                self.region = Region::ALL.get(data as usize).copied().unwrap_or_default();
+                self.play_period = self.header.play_period_cycles(self.region);
+            }
+            PLAY_DUE_REGISTER => self.play_due = false,
+            BANK_REGISTERS..=0x5FFF => self.banks[(addr - BANK_REGISTERS) as usize] = data,
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => {}
+        }
+    }
+
+    fn notify_cpu_cycles(&mut self, cycles: usize) {
+        self.play_timer += cycles;
+        if self.play_timer >= self.play_period {
+            // A PLAY that runs past its slot is called once when it returns, not once
+            // for every period it missed
+            self.play_timer %= self.play_period;
+            self.play_due = true;
+        }
+    }
+
+    fn save_state(&self) -> MapperState {
+        MapperState::Nsf(NsfBoardState {
+            prg_ram: self.prg_ram.clone(),
+            banks: self.banks,
+            song: self.song,
+            region: self.region,
+            play_period: self.play_period,
+            play_timer: self.play_timer,
+            play_due: self.play_due,
+        })
+    }
+
+    fn validate_state(&self, state: &MapperState) -> Result<(), String> {
+        match state {
+            MapperState::Nsf(state) if state.prg_ram.len() != PRG_RAM_SIZE => Err(format!(
+                "NSF PRG RAM is {} bytes, expected {}",
+                state.prg_ram.len(),
+                PRG_RAM_SIZE
+            )),
+            MapperState::Nsf(state) if state.play_period == 0 => Err("NSF play period is zero".to_string()),
+            MapperState::Nsf(_) => Ok(()),
+            _ => Err("State was not saved from an NSF tune".to_string()),
+        }
+    }
+
+    fn load_state(&mut self, state: &MapperState) {
+        if let MapperState::Nsf(state) = state {
+            self.prg_ram.copy_from_slice(&state.prg_ram);
+            self.banks = state.banks;
+            self.song = state.song;
+            self.region = state.region;
+            self.play_period = state.play_period;
+            self.play_timer = state.play_timer;
+            self.play_due = state.play_due;
+        }
+    }
+}
+
+/// Starts `song` (from 0) over: clears RAM, silences the APU and keeps the PPU off the
+/// way NSF players do before INIT, then resets the CPU into the board's driver. The
+/// board is told the console's region so PLAY runs at the matching rate.
+pub fn start_song(cpu: &mut CPU, song: u8) {
+    for addr in 0x0000..0x0800 {
+        cpu.bus.mem_write(addr, 0);
+    }
+    cpu.bus.mem_write(0x2000, 0);
+    cpu.bus.mem_write(0x2001, 0);
+    for addr in 0x4000..=0x4013 {
+        cpu.bus.mem_write(addr, 0);
+    }
+    cpu.bus.mem_write(0x4015, 0x00);
+    cpu.bus.mem_write(0x4015, 0x0F);
+    cpu.bus.mem_write(0x4017, 0x40);
+
+    let region = cpu.bus.ppu().region();
+    let region_code = Region::ALL.iter().position(|&r| r == region).unwrap_or(0) as u8;
+    cpu.bus.mem_write(REGION_REGISTER, region_code);
+    cpu.bus.mem_write(SONG_REGISTER, song);
+    cpu.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::Rom;
+
+    // A bankswitched tune with two 4 KiB banks, bank 1 filled with $11, whose INIT and
+    // PLAY both return at once
+    fn test_bus() -> Bus<'static> {
+        let mut raw = vec![0u8; HEADER_SIZE];
+        raw[0..5].copy_from_slice(&NSF_TAG);
+        raw[0x06] = 1;
+        raw[0x07] = 1;
+        raw[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        raw[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        raw[0x70..0x78].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        let mut data = vec![0x60; BANK_SIZE];
+        data.extend(vec![0x11; BANK_SIZE]);
+        raw.extend(data);
+        Bus::new(Rom::from_nsf(&raw).unwrap(), |_, _, _, _| {})
+    }
+
+    #[test]
+    fn board_state_is_saved_and_restored() {
+        let mut bus = test_bus();
+        bus.mem_write(BANK_REGISTERS, 1);
+        bus.mem_write(0x6000, 0x55);
+        let state = bus.save_state();
+
+        bus.mem_write(BANK_REGISTERS, 0);
+        bus.mem_write(0x6000, 0x00);
+        bus.tick(40_000);
+        assert_eq!(bus.mem_read(PLAY_DUE_REGISTER), 1);
+
+        bus.validate_state(&state).unwrap();
+        bus.load_state(&state);
+        assert_eq!(bus.mem_read(0x8000), 0x11);
+        assert_eq!(bus.mem_read(0x6000), 0x55);
+        assert_eq!(bus.mem_read(PLAY_DUE_REGISTER), 0);
+    }
+
+    #[test]
+    fn nrom_state_is_rejected() {
+        let nrom = Rom::from_raw(&[0xEA; 0x8000], None, crate::cartridge::Mirroring::HORIZONTAL).unwrap();
+        let state = Bus::new(nrom, |_, _, _, _| {}).save_state();
+        assert!(test_bus().validate_state(&state).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// src/perf.rs
+
+use std::time::{Duration, Instant};
+
+/// NTSC NES frame rate, the default 100% speed reference.
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+
+/// Where the time of a single frame went.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FrameTimings {
+    /// CPU/PPU/APU emulation between two frame callbacks.
+    pub emulate: Duration,
+    /// Drawing the PPU state into the `Frame` buffer.
+    pub render: Duration,
+    /// Uploading the texture and presenting it to the window.
+    pub present: Duration,
+    /// Capturing the most recent rewind snapshot.
+    pub rewind_capture: Duration,
+    /// Saving state, running the run-ahead frames and restoring state.
+    pub run_ahead: Duration,
+}
+
+/// Audio queue health as seen by the game loop each time it queues a frame's samples,
+/// for tracking down crackling. Depths are in samples.
+#[derive(Clone, Copy, Debug)]
+pub struct AudioQueueStats {
+    /// Frames whose samples found the queue already empty, so the device had run dry.
+    pub underruns: u64,
+    /// Underruns in the last full one-second window.
+    pub window_underruns: u32,
+    /// Shallowest and deepest the queue was over the last full one-second window.
+    pub min_samples: u32,
+    pub max_samples: u32,
+    window_start: Instant,
+    window_min: Option<u32>,
+    window_max: u32,
+    underruns_in_window: u32,
+}
+
+impl AudioQueueStats {
+    pub fn new() -> Self {
+        AudioQueueStats {
+            underruns: 0,
+            window_underruns: 0,
+            min_samples: 0,
+            max_samples: 0,
+            window_start: Instant::now(),
+            window_min: None,
+            window_max: 0,
+            underruns_in_window: 0,
+        }
+    }
+
+    /// Records the queue depth found just before a frame's samples are queued. Returns
+    /// true when a one-second window was completed and the published figures changed.
+    pub fn record(&mut self, queued: u32) -> bool {
+        if queued == 0 {
+            self.underruns += 1;
+            self.underruns_in_window += 1;
+        }
+        self.window_min = Some(self.window_min.map_or(queued, |min| min.min(queued)));
+        self.window_max = self.window_max.max(queued);
+
+        if self.window_start.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
+        self.min_samples = self.window_min.take().unwrap_or(0);
+        self.max_samples = self.window_max;
+        self.window_underruns = self.underruns_in_window;
+        self.window_start = Instant::now();
+        self.window_max = 0;
+        self.underruns_in_window = 0;
+        true
+    }
+}
+
+impl Default for AudioQueueStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frame-rate and frame-time measurements collected by the game-loop callback.
+pub struct PerfStats {
+    last_frame_start: Option<Instant>,
+    last_resume: Instant,
+    window_start: Instant,
+    window_frames: u32,
+    frame_start: Instant,
+
+    pub instant_fps: f64,
+    pub average_fps: f64,
+    pub speed_percent: f64,
+    /// Frame rate that counts as 100% speed; follows the console region.
+    pub target_fps: f64,
+    pub audio_queue_samples: u32,
+    /// Time from handing a sample to the audio device to hearing it.
+    pub audio_latency: Duration,
+    /// Output rate chosen by audio rate control, relative to nominal.
+    pub audio_rate: f64,
+    /// Number of times the game loop flushed an overfull audio queue.
+    pub audio_resyncs: u64,
+    pub audio_queue: AudioQueueStats,
+    pub timings: FrameTimings,
+}
+
+impl PerfStats {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        PerfStats {
+            last_frame_start: None,
+            last_resume: now,
+            window_start: now,
+            window_frames: 0,
+            frame_start: now,
+            instant_fps: 0.0,
+            average_fps: 0.0,
+            speed_percent: 0.0,
+            target_fps: NTSC_FRAME_RATE,
+            audio_queue_samples: 0,
+            audio_latency: Duration::ZERO,
+            audio_rate: 1.0,
+            audio_resyncs: 0,
+            audio_queue: AudioQueueStats::new(),
+            timings: FrameTimings::default(),
+        }
+    }
+
+    /// Call at the top of the frame callback, once emulation of the frame is done.
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        self.timings.emulate = now - self.last_resume;
+        if let Some(last) = self.last_frame_start {
+            let frame_time = (now - last).as_secs_f64();
+            if frame_time > 0.0 {
+                self.instant_fps = 1.0 / frame_time;
+            }
+        }
+        self.last_frame_start = Some(now);
+        self.frame_start = now;
+    }
+
+    /// Records render/present times. Returns true when the 1-second average was refreshed.
+    pub fn end_frame(&mut self, render: Duration, present: Duration) -> bool {
+        self.timings.render = render;
+        self.timings.present = present;
+
+        self.window_frames += 1;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+
+        self.average_fps = self.window_frames as f64 / elapsed.as_secs_f64();
+        self.speed_percent = self.average_fps / self.target_fps * 100.0;
+        self.window_start = Instant::now();
+        self.window_frames = 0;
+        true
+    }
+
+    /// Call after any frame throttling so the sleep is not counted as emulation time.
+    pub fn resume(&mut self) {
+        self.last_resume = Instant::now();
+    }
+
+    /// Start of the current frame as recorded by `begin_frame`.
+    pub fn frame_start(&self) -> Instant {
+        self.frame_start
+    }
+
+    /// Text lines for the on-screen overlay.
+    pub fn overlay_lines(&self) -> Vec<String> {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        vec![
+            format!("FPS {:.1} AVG {:.1}", self.instant_fps, self.average_fps),
+            format!("SPD {:.0}%", self.speed_percent),
+            format!("AUD {} {:+.2}%", self.audio_queue_samples, (self.audio_rate - 1.0) * 100.0),
+            format!("LAT {:.0}MS", ms(self.audio_latency)),
+            format!(
+                "Q {}-{} UND {} CLR {}",
+                self.audio_queue.min_samples, self.audio_queue.max_samples, self.audio_queue.underruns, self.audio_resyncs
+            ),
+            format!("EMU {:.2}MS", ms(self.timings.emulate)),
+            format!("REN {:.2}MS", ms(self.timings.render)),
+            format!("PRS {:.2}MS", ms(self.timings.present)),
+            format!("RWD {:.3}MS", ms(self.timings.rewind_capture)),
+            format!("RAH {:.2}MS", ms(self.timings.run_ahead)),
+        ]
+    }
+}
+
+impl Default for PerfStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,6 +1,14 @@
 use crate::cartridge::Mirroring;
 use bitflags::bitflags;
 use serde::{Serialize, Deserialize};
+use crate::rewind::SnapshotMemory;
+use crate::region::Region;
+
+/// Frames a bit of the PPU I/O latch holds its value after it was last driven (~600 ms).
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
+
+/// Dot at which a rendered scanline clocks the mapper's scanline counter.
+const SCANLINE_CLOCK_DOT: usize = 260;
 
 bitflags! {
     pub struct ControlRegister: u8 {
@@ -40,14 +48,12 @@ bitflags! {
 struct ScrollRegisterState {
     scroll_x: u8,
     scroll_y: u8,
-    write_latch: bool,
 }
 
 #[derive(Default)]
 pub struct ScrollRegister {
     pub scroll_x: u8,
     pub scroll_y: u8,
-    write_latch: bool,
 }
 
 impl ControlRegister {
@@ -94,22 +100,25 @@ impl ControlRegister {
     }
 }
 
+impl Default for ControlRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct AddrRegisterState {
     value: u16,
-    write_latch: bool,
 }
 
 pub struct AddrRegister {
     value: u16,
-    write_latch: bool, 
 }
 
 impl AddrRegister {
     pub fn new() -> Self {
         AddrRegister {
             value: 0,
-            write_latch: false,
         }
     }
 
@@ -117,16 +126,15 @@ impl AddrRegister {
         self.value = data & 0x3FFF;
     }
 
-    pub fn update(&mut self, data: u8) {
-        if !self.write_latch {
+    /// `second_write` is the PPU's shared $2005/$2006 write latch.
+    pub fn update(&mut self, data: u8, second_write: bool) {
+        if !second_write {
             self.value = (self.value & 0x00FF) | ((data as u16) << 8);
         } else {
             self.value = (self.value & 0xFF00) | (data as u16);
         }
 
         self.set(self.value);
-
-        self.write_latch = !self.write_latch;
     }
 
     pub fn increment(&mut self, inc: u8) {
@@ -134,10 +142,6 @@ impl AddrRegister {
         self.set(self.value); 
     }
 
-    pub fn reset_latch(&mut self) {
-        self.write_latch = false;
-    }
-
     pub fn get(&self) -> u16 {
         self.value
     }
@@ -145,13 +149,17 @@ impl AddrRegister {
     fn save_state(&self) -> AddrRegisterState {
         AddrRegisterState {
             value: self.value,
-            write_latch: self.write_latch,
         }
     }
     
     fn load_state(&mut self, state: &AddrRegisterState) {
         self.value = state.value;
-        self.write_latch = state.write_latch;
+    }
+}
+
+impl Default for AddrRegister {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -160,35 +168,28 @@ impl ScrollRegister {
         ScrollRegister {
             scroll_x: 0,
             scroll_y: 0,
-            write_latch: false,
         }
     }
 
-    pub fn write(&mut self, data: u8) {
-        if !self.write_latch {
+    /// `second_write` is the PPU's shared $2005/$2006 write latch.
+    pub fn write(&mut self, data: u8, second_write: bool) {
+        if !second_write {
             self.scroll_x = data;
         } else {
             self.scroll_y = data;
         }
-        self.write_latch = !self.write_latch;
-    }
-
-    pub fn reset_latch(&mut self) {
-        self.write_latch = false;
     }
     
     fn save_state(&self) -> ScrollRegisterState {
         ScrollRegisterState {
             scroll_x: self.scroll_x,
             scroll_y: self.scroll_y,
-            write_latch: self.write_latch,
         }
     }
 
     fn load_state(&mut self, state: &ScrollRegisterState) {
         self.scroll_x = state.scroll_x;
         self.scroll_y = state.scroll_y;
-        self.write_latch = state.write_latch;
     }
 }
 
@@ -203,14 +204,55 @@ pub struct PpuState {
     oam_data: Vec<u8>,
     palette_table: [u8; 32],
     addr: AddrRegisterState,
+    write_latch: bool,
     internal_data_buf: u8,
     scanline: u16,
     cycles: usize,
     nmi_interrupt: Option<u8>,
+    nmi_line: bool,
+    open_bus: u8,
+    open_bus_decay: [u8; 8],
+    chr_ram: Option<Vec<u8>>,
+}
+
+impl PpuState {
+    pub(crate) fn take_memory(&mut self, mem: &mut SnapshotMemory) {
+        mem.vram = std::mem::take(&mut self.vram);
+        mem.oam = std::mem::take(&mut self.oam_data);
+        mem.palette = self.palette_table.to_vec();
+        mem.chr_ram = self.chr_ram.take();
+    }
+
+    pub(crate) fn restore_memory(&mut self, mem: SnapshotMemory) {
+        self.vram = mem.vram;
+        self.oam_data = mem.oam;
+        self.palette_table.copy_from_slice(&mem.palette);
+        self.chr_ram = mem.chr_ram;
+    }
+
+    pub(crate) fn registers(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("PPUCTRL", format!("${:02X}", self.ctrl)),
+            ("PPUMASK", format!("${:02X}", self.mask)),
+            ("PPUSTATUS", format!("${:02X}", self.status)),
+            ("OAMADDR", format!("${:02X}", self.oam_addr)),
+            ("Scroll X", self.scroll.scroll_x.to_string()),
+            ("Scroll Y", self.scroll.scroll_y.to_string()),
+            ("PPUADDR", format!("${:04X}", self.addr.value)),
+            ("Write latch", self.write_latch.to_string()),
+            ("Read buffer", format!("${:02X}", self.internal_data_buf)),
+            ("Scanline", self.scanline.to_string()),
+            ("Dot", self.cycles.to_string()),
+            ("PPU NMI pending", format!("{:?}", self.nmi_interrupt)),
+            ("PPU NMI line", self.nmi_line.to_string()),
+            ("PPU open bus", format!("${:02X}", self.open_bus)),
+        ]
+    }
 }
 
 pub struct NesPPU {
     pub chr_rom: Vec<u8>,
+    pub chr_is_ram: bool,
     pub mirroring: Mirroring,
     pub ctrl: ControlRegister,
     pub mask: MaskRegister,
@@ -223,18 +265,46 @@ pub struct NesPPU {
     pub palette_table: [u8; 32],
 
     addr: AddrRegister,
+    // $2005 and $2006 share a single first/second write toggle ("w") on hardware
+    write_latch: bool,
     internal_data_buf: u8,
 
     scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>, 
+    // Level of the NMI output, the vblank flag ANDed with $2000 bit 7, as of the last
+    // change to either; the CPU is interrupted on its rising edge only
+    nmi_line: bool,
+    // I/O latch between the CPU and PPU: the value last put on the PPU data bus, which
+    // undriven bits of a register read return
+    open_bus: u8,
+    // Frames left before each latch bit fades to 0, bit 0 first
+    open_bus_decay: [u8; 8],
+    // Scanline whose mapper clock point was passed during the last tick, until polled
+    scanline_clock: Option<u16>,
+    region: Region,
+    // Accuracy option for OAM access during rendering; a setting, not part of the state
+    oam_quirks: bool,
+    // Debug overrides of PPUMASK's layer bits for drawing; None follows the game
+    show_background_override: Option<bool>,
+    show_sprites_override: Option<bool>,
 }
 
 impl NesPPU {
 
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// PPU dot within the current scanline, 0-340.
+    pub fn cycle(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring, chr_is_ram: bool) -> Self {
         NesPPU {
             chr_rom,
+            chr_is_ram,
             mirroring,
             ctrl: ControlRegister::new(),
             mask: MaskRegister::from_bits_truncate(0),
@@ -245,15 +315,102 @@ impl NesPPU {
             oam_data: [0; 256],
             palette_table: [0; 32],
             addr: AddrRegister::new(),
+            write_latch: false,
             internal_data_buf: 0,
             scanline: 0,
             cycles: 0,
             nmi_interrupt: None,
+            nmi_line: false,
+            open_bus: 0,
+            open_bus_decay: [0; 8],
+            scanline_clock: None,
+            region: Region::Ntsc,
+            oam_quirks: false,
+            show_background_override: None,
+            show_sprites_override: None,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Changes the frame length and vblank line. Call at a frame boundary; see `Bus::set_region`.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Emulates what OAM does while the PPU renders, off by default:
+    ///
+    /// - OAMADDR is cleared on dots 257-320 of the visible and pre-render lines, where
+    ///   the PPU fetches the next line's sprites.
+    /// - $2004 reads on those lines return what the sprite logic is looking at: $FF on
+    ///   dots 1-64 while secondary OAM is cleared, the primary OAM byte at OAMADDR
+    ///   during evaluation, and the secondary OAM entry being fetched on dots 257-320.
+    ///
+    /// A game that writes OAM through $2003/$2004 while rendering can glitch with these
+    /// on, exactly as it would on a console. Either way, bits 2-4 of attribute bytes read
+    /// back as 0 through $2004, since the console has no storage for them.
+    pub fn set_oam_quirks(&mut self, enabled: bool) {
+        self.oam_quirks = enabled;
+    }
+
+    pub fn oam_quirks(&self) -> bool {
+        self.oam_quirks
+    }
+
+    /// Debug overrides for what the renderer draws. `Some(false)` hides a layer and
+    /// `Some(true)` shows it whatever the game has written to $2001; `None` defers to the
+    /// game. Only the picture changes: sprite 0 hits and the rest of the emulation still
+    /// follow the game's mask bits.
+    pub fn set_layer_visibility(&mut self, background: Option<bool>, sprites: Option<bool>) {
+        self.show_background_override = background;
+        self.show_sprites_override = sprites;
+    }
+
+    pub fn layer_visibility(&self) -> (Option<bool>, Option<bool>) {
+        (self.show_background_override, self.show_sprites_override)
+    }
+
+    /// Whether the renderer draws the background, after any debug override.
+    pub fn show_background(&self) -> bool {
+        self.show_background_override.unwrap_or(self.mask.contains(MaskRegister::SHOW_BACKGROUND))
+    }
+
+    /// Whether the renderer draws sprites, after any debug override.
+    pub fn show_sprites(&self) -> bool {
+        self.show_sprites_override.unwrap_or(self.mask.contains(MaskRegister::SHOW_SPRITES))
+    }
+
+    // Visible or pre-render line with rendering on, where the sprite logic owns OAM
+    fn oam_busy(&self) -> bool {
+        let rendering_line = self.scanline < 240 || self.scanline == self.region.scanlines_per_frame() - 1;
+        rendering_line && self.mask.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+
+    // Secondary OAM byte read on a sprite fetch dot (257-320): the Y, tile, attribute and
+    // X of each of the eight sprites found for the next line, then X three more times.
+    // Empty slots, and everything on the pre-render line, hold $FF.
+    fn sprite_fetch_byte(&self, dot: usize) -> u8 {
+        let slot = (dot - 257) / 8;
+        let byte = ((dot - 257) % 8).min(3);
+        if self.scanline >= 240 {
+            return 0xFF;
         }
+        let height = if self.ctrl.contains(ControlRegister::SPRITE_SIZE) { 16 } else { 8 };
+        self.oam_data
+            .chunks_exact(4)
+            .filter(|sprite| (self.scanline as usize).wrapping_sub(sprite[0] as usize) < height)
+            .nth(slot)
+            .map_or(0xFF, |sprite| sprite[byte])
     }
 
     pub fn tick(&mut self, cycles: usize) -> bool {
+        let start_dot = self.cycles;
         self.cycles += cycles;
+        if self.oam_quirks && start_dot <= 320 && self.cycles >= 257 && self.oam_busy() {
+            self.oam_addr = 0;
+        }
         if self.scanline < 240 && self.cycles >= 1 && self.cycles <= 256 {
             if self.mask.contains(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES) {
                 if !self.status.contains(StatusRegister::SPRITE_0_HIT) {
@@ -270,23 +427,28 @@ impl NesPPU {
                 }
             }
         }
+        // Fetches move from background to sprite patterns at dot 257; with the usual
+        // sprites-at-$1000 setup, A12 rises around dot 260, where MMC3 counts a scanline
+        let fetching = self.scanline < 240 || self.scanline == self.region.scanlines_per_frame() - 1;
+        if fetching && start_dot < SCANLINE_CLOCK_DOT && self.cycles >= SCANLINE_CLOCK_DOT && self.rendering_enabled() {
+            self.scanline_clock = Some(self.scanline);
+        }
         if self.cycles >= 341 {
             self.cycles %= 341; 
             self.scanline += 1; 
 
-            if self.scanline == 241 {
+            if self.scanline == self.region.vblank_scanline() {
                 self.status.insert(StatusRegister::VBLANK_STARTED);
-                if self.ctrl.contains(ControlRegister::GENERATE_NMI) {
-                    self.nmi_interrupt = Some(1);
-                }
+                self.update_nmi_line();
             }
 
-            if self.scanline >= 262 {
+            if self.scanline >= self.region.scanlines_per_frame() {
                 self.scanline = 0;
                 self.status.remove(StatusRegister::VBLANK_STARTED);
                 self.status.remove(StatusRegister::SPRITE_0_HIT);
                 self.status.remove(StatusRegister::SPRITE_OVERFLOW);
-                self.nmi_interrupt = None;
+                self.update_nmi_line();
+                self.decay_open_bus();
                 
                 return true; 
             }
@@ -294,32 +456,90 @@ impl NesPPU {
         false 
     }
 
+    /// Puts `data` on the PPU data bus for the bits in `mask`, refreshing their decay timer.
+    /// Every CPU write to $2000-$2007 drives all eight bits.
+    pub fn drive_open_bus(&mut self, data: u8, mask: u8) {
+        self.open_bus = (self.open_bus & !mask) | (data & mask);
+        for (bit, decay) in self.open_bus_decay.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *decay = OPEN_BUS_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_open_bus(&mut self) {
+        for (bit, decay) in self.open_bus_decay.iter_mut().enumerate() {
+            *decay = decay.saturating_sub(1);
+            if *decay == 0 {
+                self.open_bus &= !(1 << bit);
+            }
+        }
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
 
+    /// The scanline, visible or pre-render, whose dot 260 was passed while rendering was
+    /// on since the last poll. The bus hands it to the mapper's scanline counter.
+    pub fn poll_scanline_clock(&mut self) -> Option<u16> {
+        self.scanline_clock.take()
+    }
 
-    pub fn write_to_ctrl(&mut self, value: u8) {
-        let before_nmi_enabled = self.ctrl.contains(ControlRegister::GENERATE_NMI);
-        self.ctrl.update(value);
-        let after_nmi_enabled = self.ctrl.contains(ControlRegister::GENERATE_NMI);
+    // Either layer on keeps the PPU fetching from the cartridge
+    fn rendering_enabled(&self) -> bool {
+        self.mask.intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
 
-        if !before_nmi_enabled && after_nmi_enabled && self.status.contains(StatusRegister::VBLANK_STARTED) {
+    // The one place an NMI is raised; call after anything that changes the vblank flag
+    // or $2000 bit 7. Setting bit 7 again while the line is high does nothing, but
+    // clearing and setting it during vblank, with no $2002 read between, raises another.
+    fn update_nmi_line(&mut self) {
+        let line = self.status.contains(StatusRegister::VBLANK_STARTED)
+            && self.ctrl.contains(ControlRegister::GENERATE_NMI);
+        if line && !self.nmi_line {
             self.nmi_interrupt = Some(1);
         }
+        self.nmi_line = line;
+    }
+
+    pub fn write_to_ctrl(&mut self, value: u8) {
+        self.ctrl.update(value);
+        self.update_nmi_line();
     }
 
     pub fn write_to_mask(&mut self, value: u8) {
         self.mask = MaskRegister::from_bits_truncate(value);
     }
 
+    /// True in the first three dots after the vblank flag goes up. A $2002 read then
+    /// still sees the flag, but clears it before the NMI it raised is taken, so that
+    /// frame has no NMI.
+    pub fn vblank_just_started(&self) -> bool {
+        self.scanline == self.region.vblank_scanline()
+            && self.cycles < 3
+            && self.status.contains(StatusRegister::VBLANK_STARTED)
+    }
+
     pub fn read_status(&mut self) -> u8 {
-        let data = self.status.bits();
+        if self.vblank_just_started() {
+            self.nmi_interrupt = None;
+        }
+        // Only the three flag bits are driven; bits 4-0 read back whatever the latch
+        // still holds
+        self.drive_open_bus(self.status.bits(), 0xE0);
+        let data = self.open_bus;
         self.status.remove(StatusRegister::VBLANK_STARTED);
-        self.addr.reset_latch();
-        self.scroll.reset_latch();
+        self.update_nmi_line();
+        self.write_latch = false;
         data
     }
+
+    /// Reads of the write-only registers ($2000, $2001, $2003, $2005, $2006) return the
+    /// I/O latch as it stands. Nothing drives the bus, so its bits keep decaying.
+    pub fn read_open_bus(&self) -> u8 {
+        self.open_bus
+    }
     pub fn write_to_oam_addr(&mut self, value: u8) {
         self.oam_addr = value;
     }
@@ -328,23 +548,32 @@ impl NesPPU {
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
-    pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_addr as usize]
+    /// $2004. Bits 2-4 of a sprite's attribute byte do not exist and read back as 0.
+    pub fn read_oam_data(&mut self) -> u8 {
+        let data = match self.cycles {
+            1..=64 if self.oam_quirks && self.oam_busy() => 0xFF,
+            257..=320 if self.oam_quirks && self.oam_busy() => self.sprite_fetch_byte(self.cycles),
+            _ if self.oam_addr & 3 == 2 => self.oam_data[self.oam_addr as usize] & 0xE3,
+            _ => self.oam_data[self.oam_addr as usize],
+        };
+        self.drive_open_bus(data, 0xFF);
+        data
     }
 
-    pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
-        for &byte in data.iter() {
-            self.oam_data[self.oam_addr as usize] = byte;
-            self.oam_addr = self.oam_addr.wrapping_add(1);
-        }
+    /// One byte of an OAM DMA, written at OAMADDR like a $2004 write outside rendering.
+    pub fn write_oam_dma_byte(&mut self, data: u8) {
+        self.oam_data[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.scroll.write(value, self.write_latch);
+        self.write_latch = !self.write_latch;
     }
 
     pub fn write_to_ppu_addr(&mut self, value: u8) {
-        self.addr.update(value);
+        self.addr.update(value, self.write_latch);
+        self.write_latch = !self.write_latch;
     }
 
     pub fn write_to_data(&mut self, value: u8) {
@@ -352,7 +581,10 @@ impl NesPPU {
 
         match addr {
             0..=0x1FFF => {
-                eprintln!("Warning: Attempted write to CHR address {:#X}", addr);
+                // Writes to real CHR ROM are dropped, as on hardware
+                if self.chr_is_ram {
+                    self.chr_rom[addr as usize] = value;
+                }
             }
             0x2000..=0x3EFF => {
                 let mirrored_addr = self.mirror_vram_addr(addr);
@@ -390,6 +622,7 @@ impl NesPPU {
                     }
                     _ => unreachable!(),
                 };
+                self.drive_open_bus(buffered_data, 0xFF);
                 buffered_data
             }
 
@@ -402,7 +635,14 @@ impl NesPPU {
                 if palette_addr == 0x10 || palette_addr == 0x14 || palette_addr == 0x18 || palette_addr == 0x1C {
                     palette_addr -= 0x10;
                 }
-                self.palette_table[palette_addr]
+                // Palette RAM is 6 bits wide, so bits 7-6 come from the I/O latch. Greyscale
+                // applies to reads as well as to the picture.
+                let mut color = self.palette_table[palette_addr] & 0x3F;
+                if self.mask.contains(MaskRegister::GREYSCALE) {
+                    color &= 0x30;
+                }
+                self.drive_open_bus(color, 0x3F);
+                self.open_bus
             }
             _ => unreachable!(),
         }
@@ -448,10 +688,42 @@ impl NesPPU {
             oam_data: self.oam_data.to_vec(),
             palette_table: self.palette_table,
             addr: self.addr.save_state(),
+            write_latch: self.write_latch,
             internal_data_buf: self.internal_data_buf,
             scanline: self.scanline,
             cycles: self.cycles,
             nmi_interrupt: self.nmi_interrupt,
+            nmi_line: self.nmi_line,
+            open_bus: self.open_bus,
+            open_bus_decay: self.open_bus_decay,
+            chr_ram: if self.chr_is_ram { Some(self.chr_rom.clone()) } else { None },
+        }
+    }
+
+    /// Checks that a state fits this PPU and cartridge before `load_state` touches anything.
+    pub fn validate_state(&self, state: &PpuState) -> Result<(), String> {
+        if state.vram.len() != self.vram.len() {
+            return Err(format!("PPU VRAM is {} bytes, expected {}", state.vram.len(), self.vram.len()));
+        }
+        if state.oam_data.len() != self.oam_data.len() {
+            return Err(format!("OAM is {} bytes, expected {}", state.oam_data.len(), self.oam_data.len()));
+        }
+        if state.scanline >= self.region.scanlines_per_frame() || state.cycles >= 341 {
+            return Err(format!(
+                "PPU position scanline {} dot {} is outside a {} frame",
+                state.scanline,
+                state.cycles,
+                self.region.name()
+            ));
+        }
+        match (&state.chr_ram, self.chr_is_ram) {
+            (Some(chr_ram), true) if chr_ram.len() != self.chr_rom.len() => Err(format!(
+                "CHR RAM is {} bytes, this cartridge has {}",
+                chr_ram.len(),
+                self.chr_rom.len()
+            )),
+            (Some(_), false) => Err("State has CHR RAM but this cartridge uses CHR ROM".to_string()),
+            _ => Ok(()),
         }
     }
 
@@ -465,9 +737,196 @@ impl NesPPU {
         self.oam_data.copy_from_slice(&state.oam_data);
         self.palette_table = state.palette_table;
         self.addr.load_state(&state.addr);
+        self.write_latch = state.write_latch;
         self.internal_data_buf = state.internal_data_buf;
         self.scanline = state.scanline;
         self.cycles = state.cycles;
         self.nmi_interrupt = state.nmi_interrupt;
+        self.nmi_line = state.nmi_line;
+        self.open_bus = state.open_bus;
+        self.open_bus_decay = state.open_bus_decay;
+        if let (true, Some(chr_ram)) = (self.chr_is_ram, &state.chr_ram) {
+            self.chr_rom.copy_from_slice(chr_ram);
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ppu() -> NesPPU {
+        NesPPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL, true)
     }
-}
\ No newline at end of file
+
+    // Ticks a dot at a time until the vblank flag goes up, returning the NMIs raised
+    fn run_to_vblank(ppu: &mut NesPPU) -> usize {
+        let mut nmis = 0;
+        while !ppu.status.contains(StatusRegister::VBLANK_STARTED) {
+            ppu.tick(1);
+            nmis += ppu.poll_nmi_interrupt().is_some() as usize;
+        }
+        nmis
+    }
+
+    // Ticks `dots` dots, returning the NMIs raised
+    fn run_dots(ppu: &mut NesPPU, dots: usize) -> usize {
+        (0..dots)
+            .filter(|_| {
+                ppu.tick(1);
+                ppu.poll_nmi_interrupt().is_some()
+            })
+            .count()
+    }
+
+    const FRAME_DOTS: usize = 341 * 262;
+
+    #[test]
+    fn one_nmi_per_vblank() {
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(run_to_vblank(&mut ppu), 1);
+        assert_eq!(ppu.scanline(), 241);
+        assert_eq!(run_dots(&mut ppu, FRAME_DOTS), 1);
+    }
+
+    #[test]
+    fn rewriting_nmi_enable_in_vblank_raises_nothing() {
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0x80);
+        run_to_vblank(&mut ppu);
+        for _ in 0..3 {
+            ppu.write_to_ctrl(0x80);
+            assert_eq!(ppu.poll_nmi_interrupt(), None);
+            run_dots(&mut ppu, 100);
+        }
+    }
+
+    #[test]
+    fn toggling_nmi_enable_in_vblank_raises_one_nmi_per_enable() {
+        let mut ppu = test_ppu();
+        run_to_vblank(&mut ppu);
+        let mut nmis = 0;
+        for _ in 0..3 {
+            ppu.write_to_ctrl(0x80);
+            ppu.write_to_ctrl(0x80);
+            nmis += ppu.poll_nmi_interrupt().is_some() as usize;
+            ppu.write_to_ctrl(0x00);
+            nmis += run_dots(&mut ppu, 100);
+        }
+        assert_eq!(nmis, 3);
+    }
+
+    #[test]
+    fn enabling_nmi_in_vblank_raises_it_at_once() {
+        let mut ppu = test_ppu();
+        assert_eq!(run_to_vblank(&mut ppu), 0);
+        run_dots(&mut ppu, 100);
+        ppu.write_to_ctrl(0x80);
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+    }
+
+    #[test]
+    fn reading_status_as_vblank_starts_suppresses_the_nmi() {
+        let mut ppu = test_ppu();
+        ppu.write_to_ctrl(0x80);
+        while !(ppu.scanline() == 240 && ppu.cycle() == 340) {
+            ppu.tick(1);
+        }
+        ppu.poll_nmi_interrupt();
+        ppu.tick(1);
+        assert!(ppu.vblank_just_started());
+        assert_eq!(ppu.read_status() & 0x80, 0x80);
+        assert_eq!(ppu.poll_nmi_interrupt(), None);
+        assert_eq!(run_dots(&mut ppu, FRAME_DOTS - 1), 0);
+
+        // A read a CPU cycle later leaves the NMI alone
+        ppu.tick(4);
+        assert!(!ppu.vblank_just_started());
+        ppu.read_status();
+        assert_eq!(ppu.poll_nmi_interrupt(), Some(1));
+    }
+
+    #[test]
+    fn scroll_and_address_writes_share_one_latch() {
+        let mut ppu = test_ppu();
+        // First write to $2006, then the second one lands on $2005 as the Y scroll
+        ppu.write_to_ppu_addr(0x21);
+        ppu.write_to_scroll(0x40);
+        assert_eq!((ppu.scroll.scroll_x, ppu.scroll.scroll_y), (0x00, 0x40));
+        ppu.write_to_ppu_addr(0x08);
+        ppu.write_to_ppu_addr(0x80);
+        assert_eq!(ppu.addr.get(), 0x0880);
+
+        // A status read puts the next write back to being a first write
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.read_status();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0xC0);
+        assert_eq!(ppu.addr.get(), 0x23C0);
+
+        // $2005 then $2006: X scroll, then the address's low byte
+        ppu.write_to_scroll(0x10);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.scroll.scroll_x, 0x10);
+        assert_eq!(ppu.addr.get(), 0x2305);
+        ppu.write_to_scroll(0x77);
+        assert_eq!((ppu.scroll.scroll_x, ppu.scroll.scroll_y), (0x77, 0x40));
+    }
+
+    // Two sprites covering scanline 13 and the rest below the picture, on a PPU running
+    // with rendering on, at dot 1 of scanline 13
+    fn oam_quirks_ppu(quirks: bool) -> NesPPU {
+        let mut ppu = test_ppu();
+        ppu.oam_data.fill(0xF0);
+        ppu.oam_data[..8].copy_from_slice(&[10, 0x01, 0xFF, 20, 12, 0x02, 0x41, 30]);
+        ppu.set_oam_quirks(quirks);
+        ppu.write_to_mask(0x18);
+        while (ppu.scanline(), ppu.cycle()) != (13, 1) {
+            ppu.tick(1);
+        }
+        ppu
+    }
+
+    // $2004 reads on 340 dots in a row, one per dot, with OAMADDR set to `oam_addr`
+    // first, and OAMADDR after the last one
+    fn oam_reads_over_a_line(ppu: &mut NesPPU, oam_addr: u8) -> (Vec<u8>, u8) {
+        ppu.write_to_oam_addr(oam_addr);
+        let reads = (1..=340)
+            .map(|_| {
+                let data = ppu.read_oam_data();
+                ppu.tick(1);
+                data
+            })
+            .collect();
+        (reads, ppu.oam_addr)
+    }
+
+    #[test]
+    fn oam_quirks_show_what_the_sprite_logic_reads() {
+        let mut ppu = oam_quirks_ppu(true);
+        let (reads, oam_addr) = oam_reads_over_a_line(&mut ppu, 0x06);
+        assert_eq!(reads[..64], [0xFF; 64], "secondary OAM clear");
+        // Both sprites are in range of this line, then the slots are empty
+        let fetched: [u8; 16] = [10, 0x01, 0xFF, 20, 20, 20, 20, 20, 12, 0x02, 0x41, 30, 30, 30, 30, 30];
+        assert_eq!(reads[256..272], fetched);
+        assert_eq!(reads[272..320], [0xFF; 48]);
+        assert_eq!(oam_addr, 0, "OAMADDR is cleared on the sprite fetch dots");
+
+        // Nothing changes with rendering off
+        ppu.write_to_mask(0x00);
+        let (reads, oam_addr) = oam_reads_over_a_line(&mut ppu, 0x06);
+        assert_eq!(reads, [0x41 & 0xE3; 340]);
+        assert_eq!(oam_addr, 0x06);
+    }
+
+    #[test]
+    fn without_oam_quirks_reads_follow_oamaddr() {
+        let mut ppu = oam_quirks_ppu(false);
+        let (reads, oam_addr) = oam_reads_over_a_line(&mut ppu, 0x06);
+        // Attribute bytes lose bits 2-4 whatever the setting
+        assert_eq!(reads, [0x41 & 0xE3; 340]);
+        assert_eq!(oam_addr, 0x06);
+        let (reads, _) = oam_reads_over_a_line(&mut ppu, 0x01);
+        assert_eq!(reads, [0x01; 340]);
+    }
+}
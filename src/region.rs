@@ -0,0 +1,74 @@
+// src/region.rs
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::perf::NTSC_FRAME_RATE;
+
+/// Console timing variant. The region decides the CPU clock, how many scanlines a
+/// frame has, where vblank starts and how many PPU dots run per CPU cycle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// Famiclone timing: PAL frame length with NTSC's 3:1 dot ratio and a late vblank.
+    Dendy,
+}
+
+impl Region {
+    pub const ALL: [Region; 3] = [Region::Ntsc, Region::Pal, Region::Dendy];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+        }
+    }
+
+    pub fn cpu_clock_hz(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_773.0,
+            Region::Pal => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_FRAME_RATE,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    /// Wall-clock length of one frame at full speed.
+    pub fn frame_time(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.frame_rate())
+    }
+
+    /// Scanlines per frame, including the pre-render line.
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanline on which the vblank flag is set and NMI fires.
+    pub fn vblank_scanline(&self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// PPU dots per CPU cycle as (numerator, denominator); PAL runs 3.2 dots a cycle.
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 // ADD ALL THESE IMPORTS AT THE TOP
+pub mod filter;
 pub mod frame;
+pub mod overlay;
 use crate::cartridge::Mirroring;
 use crate::palette;
 use crate::ppu::NesPPU;
@@ -27,6 +29,15 @@ fn bg_palette(ppu: &NesPPU, attribute_table: &[u8], tile_column: usize, tile_row
     ]
 }
 
+// HELPER FUNCTION FOR NAMETABLE MIRRORING: logical nametable 0-3 -> 1 KiB VRAM page
+fn nametable_page(ppu: &NesPPU, nametable_idx: usize) -> usize {
+    match ppu.mirroring {
+        Mirroring::VERTICAL => [0, 1, 0, 1][nametable_idx],
+        Mirroring::HORIZONTAL => [0, 0, 1, 1][nametable_idx],
+        _ => nametable_idx,
+    }
+}
+
 // HELPER FUNCTION FOR SPRITE PALETTES
 fn sprite_palette(ppu: &NesPPU, palette_idx: u8) -> [u8; 4] {
     let start = 0x11 + (palette_idx * 4) as usize;
@@ -38,77 +49,112 @@ fn sprite_palette(ppu: &NesPPU, palette_idx: u8) -> [u8; 4] {
     ]
 }
 
+// Each bitplane byte spread to one byte per pixel, with bit 7 (the leftmost pixel) in
+// the most significant byte, so two planes combine into a row of pixel values at once
+const BITPLANE_SPREAD: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut bit = 0;
+        while bit < 8 {
+            table[byte] |= ((byte as u64 >> bit) & 1) << (bit * 8);
+            bit += 1;
+        }
+        byte += 1;
+    }
+    table
+};
+
+// The 2-bit values of a tile row's 8 pixels, left to right, from its two pattern bytes
+fn decode_tile_row(plane0: u8, plane1: u8) -> [u8; 8] {
+    (BITPLANE_SPREAD[plane0 as usize] | BITPLANE_SPREAD[plane1 as usize] << 1).to_be_bytes()
+}
+
+// A scrolled scanline spans 32 tiles plus the partly shown one at its right edge
+const BACKGROUND_ROW_TILES: usize = 33;
+
+// A background tile as cached for its row: where its pattern starts in CHR and the
+// colours of its four pixel values
+#[derive(Clone, Copy, Default)]
+struct BackgroundTile {
+    pattern: usize,
+    colors: [(u8, u8, u8); 4],
+}
+
 pub fn render(ppu: &NesPPU, frame: &mut Frame) {
     let scroll_x = ppu.scroll.scroll_x as i32;
     let scroll_y = ppu.scroll.scroll_y as i32;
 
     // --- Draw Background ---
-    if ppu.mask.contains(crate::ppu::MaskRegister::SHOW_BACKGROUND) {
-        let base_nametable_addr = ppu.ctrl.nametable_addr();
-        let vram = &ppu.vram;
+    // Walks each scanline a tile at a time. A tile row's nametable entries and palettes
+    // are looked up once and kept for its 8 scanlines; each scanline then reads two
+    // pattern bytes per tile and decodes the tile's 8 pixels from them.
+    if ppu.show_background() {
+        let base_nametable_idx = ((ppu.ctrl.nametable_addr() - 0x2000) / 0x400) as usize;
+        let bank = ppu.ctrl.background_pattern_addr();
+        // Columns in the 512-pixel-wide world, from the one under the left edge
+        let first_column = scroll_x as u32 / 8;
+        let fine_scroll_x = (scroll_x % 8) as usize;
+        let mut row_tiles = [BackgroundTile::default(); BACKGROUND_ROW_TILES];
+        // One scanline of those tiles, decoded before it is cut to the picture's width
+        let mut line = [(0u8, 0u8, 0u8); BACKGROUND_ROW_TILES * 8];
+        let mut cached_row = None;
 
-        for y in 0..240 {
-            for x in 0..256 {
-                let world_x = (x as i32 + scroll_x) as u32;
-                let world_y = (y as i32 + scroll_y) as u32;
+        for y in 0..Frame::HEIGHT {
+            let world_y = y as u32 + scroll_y as u32;
+            let nametable_y = (world_y / 240) % 2;
+            let tile_y = (world_y % 240) / 8;
 
-                let nametable_x = (world_x / 256) % 2;
-                let nametable_y = (world_y / 240) % 2;
+            if cached_row != Some((nametable_y, tile_y)) {
+                cached_row = Some((nametable_y, tile_y));
+                for (i, tile) in row_tiles.iter_mut().enumerate() {
+                    let column = first_column + i as u32;
+                    let nametable_x = (column / 32) % 2;
+                    let tile_x = column % 32;
 
-                let nametable_idx = match (base_nametable_addr, nametable_x, nametable_y) {
-                    (0x2000, 0, 0) => 0, (0x2000, 1, 0) => 1, (0x2000, 0, 1) => 2, (0x2000, 1, 1) => 3,
-                    (0x2400, 0, 0) => 1, (0x2400, 1, 0) => 0, (0x2400, 0, 1) => 3, (0x2400, 1, 1) => 2,
-                    (0x2800, 0, 0) => 2, (0x2800, 1, 0) => 3, (0x2800, 0, 1) => 0, (0x2800, 1, 1) => 1,
-                    (0x2C00, 0, 0) => 3, (0x2C00, 1, 0) => 2, (0x2C00, 0, 1) => 1, (0x2C00, 1, 1) => 0,
-                    _ => unreachable!(),
-                };
+                    // The quadrant scrolled into is an offset from the base nametable
+                    let nametable_idx = base_nametable_idx ^ (nametable_x | nametable_y << 1) as usize;
+                    let page_idx = nametable_page(ppu, nametable_idx);
+                    let nametable_ptr = &ppu.vram[(page_idx * 0x400)..((page_idx + 1) * 0x400)];
 
-                let page_idx = match ppu.mirroring {
-                    Mirroring::VERTICAL => [0, 1, 0, 1][nametable_idx],
-                    Mirroring::HORIZONTAL => [0, 0, 1, 1][nametable_idx],
-                    _ => nametable_idx,
-                };
-                let nametable_ptr = &vram[(page_idx * 0x400)..((page_idx + 1) * 0x400)];
+                    let tile_id = nametable_ptr[(tile_y * 32 + tile_x) as usize] as u16;
+                    let palette = bg_palette(ppu, &nametable_ptr[0x3c0..0x400], tile_x as usize, tile_y as usize);
+                    *tile = BackgroundTile {
+                        pattern: (bank + tile_id * 16) as usize,
+                        colors: palette.map(|color| palette::SYSTEM_PALLETE[color as usize]),
+                    };
+                }
+            }
 
-                let tile_x = (world_x % 256) / 8;
-                let tile_y = (world_y % 240) / 8;
-                let tile_idx_in_nametable = tile_y * 32 + tile_x;
+            let pixel_in_tile_y = (world_y % 8) as usize;
+            for (tile, pixels) in row_tiles.iter().zip(line.chunks_exact_mut(8)) {
+                let upper = ppu.chr_rom[tile.pattern + pixel_in_tile_y];
+                let lower = ppu.chr_rom[tile.pattern + pixel_in_tile_y + 8];
 
-                let tile_id = nametable_ptr[tile_idx_in_nametable as usize] as u16;
-                let bank = ppu.ctrl.background_pattern_addr();
-                let tile = &ppu.chr_rom[(bank + tile_id * 16) as usize..];
-                
-                let palette = bg_palette(ppu, &nametable_ptr[0x3c0..0x400], tile_x as usize, tile_y as usize);
+                for (pixel, value) in pixels.iter_mut().zip(decode_tile_row(upper, lower)) {
+                    *pixel = tile.colors[value as usize];
+                }
+            }
 
-                let pixel_in_tile_x = world_x % 8;
-                let pixel_in_tile_y = world_y % 8;
-                
-                let upper = tile[pixel_in_tile_y as usize];
-                let lower = tile[(pixel_in_tile_y + 8) as usize];
-                
-                let value = ((lower >> (7 - pixel_in_tile_x)) & 1) << 1 | ((upper >> (7 - pixel_in_tile_x)) & 1);
-                
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => unreachable!(),
-                };
-                frame.set_pixel(x as usize, y as usize, rgb);
+            // The picture starts fine X pixels into the first tile
+            let row = &mut frame.data[y * Frame::WIDTH * 3..(y + 1) * Frame::WIDTH * 3];
+            for (out, rgb) in row.chunks_exact_mut(3).zip(&line[fine_scroll_x..]) {
+                out.copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
             }
         }
     }
 
     // --- Draw Sprites ---
-    if ppu.mask.contains(crate::ppu::MaskRegister::SHOW_SPRITES) {
+    if ppu.show_sprites() {
         for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-            let tile_y = ppu.oam_data[i] as usize;
+            // OAM holds the scanline above the sprite's top row
+            let tile_y = ppu.oam_data[i] as usize + 1;
             let tile_idx = ppu.oam_data[i + 1] as u16;
             let attributes = ppu.oam_data[i + 2];
             let tile_x = ppu.oam_data[i + 3] as usize;
 
-            if tile_y >= 239 {
+            // Entirely below the picture; partly visible sprites are clipped per pixel below
+            if tile_y >= Frame::HEIGHT {
                 continue;
             }
 
@@ -120,15 +166,8 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
             let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
 
             for y in 0..=7 {
-                let mut upper = tile[y];
-                let mut lower = tile[y + 8];
-
-                'pixel_loop: for x in (0..=7).rev() {
-                    let value = (1 & lower) << 1 | (1 & upper);
-                    upper >>= 1;
-                    lower >>= 1;
-
-                    if value == 0 { continue 'pixel_loop; }
+                for (x, value) in decode_tile_row(tile[y], tile[y + 8]).into_iter().enumerate() {
+                    if value == 0 { continue; }
 
                     let rgb = match value {
                         1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
@@ -146,11 +185,258 @@ pub fn render(ppu: &NesPPU, frame: &mut Frame) {
                         false => tile_y + y,
                     };
                     
-                    if pixel_x < 256 && pixel_y < 240 {
+                    if pixel_x < Frame::WIDTH && pixel_y < Frame::HEIGHT {
                         frame.set_pixel(pixel_x, pixel_y, rgb);
                     }
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+pub const NAMETABLES_WIDTH: usize = 512;
+pub const NAMETABLES_HEIGHT: usize = 480;
+
+/// Renders all four nametables, composited per the cartridge mirroring, into a
+/// 512x480 RGB24 buffer. Scroll and the background-enable bit are ignored.
+pub fn render_nametables(ppu: &NesPPU) -> Vec<u8> {
+    let mut data = vec![0u8; NAMETABLES_WIDTH * NAMETABLES_HEIGHT * 3];
+    let bank = ppu.ctrl.background_pattern_addr();
+
+    for nametable_idx in 0..4 {
+        let page_idx = nametable_page(ppu, nametable_idx);
+        let nametable_ptr = &ppu.vram[(page_idx * 0x400)..((page_idx + 1) * 0x400)];
+        let origin_x = (nametable_idx % 2) * 256;
+        let origin_y = (nametable_idx / 2) * 240;
+
+        for tile_y in 0..30 {
+            for tile_x in 0..32 {
+                let tile_id = nametable_ptr[tile_y * 32 + tile_x] as u16;
+                let tile = &ppu.chr_rom[(bank + tile_id * 16) as usize..=(bank + tile_id * 16 + 15) as usize];
+                let palette = bg_palette(ppu, &nametable_ptr[0x3c0..0x400], tile_x, tile_y);
+
+                for y in 0..8 {
+                    for (x, value) in decode_tile_row(tile[y], tile[y + 8]).into_iter().enumerate() {
+                        let rgb = palette::SYSTEM_PALLETE[palette[value as usize] as usize];
+
+                        let px = origin_x + tile_x * 8 + x;
+                        let py = origin_y + tile_y * 8 + y;
+                        let base = (py * NAMETABLES_WIDTH + px) * 3;
+                        data[base] = rgb.0;
+                        data[base + 1] = rgb.1;
+                        data[base + 2] = rgb.2;
+                    }
+                }
+            }
+        }
+    }
+    data
+}
+
+pub const PALETTE_SWATCH_SIZE: usize = 16;
+pub const PALETTE_WIDTH: usize = 16 * PALETTE_SWATCH_SIZE;
+pub const PALETTE_HEIGHT: usize = 6 * PALETTE_SWATCH_SIZE;
+
+/// Renders the palettes as 16px swatches into an RGB24 buffer: four rows with the
+/// 64-colour system palette, then the 32 palette RAM entries (background row, sprite row).
+pub fn render_palette(ppu: &NesPPU) -> Vec<u8> {
+    let mut data = vec![0u8; PALETTE_WIDTH * PALETTE_HEIGHT * 3];
+
+    let entries = (0..64usize)
+        .map(|i| palette::SYSTEM_PALLETE[i])
+        .chain(ppu.palette_table.iter().map(|&i| palette::SYSTEM_PALLETE[(i & 0x3F) as usize]));
+
+    for (i, rgb) in entries.enumerate() {
+        let origin_x = (i % 16) * PALETTE_SWATCH_SIZE;
+        let origin_y = (i / 16) * PALETTE_SWATCH_SIZE;
+        for py in origin_y..origin_y + PALETTE_SWATCH_SIZE {
+            for px in origin_x..origin_x + PALETTE_SWATCH_SIZE {
+                let base = (py * PALETTE_WIDTH + px) * 3;
+                data[base] = rgb.0;
+                data[base + 1] = rgb.1;
+                data[base + 2] = rgb.2;
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // A PPU with random pattern, nametable and palette contents, the background shown
+    fn random_ppu(rng: &mut StdRng, mirroring: Mirroring) -> NesPPU {
+        let mut chr = vec![0; 0x2000];
+        rng.fill(&mut chr[..]);
+        let mut ppu = NesPPU::new(chr, mirroring, false);
+        rng.fill(&mut ppu.vram[..]);
+        for entry in ppu.palette_table.iter_mut() {
+            *entry = rng.gen_range(0..64);
+        }
+        ppu.write_to_mask(0x08);
+        ppu
+    }
+
+    // The background as drawn before tile rows were cached: every pixel looks up its own
+    // nametable entry, palette and pattern bits
+    fn render_background_per_pixel(ppu: &NesPPU, frame: &mut Frame) {
+        let scroll_x = ppu.scroll.scroll_x as i32;
+        let scroll_y = ppu.scroll.scroll_y as i32;
+        let base_nametable_addr = ppu.ctrl.nametable_addr();
+
+        for y in 0..240 {
+            for x in 0..256 {
+                let world_x = (x + scroll_x) as u32;
+                let world_y = (y + scroll_y) as u32;
+
+                let nametable_x = (world_x / 256) % 2;
+                let nametable_y = (world_y / 240) % 2;
+
+                let nametable_idx = match (base_nametable_addr, nametable_x, nametable_y) {
+                    (0x2000, 0, 0) => 0, (0x2000, 1, 0) => 1, (0x2000, 0, 1) => 2, (0x2000, 1, 1) => 3,
+                    (0x2400, 0, 0) => 1, (0x2400, 1, 0) => 0, (0x2400, 0, 1) => 3, (0x2400, 1, 1) => 2,
+                    (0x2800, 0, 0) => 2, (0x2800, 1, 0) => 3, (0x2800, 0, 1) => 0, (0x2800, 1, 1) => 1,
+                    (0x2C00, 0, 0) => 3, (0x2C00, 1, 0) => 2, (0x2C00, 0, 1) => 1, (0x2C00, 1, 1) => 0,
+                    _ => unreachable!(),
+                };
+
+                let page_idx = nametable_page(ppu, nametable_idx);
+                let nametable_ptr = &ppu.vram[(page_idx * 0x400)..((page_idx + 1) * 0x400)];
+
+                let tile_x = (world_x % 256) / 8;
+                let tile_y = (world_y % 240) / 8;
+                let tile_id = nametable_ptr[(tile_y * 32 + tile_x) as usize] as u16;
+                let bank = ppu.ctrl.background_pattern_addr();
+                let tile = &ppu.chr_rom[(bank + tile_id * 16) as usize..];
+
+                let palette = bg_palette(ppu, &nametable_ptr[0x3c0..0x400], tile_x as usize, tile_y as usize);
+
+                let pixel_in_tile_x = world_x % 8;
+                let pixel_in_tile_y = world_y % 8;
+                let upper = tile[pixel_in_tile_y as usize];
+                let lower = tile[(pixel_in_tile_y + 8) as usize];
+                let value = ((lower >> (7 - pixel_in_tile_x)) & 1) << 1 | ((upper >> (7 - pixel_in_tile_x)) & 1);
+
+                let rgb = match value {
+                    0 => palette::SYSTEM_PALLETE[ppu.palette_table[0] as usize],
+                    _ => palette::SYSTEM_PALLETE[palette[value as usize] as usize],
+                };
+                frame.set_pixel(x as usize, y as usize, rgb);
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_at_y_238_shows_only_its_top_row() {
+        let mut chr = vec![0; 0x2000];
+        // Tile 1: top row colour 1, the other seven colour 2
+        chr[0x10] = 0xFF;
+        chr[0x19..0x20].fill(0xFF);
+        let mut ppu = NesPPU::new(chr, Mirroring::HORIZONTAL, false);
+        ppu.palette_table[0x11..0x14].copy_from_slice(&[0x16, 0x2A, 0x12]);
+        ppu.write_to_mask(0x10);
+        ppu.oam_data.fill(0xFF);
+        let pixel = |frame: &Frame, x: usize, y: usize| {
+            let base = (y * Frame::WIDTH + x) * 3;
+            (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+        };
+
+        for (attributes, bottom_color) in [(0x00, 0x16), (0x80, 0x2A)] {
+            ppu.oam_data[..4].copy_from_slice(&[238, 1, attributes, 100]);
+            let mut frame = Frame::new();
+            render(&ppu, &mut frame);
+            for x in 0..Frame::WIDTH {
+                let expected = if (100..108).contains(&x) { palette::SYSTEM_PALLETE[bottom_color] } else { (0, 0, 0) };
+                assert_eq!(pixel(&frame, x, 239), expected, "attributes {:#04X} x {}", attributes, x);
+                assert_eq!(pixel(&frame, x, 238), (0, 0, 0));
+            }
+        }
+
+        // One line lower the sprite starts below the picture
+        ppu.oam_data[..4].copy_from_slice(&[239, 1, 0, 100]);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert!(frame.data.iter().all(|&byte| byte == 0));
+    }
+
+    // The tile row decode before `BITPLANE_SPREAD`: two shifts and masks per pixel
+    fn decode_tile_row_bit_by_bit(plane0: u8, plane1: u8) -> [u8; 8] {
+        std::array::from_fn(|x| ((plane1 >> (7 - x)) & 1) << 1 | ((plane0 >> (7 - x)) & 1))
+    }
+
+    #[test]
+    fn tile_rows_decode_as_bit_by_bit() {
+        for plane0 in 0..=255u8 {
+            for plane1 in 0..=255u8 {
+                let expected = decode_tile_row_bit_by_bit(plane0, plane1);
+                assert_eq!(decode_tile_row(plane0, plane1), expected, "planes {:#04X} {:#04X}", plane0, plane1);
+            }
+        }
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture` to compare the two decodes
+    #[test]
+    #[ignore]
+    fn tile_row_decode_cost() {
+        let time = |name: &str, decode: fn(u8, u8) -> [u8; 8]| {
+            let start = std::time::Instant::now();
+            let mut checksum = 0u64;
+            for _ in 0..100 {
+                for planes in 0..=u16::MAX {
+                    let [plane0, plane1] = std::hint::black_box(planes).to_le_bytes();
+                    checksum = checksum.wrapping_add(u64::from_le_bytes(decode(plane0, plane1)));
+                }
+            }
+            println!("{}: {:?} for 6.5M rows (checksum {:#X})", name, start.elapsed(), checksum);
+        };
+        time("bit by bit", decode_tile_row_bit_by_bit);
+        time("table", decode_tile_row);
+    }
+
+    #[test]
+    fn tile_row_background_matches_the_per_pixel_one() {
+        let mut rng = StdRng::seed_from_u64(455);
+        for mirroring in [Mirroring::HORIZONTAL, Mirroring::VERTICAL] {
+            for ctrl in [0x00, 0x01, 0x02, 0x03, 0x10, 0x13] {
+                for (scroll_x, scroll_y) in [(0, 0), (3, 7), (129, 100), (255, 239), (8, 250), (200, 255)] {
+                    let mut ppu = random_ppu(&mut rng, mirroring.clone());
+                    ppu.write_to_ctrl(ctrl);
+                    ppu.scroll.scroll_x = scroll_x;
+                    ppu.scroll.scroll_y = scroll_y;
+
+                    let mut expected = Frame::new();
+                    render_background_per_pixel(&ppu, &mut expected);
+                    let mut frame = Frame::new();
+                    render(&ppu, &mut frame);
+                    assert!(
+                        frame.data == expected.data,
+                        "{:?} ctrl {:#04X} scroll ({}, {})",
+                        mirroring, ctrl, scroll_x, scroll_y
+                    );
+                }
+            }
+        }
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture` to compare the two backgrounds
+    #[test]
+    #[ignore]
+    fn tile_row_background_cost() {
+        let mut ppu = random_ppu(&mut StdRng::seed_from_u64(455), Mirroring::VERTICAL);
+        ppu.scroll.scroll_x = 3;
+        ppu.scroll.scroll_y = 7;
+        let time = |name: &str, render_frame: fn(&NesPPU, &mut Frame)| {
+            let mut frame = Frame::new();
+            let start = std::time::Instant::now();
+            for _ in 0..200 {
+                render_frame(std::hint::black_box(&ppu), &mut frame);
+            }
+            println!("{}: {:?} per frame", name, start.elapsed() / 200);
+        };
+        time("per pixel", render_background_per_pixel);
+        time("tile rows", render);
+    }
+}
@@ -0,0 +1,152 @@
+// src/render/filter.rs
+
+use super::frame::Frame;
+
+/// Output lines of the scanline filter are scaled by this much (out of 256).
+const SCANLINE_BRIGHTNESS: u32 = 160;
+/// Strength of the CRT barrel distortion; 0 leaves the image flat.
+const CRT_CURVATURE: f32 = 0.06;
+/// Weight of the two phosphor colours that are not lit by each mask column.
+const CRT_MASK_DIM: f32 = 0.7;
+
+/// A post-processing step that scales the 256x240 frame up for display.
+pub trait VideoFilter {
+    /// Writes `frame` scaled by `scale` into `out` as RGB24, resizing `out` as needed.
+    fn apply(&mut self, frame: &Frame, scale: usize, out: &mut Vec<u8>);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    None,
+    Scanlines,
+    Crt,
+}
+
+impl FilterKind {
+    pub const ALL: [FilterKind; 3] = [FilterKind::None, FilterKind::Scanlines, FilterKind::Crt];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::None => "None",
+            FilterKind::Scanlines => "Scanlines",
+            FilterKind::Crt => "CRT",
+        }
+    }
+
+    /// `None` means the frame is shown as-is and SDL does the scaling.
+    pub fn create(&self) -> Option<Box<dyn VideoFilter>> {
+        match self {
+            FilterKind::None => None,
+            FilterKind::Scanlines => Some(Box::new(Scanlines)),
+            FilterKind::Crt => Some(Box::new(Crt::new())),
+        }
+    }
+}
+
+/// Nearest-neighbour upscale with every other output line darkened.
+pub struct Scanlines;
+
+impl VideoFilter for Scanlines {
+    fn apply(&mut self, frame: &Frame, scale: usize, out: &mut Vec<u8>) {
+        let width = Frame::WIDTH * scale;
+        out.resize(width * Frame::HEIGHT * scale * 3, 0);
+
+        for (oy, line) in out.chunks_exact_mut(width * 3).enumerate() {
+            let src_line = &frame.data[(oy / scale) * Frame::WIDTH * 3..][..Frame::WIDTH * 3];
+            let dark = oy % 2 == 1;
+            for (ox, px) in line.chunks_exact_mut(3).enumerate() {
+                let src = &src_line[(ox / scale) * 3..][..3];
+                for c in 0..3 {
+                    px[c] = if dark {
+                        (src[c] as u32 * SCANLINE_BRIGHTNESS / 256) as u8
+                    } else {
+                        src[c]
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Rough CRT look: barrel curvature, an RGB phosphor mask and soft scanlines.
+/// The geometry is the same every frame, so it is worked out once per scale.
+pub struct Crt {
+    scale: usize,
+    // Per output pixel: byte offset of the source pixel, or None outside the curved screen
+    sources: Vec<Option<u32>>,
+    // Per output pixel and channel, brightness out of 256
+    weights: Vec<[u16; 3]>,
+}
+
+impl Crt {
+    pub fn new() -> Self {
+        Crt {
+            scale: 0,
+            sources: Vec::new(),
+            weights: Vec::new(),
+        }
+    }
+
+    fn build(&mut self, scale: usize) {
+        let width = Frame::WIDTH * scale;
+        let height = Frame::HEIGHT * scale;
+        self.scale = scale;
+        self.sources.clear();
+        self.weights.clear();
+
+        for oy in 0..height {
+            // Brightest in the middle of each source line, dimmer towards its edges
+            let line_pos = (oy % scale) as f32 + 0.5;
+            let line_dist = (line_pos / scale as f32 - 0.5).abs() * 2.0;
+            let scanline = 1.0 - 0.35 * line_dist * line_dist;
+
+            for ox in 0..width {
+                let u = (ox as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+                let v = (oy as f32 + 0.5) / height as f32 * 2.0 - 1.0;
+                let cu = u * (1.0 + CRT_CURVATURE * v * v);
+                let cv = v * (1.0 + CRT_CURVATURE * u * u);
+
+                if cu.abs() >= 1.0 || cv.abs() >= 1.0 {
+                    self.sources.push(None);
+                    self.weights.push([0; 3]);
+                    continue;
+                }
+
+                let sx = ((cu + 1.0) / 2.0 * Frame::WIDTH as f32) as usize;
+                let sy = ((cv + 1.0) / 2.0 * Frame::HEIGHT as f32) as usize;
+                self.sources.push(Some(((sy * Frame::WIDTH + sx) * 3) as u32));
+
+                let mut weight = [(CRT_MASK_DIM * scanline * 256.0) as u16; 3];
+                weight[ox % 3] = (scanline * 256.0) as u16;
+                self.weights.push(weight);
+            }
+        }
+    }
+}
+
+impl Default for Crt {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoFilter for Crt {
+    fn apply(&mut self, frame: &Frame, scale: usize, out: &mut Vec<u8>) {
+        if scale != self.scale {
+            self.build(scale);
+        }
+        out.resize(self.sources.len() * 3, 0);
+
+        for ((px, source), weight) in out.chunks_exact_mut(3).zip(&self.sources).zip(&self.weights) {
+            match source {
+                Some(offset) => {
+                    let src = &frame.data[*offset as usize..][..3];
+                    for c in 0..3 {
+                        px[c] = ((src[c] as u32 * weight[c] as u32) >> 8) as u8;
+                    }
+                }
+                None => px.fill(0),
+            }
+        }
+    }
+}
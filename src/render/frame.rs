@@ -20,4 +20,10 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,146 @@
+// src/render/overlay.rs
+
+use super::frame::Frame;
+use crate::joypad::JoypadButton;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const CHAR_ADVANCE: usize = GLYPH_WIDTH + 1;
+const LINE_ADVANCE: usize = GLYPH_HEIGHT + 2;
+
+pub const CONTROLLER_WIDTH: usize = 29;
+pub const CONTROLLER_HEIGHT: usize = 13;
+
+const CONTROLLER_BODY: (u8, u8, u8) = (0x50, 0x50, 0x50);
+const BUTTON_RELEASED: (u8, u8, u8) = (0x10, 0x10, 0x10);
+const BUTTON_PRESSED: (u8, u8, u8) = (0xFF, 0xFF, 0xFF);
+const AB_PRESSED: (u8, u8, u8) = (0xFF, 0x30, 0x30);
+
+// Controller diagram layout: button, x, y, width, height relative to the body
+#[rustfmt::skip]
+const CONTROLLER_BUTTONS: [(JoypadButton, usize, usize, usize, usize); 8] = [
+    (JoypadButton::UP,       5,  2, 3, 3),
+    (JoypadButton::DOWN,     5,  8, 3, 3),
+    (JoypadButton::LEFT,     2,  5, 3, 3),
+    (JoypadButton::RIGHT,    8,  5, 3, 3),
+    (JoypadButton::SELECT,   12, 8, 3, 2),
+    (JoypadButton::START,    16, 8, 3, 2),
+    (JoypadButton::BUTTON_B, 20, 5, 3, 3),
+    (JoypadButton::BUTTON_A, 24, 5, 3, 3),
+];
+
+// 3x5 font, one byte per row, bit 2 is the leftmost pixel
+#[rustfmt::skip]
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '?' => [0b111, 0b001, 0b010, 0b000, 0b010],
+        _ => [0; GLYPH_HEIGHT],
+    }
+}
+
+/// Draws a single line of text with its top-left corner at (x, y).
+pub fn draw_text(frame: &mut Frame, x: usize, y: usize, text: &str, rgb: (u8, u8, u8)) {
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        let glyph_x = x + i * CHAR_ADVANCE;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> col) != 0 {
+                    let px = glyph_x + col;
+                    if px < Frame::WIDTH && y + row < Frame::HEIGHT {
+                        frame.set_pixel(px, y + row, rgb);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fill_rect(frame: &mut Frame, x: usize, y: usize, width: usize, height: usize, rgb: (u8, u8, u8)) {
+    for py in y..(y + height).min(Frame::HEIGHT) {
+        for px in x..(x + width).min(Frame::WIDTH) {
+            frame.set_pixel(px, py, rgb);
+        }
+    }
+}
+
+/// Width in pixels of the box `draw_text_block` draws for `lines`.
+pub fn text_block_width(lines: &[String]) -> usize {
+    lines.iter().map(|l| l.chars().count()).max().unwrap_or(0) * CHAR_ADVANCE + 1
+}
+
+/// Draws white text lines on a black backing box so they stay legible over any game.
+pub fn draw_text_block(frame: &mut Frame, x: usize, y: usize, lines: &[String]) {
+    let width = text_block_width(lines);
+    let height = lines.len() * LINE_ADVANCE;
+    fill_rect(frame, x, y, width, height, (0, 0, 0));
+
+    for (i, line) in lines.iter().enumerate() {
+        draw_text(frame, x + 1, y + 1 + i * LINE_ADVANCE, line, (0xFF, 0xFF, 0xFF));
+    }
+}
+
+/// Draws a small controller with its pressed buttons lit, labelled with `label`.
+pub fn draw_controller(frame: &mut Frame, x: usize, y: usize, label: &str, buttons: JoypadButton) {
+    fill_rect(frame, x, y, CONTROLLER_WIDTH, CONTROLLER_HEIGHT, CONTROLLER_BODY);
+    // D-pad centre, never lit
+    fill_rect(frame, x + 5, y + 5, 3, 3, BUTTON_RELEASED);
+    draw_text(frame, x + 14, y + 1, label, BUTTON_PRESSED);
+
+    for (button, bx, by, width, height) in CONTROLLER_BUTTONS {
+        let rgb = if !buttons.contains(button) {
+            BUTTON_RELEASED
+        } else if button.intersects(JoypadButton::BUTTON_A | JoypadButton::BUTTON_B) {
+            AB_PRESSED
+        } else {
+            BUTTON_PRESSED
+        };
+        fill_rect(frame, x + bx, y + by, width, height, rgb);
+    }
+}
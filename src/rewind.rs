@@ -0,0 +1,295 @@
+// src/rewind.rs
+
+use std::collections::VecDeque;
+use crate::cpu::EmulatorSnapshot;
+
+/// The large RAM arrays of a snapshot, split out so they can be stored as deltas.
+#[derive(Clone, Default)]
+pub struct SnapshotMemory {
+    pub(crate) cpu_ram: Vec<u8>,
+    pub(crate) vram: Vec<u8>,
+    pub(crate) oam: Vec<u8>,
+    pub(crate) palette: Vec<u8>,
+    pub(crate) chr_ram: Option<Vec<u8>>,
+}
+
+/// XOR of two equally sized byte arrays, stored as runs of differing bytes.
+/// Applying the same delta twice restores the original, so it works in both directions.
+#[derive(Default)]
+struct ByteDelta {
+    spans: Vec<(u32, Vec<u8>)>,
+}
+
+impl ByteDelta {
+    fn between(old: &[u8], new: &[u8]) -> Self {
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < old.len() {
+            if old[i] == new[i] {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            let mut run = Vec::new();
+            while i < old.len() && old[i] != new[i] {
+                run.push(old[i] ^ new[i]);
+                i += 1;
+            }
+            spans.push((start as u32, run));
+        }
+        ByteDelta { spans }
+    }
+
+    fn apply(&self, data: &mut [u8]) {
+        for (offset, run) in &self.spans {
+            let start = *offset as usize;
+            for (byte, x) in data[start..start + run.len()].iter_mut().zip(run) {
+                *byte ^= x;
+            }
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.spans.iter().map(|(_, run)| run.len() + 4).sum()
+    }
+}
+
+#[derive(Default)]
+struct MemoryDelta {
+    cpu_ram: ByteDelta,
+    vram: ByteDelta,
+    oam: ByteDelta,
+    palette: ByteDelta,
+    chr_ram: ByteDelta,
+}
+
+impl MemoryDelta {
+    // None when the layouts differ (e.g. CHR RAM appeared), which forces the history to start over
+    fn between(old: &SnapshotMemory, new: &SnapshotMemory) -> Option<Self> {
+        let same_len = |a: &[u8], b: &[u8]| a.len() == b.len();
+        let chr_ram = match (&old.chr_ram, &new.chr_ram) {
+            (Some(a), Some(b)) if same_len(a, b) => ByteDelta::between(a, b),
+            (None, None) => ByteDelta::default(),
+            _ => return None,
+        };
+        if !same_len(&old.cpu_ram, &new.cpu_ram)
+            || !same_len(&old.vram, &new.vram)
+            || !same_len(&old.oam, &new.oam)
+            || !same_len(&old.palette, &new.palette)
+        {
+            return None;
+        }
+
+        Some(MemoryDelta {
+            cpu_ram: ByteDelta::between(&old.cpu_ram, &new.cpu_ram),
+            vram: ByteDelta::between(&old.vram, &new.vram),
+            oam: ByteDelta::between(&old.oam, &new.oam),
+            palette: ByteDelta::between(&old.palette, &new.palette),
+            chr_ram,
+        })
+    }
+
+    fn apply(&self, mem: &mut SnapshotMemory) {
+        self.cpu_ram.apply(&mut mem.cpu_ram);
+        self.vram.apply(&mut mem.vram);
+        self.oam.apply(&mut mem.oam);
+        self.palette.apply(&mut mem.palette);
+        if let Some(chr_ram) = mem.chr_ram.as_mut() {
+            self.chr_ram.apply(chr_ram);
+        }
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.cpu_ram.size_bytes()
+            + self.vram.size_bytes()
+            + self.oam.size_bytes()
+            + self.palette.size_bytes()
+            + self.chr_ram.size_bytes()
+    }
+}
+
+struct RewindEntry {
+    /// Registers, APU and other small state, with the RAM arrays moved out.
+    state: EmulatorSnapshot,
+    /// Memory of this entry XOR the previous one; empty for the oldest entry.
+    delta: MemoryDelta,
+}
+
+/// Bounded history of snapshots for rewind, stored as per-frame deltas back from the newest.
+///
+/// Only the newest entry's memory is kept in full; popping XORs one delta out of it to
+/// get the previous entry's, so it never has to replay the whole chain.
+pub struct RewindBuffer {
+    entries: VecDeque<RewindEntry>,
+    latest: SnapshotMemory,
+    capacity: usize,
+    memory_budget: usize,
+    delta_bytes: usize,
+}
+
+impl RewindBuffer {
+    /// `capacity` caps the number of entries and `memory_budget` the approximate bytes of
+    /// RAM data held; whichever is hit first evicts the oldest entries.
+    pub fn new(capacity: usize, memory_budget: usize) -> Self {
+        RewindBuffer {
+            entries: VecDeque::with_capacity(capacity),
+            latest: SnapshotMemory::default(),
+            capacity: capacity.max(1),
+            memory_budget,
+            delta_bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.latest = SnapshotMemory::default();
+        self.delta_bytes = 0;
+    }
+
+    /// Records the newest snapshot, evicting the oldest one when full.
+    pub fn push(&mut self, mut snapshot: EmulatorSnapshot) {
+        let memory = snapshot.take_memory();
+
+        let delta = if self.entries.is_empty() {
+            None
+        } else {
+            MemoryDelta::between(&self.latest, &memory)
+        };
+
+        match delta {
+            Some(delta) => {
+                self.delta_bytes += delta.size_bytes();
+                self.entries.push_back(RewindEntry { state: snapshot, delta });
+            }
+            None => {
+                // First entry, or memory layout changed: start over from this one
+                self.entries.clear();
+                self.delta_bytes = 0;
+                self.entries.push_back(RewindEntry {
+                    state: snapshot,
+                    delta: MemoryDelta::default(),
+                });
+            }
+        }
+        self.latest = memory;
+
+        while self.entries.len() > 1
+            && (self.entries.len() > self.capacity || self.memory_usage() > self.memory_budget)
+        {
+            self.entries.pop_front();
+            // Nothing is ever popped past the oldest entry, so its delta can go
+            if let Some(oldest) = self.entries.front_mut() {
+                self.delta_bytes -= oldest.delta.size_bytes();
+                oldest.delta = MemoryDelta::default();
+            }
+        }
+    }
+
+    /// Removes and returns the newest snapshot.
+    pub fn pop(&mut self) -> Option<EmulatorSnapshot> {
+        let entry = self.entries.pop_back()?;
+        let mut snapshot = entry.state;
+        snapshot.restore_memory(self.latest.clone());
+
+        if self.entries.is_empty() {
+            self.latest = SnapshotMemory::default();
+        } else {
+            // XOR the delta back out to recover the previous entry's memory
+            entry.delta.apply(&mut self.latest);
+            self.delta_bytes -= entry.delta.size_bytes();
+        }
+        Some(snapshot)
+    }
+
+    /// Approximate heap usage of the stored RAM data, for memory budgeting.
+    pub fn memory_usage(&self) -> usize {
+        let full = |m: &SnapshotMemory| {
+            m.cpu_ram.len() + m.vram.len() + m.oam.len() + m.palette.len()
+                + m.chr_ram.as_ref().map_or(0, |c| c.len())
+        };
+        full(&self.latest) + self.delta_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::{Mirroring, Rom};
+    use crate::cpu::CPU;
+
+    // A game that rewrites RAM all the time: INC $00, INX, TXA, STA $0300,X, JMP $8000
+    fn busy_cpu() -> CPU<'static> {
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..10].copy_from_slice(&[0xE6, 0x00, 0xE8, 0x8A, 0x9D, 0x00, 0x03, 0x4C, 0x00, 0x80]);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        let rom = Rom::from_raw(&prg, None, Mirroring::VERTICAL).unwrap();
+        let mut cpu = CPU::new(Bus::new(rom, |_, _, _, _| {}));
+        cpu.reset();
+        cpu
+    }
+
+    fn run_frame(cpu: &mut CPU) {
+        let target = cpu.bus.frame_count() + 1;
+        while cpu.bus.frame_count() < target {
+            cpu.step();
+        }
+    }
+
+    fn encoded(snapshot: &EmulatorSnapshot) -> Vec<u8> {
+        bincode::serialize(snapshot).unwrap()
+    }
+
+    #[test]
+    fn pops_return_the_pushed_snapshots_newest_first() {
+        let mut cpu = busy_cpu();
+        let mut buffer = RewindBuffer::new(8, usize::MAX);
+        let mut pushed = Vec::new();
+        for _ in 0..20 {
+            run_frame(&mut cpu);
+            pushed.push(encoded(&cpu.save_snapshot()));
+            buffer.push(cpu.save_snapshot());
+        }
+        assert_eq!(buffer.len(), 8);
+        for expected in pushed.iter().rev().take(8) {
+            let snapshot = buffer.pop().unwrap();
+            assert!(encoded(&snapshot) == *expected);
+            cpu.try_load_snapshot(&snapshot).unwrap();
+        }
+        assert!(buffer.pop().is_none());
+        assert_eq!(buffer.memory_usage(), 0);
+    }
+
+    #[test]
+    fn memory_budget_evicts_the_oldest_entries() {
+        let mut cpu = busy_cpu();
+        // Room for about five frames of this game
+        let mut unbounded = RewindBuffer::new(100, usize::MAX);
+        for _ in 0..5 {
+            run_frame(&mut cpu);
+            unbounded.push(cpu.save_snapshot());
+        }
+        let budget = unbounded.memory_usage();
+        let mut bounded = RewindBuffer::new(100, budget);
+        for _ in 0..30 {
+            run_frame(&mut cpu);
+            bounded.push(cpu.save_snapshot());
+            assert!(bounded.len() == 1 || bounded.memory_usage() <= budget);
+        }
+        assert!((2..10).contains(&bounded.len()), "{} entries", bounded.len());
+
+        // The newest entry always stays, whatever the budget
+        let mut tiny = RewindBuffer::new(100, 0);
+        tiny.push(cpu.save_snapshot());
+        tiny.push(cpu.save_snapshot());
+        assert_eq!(tiny.len(), 1);
+    }
+}
@@ -0,0 +1,160 @@
+// src/settings.rs
+
+use std::fs;
+
+use log::warn;
+
+use crate::emulator::{AudioBackend, FastForwardAudio};
+use nesemu::apu;
+use nesemu::joypad::{AnalogToDpad, Autofire, JoypadButton, OpposingDirections};
+
+const SETTINGS_PATH: &str = "jazzness.cfg";
+
+/// Front-end options that are kept between runs, stored as `key = value` lines in the
+/// working directory. Unknown keys and bad values are skipped, so an old or hand-edited
+/// file never stops the emulator from starting.
+pub struct Settings {
+    pub autofire_buttons: JoypadButton,
+    pub autofire_period: u32,
+    pub autofire_duty: u32,
+    pub opposing_directions: OpposingDirections,
+    pub stick_deadzone: f32,
+    pub stick_sensitivity: f32,
+    pub stick_eight_way: bool,
+    pub fast_forward_audio: FastForwardAudio,
+    pub mute_on_pause: bool,
+    pub band_limited_audio: bool,
+    pub hardware_filters: bool,
+    pub silence_ultrasonic_triangle: bool,
+    /// One of `apu::SAMPLE_RATES`.
+    pub sample_rate: u32,
+    pub audio_backend: AudioBackend,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        Settings {
+            autofire_buttons: JoypadButton::empty(),
+            autofire_period: Autofire::DEFAULT_PERIOD,
+            autofire_duty: Autofire::DEFAULT_DUTY,
+            opposing_directions: OpposingDirections::default(),
+            stick_deadzone: AnalogToDpad::DEFAULT_DEADZONE,
+            stick_sensitivity: AnalogToDpad::DEFAULT_SENSITIVITY,
+            stick_eight_way: true,
+            fast_forward_audio: FastForwardAudio::default(),
+            mute_on_pause: true,
+            band_limited_audio: true,
+            hardware_filters: true,
+            silence_ultrasonic_triangle: true,
+            sample_rate: apu::DEFAULT_SAMPLE_RATE,
+            audio_backend: AudioBackend::default(),
+        }
+    }
+
+    /// Reads the settings file, falling back to the defaults for anything missing.
+    pub fn load() -> Self {
+        let mut settings = Settings::new();
+        let Ok(text) = fs::read_to_string(SETTINGS_PATH) else {
+            return settings;
+        };
+
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let Some((key, value)) = line.split_once('=') else {
+                warn!("Ignoring malformed settings line '{}'", line);
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let number = || value.parse::<u32>().ok();
+            let fraction = || value.parse::<f32>().ok().filter(|f| f.is_finite());
+            let parsed = match key {
+                "autofire_buttons" => number().map(|n| settings.autofire_buttons = JoypadButton::from_bits_truncate(n as u8)),
+                "autofire_period" => number().map(|n| settings.autofire_period = n),
+                "autofire_duty" => number().map(|n| settings.autofire_duty = n),
+                "opposing_directions" => opposing_directions_from_key(value).map(|mode| settings.opposing_directions = mode),
+                "stick_deadzone" => fraction().map(|f| settings.stick_deadzone = f),
+                "stick_sensitivity" => fraction().map(|f| settings.stick_sensitivity = f),
+                "stick_eight_way" => value.parse::<bool>().ok().map(|b| settings.stick_eight_way = b),
+                "fast_forward_audio" => fast_forward_audio_from_key(value).map(|mode| settings.fast_forward_audio = mode),
+                "mute_on_pause" => value.parse::<bool>().ok().map(|b| settings.mute_on_pause = b),
+                "band_limited_audio" => value.parse::<bool>().ok().map(|b| settings.band_limited_audio = b),
+                "hardware_filters" => value.parse::<bool>().ok().map(|b| settings.hardware_filters = b),
+                "silence_ultrasonic_triangle" => value.parse::<bool>().ok().map(|b| settings.silence_ultrasonic_triangle = b),
+                "sample_rate" => number().filter(|n| apu::SAMPLE_RATES.contains(n)).map(|n| settings.sample_rate = n),
+                "audio_backend" => audio_backend_from_key(value).map(|backend| settings.audio_backend = backend),
+                _ => {
+                    warn!("Ignoring unknown setting '{}'", key);
+                    continue;
+                }
+            };
+            if parsed.is_none() {
+                warn!("Ignoring setting '{}': bad value '{}'", key, value);
+            }
+        }
+        settings
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let text = format!(
+            "autofire_buttons = {}\nautofire_period = {}\nautofire_duty = {}\nopposing_directions = {}\n\
+             stick_deadzone = {}\nstick_sensitivity = {}\nstick_eight_way = {}\nfast_forward_audio = {}\n\
+             mute_on_pause = {}\nband_limited_audio = {}\n\
+             hardware_filters = {}\nsilence_ultrasonic_triangle = {}\nsample_rate = {}\naudio_backend = {}\n",
+            self.autofire_buttons.bits(),
+            self.autofire_period,
+            self.autofire_duty,
+            opposing_directions_key(self.opposing_directions),
+            self.stick_deadzone,
+            self.stick_sensitivity,
+            self.stick_eight_way,
+            fast_forward_audio_key(self.fast_forward_audio),
+            self.mute_on_pause,
+            self.band_limited_audio,
+            self.hardware_filters,
+            self.silence_ultrasonic_triangle,
+            self.sample_rate,
+            audio_backend_key(self.audio_backend)
+        );
+        fs::write(SETTINGS_PATH, text).map_err(|e| format!("Failed to save settings to {}: {}", SETTINGS_PATH, e))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn opposing_directions_key(mode: OpposingDirections) -> &'static str {
+    match mode {
+        OpposingDirections::Allow => "allow",
+        OpposingDirections::Block => "block",
+        OpposingDirections::LastPressed => "last_pressed",
+    }
+}
+
+fn opposing_directions_from_key(key: &str) -> Option<OpposingDirections> {
+    OpposingDirections::ALL.into_iter().find(|&mode| opposing_directions_key(mode) == key)
+}
+
+fn fast_forward_audio_key(mode: FastForwardAudio) -> &'static str {
+    match mode {
+        FastForwardAudio::Mute => "mute",
+        FastForwardAudio::Pitch => "pitch",
+        FastForwardAudio::Skip => "skip",
+    }
+}
+
+fn fast_forward_audio_from_key(key: &str) -> Option<FastForwardAudio> {
+    FastForwardAudio::ALL.into_iter().find(|&mode| fast_forward_audio_key(mode) == key)
+}
+
+fn audio_backend_key(backend: AudioBackend) -> &'static str {
+    match backend {
+        AudioBackend::Queue => "queue",
+        AudioBackend::Callback => "callback",
+    }
+}
+
+fn audio_backend_from_key(key: &str) -> Option<AudioBackend> {
+    AudioBackend::ALL.into_iter().find(|&backend| audio_backend_key(backend) == key)
+}
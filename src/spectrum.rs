@@ -0,0 +1,72 @@
+// src/spectrum.rs
+
+use std::f32::consts::PI;
+
+/// Floor of `magnitude_spectrum`, so silent bins do not come out as minus infinity.
+pub const SPECTRUM_FLOOR_DB: f32 = -120.0;
+
+/// Magnitude spectrum of `samples`, for the audio debug view. The input is cut to its
+/// last power-of-two `len` samples and Hann-windowed. Entry `k` of the result is the
+/// level at `k * sample_rate / len` Hz, in dB relative to a full-scale sine, from DC up
+/// to just below half the sample rate.
+pub fn magnitude_spectrum(samples: &[f32]) -> Vec<f32> {
+    if samples.len() < 2 {
+        return Vec::new();
+    }
+    let len = 1 << samples.len().ilog2();
+    let samples = &samples[samples.len() - len..];
+
+    let mut re: Vec<f32> = samples
+        .iter()
+        .enumerate()
+        .map(|(i, &s)| s * (0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos()))
+        .collect();
+    let mut im = vec![0.0; len];
+    fft(&mut re, &mut im);
+
+    // A full-scale sine peaks at len / 4: half from the one-sided spectrum, half from
+    // the window's average gain
+    let full_scale = len as f32 / 4.0;
+    re.iter()
+        .zip(&im)
+        .take(len / 2)
+        .map(|(r, i)| (20.0 * ((r * r + i * i).sqrt() / full_scale).log10()).max(SPECTRUM_FLOOR_DB))
+        .collect()
+}
+
+// In-place iterative radix-2 FFT; the length must be a power of two
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut size = 2;
+    while size <= n {
+        let step = -2.0 * PI / size as f32;
+        for start in (0..n).step_by(size) {
+            for k in 0..size / 2 {
+                let (sin, cos) = (step * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + size / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        size <<= 1;
+    }
+}
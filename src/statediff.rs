@@ -0,0 +1,140 @@
+// src/statediff.rs
+
+use std::fmt;
+use std::fs;
+
+use crate::cpu::EmulatorSnapshot;
+
+/// One byte that differs between two states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteDiff {
+    pub addr: usize,
+    pub left: u8,
+    pub right: u8,
+}
+
+/// A named register whose value differs between two states.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterDiff {
+    pub name: &'static str,
+    pub left: String,
+    pub right: String,
+}
+
+/// A memory region of a state, compared byte by byte.
+#[derive(Clone, Debug, Default)]
+pub struct RegionDiff {
+    pub bytes: Vec<ByteDiff>,
+    /// Sizes of the two arrays when they differ; only the common prefix is compared.
+    pub size_mismatch: Option<(usize, usize)>,
+}
+
+impl RegionDiff {
+    fn between(left: &[u8], right: &[u8]) -> Self {
+        let bytes = left
+            .iter()
+            .zip(right)
+            .enumerate()
+            .filter(|(_, (l, r))| l != r)
+            .map(|(addr, (&left, &right))| ByteDiff { addr, left, right })
+            .collect();
+        let size_mismatch = (left.len() != right.len()).then_some((left.len(), right.len()));
+        RegionDiff { bytes, size_mismatch }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty() && self.size_mismatch.is_none()
+    }
+}
+
+/// Everything that differs between two save states, for tracking down desyncs and
+/// save/load round trips that are not exact. Addresses are offsets into each array:
+/// CPU RAM from $0000, PPU VRAM from the first nametable, OAM and palette from 0.
+#[derive(Clone, Debug, Default)]
+pub struct StateDiff {
+    pub registers: Vec<RegisterDiff>,
+    pub cpu_ram: RegionDiff,
+    pub vram: RegionDiff,
+    pub oam: RegionDiff,
+    pub palette: RegionDiff,
+    pub chr_ram: RegionDiff,
+}
+
+impl StateDiff {
+    pub fn between(mut left: EmulatorSnapshot, mut right: EmulatorSnapshot) -> Self {
+        let registers = left
+            .registers()
+            .into_iter()
+            .zip(right.registers())
+            .filter(|((_, l), (_, r))| l != r)
+            .map(|((name, left), (_, right))| RegisterDiff { name, left, right })
+            .collect();
+
+        let left_mem = left.take_memory();
+        let right_mem = right.take_memory();
+        let empty = Vec::new();
+        StateDiff {
+            registers,
+            cpu_ram: RegionDiff::between(&left_mem.cpu_ram, &right_mem.cpu_ram),
+            vram: RegionDiff::between(&left_mem.vram, &right_mem.vram),
+            oam: RegionDiff::between(&left_mem.oam, &right_mem.oam),
+            palette: RegionDiff::between(&left_mem.palette, &right_mem.palette),
+            chr_ram: RegionDiff::between(
+                left_mem.chr_ram.as_ref().unwrap_or(&empty),
+                right_mem.chr_ram.as_ref().unwrap_or(&empty),
+            ),
+        }
+    }
+
+    /// Decodes two `.state` files, as written by the front-end, and compares them.
+    pub fn from_files(left_path: &str, right_path: &str) -> Result<Self, String> {
+        Ok(StateDiff::between(read_state(left_path)?, read_state(right_path)?))
+    }
+
+    /// The memory regions with their display names, in a fixed order.
+    pub fn regions(&self) -> [(&'static str, &RegionDiff); 5] {
+        [
+            ("CPU RAM", &self.cpu_ram),
+            ("PPU VRAM", &self.vram),
+            ("OAM", &self.oam),
+            ("Palette", &self.palette),
+            ("CHR RAM", &self.chr_ram),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registers.is_empty() && self.regions().iter().all(|(_, region)| region.is_empty())
+    }
+}
+
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "States are identical");
+        }
+        if !self.registers.is_empty() {
+            writeln!(f, "Registers:")?;
+            for reg in &self.registers {
+                writeln!(f, "  {:<18} {} -> {}", reg.name, reg.left, reg.right)?;
+            }
+        }
+        for (name, region) in self.regions() {
+            if region.is_empty() {
+                continue;
+            }
+            writeln!(f, "{}: {} byte(s) differ", name, region.bytes.len())?;
+            if let Some((left, right)) = region.size_mismatch {
+                writeln!(f, "  size {} -> {} bytes", left, right)?;
+            }
+            for byte in &region.bytes {
+                writeln!(f, "  ${:04X}  {:02X} -> {:02X}", byte.addr, byte.left, byte.right)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_state(path: &str) -> Result<EmulatorSnapshot, String> {
+    let file = fs::File::open(path).map_err(|e| format!("Failed to open state '{}': {}", path, e))?;
+    bincode::deserialize_from(file).map_err(|e| format!("Failed to deserialize state '{}': {}", path, e))
+}
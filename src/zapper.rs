@@ -0,0 +1,103 @@
+// src/zapper.rs
+
+use crate::ppu::NesPPU;
+use crate::render::{self, frame::Frame};
+
+/// $4017 bit that reads 0 while the photodiode sees light.
+const LIGHT_NOT_DETECTED: u8 = 0x08;
+/// $4017 bit that reads 1 while the trigger is pulled.
+const TRIGGER_PULLED: u8 = 0x10;
+
+/// The photodiode keeps reporting light for roughly this many scanlines after the
+/// beam has passed the point the gun is aimed at.
+const LIGHT_SCANLINES: u16 = 26;
+/// Average of a pixel's RGB levels needed for the sensor to see it.
+const LIGHT_THRESHOLD: u32 = 0xC0;
+/// Pixels either side of the aim point that fall inside the sensor's view.
+const SENSOR_RADIUS: usize = 2;
+
+/// Light gun for controller port 2, as used by Duck Hunt and Wild Gunman.
+///
+/// The picture is only rendered once a frame, so the sensor looks at the frame the
+/// PPU is currently drawing by rendering it from the PPU state the first time the
+/// beam reaches the aim point.
+pub struct Zapper {
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+    frame: Frame,
+    // Frame number whose picture `lit` was sampled from
+    sampled_frame: Option<u64>,
+    lit: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            aim: None,
+            trigger: false,
+            frame: Frame::new(),
+            sampled_frame: None,
+            lit: false,
+        }
+    }
+
+    /// Point on the 256x240 picture the gun is aimed at, or None when off screen.
+    pub fn set_aim(&mut self, aim: Option<(usize, usize)>) {
+        self.aim = aim.filter(|&(x, y)| x < Frame::WIDTH && y < Frame::HEIGHT);
+        self.sampled_frame = None;
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    /// Value of $4017 with the Zapper plugged into port 2. `frame_number` tells
+    /// frames apart so the picture is sampled once per frame.
+    pub fn read(&mut self, ppu: &NesPPU, frame_number: u64) -> u8 {
+        let mut data = 0;
+        if !self.detects_light(ppu, frame_number) {
+            data |= LIGHT_NOT_DETECTED;
+        }
+        if self.trigger {
+            data |= TRIGGER_PULLED;
+        }
+        data
+    }
+
+    fn detects_light(&mut self, ppu: &NesPPU, frame_number: u64) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+
+        // Only lit while the beam is at or just below the aim point
+        let scanline = ppu.scanline() as usize;
+        if scanline < y || scanline >= y + LIGHT_SCANLINES as usize {
+            return false;
+        }
+
+        if self.sampled_frame != Some(frame_number) {
+            render::render(ppu, &mut self.frame);
+            self.lit = self.area_is_lit(x, y);
+            self.sampled_frame = Some(frame_number);
+        }
+        self.lit
+    }
+
+    fn area_is_lit(&self, x: usize, y: usize) -> bool {
+        let rows = y.saturating_sub(SENSOR_RADIUS)..(y + SENSOR_RADIUS + 1).min(Frame::HEIGHT);
+        rows.into_iter().any(|py| {
+            let columns = x.saturating_sub(SENSOR_RADIUS)..(x + SENSOR_RADIUS + 1).min(Frame::WIDTH);
+            columns.into_iter().any(|px| {
+                let pixel = &self.frame.data[(py * Frame::WIDTH + px) * 3..][..3];
+                let level = (pixel[0] as u32 + pixel[1] as u32 + pixel[2] as u32) / 3;
+                level >= LIGHT_THRESHOLD
+            })
+        })
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}